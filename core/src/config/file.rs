@@ -0,0 +1,569 @@
+//! The config file (`karaokify.toml`), which can contain any of the settings that are read from
+//! the environment.
+//!
+//! The keys are the names of the environment variables without the `KARAOKIFY_` prefix, in
+//! lowercase. Tables are joined with `_`, so `KARAOKIFY_DEMUCS_TIMEOUT_RATIO` can be set as
+//! `demucs_timeout_ratio = 6` or as `timeout_ratio = 6` in a `[demucs]` table. Arrays are the
+//! same as the comma separated lists, and tables (eg. `[processing_ratios]`) are the same as
+//! lists of `key=value` pairs.
+//!
+//! Only the parts of TOML the settings need are supported: tables, dotted keys, strings,
+//! numbers, booleans, arrays and inline tables.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Used if `KARAOKIFY_CONFIG` isn't set and the file exists
+const DEFAULT_PATH: &str = "karaokify.toml";
+
+#[derive(Debug)]
+pub struct ConfigFile {
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+impl ConfigFile {
+    /// Read the file from `KARAOKIFY_CONFIG`, or `karaokify.toml` if it exists.
+    ///
+    /// Panics if the file can't be read or isn't valid, like invalid environment variables.
+    pub fn load() -> Option<Self> {
+        let path = match std::env::var_os("KARAOKIFY_CONFIG") {
+            Some(path) if !path.is_empty() => PathBuf::from(path),
+            _ if Path::new(DEFAULT_PATH).is_file() => PathBuf::from(DEFAULT_PATH),
+            _ => return None,
+        };
+
+        let text = match std::fs::read_to_string(&path) {
+            Ok(x) => x,
+            Err(e) => panic!("Could not read the config file {}: {e}", path.display()),
+        };
+        match Self::parse(path, &text) {
+            Ok(x) => Some(x),
+            Err((path, e)) => panic!("Invalid config file {}:{e}", path.display()),
+        }
+    }
+
+    /// Parse the text of the file at `path`, returning the path back with the error
+    pub(crate) fn parse(path: PathBuf, text: &str) -> Result<Self, (PathBuf, ParseError)> {
+        match parse(text) {
+            Ok(entries) => Ok(Self { path, entries }),
+            Err(e) => Err((path, e)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The value of the setting in the same format as the environment variable, along with
+    /// where it's set (eg. `demucs.timeout_ratio (karaokify.toml:12)`)
+    pub fn get(&self, var_name: &str) -> Option<(String, String)> {
+        let name = var_name
+            .strip_prefix("KARAOKIFY_")
+            .unwrap_or(var_name)
+            .to_lowercase();
+
+        if let Some(entry) = self.entries.iter().find(|x| x.name() == name) {
+            entry.used.store(true, Ordering::Relaxed);
+            return Some((entry.value.clone(), self.location(entry)));
+        }
+
+        // The entries of a table are the pairs of a list, eg. `[processing_ratios]`
+        let pairs = self
+            .entries
+            .iter()
+            .filter(|x| x.table_name().is_some_and(|x| x == name))
+            .collect::<Vec<_>>();
+        let first = pairs.first()?;
+
+        let value = pairs
+            .iter()
+            .map(|x| {
+                x.used.store(true, Ordering::Relaxed);
+                let key = x.key.last().map_or("", String::as_str);
+                // They'd be split into more pairs
+                assert!(
+                    !key.contains([',', '=']) && !x.value.contains(','),
+                    "{} can't contain `,` in a table of pairs",
+                    self.location(x)
+                );
+                format!("{key}={}", x.value)
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let table = &first.key[..first.key.len() - 1];
+        let location = format!(
+            "{} ({}:{})",
+            table.join("."),
+            self.path.display(),
+            first.line
+        );
+
+        Some((value, location))
+    }
+
+    /// The settings that were never read, with where they're set. They're either misspelled or
+    /// not used with the other settings.
+    pub fn unused(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|x| !x.used.load(Ordering::Relaxed))
+            .map(|x| self.location(x))
+            .collect()
+    }
+
+    fn location(&self, entry: &Entry) -> String {
+        format!(
+            "{} ({}:{})",
+            entry.key.join("."),
+            self.path.display(),
+            entry.line
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// The table and the key, eg. `["demucs", "timeout_ratio"]`
+    key: Vec<String>,
+    /// The value as it would be in the environment variable, eg. `a,b` for an array
+    value: String,
+    line: usize,
+    used: AtomicBool,
+}
+impl Entry {
+    /// Name of the environment variable (without the prefix), eg. `demucs_timeout_ratio`
+    fn name(&self) -> String {
+        self.key.join("_").to_lowercase()
+    }
+
+    fn table_name(&self) -> Option<String> {
+        let (_, table) = self.key.split_last()?;
+        if table.is_empty() {
+            return None;
+        }
+
+        Some(table.join("_").to_lowercase())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ParseError {
+    line: usize,
+    message: String,
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+fn parse(text: &str) -> Result<Vec<Entry>, ParseError> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        pos: 0,
+        line: 1,
+    };
+    let mut table = vec![];
+    let mut entries = Vec::<Entry>::new();
+
+    loop {
+        parser.skip_blank(true);
+        let Some(c) = parser.peek() else {
+            break;
+        };
+
+        if c == '[' {
+            parser.pos += 1;
+            if parser.peek() == Some('[') {
+                return Err(parser.error("arrays of tables aren't supported"));
+            }
+            table = parser.key()?;
+            parser.expect(']')?;
+            parser.end_of_line()?;
+            continue;
+        }
+
+        let line = parser.line;
+        let mut key = table.clone();
+        key.extend(parser.key()?);
+        parser.expect('=')?;
+        let value = parser.value()?;
+        parser.end_of_line()?;
+
+        if entries.iter().any(|x| x.key == key) {
+            return Err(ParseError {
+                line,
+                message: format!("{} is set more than once", key.join(".")),
+            });
+        }
+        entries.push(Entry {
+            key,
+            value,
+            line,
+            used: AtomicBool::new(false),
+        });
+    }
+
+    Ok(entries)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+
+        Some(c)
+    }
+
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError {
+            line: self.line,
+            message: message.into(),
+        }
+    }
+
+    /// Skip spaces and comments, and the line breaks too if `newlines` is set
+    fn skip_blank(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => {}
+                '\n' if newlines => {}
+                '#' => {
+                    while self.peek().is_some_and(|x| x != '\n') {
+                        self.pos += 1;
+                    }
+                    continue;
+                }
+                _ => break,
+            }
+            self.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        self.skip_blank(false);
+        if self.peek() != Some(expected) {
+            return Err(self.error(format!("expected `{expected}`")));
+        }
+        self.next();
+
+        Ok(())
+    }
+
+    fn end_of_line(&mut self) -> Result<(), ParseError> {
+        self.skip_blank(false);
+        match self.next() {
+            None | Some('\n') => Ok(()),
+            Some(c) => Err(self.error(format!("unexpected `{c}` after the value"))),
+        }
+    }
+
+    /// A (dotted) key, eg. `demucs.timeout_ratio` or `"quoted key"`
+    fn key(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut res = vec![];
+        loop {
+            self.skip_blank(false);
+            let part = match self.peek() {
+                Some('"') => self.basic_string()?,
+                Some('\'') => self.literal_string()?,
+                _ => {
+                    let start = self.pos;
+                    while self
+                        .peek()
+                        .is_some_and(|x| x.is_ascii_alphanumeric() || x == '_' || x == '-')
+                    {
+                        self.pos += 1;
+                    }
+                    if start == self.pos {
+                        return Err(self.error("expected a key"));
+                    }
+                    self.chars[start..self.pos].iter().collect()
+                }
+            };
+            res.push(part);
+
+            self.skip_blank(false);
+            if self.peek() != Some('.') {
+                return Ok(res);
+            }
+            self.pos += 1;
+        }
+    }
+
+    /// A value, in the format of the environment variables
+    fn value(&mut self) -> Result<String, ParseError> {
+        self.skip_blank(false);
+        match self.peek() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            _ => self.scalar(),
+        }
+    }
+
+    fn array(&mut self) -> Result<String, ParseError> {
+        self.next();
+        let mut items = vec![];
+        loop {
+            self.skip_blank(true);
+            if self.peek() == Some(']') {
+                self.next();
+                break;
+            }
+            if matches!(self.peek(), Some('[' | '{')) {
+                return Err(self.error("nested arrays and tables aren't supported"));
+            }
+            // The items are separated by commas, like in the environment variable
+            let item = self.value()?;
+            if item.contains(',') {
+                return Err(self.error("the items of arrays can't contain `,`"));
+            }
+            items.push(item);
+
+            self.skip_blank(true);
+            match self.next() {
+                Some(',') => {}
+                Some(']') => break,
+                _ => return Err(self.error("expected `,` or `]` in the array")),
+            }
+        }
+
+        Ok(items.join(","))
+    }
+
+    fn inline_table(&mut self) -> Result<String, ParseError> {
+        self.next();
+        let mut pairs = vec![];
+        loop {
+            self.skip_blank(false);
+            if self.peek() == Some('}') {
+                self.next();
+                break;
+            }
+            let key = self.key()?.join(".");
+            self.expect('=')?;
+            self.skip_blank(false);
+            if matches!(self.peek(), Some('[' | '{')) {
+                return Err(self.error("nested arrays and tables aren't supported"));
+            }
+            // The pairs are `key=value`, separated by commas like in the environment variable
+            let value = self.value()?;
+            if key.contains([',', '=']) {
+                return Err(self.error("the keys of inline tables can't contain `,` or `=`"));
+            }
+            if value.contains(',') {
+                return Err(self.error("the values of inline tables can't contain `,`"));
+            }
+            pairs.push(format!("{key}={value}"));
+
+            self.skip_blank(false);
+            match self.next() {
+                Some(',') => {}
+                Some('}') => break,
+                _ => return Err(self.error("expected `,` or `}` in the inline table")),
+            }
+        }
+
+        Ok(pairs.join(","))
+    }
+
+    fn basic_string(&mut self) -> Result<String, ParseError> {
+        self.next();
+        let mut res = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some('"') => return Ok(res),
+                Some('\\') => {
+                    let c = match self.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('u') => self.unicode_escape()?,
+                        _ => return Err(self.error("invalid escape in the string")),
+                    };
+                    res.push(c);
+                }
+                Some(c) => res.push(c),
+            }
+        }
+    }
+
+    fn unicode_escape(&mut self) -> Result<char, ParseError> {
+        let hex = (0..4).filter_map(|_| self.next()).collect::<String>();
+
+        u32::from_str_radix(&hex, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| self.error(format!("invalid unicode escape `\\u{hex}`")))
+    }
+
+    fn literal_string(&mut self) -> Result<String, ParseError> {
+        self.next();
+        let mut res = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some('\'') => return Ok(res),
+                Some(c) => res.push(c),
+            }
+        }
+    }
+
+    /// A boolean or a number
+    fn scalar(&mut self) -> Result<String, ParseError> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|x| x.is_ascii_alphanumeric() || matches!(x, '_' | '-' | '+' | '.'))
+        {
+            self.pos += 1;
+        }
+        let token = self.chars[start..self.pos].iter().collect::<String>();
+
+        if matches!(token.as_str(), "true" | "false") {
+            return Ok(token);
+        }
+
+        let number = token.replace('_', "");
+        if number.parse::<i64>().is_ok() || number.parse::<f64>().is_ok_and(f64::is_finite) {
+            return Ok(number);
+        }
+
+        Err(self.error(if token.is_empty() {
+            "expected a value".to_string()
+        } else {
+            format!("invalid value `{token}`, strings have to be quoted")
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(text: &str) -> Result<Vec<(String, String)>, usize> {
+        parse(text)
+            .map(|entries| {
+                entries
+                    .into_iter()
+                    .map(|x| (x.key.join("."), x.value))
+                    .collect()
+            })
+            .map_err(|e| e.line)
+    }
+
+    #[test]
+    fn values_are_parsed_like_the_environment_variables() {
+        let cases: &[(&str, &[(&str, &str)])] = &[
+            ("", &[]),
+            ("# Only a comment\n\n", &[]),
+            ("max_zip_size = 1_000_000", &[("max_zip_size", "1000000")]),
+            ("ratio = 2.5 # comment", &[("ratio", "2.5")]),
+            ("search = true", &[("search", "true")]),
+            (r#"name = "a \"b\" \u0161""#, &[("name", "a \"b\" š")]),
+            (r"path = 'C:\songs'", &[("path", r"C:\songs")]),
+            (r#""quoted key" = 1"#, &[("quoted key", "1")]),
+            ("demucs.timeout_ratio = 6", &[("demucs.timeout_ratio", "6")]),
+            (
+                "[demucs]\ntimeout_ratio = 6\nmin_timeout = 60",
+                &[("demucs.timeout_ratio", "6"), ("demucs.min_timeout", "60")],
+            ),
+            (
+                "handlers = [\n  \"yams\", # first\n  'spotifydown',\n]",
+                &[("handlers", "yams,spotifydown")],
+            ),
+            ("hosts = []", &[("hosts", "")]),
+            (
+                "ratios = { cpu = 2, cuda = 0.5 }",
+                &[("ratios", "cpu=2,cuda=0.5")],
+            ),
+        ];
+
+        for (text, expected) in cases {
+            let expected = expected
+                .iter()
+                .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+                .collect::<Vec<_>>();
+
+            assert_eq!(parsed(text), Ok(expected), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn invalid_files_are_rejected_with_the_line() {
+        let cases = [
+            ("name = unquoted", 1),
+            ("name = \"unterminated\nnext = 1", 2),
+            ("a = 1\na = 2", 2),
+            (
+                "[demucs]\ntimeout_ratio = 1\n[demucs]\ntimeout_ratio = 2",
+                4,
+            ),
+            ("a = 1 b = 2", 1),
+            ("[[songs]]", 1),
+            ("a = [[1]]", 1),
+            ("a = { b = [1] }", 1),
+            ("a = {b=[1]}", 1),
+            ("a = { b = { c = 1 } }", 1),
+            ("a = [\"x,y\"]", 1),
+            ("\n\na = [\n  'x',\n  'y,z',\n]", 5),
+            ("a = { b = \"x,y\" }", 1),
+            ("a = { \"b=c\" = 1 }", 1),
+            ("a = \"\\x\"", 1),
+            ("a =", 1),
+            ("= 1", 1),
+        ];
+
+        for (text, line) in cases {
+            assert_eq!(parsed(text).map(|_| ()), Err(line), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn tables_are_read_as_lists_of_pairs() {
+        let text = "[processing_ratios]\ncpu = 2\ncuda = 0.5\n\n[demucs]\ndevice = 'cpu'";
+        let file =
+            ConfigFile::parse(PathBuf::from("karaokify.toml"), text).expect("Valid config file");
+
+        let (value, location) = file
+            .get("KARAOKIFY_PROCESSING_RATIOS")
+            .expect("Table is set");
+        assert_eq!(value, "cpu=2,cuda=0.5");
+        assert_eq!(location, "processing_ratios (karaokify.toml:2)");
+
+        let (value, location) = file.get("KARAOKIFY_DEMUCS_DEVICE").expect("Key is set");
+        assert_eq!(value, "cpu");
+        assert_eq!(location, "demucs.device (karaokify.toml:6)");
+
+        assert!(file.get("KARAOKIFY_MISSING").is_none());
+        assert!(file.unused().is_empty());
+    }
+
+    #[test]
+    fn settings_that_are_never_read_are_unused() {
+        let file = ConfigFile::parse(PathBuf::from("karaokify.toml"), "demucs_tmieout = 1")
+            .expect("Valid config file");
+
+        assert_eq!(file.unused(), vec!["demucs_tmieout (karaokify.toml:1)"]);
+    }
+}
@@ -0,0 +1,498 @@
+//! Settings of downloading and processing the songs.
+//!
+//! They're read from the environment by default, the same variables the bot uses, or from the
+//! config file (see [`file`]) if they aren't set. Services embedding the crate can set them with
+//! [`Config::init`] instead.
+
+use std::{env, fmt::Debug, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+
+use once_cell::sync::{Lazy, OnceCell};
+use url::Url;
+
+pub use self::file::ConfigFile;
+use crate::{downloader::HandlerKind, processor::demucs::DemucsDevice};
+
+pub mod file;
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+static FILE: Lazy<Option<ConfigFile>> = Lazy::new(ConfigFile::load);
+
+/// Quality yams downloads the songs in, by service
+const DEFAULT_YAMS_QUALITY: &[(&str, &str)] = &[
+    ("spotify", "very_high"),
+    ("qobuz", "27"),
+    ("tidal", "3"),
+    ("apple", "high"),
+    ("deezer", "2"),
+    ("youtube", "0"),
+];
+
+#[derive(Debug)]
+pub struct Config {
+    /// Directory where the temp files (eg. downloads and stems) are stored
+    pub work_dir: PathBuf,
+    /// Maximum total size of the temp files of the running jobs
+    pub work_dir_max_size: Option<u64>,
+    /// Temp files older than this that aren't in use are left over from crashes and get removed
+    pub temp_max_age: Duration,
+    /// Path to (or name of) the `demucs` executable
+    pub demucs_path: PathBuf,
+    /// Run demucs in a Docker container instead of using `demucs_path`
+    pub demucs_docker: Option<DemucsDockerConfig>,
+    /// Path to (or name of) the `ffmpeg` executable
+    pub ffmpeg_path: PathBuf,
+    /// Path to (or name of) the `ffprobe` executable
+    pub ffprobe_path: PathBuf,
+    /// Path to (or name of) the `yt-dlp` executable, used to download from `SoundCloud`
+    pub ytdlp_path: PathBuf,
+    /// Demucs is killed if it takes this many times longer than the song
+    pub demucs_timeout_ratio: f64,
+    /// Demucs always gets at least this long before it's killed
+    pub demucs_min_timeout: Duration,
+    /// Device demucs runs on, by default CUDA if it works and the CPU otherwise
+    pub demucs_device: DemucsDevice,
+    /// Fail at startup instead of falling back to the CPU if `demucs_device` is CUDA and it
+    /// doesn't work
+    pub demucs_device_strict: bool,
+    /// Karaoke videos are cut off after this long
+    pub max_video_duration: Duration,
+    /// Maximum total size of the files extracted from a downloaded zip
+    pub max_zip_size: u64,
+    /// Maximum size of a file downloaded from a provider
+    pub max_download_size: u64,
+    /// User-Agent header of the HTTP requests to the download providers
+    pub user_agent: String,
+    /// The enabled download handlers, in the order they're tried
+    pub handlers: Vec<HandlerKind>,
+    /// How long a handler can take to download a song before the next one is tried
+    pub handler_timeout: Duration,
+    /// After this many failed downloads in a row, a handler is skipped for a while
+    pub handler_failure_threshold: usize,
+    /// How long a handler that keeps failing is skipped for
+    pub handler_cooldown: Duration,
+    pub yams: YamsConfig,
+    pub spotifydown: SpotifydownConfig,
+    pub worker: WorkerConfig,
+}
+
+/// Settings of the yams.tf download provider
+#[derive(Debug)]
+pub struct YamsConfig {
+    pub api_url: Url,
+    /// Where yams uploads the downloaded songs, in the order they're tried
+    pub hosts: Vec<String>,
+    /// Quality the songs are downloaded in, by service (eg. `tidal`)
+    pub quality: Vec<(String, String)>,
+}
+impl YamsConfig {
+    fn from_env() -> Self {
+        let quality = yams_quality(env_var_pairs("YAMS_QUALITY_OVERRIDES"));
+
+        let mut hosts = env_var_list::<String>("YAMS_HOSTS");
+        if hosts.is_empty() {
+            hosts = vec![env_var("YAMS_HOST").unwrap_or_else(|| "filehaus".to_string())];
+        }
+
+        Self {
+            api_url: env_var("YAMS_API_URL")
+                .unwrap_or_else(|| Url::parse("https://yams.tf/api").expect("Invalid API URL")),
+            hosts,
+            quality,
+        }
+    }
+}
+
+/// The default yams qualities with the `overrides` (as `service=quality`) applied
+fn yams_quality(overrides: Vec<(String, String)>) -> Vec<(String, String)> {
+    let mut quality = DEFAULT_YAMS_QUALITY
+        .iter()
+        .map(|(service, quality)| ((*service).to_string(), (*quality).to_string()))
+        .collect::<Vec<_>>();
+
+    for (service, value) in overrides {
+        let Some(entry) = quality
+            .iter_mut()
+            .find(|(x, _)| x.eq_ignore_ascii_case(&service))
+        else {
+            panic!(
+                "Unknown service {service:?} in YAMS_QUALITY_OVERRIDES, expected one of: {}",
+                DEFAULT_YAMS_QUALITY
+                    .iter()
+                    .map(|(x, _)| *x)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        };
+        entry.1 = value;
+    }
+
+    quality
+}
+/// Settings of the spotifydown.com download provider
+#[derive(Debug)]
+pub struct SpotifydownConfig {
+    /// The site the requests claim to come from
+    pub site_url: Url,
+    pub api_url: Url,
+}
+impl SpotifydownConfig {
+    fn from_env() -> Self {
+        Self {
+            site_url: env_var("SPOTIFYDOWN_URL").unwrap_or_else(|| {
+                Url::parse("https://spotifydown.com").expect("Invalid site URL")
+            }),
+            api_url: env_var("SPOTIFYDOWN_API_URL").unwrap_or_else(|| {
+                Url::parse("https://api.spotifydown.com").expect("Invalid API URL")
+            }),
+        }
+    }
+}
+
+/// Settings of running demucs in a Docker container
+#[derive(Debug)]
+pub struct DemucsDockerConfig {
+    /// Image with the `demucs` executable (`KARAOKIFY_DEMUCS_DOCKER_IMAGE`)
+    pub image: String,
+    /// Path to (or name of) the `docker` executable
+    pub docker_path: PathBuf,
+    /// Extra `docker run` arguments, eg. `--volume,demucs-models:/root/.cache/torch` to keep
+    /// the downloaded models
+    pub extra_args: Vec<String>,
+}
+impl DemucsDockerConfig {
+    fn from_env() -> Option<Self> {
+        Some(Self {
+            image: env_var("KARAOKIFY_DEMUCS_DOCKER_IMAGE")?,
+            docker_path: env_var("DOCKER_PATH").unwrap_or_else(|| PathBuf::from("docker")),
+            extra_args: env_var_list("KARAOKIFY_DEMUCS_DOCKER_ARGS"),
+        })
+    }
+}
+/// Settings of splitting the songs on another machine, shared by the bot and the worker
+#[derive(Debug)]
+pub struct WorkerConfig {
+    /// Where the bot sends the songs to be split (`KARAOKIFY_WORKER_URL`). Demucs is run
+    /// locally if it's not set.
+    pub url: Option<Url>,
+    /// Where the worker listens (`KARAOKIFY_WORKER_ADDR`)
+    pub listen_addr: SocketAddr,
+    /// Sent by the bot with every request and checked by the worker
+    /// (`KARAOKIFY_WORKER_SECRET`)
+    pub secret: Option<String>,
+}
+impl WorkerConfig {
+    fn from_env() -> Self {
+        let url = env_var("KARAOKIFY_WORKER_URL");
+        let secret = env_var("KARAOKIFY_WORKER_SECRET");
+        assert!(
+            url.is_none() || secret.is_some(),
+            "KARAOKIFY_WORKER_SECRET must be set if KARAOKIFY_WORKER_URL is"
+        );
+
+        Self {
+            url,
+            listen_addr: env_var("KARAOKIFY_WORKER_ADDR")
+                .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 8787))),
+            secret,
+        }
+    }
+}
+impl Config {
+    pub fn global() -> &'static Self {
+        CONFIG.get_or_init(Self::from_env)
+    }
+
+    /// Use these settings instead of the ones from the environment.
+    ///
+    /// Has to be called before anything is downloaded or processed. Returns whether the
+    /// settings were used, they aren't if the global ones are already set.
+    pub fn init(config: Self) -> bool {
+        CONFIG.set(config).is_ok()
+    }
+
+    /// Read the settings from the environment variables
+    pub fn from_env() -> Self {
+        Self {
+            work_dir: env_var("KARAOKIFY_WORK_DIR").unwrap_or_else(env::temp_dir),
+            work_dir_max_size: env_var_positive("KARAOKIFY_WORK_DIR_MAX_GB")
+                .map(|x| x as u64 * 1000 * 1000 * 1000),
+            temp_max_age: Duration::from_secs(
+                env_var_positive("KARAOKIFY_TEMP_MAX_AGE_HOURS").unwrap_or(6) as u64 * 60 * 60,
+            ),
+            demucs_path: env_var("DEMUCS_PATH").unwrap_or_else(|| PathBuf::from("demucs")),
+            demucs_docker: DemucsDockerConfig::from_env(),
+            ffmpeg_path: env_var("FFMPEG_PATH").unwrap_or_else(|| PathBuf::from("ffmpeg")),
+            ffprobe_path: env_var("FFPROBE_PATH").unwrap_or_else(|| PathBuf::from("ffprobe")),
+            ytdlp_path: env_var("YTDLP_PATH").unwrap_or_else(|| PathBuf::from("yt-dlp")),
+            demucs_timeout_ratio: env_var_positive_f64("KARAOKIFY_DEMUCS_TIMEOUT_RATIO")
+                .unwrap_or(4.0),
+            demucs_min_timeout: Duration::from_secs(
+                env_var_positive("KARAOKIFY_DEMUCS_MIN_TIMEOUT_MINS").unwrap_or(10) as u64 * 60,
+            ),
+            demucs_device: env_var("KARAOKIFY_DEMUCS_DEVICE").unwrap_or_default(),
+            demucs_device_strict: env_var("KARAOKIFY_DEMUCS_DEVICE_STRICT").unwrap_or(false),
+            max_video_duration: Duration::from_secs(
+                env_var_positive("KARAOKIFY_MAX_VIDEO_MINS").unwrap_or(10) as u64 * 60,
+            ),
+            max_zip_size: env_var_positive("KARAOKIFY_MAX_ZIP_MB").unwrap_or(1000) as u64
+                * 1000
+                * 1000,
+            max_download_size: env_var_positive("KARAOKIFY_MAX_DOWNLOAD_MB").unwrap_or(200) as u64
+                * 1000
+                * 1000,
+            user_agent: env_var("KARAOKIFY_USER_AGENT")
+                .unwrap_or_else(|| format!("karaokify/{}", env!("CARGO_PKG_VERSION"))),
+            handlers: handlers_from_env(),
+            handler_timeout: Duration::from_secs(
+                env_var_positive("KARAOKIFY_HANDLER_TIMEOUT_SECS").unwrap_or(90) as u64,
+            ),
+            handler_failure_threshold: env_var_positive("KARAOKIFY_HANDLER_FAILURE_THRESHOLD")
+                .unwrap_or(3),
+            handler_cooldown: Duration::from_secs(
+                env_var_positive("KARAOKIFY_HANDLER_COOLDOWN_SECS").unwrap_or(300) as u64,
+            ),
+            yams: YamsConfig::from_env(),
+            spotifydown: SpotifydownConfig::from_env(),
+            worker: WorkerConfig::from_env(),
+        }
+    }
+}
+
+/// The config file, if there is one
+pub fn config_file() -> Option<&'static ConfigFile> {
+    FILE.as_ref()
+}
+
+/// The value of the setting and where it's set, for the error messages. The environment variable
+/// overrides the config file.
+fn lookup(name: &str) -> Option<(String, String)> {
+    lookup_in(name, config_file())
+}
+
+fn lookup_in(name: &str, file: Option<&ConfigFile>) -> Option<(String, String)> {
+    if let Ok(val) = env::var(name) {
+        if !val.trim().is_empty() {
+            return Some((val, name.to_string()));
+        }
+    }
+
+    file?.get(name)
+}
+
+/// Parse the environment variable (or the setting in the config file) if it's set
+pub fn env_var<T>(name: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    env_var_with_source(name).map(|(x, _)| x)
+}
+
+fn env_var_with_source<T>(name: &str) -> Option<(T, String)>
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    let (val, source) = lookup(name)?;
+    let val = val.trim();
+
+    if val.is_empty() {
+        return None;
+    }
+
+    match val.parse() {
+        Ok(x) => Some((x, source)),
+        Err(e) => panic!("Invalid value for {source} ({val:?}): {e:?}"),
+    }
+}
+
+pub fn env_var_positive(name: &str) -> Option<usize> {
+    let (val, source) = env_var_with_source(name)?;
+
+    assert!(val > 0, "{source} must be greater than 0");
+
+    Some(val)
+}
+
+pub fn env_var_positive_f64(name: &str) -> Option<f64> {
+    let (val, source) = env_var_with_source(name)?;
+
+    assert!(val > 0.0, "{source} must be greater than 0");
+
+    Some(val)
+}
+
+/// The enabled handlers, all but the direct file one by default
+fn handlers_from_env() -> Vec<HandlerKind> {
+    let mut handlers = env_var_list::<HandlerKind>("KARAOKIFY_HANDLERS");
+    if handlers.is_empty() {
+        handlers = HandlerKind::DEFAULT.to_vec();
+    }
+    for (i, handler) in handlers.iter().enumerate() {
+        assert!(
+            !handlers[..i].contains(handler),
+            "KARAOKIFY_HANDLERS contains {handler} more than once"
+        );
+    }
+
+    handlers
+}
+
+/// Parse the comma separated list from the environment variable (or the array in the config
+/// file)
+pub fn env_var_list<T>(name: &str) -> Vec<T>
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    env_var_list_with_source(name).0
+}
+
+fn env_var_list_with_source<T>(name: &str) -> (Vec<T>, String)
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    let Some((val, source)) = lookup(name) else {
+        return (vec![], name.to_string());
+    };
+
+    let list = val
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|x| match x.parse() {
+            Ok(x) => x,
+            Err(e) => panic!("Invalid value in {source} ({x:?}): {e:?}"),
+        })
+        .collect();
+
+    (list, source)
+}
+
+/// Parse the comma separated list of `key=value` pairs from the environment variable
+pub fn env_var_pairs<K, V>(name: &str) -> Vec<(K, V)>
+where
+    K: FromStr,
+    K::Err: Debug,
+    V: FromStr,
+    V::Err: Debug,
+{
+    let (list, source) = env_var_list_with_source::<String>(name);
+
+    list.into_iter()
+        .map(|x| {
+            let Some((key, val)) = x.split_once('=') else {
+                panic!("Invalid value in {source} ({x:?}): expected key=value");
+            };
+
+            match (key.trim().parse(), val.trim().parse()) {
+                (Ok(key), Ok(val)) => (key, val),
+                (Err(e), _) => panic!("Invalid key in {source} ({key:?}): {e:?}"),
+                (_, Err(e)) => panic!("Invalid value in {source} ({val:?}): {e:?}"),
+            }
+        })
+        .collect()
+}
+
+/// Log which config file is used and warn about its settings that were never read, eg. because
+/// they're misspelled. Has to be called after all the settings are loaded.
+pub fn check_config_file() {
+    let Some(file) = config_file() else {
+        return;
+    };
+
+    tracing::info!(path = ?file.path(), "Loaded config file");
+    for setting in file.unused() {
+        tracing::warn!(setting, "Unknown setting in the config file");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The value of the setting, or the default if it isn't set anywhere
+    fn setting(name: &str, file: &ConfigFile) -> String {
+        lookup_in(name, Some(file)).map_or_else(|| "default".to_string(), |(x, _)| x)
+    }
+
+    #[test]
+    fn the_environment_overrides_the_file_which_overrides_the_default() {
+        let file = ConfigFile::parse(
+            PathBuf::from("karaokify.toml"),
+            "[test_precedence]\nboth = 'file'\nfile_only = 'file'\nblank_env = 'file'",
+        )
+        .expect("Valid config file");
+        // Unique names, since the tests share the environment
+        env::set_var("KARAOKIFY_TEST_PRECEDENCE_BOTH", "env");
+        env::set_var("KARAOKIFY_TEST_PRECEDENCE_ENV_ONLY", "env");
+        env::set_var("KARAOKIFY_TEST_PRECEDENCE_BLANK_ENV", " ");
+
+        let cases = [
+            ("KARAOKIFY_TEST_PRECEDENCE_BOTH", "env"),
+            ("KARAOKIFY_TEST_PRECEDENCE_ENV_ONLY", "env"),
+            ("KARAOKIFY_TEST_PRECEDENCE_FILE_ONLY", "file"),
+            ("KARAOKIFY_TEST_PRECEDENCE_BLANK_ENV", "file"),
+            ("KARAOKIFY_TEST_PRECEDENCE_NEITHER", "default"),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(setting(name, &file), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn the_source_of_the_setting_is_reported() {
+        let file = ConfigFile::parse(
+            PathBuf::from("karaokify.toml"),
+            "test_source_file = 1\ntest_source_env = 1",
+        )
+        .expect("Valid config file");
+        env::set_var("KARAOKIFY_TEST_SOURCE_ENV", "2");
+
+        assert_eq!(
+            lookup_in("KARAOKIFY_TEST_SOURCE_FILE", Some(&file)),
+            Some((
+                "1".to_string(),
+                "test_source_file (karaokify.toml:1)".to_string()
+            ))
+        );
+        assert_eq!(
+            lookup_in("KARAOKIFY_TEST_SOURCE_ENV", Some(&file)),
+            Some(("2".to_string(), "KARAOKIFY_TEST_SOURCE_ENV".to_string()))
+        );
+    }
+
+    #[test]
+    fn yams_quality_overrides() {
+        env::set_var("KARAOKIFY_TEST_YAMS_QUALITY", "tidal=2, DEEZER = 1");
+
+        let quality = yams_quality(env_var_pairs("KARAOKIFY_TEST_YAMS_QUALITY"));
+
+        let get = |service: &str| {
+            quality
+                .iter()
+                .find(|(x, _)| x == service)
+                .map(|(_, x)| x.as_str())
+        };
+        assert_eq!(get("tidal"), Some("2"));
+        assert_eq!(get("deezer"), Some("1"));
+        assert_eq!(get("spotify"), Some("very_high"));
+        assert_eq!(quality.len(), DEFAULT_YAMS_QUALITY.len());
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown service \"napster\" in YAMS_QUALITY_OVERRIDES")]
+    fn unknown_yams_services() {
+        yams_quality(vec![("napster".to_string(), "1".to_string())]);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected key=value")]
+    fn pairs_without_values() {
+        env::set_var("KARAOKIFY_TEST_PAIRS_WITHOUT_VALUES", "tidal=2,deezer");
+
+        env_var_pairs::<String, String>("KARAOKIFY_TEST_PAIRS_WITHOUT_VALUES");
+    }
+}
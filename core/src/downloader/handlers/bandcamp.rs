@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
+use tokio::sync::watch;
+use tracing::{debug, trace};
+use url::Url;
+
+use super::{DownloadedSong, Handler};
+use crate::helpers::{
+    domain::DomainParser,
+    download::{download_file_inferred, DownloadProgress},
+    file_name::sanitize_file_name,
+    html,
+    http::CLIENT,
+};
+
+/// The album/track details embedded into Bandcamp pages
+static TRALBUM_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"data-tralbum="([^"]*)""#).expect("Invalid regex"));
+
+/// Downloads the free streams (128 kbps MP3) of Bandcamp tracks
+#[derive(Debug)]
+pub struct BandcampProvider;
+
+#[async_trait::async_trait]
+impl Handler for BandcampProvider {
+    #[tracing::instrument(skip(self, song_url, progress), fields(url = ?song_url.as_str()))]
+    async fn download(
+        &self,
+        download_dir: &Path,
+        song_url: &Url,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<Vec<DownloadedSong>> {
+        if song_url.path().starts_with("/album/") {
+            anyhow::bail!("Bandcamp albums aren't supported, send a track link, not an album");
+        }
+
+        debug!("Downloading song");
+        let page = CLIENT
+            .get(song_url.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        let track = Tralbum::from_page(&page)?.into_track()?;
+        trace!(?track, "Got track from page");
+
+        let download_path = download_dir.join("some song.mp3");
+        let path = download_file_inferred(&download_path, &track.stream_url, progress).await?;
+        let path = track.rename_file(path).await;
+
+        Ok(vec![DownloadedSong {
+            path,
+            title: track.title,
+            artist: track.artist,
+        }])
+    }
+
+    fn name(&self) -> &'static str {
+        "bandcamp"
+    }
+
+    fn supported_services(&self) -> &'static [&'static str] {
+        &["Bandcamp"]
+    }
+
+    async fn supports(&self, song_url: &Url) -> bool {
+        DomainParser::is_on(song_url, &["bandcamp.com"])
+    }
+}
+
+/// The `data-tralbum` attribute of a Bandcamp page
+#[derive(Debug, Deserialize)]
+struct Tralbum {
+    artist: Option<String>,
+    item_type: Option<String>,
+    #[serde(default)]
+    trackinfo: Vec<TrackInfo>,
+}
+impl Tralbum {
+    fn from_page(page: &str) -> anyhow::Result<Self> {
+        let attr = TRALBUM_REGEX
+            .captures(page)
+            .and_then(|x| x.get(1))
+            .ok_or_else(|| anyhow::anyhow!("Couldn't find the track details on the page"))?;
+
+        Ok(serde_json::from_str(&html::unescape(attr.as_str()))?)
+    }
+
+    fn into_track(self) -> anyhow::Result<BandcampTrack> {
+        if self.item_type.as_deref() == Some("album") {
+            anyhow::bail!("Bandcamp albums aren't supported, send a track link, not an album");
+        }
+
+        let Some(info) = self.trackinfo.into_iter().next() else {
+            anyhow::bail!("The page doesn't have any tracks");
+        };
+        let Some(stream_url) = info.file.and_then(|mut x| x.remove("mp3-128")) else {
+            anyhow::bail!("This Bandcamp track can't be streamed, it might only be for sale");
+        };
+
+        Ok(BandcampTrack {
+            title: info.title,
+            // Set on compilations where the tracks are by different artists
+            artist: info.artist.or(self.artist),
+            stream_url,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TrackInfo {
+    title: Option<String>,
+    artist: Option<String>,
+    /// Stream URLs by format, eg. `mp3-128`
+    file: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug)]
+struct BandcampTrack {
+    title: Option<String>,
+    artist: Option<String>,
+    stream_url: String,
+}
+impl BandcampTrack {
+    /// Name the file after the track
+    async fn rename_file(&self, file_path: PathBuf) -> PathBuf {
+        let name = match (&self.artist, &self.title) {
+            (Some(artist), Some(title)) => format!("{artist} - {title}"),
+            (None, Some(title)) => title.clone(),
+            _ => return file_path,
+        };
+        let extension = file_path
+            .extension()
+            .map_or_else(|| "mp3".into(), |x| x.to_string_lossy());
+        let new_path = file_path.with_file_name(sanitize_file_name(&format!("{name}.{extension}")));
+
+        match tokio::fs::rename(&file_path, &new_path).await {
+            Ok(()) => new_path,
+            Err(e) => {
+                debug!(?e, "Failed to rename downloaded song");
+                file_path
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::temp_dir::TempDir;
+
+    fn page_track(name: &str) -> anyhow::Result<BandcampTrack> {
+        let page = std::fs::read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/bandcamp")
+                .join(name),
+        )
+        .expect("Fixture exists");
+
+        Tralbum::from_page(&page)?.into_track()
+    }
+
+    #[test]
+    fn track_page() {
+        let track = page_track("track.html").expect("Track");
+
+        assert_eq!(track.title.as_deref(), Some("Rock \"n\" Roll <Live>"));
+        assert_eq!(track.artist.as_deref(), Some("Mötley & Sons"));
+        assert_eq!(
+            track.stream_url,
+            "https://t4.bcbits.com/stream/abc/mp3-128/42?p=0&ts=1700000000&t=def&token=1700000000_ghi"
+        );
+    }
+
+    #[test]
+    fn compilation_track_page() {
+        let track = page_track("compilation_track.html").expect("Track");
+
+        assert_eq!(track.title.as_deref(), Some("Đurđevdan"));
+        assert_eq!(track.artist.as_deref(), Some("Bijelo Dugme"));
+    }
+
+    #[test]
+    fn pages_without_a_track() {
+        for (name, error) in [
+            (
+                "album.html",
+                "Bandcamp albums aren't supported, send a track link, not an album",
+            ),
+            (
+                "not_streamable.html",
+                "This Bandcamp track can't be streamed, it might only be for sale",
+            ),
+            ("no_tracks.html", "The page doesn't have any tracks"),
+            (
+                "no_tralbum.html",
+                "Couldn't find the track details on the page",
+            ),
+        ] {
+            let err = page_track(name).expect_err(name);
+
+            assert_eq!(err.to_string(), error, "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn supported_links() {
+        for (url, supported) in [
+            ("https://artist.bandcamp.com/track/song", true),
+            ("https://bandcamp.com/track/song", true),
+            ("https://daily.bandcamp.com/features/x", true),
+            ("https://bandcamp.com.evil.com/track/song", false),
+            ("https://notbandcamp.com/track/song", false),
+        ] {
+            let url = Url::parse(url).expect("Valid URL");
+
+            assert_eq!(BandcampProvider.supports(&url).await, supported, "{url}");
+        }
+    }
+
+    #[tokio::test]
+    async fn album_links_are_rejected() {
+        let url = Url::parse("https://artist.bandcamp.com/album/record").expect("Valid URL");
+
+        let err = BandcampProvider
+            .download(Path::new("/nonexistent"), &url, None)
+            .await
+            .expect_err("Album");
+
+        assert_eq!(
+            err.to_string(),
+            "Bandcamp albums aren't supported, send a track link, not an album"
+        );
+    }
+
+    #[tokio::test]
+    async fn files_are_named_after_the_track() {
+        let dir = TempDir::with_prefix("karaokify-test-bandcamp-")
+            .await
+            .expect("Directory created");
+        let track = |artist: Option<&str>, title: Option<&str>| BandcampTrack {
+            title: title.map(Into::into),
+            artist: artist.map(Into::into),
+            stream_url: String::new(),
+        };
+
+        let mut names = vec![];
+        for track in [
+            track(Some("AC/DC"), Some("T.N.T.")),
+            track(None, Some("Untitled")),
+            track(Some("Band"), None),
+        ] {
+            let path = dir.path().join("some song.mp3");
+            std::fs::write(&path, "song").expect("File written");
+
+            let path = track.rename_file(path).await;
+            names.push(path.file_name().map(|x| x.to_string_lossy().to_string()));
+        }
+
+        assert_eq!(
+            names,
+            [
+                Some("AC_DC - T.N.T..mp3".to_string()),
+                Some("Untitled.mp3".to_string()),
+                Some("some song.mp3".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use std::path::Path;
+
+use percent_encoding::percent_decode_str;
+use reqwest::header;
+use tokio::sync::watch;
+use tracing::{debug, trace};
+use url::Url;
+
+use super::{DownloadedSong, Handler};
+use crate::helpers::{
+    download::{download_public_file_inferred, is_audio_file_name, DownloadProgress},
+    file_name::sanitize_file_name,
+    public_host::{self, PUBLIC_CLIENT},
+};
+
+/// Downloads plain links to audio files, eg. hosted on someone's own server.
+///
+/// Tried last since it makes a request to check every URL. Only links to public hosts are
+/// fetched, but it's still off by default since it makes the bot fetch any link users send.
+#[derive(Debug)]
+pub struct DirectFileProvider;
+
+#[async_trait::async_trait]
+impl Handler for DirectFileProvider {
+    #[tracing::instrument(skip(self, song_url, progress), fields(url = ?song_url.as_str()))]
+    async fn download(
+        &self,
+        download_dir: &Path,
+        song_url: &Url,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<Vec<DownloadedSong>> {
+        debug!("Downloading song");
+
+        let file_name = song_url
+            .path_segments()
+            .and_then(|mut x| x.next_back())
+            .filter(|x| !x.is_empty())
+            .map_or_else(
+                || "some song.mp3".into(),
+                |x| percent_decode_str(x).decode_utf8_lossy(),
+            );
+        let download_path = download_dir.join(sanitize_file_name(&file_name));
+
+        // Also takes care of the size limit
+        let path = download_public_file_inferred(&download_path, song_url, progress).await?;
+        trace!(?path, "Downloaded song");
+
+        Ok(vec![DownloadedSong::from_path(path)])
+    }
+
+    fn name(&self) -> &'static str {
+        "direct"
+    }
+
+    fn supported_services(&self) -> &'static [&'static str] {
+        &["direct links to audio files"]
+    }
+
+    /// Checks the type of the file with a `HEAD` request.
+    ///
+    /// Audio content types are supported, as are files with audio extensions that aren't
+    /// HTML pages.
+    async fn supports(&self, song_url: &Url) -> bool {
+        if let Err(e) = public_host::check_url(song_url) {
+            trace!(?e, "URL can't be fetched");
+            return false;
+        }
+
+        let resp = match PUBLIC_CLIENT.head(song_url.clone()).send().await {
+            Ok(x) => x,
+            Err(e) => {
+                trace!(?e, "Failed to check URL");
+                return false;
+            }
+        };
+        if !resp.status().is_success() {
+            trace!(status = ?resp.status(), "URL can't be downloaded");
+            return false;
+        }
+
+        let content_type = resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.trim().to_ascii_lowercase());
+        trace!(?content_type, "Got content type of URL");
+
+        match content_type {
+            Some(x) if x.starts_with("audio/") => true,
+            Some(x) if x.starts_with("text/html") => false,
+            _ => is_audio_file_name(Path::new(resp.url().path())),
+        }
+    }
+}
@@ -0,0 +1,449 @@
+pub mod bandcamp;
+pub mod direct_file;
+pub mod soundcloud;
+pub mod spotifydown;
+pub mod yams;
+
+use std::{
+    fmt::Display,
+    future::Future,
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use super::DownloadedSong;
+use crate::{config::Config, helpers::download::DownloadProgress};
+
+/// The configured handlers, in the order they're tried
+pub static HANDLERS: Lazy<Vec<DownloadHandler>> =
+    Lazy::new(|| HandlerKind::build_all(&Config::global().handlers));
+
+/// The handlers that can be enabled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandlerKind {
+    Yams,
+    Spotifydown,
+    Soundcloud,
+    Bandcamp,
+    DirectFile,
+}
+impl HandlerKind {
+    /// All the handlers that can be enabled
+    pub const ALL: [Self; 5] = [
+        Self::Yams,
+        Self::Spotifydown,
+        Self::Soundcloud,
+        Self::Bandcamp,
+        // Claims any link to an audio file, so it's the fallback
+        Self::DirectFile,
+    ];
+
+    /// The handlers enabled by default, in the order they're tried. The direct file handler
+    /// fetches any link users send, so public bots have to opt into it.
+    pub const DEFAULT: [Self; 4] = [
+        Self::Yams,
+        Self::Spotifydown,
+        Self::Soundcloud,
+        Self::Bandcamp,
+    ];
+
+    const fn id(self) -> &'static str {
+        match self {
+            Self::Yams => "yams",
+            Self::Spotifydown => "spotifydown",
+            Self::Soundcloud => "soundcloud",
+            Self::Bandcamp => "bandcamp",
+            Self::DirectFile => "direct",
+        }
+    }
+
+    fn build(self) -> DownloadHandler {
+        match self {
+            Self::Yams => DownloadHandler::new(yams::YamsProvider),
+            Self::Spotifydown => DownloadHandler::new(spotifydown::SpotifydownProvider),
+            Self::Soundcloud => DownloadHandler::new(soundcloud::SoundcloudProvider),
+            Self::Bandcamp => DownloadHandler::new(bandcamp::BandcampProvider),
+            Self::DirectFile => DownloadHandler::new(direct_file::DirectFileProvider),
+        }
+    }
+
+    /// Create the handlers in the given order
+    fn build_all(kinds: &[Self]) -> Vec<DownloadHandler> {
+        kinds.iter().map(|x| x.build()).collect()
+    }
+}
+impl FromStr for HandlerKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|x| x.id().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "unknown handler {s:?}, expected one of: {}",
+                    Self::ALL.map(Self::id).join(", ")
+                )
+            })
+    }
+}
+impl Display for HandlerKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.id())
+    }
+}
+
+/// Outcomes of the latest downloads using a handler
+#[derive(Debug, Clone, Default)]
+pub struct HandlerHealth {
+    pub last_success: Option<Instant>,
+    pub last_failure: Option<Instant>,
+    pub consecutive_failures: usize,
+    /// The handler is skipped until then because it kept failing
+    pub skipped_until: Option<Instant>,
+}
+impl HandlerHealth {
+    /// Whether the handler is currently skipped
+    pub fn is_skipped(&self) -> bool {
+        self.skipped_until.is_some_and(|x| x > Instant::now())
+    }
+}
+
+#[derive(Debug)]
+pub struct DownloadHandler {
+    provider: Box<dyn Handler>,
+    health: Mutex<HandlerHealth>,
+}
+impl DownloadHandler {
+    pub(crate) fn new<T>(provider: T) -> Self
+    where
+        T: Handler + 'static,
+    {
+        Self {
+            provider: Box::new(provider),
+            health: Mutex::new(HandlerHealth::default()),
+        }
+    }
+
+    /// Name of the handler, eg. `yams`
+    pub fn name(&self) -> &'static str {
+        self.provider.name()
+    }
+
+    pub fn supported_services(&self) -> &'static [&'static str] {
+        self.provider.supported_services()
+    }
+
+    pub fn health(&self) -> HandlerHealth {
+        self.health
+            .lock()
+            .expect("Handler health lock poisoned")
+            .clone()
+    }
+
+    /// Whether the handler should be tried.
+    ///
+    /// Once a handler fails too many times in a row, it's skipped until the cool-down passes.
+    /// After that it's tried again, and a single failure skips it again.
+    pub fn is_available(&self) -> bool {
+        self.is_available_at(Instant::now())
+    }
+
+    fn is_available_at(&self, now: Instant) -> bool {
+        let mut health = self.health.lock().expect("Handler health lock poisoned");
+
+        match health.skipped_until {
+            Some(x) if x > now => false,
+            Some(_) => {
+                info!(
+                    handler = self.name(),
+                    "Trying handler again after cool-down"
+                );
+                health.skipped_until = None;
+                true
+            }
+            None => true,
+        }
+    }
+
+    pub fn record_download(&self, success: bool) {
+        self.record_download_at(success, Instant::now());
+    }
+
+    fn record_download_at(&self, success: bool, now: Instant) {
+        let mut health = self.health.lock().expect("Handler health lock poisoned");
+
+        if success {
+            if health.consecutive_failures >= Config::global().handler_failure_threshold {
+                info!(
+                    handler = self.name(),
+                    "Handler recovered, no longer skipping it"
+                );
+            }
+            health.last_success = Some(now);
+            health.consecutive_failures = 0;
+        } else {
+            health.last_failure = Some(now);
+            health.consecutive_failures += 1;
+
+            let config = Config::global();
+            if health.consecutive_failures >= config.handler_failure_threshold {
+                warn!(
+                    handler = self.name(),
+                    failures = health.consecutive_failures,
+                    cooldown = ?config.handler_cooldown,
+                    "Handler keeps failing, skipping it for a while"
+                );
+                health.skipped_until = Some(now + config.handler_cooldown);
+            }
+        }
+    }
+
+    pub async fn supports(&self, url: &Url) -> bool {
+        self.provider.supports(url).await
+    }
+
+    pub async fn download(
+        &self,
+        download_dir: &Path,
+        url: &Url,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> Result<Vec<DownloadedSong>, anyhow::Error> {
+        self.provider.download(download_dir, url, progress).await
+    }
+
+    pub async fn expand_collection(
+        &self,
+        url: &Url,
+        limit: usize,
+    ) -> Option<anyhow::Result<Vec<Url>>> {
+        self.provider.expand_collection(url, limit).await
+    }
+}
+
+#[async_trait::async_trait]
+pub trait Handler: std::fmt::Debug + Send + Sync {
+    /// Download the song into `download_dir`.
+    ///
+    /// Returns the downloaded songs, which can be several if the whole album is downloaded at
+    /// once. Handlers that know the title and artist of the song return them as well.
+    ///
+    /// Progress of the download is reported through `progress`, if the handler knows it.
+    async fn download(
+        &self,
+        download_dir: &Path,
+        song_url: &Url,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<Vec<DownloadedSong>>;
+
+    /// Short name of the handler shown to users and admins, eg. `yams`
+    fn name(&self) -> &'static str;
+
+    /// Names of the services the handler can download from, shown to users, eg. `Spotify`
+    fn supported_services(&self) -> &'static [&'static str];
+
+    async fn supports(&self, song_url: &Url) -> bool;
+
+    /// Expand a collection (eg. an album or playlist) into the URLs of the songs in it.
+    ///
+    /// Fetching may stop early once at least `limit` songs are found.
+    ///
+    /// Returns `None` if the URL isn't a collection that the handler knows about.
+    async fn expand_collection(
+        &self,
+        _collection_url: &Url,
+        _limit: usize,
+    ) -> Option<anyhow::Result<Vec<Url>>> {
+        None
+    }
+}
+
+/// Why an attempt to download a song from a provider failed
+#[derive(Debug)]
+enum AttemptError {
+    /// Trying again can help, eg. the provider couldn't be reached
+    Retry(anyhow::Error),
+    /// Trying again won't help, eg. the provider doesn't have the song. The error is shown to
+    /// the user as it is.
+    Fatal(anyhow::Error),
+}
+
+/// Try `attempt` until it succeeds, fails for good or was tried `attempts` times, waiting `delay`
+/// between the attempts. The number of the attempt is passed to it, eg. to use another host.
+///
+/// If the attempts run out, the user is only told that the provider failed (or is down, if it
+/// timed out), the details are logged.
+async fn retry_provider<T, F, Fut>(
+    attempts: usize,
+    delay: Duration,
+    mut attempt: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut(usize) -> Fut,
+    Fut: Future<Output = Result<T, AttemptError>>,
+{
+    let mut last_error = None;
+    for i in 0..attempts {
+        if i > 0 {
+            tokio::time::sleep(delay).await;
+            debug!(attempt = i, "Retrying song download");
+        }
+
+        match attempt(i).await {
+            Ok(x) => return Ok(x),
+            Err(AttemptError::Retry(e)) => {
+                debug!(?e, attempt = i, "Song download attempt failed");
+                last_error = Some(e);
+            }
+            Err(AttemptError::Fatal(e)) => return Err(e),
+        }
+    }
+
+    let Some(e) = last_error else {
+        anyhow::bail!("Failed to download song from provider");
+    };
+    if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+        if e.is_timeout() {
+            warn!(
+                ?e,
+                "Timeout downloading song. Download provider may be down."
+            );
+            anyhow::bail!("Timeout downloading song. Download provider may be down.");
+        }
+    }
+    warn!(?e, "Failed to download song");
+    anyhow::bail!("Failed to download song from provider")
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    /// A handler for every URL that succeeds, fails or never finishes on demand
+    #[derive(Debug, Default)]
+    pub struct FakeHandler {
+        pub fails: AtomicBool,
+        pub hangs: AtomicBool,
+        /// How many times downloading was started
+        pub downloads: AtomicUsize,
+    }
+    #[async_trait::async_trait]
+    impl Handler for Arc<FakeHandler> {
+        async fn download(
+            &self,
+            download_dir: &Path,
+            _song_url: &Url,
+            _progress: Option<&watch::Sender<DownloadProgress>>,
+        ) -> anyhow::Result<Vec<DownloadedSong>> {
+            self.downloads.fetch_add(1, Ordering::SeqCst);
+
+            if self.hangs.load(Ordering::SeqCst) {
+                std::future::pending::<()>().await;
+            }
+            if self.fails.load(Ordering::SeqCst) {
+                anyhow::bail!("the provider is down");
+            }
+
+            Ok(vec![DownloadedSong::from_path(
+                download_dir.join("song.mp3"),
+            )])
+        }
+
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn supported_services(&self) -> &'static [&'static str] {
+            &["Fake"]
+        }
+
+        async fn supports(&self, _song_url: &Url) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn breaker_opens_and_recovers() {
+        let config = Config::global();
+        let handler = DownloadHandler::new(Arc::new(FakeHandler::default()));
+        let start = Instant::now();
+
+        for i in 0..config.handler_failure_threshold {
+            assert!(handler.is_available_at(start), "Failure {i}");
+            handler.record_download_at(false, start);
+        }
+        assert_eq!(
+            handler.health().consecutive_failures,
+            config.handler_failure_threshold
+        );
+
+        // Skipped during the cool-down
+        assert!(!handler.is_available_at(start));
+        assert!(!handler.is_available_at(start + config.handler_cooldown / 2));
+        assert!(handler.health().skipped_until.is_some());
+
+        // Tried again once it passes, and a single failure opens the breaker again
+        let later = start + config.handler_cooldown;
+        assert!(handler.is_available_at(later));
+        assert_eq!(handler.health().skipped_until, None);
+        handler.record_download_at(false, later);
+        assert!(!handler.is_available_at(later));
+
+        // Until a download succeeds
+        let even_later = later + config.handler_cooldown;
+        assert!(handler.is_available_at(even_later));
+        handler.record_download_at(true, even_later);
+        let health = handler.health();
+        assert_eq!(health.consecutive_failures, 0);
+        assert_eq!(health.last_success, Some(even_later));
+        assert_eq!(health.last_failure, Some(later));
+
+        handler.record_download_at(false, even_later);
+        assert!(handler.is_available_at(even_later));
+    }
+
+    #[test]
+    fn successes_reset_the_failures() {
+        let config = Config::global();
+        let handler = DownloadHandler::new(Arc::new(FakeHandler::default()));
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            for _ in 1..config.handler_failure_threshold {
+                handler.record_download_at(false, now);
+            }
+            handler.record_download_at(true, now);
+        }
+
+        assert!(handler.is_available_at(now));
+        assert_eq!(handler.health().consecutive_failures, 0);
+        assert!(!handler.health().is_skipped());
+    }
+
+    #[test]
+    fn handler_kinds() {
+        assert_eq!("YAMS".parse::<HandlerKind>().ok(), Some(HandlerKind::Yams));
+        assert_eq!(
+            "direct".parse::<HandlerKind>().ok(),
+            Some(HandlerKind::DirectFile)
+        );
+        assert!("youtube".parse::<HandlerKind>().is_err());
+
+        for kind in HandlerKind::ALL {
+            assert_eq!(kind.to_string().parse::<HandlerKind>().ok(), Some(kind));
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use tokio::{process::Command, sync::watch};
+use tracing::{debug, trace};
+use url::Url;
+
+use super::{DownloadedSong, Handler};
+use crate::{
+    config::Config,
+    helpers::{domain::DomainParser, download::DownloadProgress, file_name::sanitize_file_name},
+};
+
+/// Messages shown to the user for the errors of tracks that can't be downloaded, by a
+/// (lowercase) part of the yt-dlp error
+const ERROR_MESSAGES: &[(&str, &str)] = &[
+    ("404", "This SoundCloud track is private or doesn't exist"),
+    (
+        "private",
+        "This SoundCloud track is private or doesn't exist",
+    ),
+    (
+        "geo",
+        "This SoundCloud track isn't available in the bot's region",
+    ),
+    (
+        "not available from your location",
+        "This SoundCloud track isn't available in the bot's region",
+    ),
+];
+
+/// Downloads songs from `SoundCloud` using yt-dlp
+#[derive(Debug)]
+pub struct SoundcloudProvider;
+
+#[async_trait::async_trait]
+impl Handler for SoundcloudProvider {
+    #[tracing::instrument(skip(self, song_url, _progress), fields(url = ?song_url.as_str()))]
+    async fn download(
+        &self,
+        download_dir: &Path,
+        song_url: &Url,
+        _progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<Vec<DownloadedSong>> {
+        if song_url.path().contains("/sets/") {
+            anyhow::bail!("SoundCloud playlists aren't supported");
+        }
+
+        debug!("Downloading song");
+        let output = Command::new(&Config::global().ytdlp_path)
+            .args(["--use-extractors", "soundcloud"])
+            .arg("--no-playlist")
+            .arg("--no-simulate")
+            .args(["--format", "bestaudio/best"])
+            .args([
+                "--max-filesize",
+                &Config::global().max_download_size.to_string(),
+            ])
+            .arg("--ffmpeg-location")
+            .arg(&Config::global().ffmpeg_path)
+            .args(["--paths", &download_dir.to_string_lossy()])
+            // Renamed to the title once downloaded
+            .args(["--output", "%(id)s.%(ext)s"])
+            // Printed on separate lines, in this order
+            .args(["--print", "after_move:filepath"])
+            .args(["--print", "after_move:title"])
+            .args(["--print", "after_move:uploader"])
+            .arg(song_url.as_str())
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+        trace!(status = ?output.status, "yt-dlp finished");
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            debug!(?stderr, "yt-dlp failed");
+
+            return Err(Self::download_error(&stderr));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut lines = stdout.lines().map(str::trim);
+        let Some(path) = lines.next().filter(|x| !x.is_empty()) else {
+            anyhow::bail!("yt-dlp didn't download the song");
+        };
+        let mut detail = || {
+            lines
+                .next()
+                .filter(|x| !x.is_empty() && *x != "NA")
+                .map(ToString::to_string)
+        };
+
+        let title = detail();
+        let artist = detail();
+        let path = Self::rename_file(PathBuf::from(path), title.as_deref()).await;
+
+        Ok(vec![DownloadedSong {
+            path,
+            title,
+            artist,
+        }])
+    }
+
+    fn name(&self) -> &'static str {
+        "soundcloud"
+    }
+
+    fn supported_services(&self) -> &'static [&'static str] {
+        &["SoundCloud"]
+    }
+
+    async fn supports(&self, song_url: &Url) -> bool {
+        DomainParser::is_on(song_url, &["soundcloud.com"])
+    }
+}
+
+impl SoundcloudProvider {
+    /// Name the file after the track
+    async fn rename_file(file_path: PathBuf, title: Option<&str>) -> PathBuf {
+        let Some(title) = title else {
+            return file_path;
+        };
+        let extension = file_path
+            .extension()
+            .map_or_else(|| "mp3".into(), |x| x.to_string_lossy());
+        let new_path =
+            file_path.with_file_name(sanitize_file_name(&format!("{title}.{extension}")));
+
+        match tokio::fs::rename(&file_path, &new_path).await {
+            Ok(()) => new_path,
+            Err(e) => {
+                debug!(?e, "Failed to rename downloaded song");
+                file_path
+            }
+        }
+    }
+
+    /// Use a friendlier message for tracks that can't be downloaded
+    fn download_error(stderr: &str) -> anyhow::Error {
+        let error = stderr
+            .lines()
+            .rev()
+            .find(|x| x.starts_with("ERROR:"))
+            .unwrap_or("yt-dlp failed to download the song");
+        let lowercase = error.to_lowercase();
+
+        ERROR_MESSAGES
+            .iter()
+            .find(|(x, _)| lowercase.contains(x))
+            .map_or_else(
+                || anyhow::anyhow!("{error}"),
+                |(_, message)| anyhow::anyhow!("{message}"),
+            )
+    }
+}
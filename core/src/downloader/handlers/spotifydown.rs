@@ -0,0 +1,680 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
+use tokio::sync::watch;
+use tracing::{debug, trace};
+use url::Url;
+
+use super::{retry_provider, AttemptError, DownloadedSong, Handler};
+use crate::{
+    config::{Config, SpotifydownConfig},
+    helpers::{
+        domain::DomainParser,
+        download::{download_file_inferred, DownloadProgress},
+        file_name::sanitize_file_name,
+        http::{client_with_timeout, CLIENT},
+    },
+};
+
+/// How many times the download URL is requested before giving up
+const MAX_ATTEMPTS: usize = 6;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+/// Track lists of big playlists take a while to fetch
+const TRACK_LIST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Prefixes of the paths of Spotify links, eg. `/intl-de` or `/embed`
+const PATH_PREFIX: &str = r"^(?:/intl-[a-zA-Z-]+|/embed|/user/[^/]+)*";
+static PATH_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(&format!(r"{PATH_PREFIX}/track/(?<id>[a-zA-Z0-9]+)/?$")).expect("Invalid regex")
+});
+static COLLECTION_PATH_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(&format!(
+        r"{PATH_PREFIX}/(?<kind>album|playlist)/(?<id>[a-zA-Z0-9]+)/?$"
+    ))
+    .expect("Invalid regex")
+});
+static PODCAST_PATH_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(&format!(r"{PATH_PREFIX}/(?:episode|show)/")).expect("Invalid regex"));
+
+/// The spotifydown API and how long to wait between the attempts, the configured API outside of
+/// the tests
+#[derive(Debug)]
+struct SpotifydownApi<'a> {
+    config: &'a SpotifydownConfig,
+    retry_delay: Duration,
+}
+impl SpotifydownApi<'static> {
+    fn configured() -> Self {
+        Self {
+            config: &Config::global().spotifydown,
+            retry_delay: RETRY_DELAY,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SpotifydownProvider;
+
+#[async_trait::async_trait]
+impl Handler for SpotifydownProvider {
+    #[tracing::instrument(skip(self, song_url, progress), fields(url = ?song_url.as_str()))]
+    async fn download(
+        &self,
+        download_dir: &Path,
+        song_url: &Url,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<Vec<DownloadedSong>> {
+        Self::download_with(
+            &SpotifydownApi::configured(),
+            download_dir,
+            song_url,
+            progress,
+        )
+        .await
+    }
+
+    fn name(&self) -> &'static str {
+        "spotifydown"
+    }
+
+    fn supported_services(&self) -> &'static [&'static str] {
+        &["Spotify"]
+    }
+
+    async fn supports(&self, song_url: &Url) -> bool {
+        DomainParser::is_on(song_url, &["spotify.com"])
+    }
+
+    #[tracing::instrument(skip(self, collection_url), fields(url = ?collection_url.as_str()))]
+    async fn expand_collection(
+        &self,
+        collection_url: &Url,
+        limit: usize,
+    ) -> Option<anyhow::Result<Vec<Url>>> {
+        if !self.supports(collection_url).await {
+            return None;
+        }
+
+        let captures = COLLECTION_PATH_REGEX.captures(collection_url.path())?;
+        let kind = captures.name("kind")?.as_str();
+        let id = captures.name("id")?.as_str();
+
+        Some(
+            SpotifydownApi::configured()
+                .get_track_list(kind, id, limit)
+                .await,
+        )
+    }
+}
+
+impl SpotifydownProvider {
+    async fn download_with(
+        api: &SpotifydownApi<'_>,
+        download_dir: &Path,
+        song_url: &Url,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<Vec<DownloadedSong>> {
+        debug!("Downloading song");
+
+        // Checked first so the reason reaches the user instead of being retried
+        let track_id = Self::track_id(song_url)?;
+        trace!(?track_id, "Got track ID from song URL");
+
+        let download_url = retry_provider(MAX_ATTEMPTS, api.retry_delay, |_| async {
+            api.get_download_url(track_id)
+                .await
+                .map_err(AttemptError::Retry)
+        })
+        .await?;
+
+        debug!(?download_url, "Download URL found. Downloading song.");
+
+        let metadata = api.get_metadata(track_id).await.unwrap_or_else(|e| {
+            debug!(?e, "Failed to get track metadata");
+            TrackMetadata::default()
+        });
+
+        let path = Self::download_file(download_dir, &download_url, progress).await?;
+        let path = metadata.rename_file(path).await;
+
+        Ok(vec![DownloadedSong {
+            path,
+            title: metadata.title,
+            artist: metadata.artists,
+        }])
+    }
+
+    fn track_id(song_url: &Url) -> anyhow::Result<&str> {
+        let Some(track_id) = PATH_REGEX
+            .captures(song_url.path())
+            .and_then(|x| x.name("id"))
+        else {
+            if PODCAST_PATH_REGEX.is_match(song_url.path()) {
+                anyhow::bail!("Podcasts aren't supported");
+            }
+            anyhow::bail!("Invalid Spotify URL");
+        };
+
+        Ok(track_id.as_str())
+    }
+
+    pub async fn download_file(
+        download_dir: &Path,
+        url: &str,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<PathBuf> {
+        let download_path = download_dir.join("some song.mp3");
+
+        trace!(?download_path, "Downloading song");
+        let download_path = download_file_inferred(&download_path, url, progress).await?;
+        trace!(?download_path, "Downloaded song");
+
+        Ok(download_path)
+    }
+}
+
+impl SpotifydownApi<'_> {
+    /// The URL of the API endpoint at `path`
+    fn url(&self, path: &str) -> String {
+        let api_url = &self.config.api_url;
+
+        format!("{}/{path}", api_url.as_str().trim_end_matches('/'))
+    }
+
+    /// The origin of the site the requests claim to come from, eg. `https://spotifydown.com`
+    fn site_origin(&self) -> String {
+        self.config.site_url.origin().ascii_serialization()
+    }
+
+    async fn get_download_url(&self, track_id: &str) -> anyhow::Result<String> {
+        let api_url = self.url(&format!("download/{track_id}"));
+        trace!(?api_url, "Got API URL for song download request");
+        let body = CLIENT
+            .get(api_url)
+            .header("origin", self.site_origin())
+            .header("referer", self.site_origin())
+            .send()
+            .await?
+            .text()
+            .await?;
+
+        parse_download_response(&body)
+    }
+
+    /// The title and artists of the track
+    async fn get_metadata(&self, track_id: &str) -> anyhow::Result<TrackMetadata> {
+        let body = CLIENT
+            .get(self.url(&format!("metadata/track/{track_id}")))
+            .header("origin", self.site_origin())
+            .header("referer", self.site_origin())
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        parse_metadata_response(&body)
+    }
+
+    async fn get_track_list(&self, kind: &str, id: &str, limit: usize) -> anyhow::Result<Vec<Url>> {
+        debug!(?kind, ?id, "Getting track list");
+
+        let mut tracks = vec![];
+        let mut offset = None;
+        loop {
+            let mut api_url = Url::parse(&self.url(&format!("trackList/{kind}/{id}")))?;
+            if let Some(offset) = offset {
+                api_url
+                    .query_pairs_mut()
+                    .append_pair("offset", &format!("{offset}"));
+            }
+            trace!(?api_url, "Getting track list page");
+
+            let body = client_with_timeout(TRACK_LIST_TIMEOUT)
+                .get(api_url)
+                .header("origin", self.site_origin())
+                .header("referer", self.site_origin())
+                .send()
+                .await?
+                .text()
+                .await?;
+            let page = parse_track_list_response(&body)?;
+
+            tracks.extend(page.tracks);
+
+            match page.next_offset {
+                Some(next_offset) if tracks.len() < limit => offset = Some(next_offset),
+                _ => break,
+            }
+        }
+
+        debug!(tracks = tracks.len(), "Got track list");
+
+        Ok(tracks)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct TrackMetadata {
+    #[serde(default)]
+    success: bool,
+    title: Option<String>,
+    /// Names of the artists, separated by commas
+    artists: Option<String>,
+}
+impl TrackMetadata {
+    /// Name the file after the artists and title of the track (the CDN doesn't always send a
+    /// proper file name).
+    ///
+    /// Returns the new path of the file, or the old one if it couldn't be renamed.
+    async fn rename_file(&self, file_path: PathBuf) -> PathBuf {
+        let Some(new_path) = self.file_path(&file_path) else {
+            return file_path;
+        };
+
+        match tokio::fs::rename(&file_path, &new_path).await {
+            Ok(()) => new_path,
+            Err(e) => {
+                debug!(?e, "Failed to rename downloaded song");
+                file_path
+            }
+        }
+    }
+
+    /// The path the file is renamed to, if there's a title to name it after
+    fn file_path(&self, file_path: &Path) -> Option<PathBuf> {
+        let name = match (&self.artists, &self.title) {
+            (Some(artists), Some(title)) => format!("{artists} - {title}"),
+            (None, Some(title)) => title.clone(),
+            _ => return None,
+        };
+        let extension = file_path
+            .extension()
+            .map_or_else(|| "mp3".into(), |x| x.to_string_lossy());
+
+        Some(file_path.with_file_name(sanitize_file_name(&format!("{name}.{extension}"))))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum DownloadResponse {
+    Success { link: String },
+    Error { message: String },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TrackListResponse {
+    #[serde(default)]
+    success: bool,
+    message: Option<String>,
+    #[serde(default)]
+    track_list: Vec<TrackListItem>,
+    next_offset: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TrackListItem {
+    id: String,
+}
+
+/// The tracks on a page of a track list, and where the next page starts (if there is one)
+#[derive(Debug)]
+struct TrackListPage {
+    tracks: Vec<Url>,
+    next_offset: Option<u64>,
+}
+
+fn parse_download_response(body: &str) -> anyhow::Result<String> {
+    let res = serde_json::from_str::<DownloadResponse>(body)?;
+    trace!(?res, "Got download response");
+
+    match res {
+        DownloadResponse::Error { message } => {
+            Err(anyhow::anyhow!(message).context("Failed to get song download link"))
+        }
+
+        DownloadResponse::Success { link } => Ok(link),
+    }
+}
+
+fn parse_metadata_response(body: &str) -> anyhow::Result<TrackMetadata> {
+    let res = serde_json::from_str::<TrackMetadata>(body)?;
+    trace!(?res, "Got metadata response");
+
+    if !res.success {
+        anyhow::bail!("Failed to get track metadata");
+    }
+
+    Ok(res)
+}
+
+fn parse_track_list_response(body: &str) -> anyhow::Result<TrackListPage> {
+    let res = serde_json::from_str::<TrackListResponse>(body)?;
+    trace!(?res, "Got track list response");
+
+    if !res.success {
+        anyhow::bail!(res
+            .message
+            .unwrap_or_else(|| "Failed to get track list".to_string()));
+    }
+
+    Ok(TrackListPage {
+        tracks: res
+            .track_list
+            .into_iter()
+            .filter_map(|x| Url::parse(&format!("https://open.spotify.com/track/{}", x.id)).ok())
+            .collect(),
+        next_offset: res.next_offset,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Response, StatusCode};
+
+    use super::*;
+    use crate::helpers::{
+        temp_dir::TempDir,
+        test_server::{json, response, Body, TestServer},
+    };
+
+    #[test]
+    fn track_links() {
+        let id = "4cOdK2wGLETKBW3PvgPWqT";
+        for (url, expected) in [
+            ("https://open.spotify.com/track/4cOdK2wGLETKBW3PvgPWqT", Ok(id)),
+            ("https://open.spotify.com/track/4cOdK2wGLETKBW3PvgPWqT/", Ok(id)),
+            (
+                "https://open.spotify.com/track/4cOdK2wGLETKBW3PvgPWqT?si=1a2b3c4d5e6f",
+                Ok(id),
+            ),
+            (
+                "https://open.spotify.com/intl-de/track/4cOdK2wGLETKBW3PvgPWqT?si=x&utm_source=copy",
+                Ok(id),
+            ),
+            (
+                "https://open.spotify.com/intl-pt-BR/track/4cOdK2wGLETKBW3PvgPWqT",
+                Ok(id),
+            ),
+            ("https://open.spotify.com/embed/track/4cOdK2wGLETKBW3PvgPWqT", Ok(id)),
+            ("http://open.spotify.com/track/4cOdK2wGLETKBW3PvgPWqT", Ok(id)),
+            ("https://play.spotify.com/track/4cOdK2wGLETKBW3PvgPWqT", Ok(id)),
+            (
+                "https://open.spotify.com/track/4cOdK2wGLETKBW3PvgPWqT#context",
+                Ok(id),
+            ),
+            (
+                "https://open.spotify.com/episode/512ojhOuo1ktJprKbVcKyQ",
+                Err("Podcasts aren't supported"),
+            ),
+            (
+                "https://open.spotify.com/intl-fr/show/2MAi0BvDc6GTFvKFPXnkCL?si=x",
+                Err("Podcasts aren't supported"),
+            ),
+            (
+                "https://open.spotify.com/album/1DFixLWuPkv3KT3TnV35m3",
+                Err("Invalid Spotify URL"),
+            ),
+            (
+                "https://open.spotify.com/artist/0OdUWJ0sBjDrqHygGUXeCF",
+                Err("Invalid Spotify URL"),
+            ),
+            (
+                "https://open.spotify.com/track/4cOdK2wGLETKBW3PvgPWqT/extra",
+                Err("Invalid Spotify URL"),
+            ),
+            ("https://open.spotify.com/track/", Err("Invalid Spotify URL")),
+            ("https://open.spotify.com/", Err("Invalid Spotify URL")),
+        ] {
+            let url = Url::parse(url).expect("Valid URL");
+
+            assert_eq!(
+                SpotifydownProvider::track_id(&url).map_err(|e| e.to_string()),
+                expected.map_err(ToString::to_string),
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    fn collection_links() {
+        for (path, expected) in [
+            (
+                "/album/1DFixLWuPkv3KT3TnV35m3",
+                Some(("album", "1DFixLWuPkv3KT3TnV35m3")),
+            ),
+            (
+                "/intl-de/playlist/37i9dQZF1DXcBWIGoYBM5M/",
+                Some(("playlist", "37i9dQZF1DXcBWIGoYBM5M")),
+            ),
+            (
+                "/user/spotify/playlist/37i9dQZF1DXcBWIGoYBM5M",
+                Some(("playlist", "37i9dQZF1DXcBWIGoYBM5M")),
+            ),
+            ("/track/4cOdK2wGLETKBW3PvgPWqT", None),
+            ("/artist/0OdUWJ0sBjDrqHygGUXeCF", None),
+            ("/album/", None),
+        ] {
+            let captures = COLLECTION_PATH_REGEX.captures(path);
+
+            assert_eq!(
+                captures.map(|x| (
+                    x.name("kind").expect("Kind").as_str(),
+                    x.name("id").expect("ID").as_str()
+                )),
+                expected,
+                "{path}"
+            );
+        }
+    }
+
+    fn fixture(name: &str) -> String {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/spotifydown")
+            .join(name);
+
+        std::fs::read_to_string(path).expect("Fixture exists")
+    }
+
+    #[test]
+    fn download_response() {
+        assert_eq!(
+            parse_download_response(&fixture("download_success.json")).expect("Link"),
+            "https://cdn.example.com/dl/4uLU6hMCjMI75M1A2tKUQC.mp3"
+        );
+
+        let err = parse_download_response(&fixture("download_error.json")).expect_err("Error");
+        assert_eq!(err.to_string(), "Failed to get song download link");
+        assert_eq!(err.root_cause().to_string(), "Track not found");
+
+        assert!(parse_download_response("Service Unavailable").is_err());
+    }
+
+    #[test]
+    fn metadata_response() {
+        let metadata = parse_metadata_response(&fixture("metadata.json")).expect("Metadata");
+        assert_eq!(metadata.title.as_deref(), Some("Never Gonna Give You Up"));
+        assert_eq!(metadata.artists.as_deref(), Some("Rick Astley"));
+        assert_eq!(
+            metadata.file_path(Path::new("/tmp/dl/some song.m4a")),
+            Some(PathBuf::from(
+                "/tmp/dl/Rick Astley - Never Gonna Give You Up.m4a"
+            ))
+        );
+
+        assert!(parse_metadata_response(&fixture("metadata_failed.json")).is_err());
+        assert_eq!(
+            TrackMetadata::default().file_path(Path::new("/tmp/dl/some song.mp3")),
+            None
+        );
+    }
+
+    #[test]
+    fn track_list_response() {
+        let page = parse_track_list_response(&fixture("track_list_page.json")).expect("Page");
+        assert_eq!(
+            page.tracks.iter().map(Url::as_str).collect::<Vec<_>>(),
+            [
+                "https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC",
+                "https://open.spotify.com/track/7GhIk7Il098yCjg4BQjzvb",
+            ]
+        );
+        assert_eq!(page.next_offset, Some(100));
+
+        let page = parse_track_list_response(&fixture("track_list_last.json")).expect("Page");
+        assert_eq!(page.tracks.len(), 1);
+        assert_eq!(page.next_offset, None);
+
+        let err = parse_track_list_response(&fixture("track_list_error.json")).expect_err("Error");
+        assert_eq!(err.to_string(), "Playlist is private");
+    }
+
+    const TRACK_ID: &str = "4uLU6hMCjMI75M1A2tKUQC";
+
+    fn config(server: &TestServer) -> SpotifydownConfig {
+        SpotifydownConfig {
+            site_url: Url::parse("https://spotifydown.example.com").expect("Valid URL"),
+            api_url: Url::parse(&server.url("/api/")).expect("Valid URL"),
+        }
+    }
+
+    /// The API of the server, which is retried without waiting
+    fn api(config: &SpotifydownConfig) -> SpotifydownApi<'_> {
+        SpotifydownApi {
+            config,
+            retry_delay: Duration::from_millis(1),
+        }
+    }
+
+    async fn download(api: &SpotifydownApi<'_>, dir: &TempDir) -> anyhow::Result<DownloadedSong> {
+        let url =
+            Url::parse(&format!("https://open.spotify.com/track/{TRACK_ID}")).expect("Valid URL");
+
+        let mut songs = SpotifydownProvider::download_with(api, dir.path(), &url, None).await?;
+        assert_eq!(songs.len(), 1);
+
+        Ok(songs.remove(0))
+    }
+
+    fn song_file() -> Response<Body> {
+        let mut res = response(StatusCode::OK, "song");
+        res.headers_mut()
+            .insert("content-type", "audio/mp4".parse().expect("Valid header"));
+        res
+    }
+
+    fn download_requests(server: &TestServer) -> usize {
+        server
+            .received()
+            .iter()
+            .filter(|x| x.path.starts_with("/api/download/"))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn download_flow() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/api/download/4uLU6hMCjMI75M1A2tKUQC" => json(format!(
+                r#"{{"success": true, "link": "{}/cdn/song"}}"#,
+                req.base_url
+            )),
+            "/api/metadata/track/4uLU6hMCjMI75M1A2tKUQC" => json(fixture("metadata.json")),
+            "/cdn/song" => song_file(),
+            _ => response(StatusCode::NOT_FOUND, "not found"),
+        })
+        .await;
+        let config = config(&server);
+        let dir = TempDir::with_prefix("karaokify-test-spotifydown-")
+            .await
+            .expect("Directory created");
+
+        let song = download(&api(&config), &dir).await.expect("Downloaded");
+
+        assert_eq!(
+            song.path,
+            dir.path().join("Rick Astley - Never Gonna Give You Up.m4a")
+        );
+        assert_eq!(song.title.as_deref(), Some("Never Gonna Give You Up"));
+        assert_eq!(song.artist.as_deref(), Some("Rick Astley"));
+        assert_eq!(std::fs::read(&song.path).expect("Downloaded"), b"song");
+        assert_eq!(
+            server
+                .received()
+                .iter()
+                .map(|x| x.path.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "/api/download/4uLU6hMCjMI75M1A2tKUQC",
+                "/api/metadata/track/4uLU6hMCjMI75M1A2tKUQC",
+                "/cdn/song"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_are_retried() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/api/download/4uLU6hMCjMI75M1A2tKUQC" => json(fixture("download_error.json")),
+            _ => response(StatusCode::NOT_FOUND, "not found"),
+        })
+        .await;
+        let config = config(&server);
+        let dir = TempDir::with_prefix("karaokify-test-spotifydown-")
+            .await
+            .expect("Directory created");
+
+        let err = download(&api(&config), &dir).await.expect_err("Not found");
+
+        assert_eq!(err.to_string(), "Failed to download song from provider");
+        assert_eq!(download_requests(&server), MAX_ATTEMPTS);
+        assert_eq!(server.received().len(), MAX_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn malformed_responses() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/api/download/4uLU6hMCjMI75M1A2tKUQC" => json(r#"{"success": true, "link": "#),
+            _ => response(StatusCode::NOT_FOUND, "not found"),
+        })
+        .await;
+        let config = config(&server);
+        let dir = TempDir::with_prefix("karaokify-test-spotifydown-")
+            .await
+            .expect("Directory created");
+
+        let err = download(&api(&config), &dir).await.expect_err("Malformed");
+
+        assert_eq!(err.to_string(), "Failed to download song from provider");
+        assert_eq!(download_requests(&server), MAX_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn malformed_metadata_keeps_the_file_name() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/api/download/4uLU6hMCjMI75M1A2tKUQC" => json(format!(
+                r#"{{"success": true, "link": "{}/cdn/song"}}"#,
+                req.base_url
+            )),
+            "/api/metadata/track/4uLU6hMCjMI75M1A2tKUQC" => json("<html>Bad gateway</html>"),
+            "/cdn/song" => song_file(),
+            _ => response(StatusCode::NOT_FOUND, "not found"),
+        })
+        .await;
+        let config = config(&server);
+        let dir = TempDir::with_prefix("karaokify-test-spotifydown-")
+            .await
+            .expect("Directory created");
+
+        let song = download(&api(&config), &dir).await.expect("Downloaded");
+
+        assert_eq!(song.path, dir.path().join("some song.m4a"));
+        assert_eq!(song.title, None);
+        assert_eq!(song.artist, None);
+    }
+}
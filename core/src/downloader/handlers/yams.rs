@@ -0,0 +1,1375 @@
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Deserializer};
+use tokio::sync::watch;
+use tracing::{debug, info, trace, warn};
+use url::Url;
+
+use super::{retry_provider, AttemptError, DownloadedSong, Handler};
+use crate::{
+    config::{Config, YamsConfig},
+    helpers::{
+        cover_art::CoverArt,
+        domain::DomainParser,
+        download::{download_file, is_audio_file_name, DownloadProgress},
+        file_name::sanitize_file_name,
+        http::CLIENT,
+    },
+};
+
+/// Domains of the services yams can download from. Their subdomains are supported as well.
+const SERVICE_DOMAINS: &[(&str, &[&str])] = &[
+    ("spotify", &["spotify.com"]),
+    ("qobuz", &["qobuz.com"]),
+    ("tidal", &["tidal.com"]),
+    ("apple", &["music.apple.com"]),
+    ("deezer", &["deezer.com"]),
+    ("youtube", &["youtube.com", "youtu.be"]),
+];
+
+/// Maximum size of a single file extracted from a downloaded zip
+const MAX_ZIP_FILE_SIZE: u64 = 500 * 1000 * 1000;
+/// Maximum number of entries (including directories) in a downloaded zip
+const MAX_ZIP_ENTRIES: usize = 500;
+/// How many times the download is tried (with the different hosts) before giving up
+const MAX_ATTEMPTS: usize = 6;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+/// How many times the status is checked before giving up, and how often
+const MAX_STATUS_POLLS: usize = 300;
+const STATUS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How many more times the status is checked for the error once the download failed
+const FAILED_STATUS_POLLS: usize = 3;
+/// Messages shown to the user for common errors of the provider, by a (lowercase) part of the
+/// error
+const ERROR_MESSAGES: &[(&str, &str)] = &[
+    (
+        "not found",
+        "This track isn't available on the source service",
+    ),
+    ("isrc", "This track isn't available on the source service"),
+    (
+        "not available",
+        "This track isn't available on the source service",
+    ),
+    (
+        "unavailable",
+        "This track isn't available on the source service",
+    ),
+    (
+        "region",
+        "This track isn't available in the provider's region",
+    ),
+    ("rate limit", "The provider is overloaded, try again later"),
+    (
+        "too many requests",
+        "The provider is overloaded, try again later",
+    ),
+    ("overloaded", "The provider is overloaded, try again later"),
+    (
+        "queue is full",
+        "The provider is overloaded, try again later",
+    ),
+    ("timed out", "The provider took too long, try again later"),
+    ("timeout", "The provider took too long, try again later"),
+];
+
+type YamsId = String;
+
+#[derive(Debug, Deserialize)]
+struct YamsInitialResponse {
+    #[serde(deserialize_with = "deserialize_id")]
+    id: YamsId,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+struct YamsStatusResponse {
+    #[serde(default, deserialize_with = "deserialize_optional_id")]
+    id: Option<YamsId>,
+    status: YamsStatus,
+    /// Parsed separately so an unexpected format doesn't break the download
+    progress: Option<serde_json::Value>,
+    #[serde(default, deserialize_with = "deserialize_non_empty")]
+    error: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_non_empty")]
+    url: Option<String>,
+}
+
+/// The versions of the API return the IDs either as numbers or as strings
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawYamsId {
+    Number(u64),
+    String(String),
+}
+impl From<RawYamsId> for YamsId {
+    fn from(id: RawYamsId) -> Self {
+        match id {
+            RawYamsId::Number(x) => x.to_string(),
+            RawYamsId::String(x) => x,
+        }
+    }
+}
+
+fn deserialize_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<YamsId, D::Error> {
+    RawYamsId::deserialize(deserializer).map(Into::into)
+}
+
+fn deserialize_optional_id<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<YamsId>, D::Error> {
+    Ok(Option::<RawYamsId>::deserialize(deserializer)?.map(Into::into))
+}
+
+/// Some versions of the API send an empty string instead of leaving the field out
+fn deserialize_non_empty<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    let value = Option::<String>::deserialize(deserializer)?;
+
+    Ok(value.filter(|x| !x.trim().is_empty()))
+}
+
+/// State of the download on the side of yams
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "String")]
+enum YamsStatus {
+    Queued,
+    Downloading,
+    Converting,
+    Failed,
+    Done,
+    Unknown(String),
+}
+impl From<String> for YamsStatus {
+    fn from(status: String) -> Self {
+        match status.to_lowercase().as_str() {
+            "queued" | "pending" | "waiting" => Self::Queued,
+            "downloading" | "processing" => Self::Downloading,
+            "converting" | "zipping" | "uploading" => Self::Converting,
+            "failed" | "error" => Self::Failed,
+            "done" | "finished" | "completed" | "success" => Self::Done,
+            _ => Self::Unknown(status),
+        }
+    }
+}
+
+/// The provider couldn't download the song, so there's no point in retrying
+#[derive(Debug)]
+struct YamsFailure(String);
+impl YamsFailure {
+    /// Use a friendlier message for the common provider errors
+    fn from_provider_error(error: &str) -> Self {
+        let lowercase = error.to_lowercase();
+        let message = ERROR_MESSAGES
+            .iter()
+            .find(|(x, _)| lowercase.contains(x))
+            .map_or(error, |(_, message)| message);
+
+        Self(message.to_string())
+    }
+}
+impl std::fmt::Display for YamsFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+impl std::error::Error for YamsFailure {}
+
+/// Progress of the download on the side of yams, either just the percentage or details like
+/// `{"percent": 63.2, "stage": "downloading"}`
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum YamsProgress {
+    Percent(f64),
+    Details {
+        #[serde(alias = "percentage", alias = "progress")]
+        percent: Option<f64>,
+        #[serde(alias = "status", alias = "step")]
+        stage: Option<String>,
+    },
+}
+impl From<YamsProgress> for DownloadProgress {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn from(progress: YamsProgress) -> Self {
+        let (percent, stage) = match progress {
+            YamsProgress::Percent(percent) => (Some(percent), None),
+            YamsProgress::Details { percent, stage } => (percent, stage),
+        };
+
+        Self::Provider {
+            percent: percent.map(|x| x.clamp(0.0, 100.0).round() as u8),
+            stage: stage.filter(|x| !x.is_empty()),
+        }
+    }
+}
+
+/// What to do after a status response
+#[derive(Debug, PartialEq)]
+enum PollOutcome {
+    /// The download is done and can be downloaded from the URL
+    Done(String),
+    /// Still downloading. Has the progress, if yams sent it in a known format.
+    Pending(Option<DownloadProgress>),
+}
+
+/// Follows the status responses of a single download
+#[derive(Debug, Default)]
+struct StatusTracker {
+    last_status: Option<YamsStatus>,
+    failed_polls: usize,
+}
+impl StatusTracker {
+    fn handle(&mut self, resp: YamsStatusResponse) -> Result<PollOutcome, YamsFailure> {
+        trace!(?resp, "Song download status");
+
+        if let Some(err) = resp.error {
+            return Err(YamsFailure::from_provider_error(&err));
+        }
+
+        if let Some(url) = resp.url {
+            return Ok(PollOutcome::Done(url));
+        }
+
+        if self.last_status.as_ref() != Some(&resp.status) {
+            match &resp.status {
+                YamsStatus::Unknown(status) => {
+                    debug!(?status, "Unknown download status, waiting for it to change");
+                }
+                status => trace!(?status, "Download status changed"),
+            }
+            self.last_status = Some(resp.status.clone());
+        }
+
+        // The error usually comes with the status, but don't wait for it for too long
+        if resp.status == YamsStatus::Failed {
+            self.failed_polls += 1;
+            if self.failed_polls > FAILED_STATUS_POLLS {
+                return Err(YamsFailure(
+                    "The provider couldn't download the song".into(),
+                ));
+            }
+        }
+
+        let progress =
+            resp.progress.and_then(
+                |value| match serde_json::from_value::<YamsProgress>(value) {
+                    Ok(x) => Some(DownloadProgress::from(x)),
+                    Err(e) => {
+                        trace!(?e, "Unknown download progress format");
+                        None
+                    }
+                },
+            );
+
+        Ok(PollOutcome::Pending(progress))
+    }
+}
+
+fn parse_initial_response(body: &str) -> anyhow::Result<YamsId> {
+    serde_json::from_str::<YamsInitialResponse>(body)
+        .map(|x| x.id)
+        .map_err(Into::into)
+}
+
+fn parse_status_response(body: &str) -> anyhow::Result<YamsStatusResponse> {
+    serde_json::from_str(body).map_err(Into::into)
+}
+
+/// Where the songs are downloaded from and how long it's waited for, the configured API outside
+/// of the tests
+#[derive(Debug)]
+struct YamsApi<'a> {
+    config: &'a YamsConfig,
+    max_polls: usize,
+    poll_interval: Duration,
+    retry_delay: Duration,
+}
+impl YamsApi<'static> {
+    fn configured() -> Self {
+        Self {
+            config: &Config::global().yams,
+            max_polls: MAX_STATUS_POLLS,
+            poll_interval: STATUS_POLL_INTERVAL,
+            retry_delay: RETRY_DELAY,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct YamsProvider;
+
+#[async_trait::async_trait]
+impl Handler for YamsProvider {
+    #[tracing::instrument(skip(self, song_url, progress), fields(url = ?song_url.as_str()))]
+    async fn download(
+        &self,
+        download_dir: &Path,
+        song_url: &Url,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<Vec<DownloadedSong>> {
+        Self::download_with(&YamsApi::configured(), download_dir, song_url, progress).await
+    }
+
+    fn name(&self) -> &'static str {
+        "yams"
+    }
+
+    fn supported_services(&self) -> &'static [&'static str] {
+        &[
+            "YouTube",
+            "Spotify",
+            "Deezer",
+            "Tidal",
+            "Qobuz",
+            "Apple Music",
+        ]
+    }
+
+    async fn supports(&self, song_url: &Url) -> bool {
+        Self::get_quality(song_url).is_some()
+    }
+}
+
+impl YamsProvider {
+    async fn download_with(
+        api: &YamsApi<'_>,
+        download_dir: &Path,
+        song_url: &Url,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<Vec<DownloadedSong>> {
+        debug!("Downloading song");
+        let song_zip_path =
+            Self::download_song_zip_from_hosts(api, download_dir, song_url, progress).await?;
+        debug!(
+            ?song_zip_path,
+            "Song zip downloaded. Extracting song from zip."
+        );
+        let song_file_paths =
+            Self::extract_songs_from_zip(download_dir.to_path_buf(), song_zip_path.clone()).await?;
+
+        debug!(?song_file_paths, "Song downloaded and extracted");
+
+        let _ = tokio::fs::remove_file(song_zip_path).await;
+
+        Ok(song_file_paths
+            .into_iter()
+            .map(DownloadedSong::from_path)
+            .collect())
+    }
+
+    /// Extract the songs from the zip (there are several if it's an album), in the order they
+    /// appear in it.
+    ///
+    /// If the zip contains cover art (eg. `cover.jpg`), it's extracted next to the songs.
+    ///
+    /// Fails if the zip has too many entries or the extracted files would be too big.
+    #[tracing::instrument]
+    async fn extract_songs_from_zip(
+        download_dir: PathBuf,
+        zip_path: PathBuf,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        trace!("Extracting songs from zip");
+        let max_total_size = Config::global().max_zip_size;
+        tokio::task::spawn_blocking(move || extract_songs(&download_dir, &zip_path, max_total_size))
+            .await?
+    }
+
+    /// Let yams download the song and upload it to one of the configured hosts, then download
+    /// the zip from there.
+    ///
+    /// If anything fails along the way, the whole flow is tried again with the next host (and
+    /// the first one after the last) until the attempts run out.
+    #[tracing::instrument(skip_all, fields(url = ?song_url.as_str()))]
+    async fn download_song_zip_from_hosts(
+        api: &YamsApi<'_>,
+        download_dir: &Path,
+        song_url: &Url,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<PathBuf> {
+        let hosts = &api.config.hosts;
+
+        retry_provider(MAX_ATTEMPTS, api.retry_delay, |attempt| async move {
+            let host = &hosts[attempt % hosts.len()];
+
+            let download_url = match Self::get_download_url(api, song_url, host, progress).await {
+                Ok(x) => x,
+                Err(e) if e.is::<YamsFailure>() => {
+                    warn!(?e, "Provider failed to download song");
+                    return Err(AttemptError::Fatal(e));
+                }
+                Err(e) => {
+                    debug!(?e, ?host, "Failed to get download URL");
+                    return Err(AttemptError::Retry(e));
+                }
+            };
+            debug!(?download_url, "Download URL found. Downloading song zip.");
+
+            match Self::download_song_zip(download_dir, &download_url, progress).await {
+                Ok(x) => {
+                    info!(?host, "Downloaded song zip");
+                    Ok(x)
+                }
+                // The host couldn't be reached or doesn't have the file (anymore)
+                Err(e) if e.is::<reqwest::Error>() => {
+                    debug!(?e, ?host, "Failed to download song zip from host");
+                    Err(AttemptError::Retry(e))
+                }
+                Err(e) => Err(AttemptError::Fatal(e)),
+            }
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(progress))]
+    async fn download_song_zip(
+        download_dir: &Path,
+        download_url: &str,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<PathBuf> {
+        trace!("Downloading song zip");
+        let download_path = download_dir.join("file.zip");
+
+        download_file(&download_path, download_url, progress).await?;
+
+        Ok(download_path)
+    }
+
+    #[tracing::instrument(skip_all, fields(url = ?song_url.as_str()))]
+    async fn get_download_url(
+        api: &YamsApi<'_>,
+        song_url: &Url,
+        host: &str,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<String> {
+        debug!("Getting song download URL");
+        let download_id = Self::initialize_song_download(api, song_url, host).await?;
+        Self::wait_for_song_to_finish(api, &download_id, progress).await
+    }
+
+    /// Start the download of the song, which is uploaded to `host` once it's done
+    async fn initialize_song_download(
+        api: &YamsApi<'_>,
+        song_url: &Url,
+        host: &str,
+    ) -> anyhow::Result<YamsId> {
+        debug!("Initializing song download");
+
+        let quality = match Self::get_quality(song_url) {
+            Some(q) => q,
+            None => anyhow::bail!("Could not determine quality to download"),
+        };
+
+        let payload = serde_json::json!({
+            "url": song_url.as_str(),
+            "quality": quality,
+            "host": host,
+        });
+
+        trace!(
+            ?payload,
+            "Sending download request to music download service"
+        );
+
+        let resp = CLIENT
+            .post(api.config.api_url.as_str())
+            .json(&payload)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        trace!(?resp, "Response received from music download service");
+
+        let resp_body = resp.text().await?;
+
+        trace!(
+            ?resp_body,
+            "Response body received from music download service"
+        );
+
+        parse_initial_response(&resp_body)
+    }
+
+    /// The quality to download the song in, if it's from one of the supported services
+    fn get_quality(song_url: &Url) -> Option<&'static str> {
+        let (service, _) = SERVICE_DOMAINS
+            .iter()
+            .find(|(_, domains)| DomainParser::is_on(song_url, domains))?;
+
+        Config::global()
+            .yams
+            .quality
+            .iter()
+            .find(|(x, _)| x == service)
+            .map(|(_, quality)| quality.as_str())
+    }
+
+    /// Poll the status of the download until it's done, reporting the progress yams sends
+    async fn wait_for_song_to_finish(
+        api: &YamsApi<'_>,
+        download_id: &YamsId,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<String> {
+        debug!("Waiting for song to finish");
+        let mut api_url = api.config.api_url.clone();
+        api_url.query_pairs_mut().append_pair("id", download_id);
+
+        let mut tracker = StatusTracker::default();
+        for _ in 0..api.max_polls {
+            let resp_body = CLIENT
+                .get(api_url.as_str())
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+
+            match tracker.handle(parse_status_response(&resp_body)?)? {
+                PollOutcome::Done(url) => return Ok(url),
+                PollOutcome::Pending(Some(new)) => {
+                    if let Some(progress) = progress {
+                        progress.send_if_modified(|x| {
+                            let changed = *x != new;
+                            *x = new;
+                            changed
+                        });
+                    }
+                }
+                PollOutcome::Pending(None) => {}
+            }
+
+            tokio::time::sleep(api.poll_interval).await;
+        }
+
+        anyhow::bail!("Song download timed out");
+    }
+}
+
+/// Extract the songs (and the cover art) from the zip into `download_dir`. The files are at most
+/// `max_total_size` bytes in total.
+fn extract_songs(
+    download_dir: &Path,
+    zip_path: &Path,
+    max_total_size: u64,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let zip_file = std::fs::File::open(zip_path)?;
+    let mut zip = zip::ZipArchive::new(zip_file)?;
+
+    if zip.len() > MAX_ZIP_ENTRIES {
+        anyhow::bail!(
+            "The downloaded zip has too many files ({}, at most {MAX_ZIP_ENTRIES} are \
+             allowed)",
+            zip.len()
+        );
+    }
+
+    trace!("Finding files in zip");
+
+    let mut total_size = 0;
+    let mut song_paths = vec![];
+    let mut cover_path = None;
+    for i in 0..zip.len() {
+        let mut file_in_zip = zip.by_index(i)?;
+
+        if !file_in_zip.is_file() {
+            continue;
+        }
+
+        trace!(f = ?file_in_zip.name(), "Found file in zip");
+
+        // Files in nested directories are extracted directly into the download directory
+        let file_name = match file_in_zip
+            .enclosed_name()
+            .and_then(|x| x.file_name().map(|x| x.to_string_lossy().to_string()))
+        {
+            Some(x) => x,
+            None => continue,
+        };
+
+        if file_name.starts_with('.') {
+            continue;
+        }
+
+        let file_path = if CoverArt::is_cover_file_name(Path::new(&file_name)) {
+            if cover_path.is_some() {
+                continue;
+            }
+
+            let extension = Path::new(&file_name)
+                .extension()
+                .unwrap_or_default()
+                .to_os_string();
+            let file_path = download_dir.join("cover").with_extension(extension);
+            cover_path = Some(file_path.clone());
+
+            file_path
+        } else if is_audio_file_name(Path::new(&file_name)) {
+            let file_name = sanitize_file_name(&file_name);
+            let mut file_path = download_dir.join(&file_name);
+            // Songs from different directories can have the same name, and the numbered name
+            // can be taken too
+            let mut number = song_paths.len() + 1;
+            while song_paths.contains(&file_path) {
+                file_path = download_dir.join(format!("{number}-{file_name}"));
+                number += 1;
+            }
+            song_paths.push(file_path.clone());
+
+            file_path
+        } else {
+            continue;
+        };
+
+        anyhow::ensure!(
+            file_path.parent() == Some(download_dir),
+            "File in zip would be extracted outside of the download directory: {}",
+            file_path.display()
+        );
+
+        // The sizes in the zip can't be trusted, so the limit is also checked while
+        // extracting
+        let remaining_size = max_total_size.saturating_sub(total_size);
+        let size_limit = MAX_ZIP_FILE_SIZE.min(remaining_size);
+        let too_big = || {
+            if size_limit == remaining_size {
+                anyhow::anyhow!(
+                    "The files in the downloaded zip are bigger than {} MB in total",
+                    max_total_size / 1000 / 1000
+                )
+            } else {
+                anyhow::anyhow!(
+                    "{file_name} in the downloaded zip is bigger than {} MB",
+                    MAX_ZIP_FILE_SIZE / 1000 / 1000
+                )
+            }
+        };
+        if file_in_zip.size() > size_limit {
+            return Err(too_big());
+        }
+
+        trace!(?file_path, "Extracing file from zip");
+
+        let mut file_on_disk = std::fs::File::create(&file_path)?;
+
+        let written = std::io::copy(
+            &mut (&mut file_in_zip).take(size_limit + 1),
+            &mut file_on_disk,
+        )?;
+        if written > size_limit {
+            return Err(too_big());
+        }
+        total_size += written;
+    }
+
+    if song_paths.is_empty() {
+        anyhow::bail!("Could not find a song in zip");
+    }
+
+    Ok(song_paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hyper::{Method, StatusCode};
+
+    use super::*;
+    use crate::helpers::{
+        temp_dir::TempDir,
+        test_server::{json, response, Received, TestServer},
+    };
+
+    fn fixture_path(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/yams")
+            .join(name)
+    }
+
+    fn fixture(name: &str) -> String {
+        std::fs::read_to_string(fixture_path(name)).expect("Fixture exists")
+    }
+
+    fn status(name: &str) -> YamsStatusResponse {
+        parse_status_response(&fixture(name)).expect("Valid status response")
+    }
+
+    async fn extract_dir() -> TempDir {
+        TempDir::with_prefix("karaokify-test-yams-")
+            .await
+            .expect("Directory created")
+    }
+
+    #[test]
+    fn initial_response() {
+        assert_eq!(
+            parse_initial_response(&fixture("initial.json")).expect("Valid response"),
+            "4821"
+        );
+        assert!(parse_initial_response("<html>Bad gateway</html>").is_err());
+    }
+
+    #[test]
+    fn initial_response_ids() {
+        for (body, id) in [
+            (r#"{"id": 4821}"#, "4821"),
+            (r#"{"id": "4821"}"#, "4821"),
+            (r#"{"id": "a1b2-c3"}"#, "a1b2-c3"),
+        ] {
+            assert_eq!(parse_initial_response(body).expect(body), id, "{body}");
+        }
+
+        assert!(parse_initial_response(r#"{"id": null}"#).is_err());
+        assert!(parse_initial_response("{}").is_err());
+    }
+
+    #[test]
+    fn status_response_shapes() {
+        // Older versions send the ID as a string and every field, empty if there's no value
+        let resp = parse_status_response(
+            r#"{"id": "4821", "status": "Downloading", "progress": 12, "error": "", "url": ""}"#,
+        )
+        .expect("Valid response");
+        assert_eq!(resp.id.as_deref(), Some("4821"));
+        assert_eq!(resp.status, YamsStatus::Downloading);
+        assert_eq!(resp.error, None);
+        assert_eq!(resp.url, None);
+
+        // Newer versions send a numeric ID and leave out the fields without a value
+        let resp =
+            parse_status_response(r#"{"id": 4821, "status": "queued"}"#).expect("Valid response");
+        assert_eq!(resp.id.as_deref(), Some("4821"));
+        assert_eq!(resp.status, YamsStatus::Queued);
+        assert!(resp.progress.is_none());
+        assert_eq!(resp.error, None);
+        assert_eq!(resp.url, None);
+
+        let resp = parse_status_response(r#"{"status": "error", "error": "  ", "url": null}"#)
+            .expect("Valid response");
+        assert_eq!(resp.id, None);
+        assert_eq!(resp.status, YamsStatus::Failed);
+        assert_eq!(resp.error, None);
+
+        let resp = parse_status_response(
+            r#"{"id": "4821", "status": "completed", "error": "", "url": "https://files.example.com/x.zip"}"#,
+        )
+        .expect("Valid response");
+        assert_eq!(resp.status, YamsStatus::Done);
+        assert_eq!(resp.error, None);
+        assert_eq!(resp.url.as_deref(), Some("https://files.example.com/x.zip"));
+
+        assert!(parse_status_response(r#"{"id": 4821}"#).is_err());
+    }
+
+    #[test]
+    fn status_until_done() {
+        let mut tracker = StatusTracker::default();
+
+        assert_eq!(
+            tracker
+                .handle(status("status_queued.json"))
+                .expect("Pending"),
+            PollOutcome::Pending(None)
+        );
+        assert_eq!(
+            tracker
+                .handle(status("status_downloading.json"))
+                .expect("Pending"),
+            PollOutcome::Pending(Some(DownloadProgress::Provider {
+                percent: Some(63),
+                stage: Some("downloading".into()),
+            }))
+        );
+        assert_eq!(
+            tracker
+                .handle(status("status_converting.json"))
+                .expect("Pending"),
+            PollOutcome::Pending(Some(DownloadProgress::Provider {
+                percent: Some(100),
+                stage: None,
+            }))
+        );
+        assert_eq!(
+            tracker
+                .handle(status("status_unknown_progress.json"))
+                .expect("Pending"),
+            PollOutcome::Pending(None)
+        );
+        assert_eq!(
+            tracker.last_status,
+            Some(YamsStatus::Unknown("transcoding".into()))
+        );
+        assert_eq!(
+            tracker.handle(status("status_done.json")).expect("Done"),
+            PollOutcome::Done("https://files.example.com/d/4821/Artist%20-%20Song.zip".into())
+        );
+    }
+
+    #[test]
+    fn status_with_error() {
+        let err = StatusTracker::default()
+            .handle(status("status_failed_with_error.json"))
+            .expect_err("Failed");
+
+        assert_eq!(
+            err.to_string(),
+            "This track isn't available on the source service"
+        );
+    }
+
+    #[test]
+    fn status_values() {
+        for (value, status) in [
+            ("queued", YamsStatus::Queued),
+            ("Pending", YamsStatus::Queued),
+            ("waiting", YamsStatus::Queued),
+            ("DOWNLOADING", YamsStatus::Downloading),
+            ("processing", YamsStatus::Downloading),
+            ("converting", YamsStatus::Converting),
+            ("zipping", YamsStatus::Converting),
+            ("uploading", YamsStatus::Converting),
+            ("failed", YamsStatus::Failed),
+            ("Error", YamsStatus::Failed),
+            ("done", YamsStatus::Done),
+            ("finished", YamsStatus::Done),
+            ("completed", YamsStatus::Done),
+            ("success", YamsStatus::Done),
+            ("Transcoding", YamsStatus::Unknown("Transcoding".into())),
+            ("", YamsStatus::Unknown(String::new())),
+        ] {
+            assert_eq!(YamsStatus::from(value.to_string()), status, "{value}");
+        }
+    }
+
+    #[test]
+    fn error_messages() {
+        let unavailable = "This track isn't available on the source service";
+        let overloaded = "The provider is overloaded, try again later";
+
+        for (error, message) in [
+            ("ISRC not found upstream", unavailable),
+            ("Track not found", unavailable),
+            ("Song is not available in your country", unavailable),
+            ("TRACK_UNAVAILABLE", unavailable),
+            (
+                "Blocked in this region",
+                "This track isn't available in the provider's region",
+            ),
+            ("Rate limit exceeded", overloaded),
+            ("429 Too Many Requests", overloaded),
+            ("Server overloaded", overloaded),
+            ("The queue is full", overloaded),
+            (
+                "Upstream timed out",
+                "The provider took too long, try again later",
+            ),
+            (
+                "gateway timeout",
+                "The provider took too long, try again later",
+            ),
+            // Other errors are shown as they are
+            ("Invalid link", "Invalid link"),
+            ("Qobuz account expired", "Qobuz account expired"),
+        ] {
+            assert_eq!(
+                YamsFailure::from_provider_error(error).to_string(),
+                message,
+                "{error}"
+            );
+        }
+    }
+
+    #[test]
+    fn progress_formats() {
+        let progress = |json: &str| {
+            serde_json::from_str::<YamsProgress>(json)
+                .map(DownloadProgress::from)
+                .ok()
+        };
+        let provider = |percent: Option<u8>, stage: Option<&str>| {
+            Some(DownloadProgress::Provider {
+                percent,
+                stage: stage.map(Into::into),
+            })
+        };
+
+        assert_eq!(progress("42.6"), provider(Some(43), None));
+        assert_eq!(progress("-5"), provider(Some(0), None));
+        assert_eq!(progress("250"), provider(Some(100), None));
+        assert_eq!(
+            progress(r#"{"percentage": 10, "status": "zipping"}"#),
+            provider(Some(10), Some("zipping"))
+        );
+        assert_eq!(
+            progress(r#"{"progress": 99.9, "step": ""}"#),
+            provider(Some(100), None)
+        );
+        assert_eq!(
+            progress(r#"{"stage": "queued"}"#),
+            provider(None, Some("queued"))
+        );
+        assert_eq!(progress(r#""50%""#), None);
+    }
+
+    #[test]
+    fn status_failed_without_error() {
+        let mut tracker = StatusTracker::default();
+        for _ in 0..FAILED_STATUS_POLLS {
+            assert_eq!(
+                tracker
+                    .handle(status("status_failed.json"))
+                    .expect("Pending"),
+                PollOutcome::Pending(None)
+            );
+        }
+
+        let err = tracker
+            .handle(status("status_failed.json"))
+            .expect_err("Failed");
+        assert_eq!(err.to_string(), "The provider couldn't download the song");
+    }
+
+    #[test]
+    fn qualities_by_domain() {
+        for (url, quality) in [
+            (
+                "https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC",
+                Some("very_high"),
+            ),
+            ("https://spotify.com/track/x", Some("very_high")),
+            ("https://www.qobuz.com/us-en/album/x", Some("27")),
+            ("https://listen.tidal.com/track/1", Some("3")),
+            ("https://tidal.com/browse/track/1", Some("3")),
+            ("https://music.apple.com/us/album/x/1?i=2", Some("high")),
+            ("https://www.deezer.com/track/1", Some("2")),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ", Some("0")),
+            ("https://music.youtube.com/watch?v=dQw4w9WgXcQ", Some("0")),
+            ("https://youtu.be/dQw4w9WgXcQ", Some("0")),
+            // Lookalikes only contain the names of the services
+            ("https://notspotify.evil.com/track/x", None),
+            ("https://spotify.com.evil.com/track/x", None),
+            ("https://evil.com/open.spotify.com/track/x", None),
+            ("https://open.spotify.evil.com/track/x", None),
+            ("https://myspotify.com/track/x", None),
+            ("https://apple.com/music", None),
+            ("https://soundcloud.com/artist/song", None),
+        ] {
+            let url = Url::parse(url).expect("Valid URL");
+
+            assert_eq!(YamsProvider::get_quality(&url), quality, "{url}");
+        }
+    }
+
+    #[tokio::test]
+    async fn extract_album() {
+        let dir = extract_dir().await;
+
+        let songs = extract_songs(dir.path(), &fixture_path("album.zip"), 1000).expect("Extracted");
+
+        assert_eq!(
+            songs,
+            [
+                dir.path().join("01 Song.mp3"),
+                dir.path().join("2-01 Song.mp3")
+            ]
+        );
+        assert_eq!(
+            std::fs::read(&songs[0]).expect("Song extracted"),
+            b"first song"
+        );
+        assert_eq!(
+            std::fs::read(&songs[1]).expect("Song extracted"),
+            b"second song"
+        );
+        assert_eq!(
+            std::fs::read(dir.path().join("cover.jpg")).expect("Cover extracted"),
+            b"cover"
+        );
+
+        let mut files = std::fs::read_dir(dir.path())
+            .expect("Directory readable")
+            .map(|x| x.expect("Entry readable").file_name())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["01 Song.mp3", "2-01 Song.mp3", "cover.jpg"]);
+    }
+
+    #[tokio::test]
+    async fn extract_name_collisions() {
+        let dir = extract_dir().await;
+
+        let songs =
+            extract_songs(dir.path(), &fixture_path("collisions.zip"), 1000).expect("Extracted");
+
+        // The numbered name for the second `song.mp3` is already taken
+        assert_eq!(
+            songs,
+            [
+                dir.path().join("3-song.mp3"),
+                dir.path().join("song.mp3"),
+                dir.path().join("4-song.mp3"),
+            ]
+        );
+        for (song, content) in
+            songs
+                .iter()
+                .zip([&b"numbered song"[..], b"first song", b"second song"])
+        {
+            assert_eq!(std::fs::read(song).expect("Song extracted"), content);
+        }
+    }
+
+    #[tokio::test]
+    async fn extract_single_song() {
+        let dir = extract_dir().await;
+
+        let songs =
+            extract_songs(dir.path(), &fixture_path("single_song.zip"), 1000).expect("Extracted");
+
+        assert_eq!(songs, [dir.path().join("Artist - Song.FLAC")]);
+        assert_eq!(
+            std::fs::read(&songs[0]).expect("Song extracted"),
+            b"the song"
+        );
+        assert_eq!(
+            std::fs::read(dir.path().join("cover.PNG")).expect("Cover extracted"),
+            b"png"
+        );
+        assert_eq!(
+            std::fs::read_dir(dir.path())
+                .expect("Directory readable")
+                .count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn extract_path_traversal() {
+        let dir = extract_dir().await;
+
+        let songs = extract_songs(dir.path(), &fixture_path("path_traversal.zip"), 1000)
+            .expect("Extracted");
+
+        assert_eq!(songs, [dir.path().join("song.mp3")]);
+        assert!(!dir.path().join("../escaped.mp3").exists());
+        assert!(!dir.path().join("../../escaped.mp3").exists());
+        assert_eq!(
+            std::fs::read_dir(dir.path())
+                .expect("Directory readable")
+                .count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn extract_weird_names() {
+        let dir = extract_dir().await;
+
+        let songs =
+            extract_songs(dir.path(), &fixture_path("weird_names.zip"), 1000).expect("Extracted");
+
+        assert_eq!(
+            songs,
+            [
+                dir.path().join("Song_ _live_ _ _best__.mp3"),
+                dir.path().join(format!("L{}.mp3", "o".repeat(99))),
+                // Backslashes aren't separators in zips, but they are on Windows
+                dir.path().join("dir_back_slash.mp3"),
+            ]
+        );
+        assert!(songs.iter().all(|x| x.is_file()));
+    }
+
+    #[tokio::test]
+    async fn extract_without_songs() {
+        let dir = extract_dir().await;
+
+        let err =
+            extract_songs(dir.path(), &fixture_path("no_songs.zip"), 1000).expect_err("No songs");
+
+        assert_eq!(err.to_string(), "Could not find a song in zip");
+    }
+
+    #[tokio::test]
+    async fn extract_too_big() {
+        let dir = extract_dir().await;
+
+        let err =
+            extract_songs(dir.path(), &fixture_path("big_song.zip"), 1000).expect_err("Too big");
+        assert!(
+            err.to_string().contains("bigger than"),
+            "Unexpected error: {err}"
+        );
+
+        extract_songs(dir.path(), &fixture_path("big_song.zip"), 4096).expect("Small enough");
+    }
+
+    #[tokio::test]
+    async fn extract_too_many_entries() {
+        let dir = extract_dir().await;
+
+        let err = extract_songs(dir.path(), &fixture_path("too_many_entries.zip"), 1000)
+            .expect_err("Too many entries");
+
+        assert!(
+            err.to_string().contains("too many files"),
+            "Unexpected error: {err}"
+        );
+    }
+
+    const SONG_URL: &str = "https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC";
+
+    fn config(server: &TestServer, hosts: &[&str]) -> YamsConfig {
+        YamsConfig {
+            api_url: Url::parse(&server.url("/api")).expect("Valid URL"),
+            hosts: hosts.iter().map(ToString::to_string).collect(),
+            quality: vec![],
+        }
+    }
+
+    /// The API of the server, which is polled and retried without waiting for long
+    fn api(config: &YamsConfig) -> YamsApi<'_> {
+        YamsApi {
+            config,
+            max_polls: 3,
+            poll_interval: Duration::from_millis(20),
+            retry_delay: Duration::from_millis(1),
+        }
+    }
+
+    /// The progress updates sent until `progress` is dropped
+    fn collect_progress(
+        mut updates: watch::Receiver<DownloadProgress>,
+    ) -> tokio::task::JoinHandle<Vec<DownloadProgress>> {
+        tokio::spawn(async move {
+            let mut res = vec![];
+            while updates.changed().await.is_ok() {
+                res.push(updates.borrow_and_update().clone());
+            }
+            res
+        })
+    }
+
+    fn requests(server: &TestServer, method: &Method) -> Vec<Received> {
+        server
+            .received()
+            .into_iter()
+            .filter(|x| x.method == *method)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn download_flow() {
+        let polls = AtomicUsize::new(0);
+        let server = TestServer::start(move |req| match (req.method.as_str(), req.path.as_str()) {
+            ("POST", "/api") => json(fixture("initial.json")),
+            ("GET", "/api?id=4821") => match polls.fetch_add(1, Ordering::SeqCst) {
+                0 => json(fixture("status_queued.json")),
+                1 => json(r#"{"status": "downloading", "progress": 40}"#),
+                2 => json(
+                    r#"{"status": "zipping", "progress": {"percent": 90, "stage": "zipping"}}"#,
+                ),
+                _ => json(format!(
+                    r#"{{"status": "done", "url": "{}/files/song.zip"}}"#,
+                    req.base_url
+                )),
+            },
+            ("GET", "/files/song.zip") => response(
+                StatusCode::OK,
+                std::fs::read(fixture_path("album.zip")).expect("Fixture exists"),
+            ),
+            _ => response(StatusCode::NOT_FOUND, "not found"),
+        })
+        .await;
+        let config = config(&server, &["filehaus"]);
+        let api = YamsApi {
+            max_polls: 10,
+            ..api(&config)
+        };
+        let dir = extract_dir().await;
+        let (progress, updates) = watch::channel(DownloadProgress::starting());
+        let updates = collect_progress(updates);
+
+        let songs = YamsProvider::download_with(
+            &api,
+            dir.path(),
+            &Url::parse(SONG_URL).expect("Valid URL"),
+            Some(&progress),
+        )
+        .await
+        .expect("Downloaded");
+
+        assert_eq!(
+            songs.iter().map(|x| x.path.clone()).collect::<Vec<_>>(),
+            [
+                dir.path().join("01 Song.mp3"),
+                dir.path().join("2-01 Song.mp3")
+            ]
+        );
+        assert!(!dir.path().join("file.zip").exists());
+
+        let init = requests(&server, &Method::POST);
+        assert_eq!(init.len(), 1);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&init[0].body).expect("JSON request"),
+            serde_json::json!({"url": SONG_URL, "quality": "very_high", "host": "filehaus"})
+        );
+        assert_eq!(
+            requests(&server, &Method::GET)
+                .iter()
+                .map(|x| x.path.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "/api?id=4821",
+                "/api?id=4821",
+                "/api?id=4821",
+                "/api?id=4821",
+                "/files/song.zip"
+            ]
+        );
+
+        drop(progress);
+        let updates = updates.await.expect("Progress collected");
+        for expected in [
+            DownloadProgress::Provider {
+                percent: Some(40),
+                stage: None,
+            },
+            DownloadProgress::Provider {
+                percent: Some(90),
+                stage: Some("zipping".to_string()),
+            },
+        ] {
+            assert!(updates.contains(&expected), "{updates:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn provider_errors_are_not_retried() {
+        let server = TestServer::start(|req| match req.method {
+            Method::POST => json(fixture("initial.json")),
+            _ => json(r#"{"status": "failed", "error": "Track not found (ISRC lookup)"}"#),
+        })
+        .await;
+        let config = config(&server, &["filehaus", "pixeldrain"]);
+        let dir = extract_dir().await;
+
+        let err = YamsProvider::download_with(
+            &api(&config),
+            dir.path(),
+            &Url::parse(SONG_URL).expect("Valid URL"),
+            None,
+        )
+        .await
+        .expect_err("Provider failed");
+
+        assert_eq!(
+            err.to_string(),
+            "This track isn't available on the source service"
+        );
+        assert_eq!(server.received().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn polling_times_out() {
+        let server = TestServer::start(|req| match req.method {
+            Method::POST => json(fixture("initial.json")),
+            _ => json(fixture("status_downloading.json")),
+        })
+        .await;
+        let config = config(&server, &["filehaus"]);
+        let dir = extract_dir().await;
+
+        let err = YamsProvider::download_with(
+            &api(&config),
+            dir.path(),
+            &Url::parse(SONG_URL).expect("Valid URL"),
+            None,
+        )
+        .await
+        .expect_err("Timed out");
+
+        // Every attempt polls until it runs out of polls
+        assert_eq!(err.to_string(), "Failed to download song from provider");
+        assert_eq!(requests(&server, &Method::POST).len(), MAX_ATTEMPTS);
+        assert_eq!(requests(&server, &Method::GET).len(), MAX_ATTEMPTS * 3);
+    }
+
+    #[tokio::test]
+    async fn missing_zips_are_retried_with_the_next_host() {
+        let server = TestServer::start(|req| match (req.method.as_str(), req.path.as_str()) {
+            ("POST", _) if req.body.contains("filehaus") => json(r#"{"id": 1}"#),
+            ("POST", _) => json(r#"{"id": "2"}"#),
+            ("GET", "/api?id=1") => json(format!(
+                r#"{{"status": "done", "url": "{}/files/gone.zip"}}"#,
+                req.base_url
+            )),
+            ("GET", "/api?id=2") => json(format!(
+                r#"{{"status": "done", "url": "{}/files/song.zip"}}"#,
+                req.base_url
+            )),
+            ("GET", "/files/song.zip") => response(
+                StatusCode::OK,
+                std::fs::read(fixture_path("single_song.zip")).expect("Fixture exists"),
+            ),
+            _ => response(StatusCode::NOT_FOUND, "not found"),
+        })
+        .await;
+        let config = config(&server, &["filehaus", "pixeldrain"]);
+        let dir = extract_dir().await;
+
+        let songs = YamsProvider::download_with(
+            &api(&config),
+            dir.path(),
+            &Url::parse(SONG_URL).expect("Valid URL"),
+            None,
+        )
+        .await
+        .expect("Downloaded from the second host");
+
+        assert_eq!(songs.len(), 1);
+        assert_eq!(songs[0].path, dir.path().join("Artist - Song.FLAC"));
+        assert_eq!(
+            requests(&server, &Method::POST)
+                .iter()
+                .map(
+                    |x| serde_json::from_str::<serde_json::Value>(&x.body).expect("JSON")["host"]
+                        .clone()
+                )
+                .collect::<Vec<_>>(),
+            ["filehaus", "pixeldrain"]
+        );
+        assert!(server
+            .received()
+            .iter()
+            .any(|x| x.path == "/files/gone.zip"));
+    }
+
+    #[tokio::test]
+    async fn malformed_responses_are_retried() {
+        let server = TestServer::start(|req| match req.method {
+            Method::POST => json(fixture("initial.json")),
+            _ => json(r#"{"status": "downloading", "progress": 4"#),
+        })
+        .await;
+        let config = config(&server, &["filehaus"]);
+        let dir = extract_dir().await;
+
+        let err = YamsProvider::download_with(
+            &api(&config),
+            dir.path(),
+            &Url::parse(SONG_URL).expect("Valid URL"),
+            None,
+        )
+        .await
+        .expect_err("Malformed status");
+
+        assert_eq!(err.to_string(), "Failed to download song from provider");
+        assert_eq!(requests(&server, &Method::POST).len(), MAX_ATTEMPTS);
+        assert_eq!(requests(&server, &Method::GET).len(), MAX_ATTEMPTS);
+    }
+}
@@ -0,0 +1,362 @@
+pub mod handlers;
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use handlers::HANDLERS;
+pub use handlers::{DownloadHandler, Handler, HandlerHealth, HandlerKind};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::{
+    config::Config,
+    events::{self, Event},
+    helpers::{
+        download::DownloadProgress,
+        resolve_url::{is_song_link, resolve_url, song_link_platform_urls},
+    },
+};
+
+/// A song downloaded by a handler
+#[derive(Debug, Clone)]
+pub struct DownloadedSong {
+    pub path: PathBuf,
+    /// Details of the song from the provider, if it has them
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+impl DownloadedSong {
+    /// A song without any details besides the file
+    pub const fn from_path(path: PathBuf) -> Self {
+        Self {
+            path,
+            title: None,
+            artist: None,
+        }
+    }
+}
+
+/// Why none of the handlers could download the song
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct DownloadError {
+    pub url: Url,
+    /// Names of the handlers that were tried and the reasons they failed, in the order they were
+    /// tried
+    pub failures: Vec<(&'static str, anyhow::Error)>,
+}
+impl DownloadError {
+    /// The reasons the handlers failed, eg. `yams: timed out; spotifydown: track not found`
+    pub fn reasons(&self) -> impl Iterator<Item = String> + '_ {
+        self.failures
+            .iter()
+            .map(|(name, e)| format!("{name}: {e:#}"))
+    }
+}
+impl Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.failures.is_empty() {
+            return write!(f, "No handler supports the URL: {}", self.url);
+        }
+
+        write!(
+            f,
+            "No handler succeeded for provided URL: {} ({})",
+            self.url,
+            self.reasons().collect::<Vec<_>>().join("; ")
+        )
+    }
+}
+impl std::error::Error for DownloadError {}
+
+pub struct Downloader;
+impl Downloader {
+    /// Download the song using the first handler that succeeds.
+    ///
+    /// Returns the downloaded songs, which can be several if the handler downloads an album as a
+    /// whole.
+    ///
+    /// Progress of the file download is reported through `progress`.
+    #[tracing::instrument(skip_all, fields(url = ?song_url.as_str()))]
+    pub async fn download_song(
+        download_dir: &Path,
+        song_url: &Url,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> Result<Vec<DownloadedSong>, DownloadError> {
+        info!("Downloading song...");
+
+        let song_url = &Self::resolve_supported_url(song_url).await;
+
+        Self::download_with(&HANDLERS, download_dir, song_url, progress).await
+    }
+
+    /// Download the song using the first of `handlers` that succeeds, skipping the ones that
+    /// keep failing
+    async fn download_with(
+        handlers: &[DownloadHandler],
+        download_dir: &Path,
+        song_url: &Url,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> Result<Vec<DownloadedSong>, DownloadError> {
+        let mut failures = vec![];
+        for handler in handlers {
+            if !handler.supports(song_url).await {
+                continue;
+            }
+
+            if !handler.is_available() {
+                failures.push((
+                    handler.name(),
+                    anyhow::anyhow!("skipped because it failed too often recently"),
+                ));
+                continue;
+            }
+
+            let timeout = Config::global().handler_timeout;
+            let res =
+                tokio::time::timeout(timeout, handler.download(download_dir, song_url, progress))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(anyhow::anyhow!(
+                            "timed out after {} seconds",
+                            timeout.as_secs()
+                        ))
+                    });
+            handler.record_download(res.is_ok());
+            events::emit(Event::HandlerDownload {
+                handler: handler.name(),
+                success: res.is_ok(),
+            });
+
+            match res {
+                Ok(songs) => {
+                    info!(?songs, "Downloaded song");
+                    return Ok(songs);
+                }
+                Err(e) => failures.push((handler.name(), e)),
+            }
+        }
+
+        let e = DownloadError {
+            url: song_url.clone(),
+            failures,
+        };
+        warn!(%e, "Download failed");
+
+        Err(e)
+    }
+
+    /// Names of the handlers in the order they're tried
+    pub fn handler_names() -> Vec<&'static str> {
+        HANDLERS
+            .iter()
+            .map(handlers::DownloadHandler::name)
+            .collect()
+    }
+
+    /// Names of the services the enabled handlers can download from, without duplicates
+    pub fn supported_services() -> Vec<&'static str> {
+        let mut services = vec![];
+        for service in HANDLERS
+            .iter()
+            .flat_map(handlers::DownloadHandler::supported_services)
+        {
+            if !services.contains(service) {
+                services.push(*service);
+            }
+        }
+
+        services
+    }
+
+    /// Names of the handlers with the outcomes of their latest downloads
+    pub fn handler_health() -> Vec<(&'static str, HandlerHealth)> {
+        HANDLERS.iter().map(|x| (x.name(), x.health())).collect()
+    }
+
+    /// Whether any of the handlers can download the URL
+    pub async fn supports(url: &Url) -> bool {
+        for handler in HANDLERS.iter() {
+            if handler.supports(url).await {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// The URL to download the song from if any of the handlers can download it, following
+    /// redirects (eg. of short links) if needed
+    pub async fn supported_url(url: &Url) -> Option<Url> {
+        let url = Self::resolve_supported_url(url).await;
+
+        Self::supports(&url).await.then_some(url)
+    }
+
+    /// Expand a collection (eg. an album or playlist) into the URLs of the songs in it.
+    ///
+    /// Returns `None` if the URL isn't a known collection (eg. it's a link to a single song).
+    #[tracing::instrument(skip_all, fields(url = ?url.as_str()))]
+    pub async fn expand_collection(url: &Url, limit: usize) -> Option<anyhow::Result<Vec<Url>>> {
+        let url = &Self::resolve_supported_url(url).await;
+
+        for handler in HANDLERS.iter() {
+            if let Some(res) = handler.expand_collection(url, limit).await {
+                info!(ok = res.is_ok(), ?handler, "Expanded collection");
+                return Some(res);
+            }
+        }
+
+        None
+    }
+
+    /// If no handler supports the URL, follow its redirects (eg. of `spotify.link` or `t.co`
+    /// links) and use the final URL instead. For song.link pages the first supported platform
+    /// link is used.
+    ///
+    /// Returns the original URL if nothing better is found.
+    async fn resolve_supported_url(url: &Url) -> Url {
+        if Self::supports(url).await {
+            return url.clone();
+        }
+
+        let resolved = match resolve_url(url).await {
+            Ok(x) => x,
+            Err(e) => {
+                debug!(?e, url = ?url.as_str(), "Failed to resolve URL");
+                return url.clone();
+            }
+        };
+        info!(url = ?url.as_str(), resolved = ?resolved.as_str(), "Resolved URL");
+
+        if !is_song_link(&resolved) {
+            return resolved;
+        }
+
+        let platform_urls = match song_link_platform_urls(&resolved).await {
+            Ok(x) => x,
+            Err(e) => {
+                debug!(?e, "Failed to get song.link platform links");
+                return resolved;
+            }
+        };
+
+        for platform_url in platform_urls {
+            if Self::supports(&platform_url).await {
+                info!(url = ?platform_url.as_str(), "Using song.link platform link");
+                return platform_url;
+            }
+        }
+
+        resolved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::Ordering, Arc};
+
+    use super::*;
+    use crate::downloader::handlers::tests::FakeHandler;
+
+    fn fake_handlers(count: usize) -> (Vec<Arc<FakeHandler>>, Vec<DownloadHandler>) {
+        let fakes = (0..count)
+            .map(|_| Arc::new(FakeHandler::default()))
+            .collect::<Vec<_>>();
+        let handlers = fakes
+            .iter()
+            .map(|x| DownloadHandler::new(Arc::clone(x)))
+            .collect();
+
+        (fakes, handlers)
+    }
+
+    async fn download(handlers: &[DownloadHandler]) -> Result<Vec<DownloadedSong>, DownloadError> {
+        let url = Url::parse("https://example.com/song").expect("Valid URL");
+
+        Downloader::download_with(handlers, Path::new("/tmp"), &url, None).await
+    }
+
+    #[tokio::test]
+    async fn failing_handlers_are_skipped() {
+        let (fakes, handlers) = fake_handlers(2);
+        fakes[0].fails.store(true, Ordering::SeqCst);
+
+        let threshold = Config::global().handler_failure_threshold;
+        for _ in 0..threshold + 2 {
+            let songs = download(&handlers).await.expect("Second handler works");
+            assert_eq!(songs[0].path, Path::new("/tmp/song.mp3"));
+        }
+
+        // The first handler isn't tried once it failed too often
+        assert_eq!(fakes[0].downloads.load(Ordering::SeqCst), threshold);
+        assert_eq!(fakes[1].downloads.load(Ordering::SeqCst), threshold + 2);
+        assert!(handlers[0].health().is_skipped());
+        assert!(!handlers[1].health().is_skipped());
+    }
+
+    #[tokio::test]
+    async fn skipped_handlers_are_reported() {
+        let (fakes, handlers) = fake_handlers(1);
+        fakes[0].fails.store(true, Ordering::SeqCst);
+
+        let threshold = Config::global().handler_failure_threshold;
+        for _ in 0..threshold {
+            let e = download(&handlers).await.expect_err("Handler fails");
+            assert_eq!(
+                e.reasons().collect::<Vec<_>>(),
+                ["fake: the provider is down"]
+            );
+        }
+
+        let e = download(&handlers).await.expect_err("Handler is skipped");
+        assert_eq!(
+            e.reasons().collect::<Vec<_>>(),
+            ["fake: skipped because it failed too often recently"]
+        );
+        assert_eq!(fakes[0].downloads.load(Ordering::SeqCst), threshold);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_handlers_time_out() {
+        let (fakes, handlers) = fake_handlers(2);
+        fakes[0].hangs.store(true, Ordering::SeqCst);
+
+        let start = tokio::time::Instant::now();
+        download(&handlers).await.expect("Second handler works");
+
+        assert_eq!(start.elapsed(), Config::global().handler_timeout);
+        let health = handlers[0].health();
+        assert_eq!(health.consecutive_failures, 1);
+        assert!(health.last_failure.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeouts_are_reported() {
+        let (fakes, handlers) = fake_handlers(1);
+        fakes[0].hangs.store(true, Ordering::SeqCst);
+
+        let e = download(&handlers).await.expect_err("Handler times out");
+
+        assert_eq!(
+            e.reasons().collect::<Vec<_>>(),
+            [format!(
+                "fake: timed out after {} seconds",
+                Config::global().handler_timeout.as_secs()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn nothing_supports_the_url() {
+        let e = download(&[]).await.expect_err("No handlers");
+
+        assert_eq!(
+            e.to_string(),
+            "No handler supports the URL: https://example.com/song"
+        );
+    }
+}
@@ -0,0 +1,41 @@
+//! Lets the embedding application see what the pipeline does, eg. to export metrics
+
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+
+use crate::processor::demucs::DemucsModel;
+
+type Listener = Box<dyn Fn(&Event) + Send + Sync>;
+
+static LISTENER: OnceCell<Listener> = OnceCell::new();
+
+/// Something that happened while downloading or processing a song
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum Event {
+    /// A handler tried to download a song
+    HandlerDownload {
+        handler: &'static str,
+        success: bool,
+    },
+    /// Demucs split a song into stems
+    DemucsFinished {
+        model: DemucsModel,
+        duration: Duration,
+    },
+}
+
+/// Call `listener` with every event. Only a single listener can be set, returns whether it was.
+pub fn set_listener<F>(listener: F) -> bool
+where
+    F: Fn(&Event) + Send + Sync + 'static,
+{
+    LISTENER.set(Box::new(listener)).is_ok()
+}
+
+pub(crate) fn emit(event: Event) {
+    if let Some(listener) = LISTENER.get() {
+        listener(&event);
+    }
+}
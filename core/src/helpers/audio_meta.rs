@@ -0,0 +1,208 @@
+use std::{collections::HashMap, path::Path, process::Stdio, time::Duration};
+
+use serde::Deserialize;
+use tokio::process::Command;
+use tracing::trace;
+
+use crate::config::Config;
+
+/// Metadata of an audio file as reported by `ffprobe`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AudioMeta {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub duration: Option<Duration>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FfprobeOutput {
+    #[serde(default)]
+    format: FfprobeFormat,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct FfprobeFormat {
+    duration: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+impl AudioMeta {
+    #[tracing::instrument]
+    pub async fn probe(file_path: &Path) -> anyhow::Result<Self> {
+        let output = Command::new(&Config::global().ffprobe_path)
+            .args(["-v", "error"])
+            .args(["-show_entries", "format=duration:format_tags"])
+            .args(["-of", "json"])
+            .arg(file_path)
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            anyhow::bail!("Command executed with exit code {:?}", output.status.code());
+        }
+
+        let meta = Self::from_ffprobe_output(&String::from_utf8_lossy(&output.stdout))?;
+        trace!(?meta, "Got audio metadata");
+
+        Ok(meta)
+    }
+
+    /// Parse the JSON output of `ffprobe -show_entries format=duration:format_tags -of json`.
+    ///
+    /// Tag names are matched case-insensitively since different containers use different casing
+    /// (eg. `title` in ID3 and `TITLE` in Vorbis comments).
+    pub fn from_ffprobe_output(output: &str) -> anyhow::Result<Self> {
+        let output = serde_json::from_str::<FfprobeOutput>(output)?;
+
+        let tag = |names: &[&str]| {
+            names.iter().find_map(|name| {
+                output
+                    .format
+                    .tags
+                    .iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                    .map(|(_, v)| v.trim())
+                    .filter(|v| !v.is_empty())
+                    .map(ToString::to_string)
+            })
+        };
+
+        let duration = output
+            .format
+            .duration
+            .as_deref()
+            .and_then(|x| x.trim().parse::<f64>().ok())
+            .and_then(|x| Duration::try_from_secs_f64(x).ok());
+
+        Ok(Self {
+            title: tag(&["title"]),
+            artist: tag(&["artist", "album_artist", "performer"]),
+            duration,
+        })
+    }
+
+    /// The title of the song, falling back to the file name (without extension)
+    pub fn title_or_file_stem(&self, file_path: &Path) -> String {
+        self.title.clone().unwrap_or_else(|| {
+            file_path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id3_tags() {
+        let meta = AudioMeta::from_ffprobe_output(
+            r#"{"format": {"duration": "213.760000", "tags": {"title": "Song Name", "artist": "Artist", "album": "Album"}}}"#,
+        )
+        .expect("Valid output");
+
+        assert_eq!(
+            meta,
+            AudioMeta {
+                title: Some("Song Name".into()),
+                artist: Some("Artist".into()),
+                duration: Some(Duration::from_millis(213_760)),
+            }
+        );
+    }
+
+    #[test]
+    fn vorbis_tags() {
+        let meta = AudioMeta::from_ffprobe_output(
+            r#"{"format": {"duration": "61.5", "tags": {"TITLE": "Pjesma", "ARTIST": "Izvođač"}}}"#,
+        )
+        .expect("Valid output");
+
+        assert_eq!(meta.title.as_deref(), Some("Pjesma"));
+        assert_eq!(meta.artist.as_deref(), Some("Izvođač"));
+        assert_eq!(meta.duration, Some(Duration::from_millis(61_500)));
+    }
+
+    #[test]
+    fn artist_fallbacks() {
+        let meta = AudioMeta::from_ffprobe_output(
+            r#"{"format": {"tags": {"album_artist": "Album Artist", "performer": "Performer"}}}"#,
+        )
+        .expect("Valid output");
+        assert_eq!(meta.artist.as_deref(), Some("Album Artist"));
+
+        let meta =
+            AudioMeta::from_ffprobe_output(r#"{"format": {"tags": {"Performer": "Performer"}}}"#)
+                .expect("Valid output");
+        assert_eq!(meta.artist.as_deref(), Some("Performer"));
+
+        // Empty tags don't hide the next ones
+        let meta = AudioMeta::from_ffprobe_output(
+            r#"{"format": {"tags": {"artist": "  ", "album_artist": "Album Artist"}}}"#,
+        )
+        .expect("Valid output");
+        assert_eq!(meta.artist.as_deref(), Some("Album Artist"));
+    }
+
+    #[test]
+    fn weird_fields() {
+        let meta = AudioMeta::from_ffprobe_output(
+            r#"{"format": {"duration": "N/A", "tags": {"title": "  Padded  ", "artist": ""}}}"#,
+        )
+        .expect("Valid output");
+        assert_eq!(
+            meta,
+            AudioMeta {
+                title: Some("Padded".into()),
+                artist: None,
+                duration: None,
+            }
+        );
+
+        let meta = AudioMeta::from_ffprobe_output(r#"{"format": {"duration": "-3.0"}}"#)
+            .expect("Valid output");
+        assert_eq!(meta.duration, None);
+
+        let meta = AudioMeta::from_ffprobe_output(r#"{"format": {"duration": " 12 "}}"#)
+            .expect("Valid output");
+        assert_eq!(meta.duration, Some(Duration::from_secs(12)));
+    }
+
+    #[test]
+    fn missing_fields() {
+        assert_eq!(
+            AudioMeta::from_ffprobe_output("{}").expect("Valid output"),
+            AudioMeta::default()
+        );
+        assert_eq!(
+            AudioMeta::from_ffprobe_output(r#"{"format": {}}"#).expect("Valid output"),
+            AudioMeta::default()
+        );
+        assert!(AudioMeta::from_ffprobe_output("").is_err());
+        assert!(AudioMeta::from_ffprobe_output("not json").is_err());
+    }
+
+    #[test]
+    fn title_falls_back_to_file_stem() {
+        let path = Path::new("/tmp/downloads/Artist - Song.flac");
+
+        assert_eq!(
+            AudioMeta::default().title_or_file_stem(path),
+            "Artist - Song"
+        );
+        assert_eq!(
+            AudioMeta {
+                title: Some("Tagged".into()),
+                ..AudioMeta::default()
+            }
+            .title_or_file_stem(path),
+            "Tagged"
+        );
+    }
+}
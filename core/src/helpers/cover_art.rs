@@ -0,0 +1,97 @@
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, trace};
+
+use crate::processor::ffmpeg::FfmpegProcessor;
+
+const COVER_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png"];
+
+/// Maximum size of a thumbnail that Telegram accepts
+const MAX_THUMBNAIL_SIZE: u64 = 200 * 1000;
+
+/// Cover art of the song
+#[derive(Debug, Clone)]
+pub struct CoverArt {
+    /// The cover image (to embed into files)
+    pub image: PathBuf,
+    /// A small JPEG version of the cover that Telegram accepts as an audio thumbnail
+    pub thumbnail: Option<PathBuf>,
+}
+impl CoverArt {
+    /// Whether the file name looks like cover art (eg. `cover.jpg`)
+    pub fn is_cover_file_name(file_name: &Path) -> bool {
+        let is_cover = file_name
+            .file_stem()
+            .is_some_and(|x| x.eq_ignore_ascii_case("cover"));
+        let is_image = file_name
+            .extension()
+            .and_then(|x| x.to_str())
+            .is_some_and(|x| COVER_EXTENSIONS.iter().any(|e| e.eq_ignore_ascii_case(x)));
+
+        is_cover && is_image
+    }
+
+    /// Find the cover art of the song.
+    ///
+    /// A `cover.*` image next to the song (eg. extracted by a download handler) is preferred,
+    /// otherwise the picture embedded in the song's tags is used.
+    #[tracing::instrument]
+    pub async fn find(song_file_path: &Path) -> Option<Self> {
+        let dir = song_file_path.parent()?;
+
+        let image = match Self::find_cover_file(dir).await {
+            Some(x) => x,
+            None => {
+                let image = dir.join("cover-from-tags.jpg");
+
+                if let Err(e) = FfmpegProcessor::extract_cover(song_file_path, &image).await {
+                    debug!(?e, "Song doesn't have cover art");
+                    return None;
+                }
+
+                image
+            }
+        };
+        trace!(?image, "Found cover art");
+
+        let thumbnail = dir.join("cover-thumbnail.jpg");
+        let thumbnail = match FfmpegProcessor::make_thumbnail(&image, &thumbnail).await {
+            Ok(()) => tokio::fs::metadata(&thumbnail)
+                .await
+                .is_ok_and(|x| x.len() <= MAX_THUMBNAIL_SIZE)
+                .then_some(thumbnail),
+            Err(e) => {
+                debug!(?e, "Failed to create thumbnail");
+                None
+            }
+        };
+        trace!(?thumbnail, "Created thumbnail");
+
+        Some(Self { image, thumbnail })
+    }
+
+    async fn find_cover_file(dir: &Path) -> Option<PathBuf> {
+        let mut entries = tokio::fs::read_dir(dir).await.ok()?;
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+
+            if Self::is_cover_file_name(&path) {
+                return Some(path);
+            }
+        }
+
+        None
+    }
+
+    /// Embed the cover into each of the files.
+    ///
+    /// Failures are ignored since the cover is only cosmetic.
+    pub async fn embed_into(&self, file_paths: &[PathBuf]) {
+        for file_path in file_paths {
+            if let Err(e) = FfmpegProcessor::embed_cover(file_path, &self.image).await {
+                debug!(?e, ?file_path, "Failed to embed cover art");
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use url::Url;
+
+pub struct DomainParser;
+impl DomainParser {
+    /// The domain name of the URL's host.
+    ///
+    /// Only web URLs have one, since only their hosts are normalized (lowercase, with
+    /// internationalized names in punycode). IP addresses aren't domain names, so they don't
+    /// have one either.
+    pub fn get_domain(url: &Url) -> Option<addr::domain::Name<'_>> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return None;
+        }
+
+        // `spotify.com.` is the same host as `spotify.com`
+        let domain = url.domain()?;
+        let domain = domain.strip_suffix('.').unwrap_or(domain);
+
+        addr::parse_domain_name(domain).ok()
+    }
+
+    /// Get the root domain (the registrable part)
+    pub fn get_domain_root(url: &Url) -> Option<&str> {
+        Self::get_domain(url).and_then(|x| x.root())
+    }
+
+    /// Whether the URL's host is one of the domains or their subdomain, eg. `open.spotify.com`
+    /// is on `spotify.com`, but `spotify.com.evil.example` and `notspotify.com` aren't
+    pub fn is_on(url: &Url, domains: &[&str]) -> bool {
+        let Some(host) = Self::get_domain(url).map(|x| x.as_str()) else {
+            return false;
+        };
+
+        domains.iter().any(|domain| {
+            host.strip_suffix(domain)
+                .is_some_and(|prefix| prefix.is_empty() || prefix.ends_with('.'))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOMAINS: &[&str] = &["spotify.com", "youtu.be"];
+
+    /// The URL, its domain and whether it's on one of [`DOMAINS`]
+    const CASES: &[(&str, Option<&str>, bool)] = &[
+        ("https://spotify.com/track/1", Some("spotify.com"), true),
+        (
+            "https://open.spotify.com/track/1",
+            Some("open.spotify.com"),
+            true,
+        ),
+        (
+            "http://OPEN.Spotify.COM/track/1",
+            Some("open.spotify.com"),
+            true,
+        ),
+        ("https://youtu.be/dQw4w9WgXcQ", Some("youtu.be"), true),
+        // Trailing dot
+        (
+            "https://open.spotify.com./track/1",
+            Some("open.spotify.com"),
+            true,
+        ),
+        (
+            "https://spotify.com.:443/track/1",
+            Some("spotify.com"),
+            true,
+        ),
+        // Lookalikes
+        (
+            "https://spotify.com.evil.io/track/1",
+            Some("spotify.com.evil.io"),
+            false,
+        ),
+        (
+            "https://evilspotify.com/track/1",
+            Some("evilspotify.com"),
+            false,
+        ),
+        (
+            "https://open-spotify.com/track/1",
+            Some("open-spotify.com"),
+            false,
+        ),
+        ("https://spotify.co/track/1", Some("spotify.co"), false),
+        (
+            "https://notyoutu.be/dQw4w9WgXcQ",
+            Some("notyoutu.be"),
+            false,
+        ),
+        // Internationalized names are compared in punycode
+        (
+            "https://spötify.com/track/1",
+            Some("xn--sptify-xxa.com"),
+            false,
+        ),
+        (
+            "https://öpen.spotify.com/track/1",
+            Some("xn--pen-rna.spotify.com"),
+            true,
+        ),
+        (
+            "https://xn--sptify-xxa.com/track/1",
+            Some("xn--sptify-xxa.com"),
+            false,
+        ),
+        // IP literals
+        ("http://127.0.0.1/track/1", None, false),
+        ("http://[::1]/track/1", None, false),
+        ("http://35.186.224.25/track/1", None, false),
+        // Other schemes
+        ("ftp://open.spotify.com/track/1", None, false),
+        ("spotify:track:1", None, false),
+        ("file:///spotify.com/track/1", None, false),
+        ("ws://open.spotify.com/track/1", None, false),
+    ];
+
+    #[test]
+    fn domains() {
+        for (url, domain, on) in CASES {
+            let parsed = Url::parse(url).expect(url);
+
+            assert_eq!(
+                DomainParser::get_domain(&parsed).map(|x| x.as_str().to_string()),
+                domain.map(str::to_string),
+                "domain of {url}"
+            );
+            assert_eq!(DomainParser::is_on(&parsed, DOMAINS), *on, "{url} is on");
+        }
+    }
+
+    #[test]
+    fn domain_roots() {
+        for (url, root) in [
+            ("https://open.spotify.com/track/1", Some("spotify.com")),
+            ("https://spotify.com.evil.io/track/1", Some("evil.io")),
+            ("https://music.apple.com/album/1", Some("apple.com")),
+            ("https://www.bbc.co.uk/sounds", Some("bbc.co.uk")),
+            ("http://127.0.0.1/", None),
+            ("ftp://open.spotify.com/", None),
+        ] {
+            let parsed = Url::parse(url).expect(url);
+
+            assert_eq!(
+                DomainParser::get_domain_root(&parsed),
+                root,
+                "root of {url}"
+            );
+        }
+    }
+}
@@ -0,0 +1,593 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use reqwest::{header, Client, Response};
+use tokio::{
+    fs,
+    io::{AsyncWriteExt, BufWriter},
+    sync::watch,
+};
+use tracing::{debug, trace};
+use url::Url;
+
+use super::header::content_disposition::ContentDisposition;
+use super::http::client_with_timeout;
+use super::public_host::{self, public_client_builder};
+use crate::{config::Config, helpers::temp_file::TempFile};
+
+/// Progress of a download is reported at most this often
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Client for downloading the files at the URLs sent by users
+static PUBLIC_DOWNLOAD_CLIENT: Lazy<Client> = Lazy::new(|| {
+    public_client_builder()
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build()
+        .expect("Failed to build HTTP client")
+});
+
+/// File extensions of the audio types downloads can have, by their `Content-Type`
+const AUDIO_CONTENT_TYPES: &[(&str, &str)] = &[
+    ("audio/mpeg", "mp3"),
+    ("audio/mp3", "mp3"),
+    ("audio/mp4", "m4a"),
+    ("audio/x-m4a", "m4a"),
+    ("audio/ogg", "ogg"),
+    ("audio/flac", "flac"),
+    ("audio/x-flac", "flac"),
+    ("audio/wav", "wav"),
+    ("audio/x-wav", "wav"),
+    ("audio/wave", "wav"),
+];
+
+/// Extensions of the files that are songs
+const AUDIO_EXTENSIONS: &[&str] = &["mp3", "flac", "m4a", "ogg", "wav"];
+
+/// How much of a download is done
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadProgress {
+    /// The provider is getting the song ready, eg. downloading it from the streaming service
+    Provider {
+        percent: Option<u8>,
+        /// What the provider is doing, eg. `converting`
+        stage: Option<String>,
+    },
+    /// The file is being downloaded from the provider
+    File {
+        downloaded: u64,
+        /// The size of the whole file, if the server sent it
+        total: Option<u64>,
+    },
+}
+impl DownloadProgress {
+    pub const fn starting() -> Self {
+        Self::Provider {
+            percent: None,
+            stage: None,
+        }
+    }
+}
+impl Display for DownloadProgress {
+    #[allow(clippy::cast_precision_loss)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mb = |x: u64| x as f64 / 1000.0 / 1000.0;
+
+        match self {
+            Self::Provider { percent, stage } => {
+                f.write_str("Downloading from provider...")?;
+                if let Some(percent) = percent {
+                    write!(f, " {percent}%")?;
+                }
+                if let Some(stage) = stage {
+                    write!(f, " ({stage})")?;
+                }
+                Ok(())
+            }
+            Self::File {
+                downloaded,
+                total: Some(total),
+            } => write!(
+                f,
+                "Downloading song... {:.1}/{:.1} MB",
+                mb(*downloaded),
+                mb(*total)
+            ),
+            Self::File {
+                downloaded,
+                total: None,
+            } => write!(f, "Downloading song... {:.1} MB", mb(*downloaded)),
+        }
+    }
+}
+
+/// Download the file to the path.
+///
+/// Fails if the file is bigger than the configured maximum download size. Progress is reported
+/// through `progress` every so often.
+#[tracing::instrument(skip(progress))]
+pub async fn download_file(
+    download_path: &Path,
+    download_url: &str,
+    progress: Option<&watch::Sender<DownloadProgress>>,
+) -> anyhow::Result<PathBuf> {
+    let resp = get_file_response(&client_with_timeout(DOWNLOAD_TIMEOUT), download_url).await?;
+
+    write_resp_to_file(
+        resp,
+        download_path,
+        Config::global().max_download_size,
+        progress,
+    )
+    .await
+}
+
+/// Infer file name from content disposition if present, else use provided path.
+///
+/// Without a file name, the extension of the provided path is replaced with the one matching the
+/// content type of the response (if it's a known audio type).
+///
+/// The size limit and progress reporting are the same as for [`download_file`].
+#[tracing::instrument(skip(progress))]
+pub async fn download_file_inferred(
+    download_path: &Path,
+    download_url: &str,
+    progress: Option<&watch::Sender<DownloadProgress>>,
+) -> anyhow::Result<PathBuf> {
+    let resp = get_file_response(&client_with_timeout(DOWNLOAD_TIMEOUT), download_url).await?;
+
+    write_inferred_file(resp, download_path, progress).await
+}
+
+/// [`download_file_inferred`] for URLs sent by users, which must only lead to public hosts (see
+/// [`public_host`])
+#[tracing::instrument(skip(progress))]
+pub async fn download_public_file_inferred(
+    download_path: &Path,
+    download_url: &Url,
+    progress: Option<&watch::Sender<DownloadProgress>>,
+) -> anyhow::Result<PathBuf> {
+    public_host::check_url(download_url)?;
+    let resp = get_file_response(&PUBLIC_DOWNLOAD_CLIENT, download_url.as_str()).await?;
+
+    write_inferred_file(resp, download_path, progress).await
+}
+
+async fn write_inferred_file(
+    resp: Response,
+    download_path: &Path,
+    progress: Option<&watch::Sender<DownloadProgress>>,
+) -> anyhow::Result<PathBuf> {
+    let content_disposition = resp
+        .headers()
+        .get(header::CONTENT_DISPOSITION)
+        .and_then(|x| ContentDisposition::from_raw(x).ok());
+
+    let filename = content_disposition.and_then(|x| x.get_file_name());
+
+    let download_path = filename.map_or_else(
+        || {
+            content_type_extension(&resp).map_or_else(
+                || download_path.to_path_buf(),
+                |extension| download_path.with_extension(extension),
+            )
+        },
+        |filename| download_path.with_file_name(filename),
+    );
+
+    write_resp_to_file(
+        resp,
+        &download_path,
+        Config::global().max_download_size,
+        progress,
+    )
+    .await
+}
+
+/// Whether the file name has the extension of an audio file
+pub fn is_audio_file_name(file_name: &Path) -> bool {
+    file_name
+        .extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| AUDIO_EXTENSIONS.iter().any(|e| e.eq_ignore_ascii_case(x)))
+}
+
+/// The file extension matching the `Content-Type` of the response
+fn content_type_extension(resp: &Response) -> Option<&'static str> {
+    let content_type = resp.headers().get(header::CONTENT_TYPE)?.to_str().ok()?;
+    // Ignore parameters like `; charset=binary`
+    let mime = content_type.split(';').next()?.trim();
+
+    AUDIO_CONTENT_TYPES
+        .iter()
+        .find(|(x, _)| x.eq_ignore_ascii_case(mime))
+        .map(|(_, extension)| *extension)
+}
+
+async fn get_file_response(client: &Client, download_url: &str) -> anyhow::Result<Response> {
+    debug!("Starting download");
+    client
+        .get(download_url)
+        .send()
+        .await?
+        .error_for_status()
+        .map_err(|e| anyhow::anyhow!(e))
+}
+
+/// Error for downloads bigger than `max_size`
+fn too_big(max_size: u64) -> anyhow::Error {
+    anyhow::anyhow!(
+        "The download is bigger than the maximum of {} MB",
+        max_size / 1000 / 1000
+    )
+}
+
+async fn write_resp_to_file(
+    mut resp: Response,
+    file_path: &Path,
+    max_size: u64,
+    progress: Option<&watch::Sender<DownloadProgress>>,
+) -> anyhow::Result<PathBuf> {
+    trace!(path = ?file_path, "Writing request response to disk");
+
+    let total = resp.content_length();
+    if total.is_some_and(|x| x > max_size) {
+        return Err(too_big(max_size));
+    }
+
+    let mut temp_file = TempFile::with_prefix("karaokify-download-").await?;
+    trace!(f = ?temp_file.path(), "Created temp file for download");
+
+    {
+        let mut out_file = BufWriter::new(temp_file.file_mut());
+        let mut downloaded = 0;
+        let mut last_progress = Instant::now();
+
+        // The content length can be missing or wrong, so the size is also checked while
+        // downloading
+        while let Some(chunk) = resp.chunk().await? {
+            downloaded += chunk.len() as u64;
+            if downloaded > max_size {
+                return Err(too_big(max_size));
+            }
+
+            out_file.write_all(&chunk).await?;
+
+            if let Some(progress) = progress {
+                if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                    last_progress = Instant::now();
+                    progress.send_replace(DownloadProgress::File { downloaded, total });
+                }
+            }
+        }
+        out_file.flush().await?;
+    }
+    trace!("Finished writing to disk");
+
+    trace!(from = ?temp_file.path(), to = ?file_path, "Copying temp file to download path");
+    fs::copy(temp_file.path(), &file_path).await?;
+
+    debug!("Response written to disk");
+
+    Ok(file_path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use flate2::{write::GzEncoder, Compression};
+    use futures::StreamExt;
+    use hyper::body::Bytes;
+
+    use super::*;
+    use crate::helpers::{
+        temp_dir::TempDir,
+        test_server::{self, Body, TestServer},
+        work_dir::WorkDir,
+    };
+
+    /// Big enough that the download doesn't fit in the buffer of the file
+    const CHUNK_SIZE: usize = 16 * 1024;
+
+    fn response(headers: &[(header::HeaderName, &str)], body: &'static str) -> Response {
+        let mut builder = http::Response::builder();
+        for (name, value) in headers {
+            builder = builder.header(name, *value);
+        }
+
+        Response::from(builder.body(body).expect("Valid response"))
+    }
+
+    #[tokio::test]
+    async fn extensions_from_the_content_type() {
+        let dir = TempDir::with_prefix("karaokify-test-download-content-type-")
+            .await
+            .expect("Directory created");
+
+        for (content_type, file_name) in [
+            ("audio/mpeg", "some song.mp3"),
+            ("audio/mp4", "some song.m4a"),
+            ("audio/ogg", "some song.ogg"),
+            ("audio/flac", "some song.flac"),
+            ("audio/x-wav", "some song.wav"),
+            ("AUDIO/X-M4A; charset=binary", "some song.m4a"),
+            // Unknown types keep the extension
+            ("application/octet-stream", "some song.mp3"),
+            ("video/mp4", "some song.mp3"),
+            ("audio/", "some song.mp3"),
+        ] {
+            let resp = response(&[(header::CONTENT_TYPE, content_type)], "song");
+
+            let path = write_inferred_file(resp, &dir.path().join("some song.mp3"), None)
+                .await
+                .expect("Written");
+
+            assert_eq!(path, dir.path().join(file_name), "{content_type}");
+            assert_eq!(std::fs::read(&path).expect("Downloaded"), b"song");
+        }
+
+        let path = write_inferred_file(response(&[], "song"), &dir.path().join("song.mp3"), None)
+            .await
+            .expect("Written");
+        assert_eq!(path, dir.path().join("song.mp3"));
+    }
+
+    #[tokio::test]
+    async fn the_content_disposition_is_trusted_more() {
+        let dir = TempDir::with_prefix("karaokify-test-download-content-disposition-")
+            .await
+            .expect("Directory created");
+
+        let resp = response(
+            &[
+                (header::CONTENT_TYPE, "audio/mp4"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"../Artist - Song.flac\"",
+                ),
+            ],
+            "song",
+        );
+        let path = write_inferred_file(resp, &dir.path().join("some song.mp3"), None)
+            .await
+            .expect("Written");
+        assert_eq!(path, dir.path().join("Artist - Song.flac"));
+
+        // The content type is used if the disposition doesn't have a usable name
+        let resp = response(
+            &[
+                (header::CONTENT_TYPE, "audio/ogg"),
+                (header::CONTENT_DISPOSITION, "attachment; filename=\"..\""),
+            ],
+            "song",
+        );
+        let path = write_inferred_file(resp, &dir.path().join("some song.mp3"), None)
+            .await
+            .expect("Written");
+        assert_eq!(path, dir.path().join("some song.ogg"));
+    }
+
+    #[tokio::test]
+    async fn downloads_are_limited() {
+        let dir = TempDir::with_prefix("karaokify-test-download-limit-")
+            .await
+            .expect("Directory created");
+        let path = dir.path().join("song.mp3");
+
+        let err = write_resp_to_file(response(&[], "too big"), &path, 6, None)
+            .await
+            .expect_err("Too big");
+        assert_eq!(err.to_string(), too_big(6).to_string());
+        assert!(!path.exists());
+
+        write_resp_to_file(response(&[], "fits"), &path, 4, None)
+            .await
+            .expect("Small enough");
+        assert_eq!(std::fs::read(&path).expect("Downloaded"), b"fits");
+    }
+
+    /// A chunk of the download, which starts with `marker` to find leftover files
+    fn chunk(marker: &str) -> Bytes {
+        let mut res = marker.as_bytes().to_vec();
+        res.resize(CHUNK_SIZE, 0);
+        res.into()
+    }
+
+    /// A response with the chunks, each sent after its delay. `sent` counts the chunks sent.
+    fn streamed(chunks: Vec<(Duration, Bytes)>, sent: &Arc<AtomicUsize>) -> http::Response<Body> {
+        let sent = sent.clone();
+
+        test_server::stream(futures::stream::iter(chunks).then(move |(delay, chunk)| {
+            let sent = sent.clone();
+            async move {
+                tokio::time::sleep(delay).await;
+                sent.fetch_add(1, Ordering::SeqCst);
+                chunk
+            }
+        }))
+    }
+
+    async fn get(server: &TestServer) -> Response {
+        get_file_response(&Client::new(), &server.url("/song.mp3"))
+            .await
+            .expect("Response")
+    }
+
+    /// The temporary files of downloads that start with `marker`
+    fn leftover_downloads(marker: &str) -> Vec<PathBuf> {
+        std::fs::read_dir(WorkDir::path())
+            .expect("Work directory read")
+            .filter_map(Result::ok)
+            .filter(|x| {
+                x.file_name()
+                    .to_string_lossy()
+                    .starts_with("karaokify-download-")
+            })
+            .map(|x| x.path())
+            .filter(|x| {
+                std::fs::read(x).is_ok_and(|content| content.starts_with(marker.as_bytes()))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn streamed_downloads_are_limited() {
+        let dir = TempDir::with_prefix("karaokify-test-download-streamed-")
+            .await
+            .expect("Directory created");
+        let path = dir.path().join("song.mp3");
+        let marker = format!("karaokify-test-streamed-{}", std::process::id());
+        let sent = Arc::new(AtomicUsize::new(0));
+        let chunks = 50;
+
+        // Without a `Content-Length`, the size is only known while downloading
+        let server = TestServer::start({
+            let chunk = chunk(&marker);
+            let sent = sent.clone();
+            move |_| {
+                streamed(
+                    vec![(Duration::from_millis(10), chunk.clone()); chunks],
+                    &sent,
+                )
+            }
+        })
+        .await;
+        let resp = get(&server).await;
+        assert_eq!(resp.content_length(), None);
+
+        let max_size = (CHUNK_SIZE * 5 / 2) as u64;
+        let err = write_resp_to_file(resp, &path, max_size, None)
+            .await
+            .expect_err("Too big");
+
+        assert_eq!(err.to_string(), too_big(max_size).to_string());
+        assert!(sent.load(Ordering::SeqCst) < chunks / 2);
+        assert!(!path.exists());
+        assert_eq!(leftover_downloads(&marker), Vec::<PathBuf>::new());
+    }
+
+    #[tokio::test]
+    async fn compressed_downloads_are_limited() {
+        let dir = TempDir::with_prefix("karaokify-test-download-compressed-")
+            .await
+            .expect("Directory created");
+        let path = dir.path().join("song.mp3");
+        let marker = format!("karaokify-test-compressed-{}", std::process::id());
+        let sent = Arc::new(AtomicUsize::new(0));
+        let max_size = (CHUNK_SIZE * 5 / 2) as u64;
+
+        let mut encoder = GzEncoder::new(vec![], Compression::best());
+        for _ in 0..64 {
+            encoder.write_all(&chunk(&marker)).expect("Compressed");
+        }
+        let compressed = Bytes::from(encoder.finish().expect("Compressed"));
+        // The `Content-Length` is the compressed size, which is under the limit even though the
+        // download isn't
+        assert!((compressed.len() as u64) < max_size);
+
+        let server = TestServer::start({
+            let sent = sent.clone();
+            move |_| {
+                let chunks = compressed
+                    .chunks(256)
+                    .map(|x| (Duration::ZERO, compressed.slice_ref(x)))
+                    .collect();
+
+                let mut res = streamed(chunks, &sent);
+                res.headers_mut()
+                    .insert(header::CONTENT_ENCODING, "gzip".parse().expect("Valid"));
+                res.headers_mut()
+                    .insert(header::CONTENT_LENGTH, compressed.len().into());
+                res
+            }
+        })
+        .await;
+
+        let err = write_resp_to_file(get(&server).await, &path, max_size, None)
+            .await
+            .expect_err("Too big");
+
+        assert_eq!(err.to_string(), too_big(max_size).to_string());
+        assert!(!path.exists());
+        assert_eq!(leftover_downloads(&marker), Vec::<PathBuf>::new());
+    }
+
+    #[tokio::test]
+    async fn progress_is_throttled() {
+        let dir = TempDir::with_prefix("karaokify-test-download-progress-")
+            .await
+            .expect("Directory created");
+        let path = dir.path().join("song.mp3");
+        let sent = Arc::new(AtomicUsize::new(0));
+        let total = CHUNK_SIZE as u64 * 5;
+
+        // The chunks arrive after 0, 0.3, 1.3, 1.6 and 2.6 seconds
+        let server = TestServer::start({
+            let chunk = chunk("song");
+            let sent = sent.clone();
+            move |_| {
+                let mut res = streamed(
+                    [0, 300, 1000, 300, 1000]
+                        .into_iter()
+                        .map(|x| (Duration::from_millis(x), chunk.clone()))
+                        .collect(),
+                    &sent,
+                );
+                res.headers_mut()
+                    .insert(header::CONTENT_LENGTH, total.into());
+                res
+            }
+        })
+        .await;
+
+        let (progress, mut updates) = watch::channel(DownloadProgress::starting());
+        let updates = tokio::spawn(async move {
+            let mut res = vec![];
+            while updates.changed().await.is_ok() {
+                res.push(updates.borrow_and_update().clone());
+            }
+            res
+        });
+
+        write_resp_to_file(get(&server).await, &path, total, Some(&progress))
+            .await
+            .expect("Downloaded");
+        drop(progress);
+
+        assert_eq!(
+            updates.await.expect("Progress collected"),
+            [
+                DownloadProgress::File {
+                    downloaded: CHUNK_SIZE as u64 * 3,
+                    total: Some(total),
+                },
+                DownloadProgress::File {
+                    downloaded: total,
+                    total: Some(total),
+                },
+            ]
+        );
+        assert_eq!(std::fs::metadata(&path).expect("Downloaded").len(), total);
+    }
+
+    #[test]
+    fn audio_file_names() {
+        for name in ["song.mp3", "song.FLAC", "a.b.m4a", "x.ogg", "x.wav"] {
+            assert!(is_audio_file_name(Path::new(name)), "{name}");
+        }
+        for name in ["cover.jpg", "mp3", ".mp3", "song.mp3.txt", "song"] {
+            assert!(!is_audio_file_name(Path::new(name)), "{name}");
+        }
+    }
+}
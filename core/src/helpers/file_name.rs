@@ -0,0 +1,85 @@
+use std::path::Path;
+
+/// Maximum length of the sanitized file names, without the extension
+const MAX_FILE_NAME_LENGTH: usize = 100;
+
+/// Replace the characters that aren't safe in file names (including path separators) and
+/// shorten the name if it's too long
+pub fn sanitize_file_name(file_name: &str) -> String {
+    let clean = file_name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || " -_.,()[]&'".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    let file_name = Path::new(&clean);
+
+    let stem = file_name
+        .file_stem()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let stem = stem
+        .trim_start_matches(['.', ' '])
+        .chars()
+        .take(MAX_FILE_NAME_LENGTH)
+        .collect::<String>();
+    let stem = if stem.trim().is_empty() {
+        "song".to_string()
+    } else {
+        stem
+    };
+
+    match file_name.extension() {
+        Some(extension) => format!("{stem}.{}", extension.to_string_lossy()),
+        None => stem,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_names_are_kept() {
+        for name in [
+            "01 Song.mp3",
+            "Artist - Song (Live) [2020].flac",
+            "Rock & Roll, Pt. 2.mp3",
+            "Don't Stop.mp3",
+            "Đurđevdan čćžšđ.mp3",
+            "曲.mp3",
+        ] {
+            assert_eq!(sanitize_file_name(name), name);
+        }
+    }
+
+    #[test]
+    fn unsafe_characters_are_replaced() {
+        assert_eq!(sanitize_file_name("../../etc/passwd"), "_.._etc_passwd");
+        assert_eq!(sanitize_file_name("a/b\\c:d*e?.mp3"), "a_b_c_d_e_.mp3");
+        assert_eq!(sanitize_file_name("tab\there\n.mp3"), "tab_here_.mp3");
+        assert_eq!(sanitize_file_name("\u{202e}3pm.exe"), "_3pm.exe");
+    }
+
+    #[test]
+    fn empty_and_hidden_names() {
+        assert_eq!(sanitize_file_name(""), "song");
+        assert_eq!(sanitize_file_name("   "), "song");
+        // The name of hidden files doesn't have an extension
+        assert_eq!(sanitize_file_name(".mp3"), "mp3");
+        assert_eq!(sanitize_file_name(". .hidden.mp3"), "hidden.mp3");
+        assert_eq!(sanitize_file_name("???.mp3"), "___.mp3");
+        assert_eq!(sanitize_file_name("no extension"), "no extension");
+    }
+
+    #[test]
+    fn long_names_are_shortened() {
+        let name = sanitize_file_name(&format!("{}.flac", "ž".repeat(300)));
+
+        assert_eq!(name, format!("{}.flac", "ž".repeat(MAX_FILE_NAME_LENGTH)));
+    }
+}
@@ -61,7 +61,6 @@ pub struct ExtendedValue {
 }
 
 impl ExtendedValue {
-    #[allow(dead_code)]
     pub fn try_decode(&self) -> Option<String> {
         self.charset.decode(&self.value)
     }
@@ -302,12 +301,11 @@ impl ContentDisposition {
     ///
     /// # Examples
     /// ```
-    /// use actix_web::http::header::{ContentDisposition, TryIntoHeaderValue as _};
+    /// use karaokify_core::helpers::header::content_disposition::ContentDisposition;
     ///
     /// let cd = ContentDisposition::attachment("files.zip");
     ///
-    /// let cd_val = cd.try_into_value().unwrap();
-    /// assert_eq!(cd_val, "attachment; filename=\"files.zip\"");
+    /// assert_eq!(cd.to_string(), "attachment; filename=\"files.zip\"");
     /// ```
     pub fn attachment(filename: impl Into<String>) -> Self {
         Self {
@@ -342,7 +340,11 @@ impl ContentDisposition {
                 // extended parameters
                 let (ext_value, new_left) = split_once_and_trim(left, ';');
                 left = new_left;
-                let ext_value = parse_extended_value(ext_value)?;
+                // Invalid extended parameters are ignored, so the regular ones can still be used
+                // (RFC 6266 §4.3)
+                let Ok(ext_value) = parse_extended_value(ext_value) else {
+                    continue;
+                };
 
                 let param = if param_name.eq_ignore_ascii_case("filename") {
                     DispositionParam::FilenameExt(ext_value)
@@ -445,6 +447,25 @@ impl ContentDisposition {
             .find_map(DispositionParam::as_filename_ext)
     }
 
+    /// The name of the file without any directories, preferring the decoded *filename\** over
+    /// *filename* if both exist.
+    ///
+    /// Returns `None` if there is no usable file name (eg. it's empty or `..`).
+    pub fn get_file_name(&self) -> Option<String> {
+        let filename = self
+            .get_filename_ext()
+            .and_then(ExtendedValue::try_decode)
+            .or_else(|| self.get_filename().map(ToOwned::to_owned))?;
+
+        // Servers can send paths (eg. `../../x.mp3`), sometimes with Windows separators
+        let filename = filename.rsplit(['/', '\\']).next()?.trim();
+
+        match filename {
+            "" | "." | ".." => None,
+            x => Some(x.to_owned()),
+        }
+    }
+
     /// Return the value of the parameter which the `name` matches.
     pub fn get_unknown(&self, name: impl AsRef<str>) -> Option<&str> {
         let name = name.as_ref();
@@ -543,3 +564,130 @@ impl fmt::Display for ContentDisposition {
             .try_for_each(|param| write!(f, "; {}", param))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_name(header: &str) -> Option<String> {
+        let hv = header::HeaderValue::from_bytes(header.as_bytes()).expect("Valid header value");
+
+        ContentDisposition::from_raw(&hv)
+            .expect("Valid Content-Disposition")
+            .get_file_name()
+    }
+
+    #[test]
+    fn file_names() {
+        for (header, expected) in [
+            ("attachment; filename=song.mp3", Some("song.mp3")),
+            (
+                "attachment; filename=\"some song.mp3\"",
+                Some("some song.mp3"),
+            ),
+            ("attachment;filename=song.mp3;size=123", Some("song.mp3")),
+            ("ATTACHMENT; FILENAME=song.mp3", Some("song.mp3")),
+            ("inline; filename=song.mp3", Some("song.mp3")),
+            // Quoted strings with semicolons and escapes
+            ("attachment; filename=\"a; b.mp3\"; x=y", Some("a; b.mp3")),
+            (
+                r#"attachment; filename="the \"best\" song.mp3""#,
+                Some("the \"best\" song.mp3"),
+            ),
+            (
+                r#"attachment; filename="back\\slash.mp3""#,
+                Some("slash.mp3"),
+            ),
+            // Extended parameters
+            (
+                "attachment; filename*=UTF-8''Mot%C3%B6rhead%20-%20song.mp3",
+                Some("Motörhead - song.mp3"),
+            ),
+            (
+                "attachment; filename*=utf-8'hr'%C4%90ur%C4%91evdan.mp3",
+                Some("Đurđevdan.mp3"),
+            ),
+            (
+                "attachment; filename*=ISO-8859-1''Mot%F6rhead.mp3",
+                Some("Motörhead.mp3"),
+            ),
+            (
+                "attachment; filename*=UTF-8''%E6%9B%B2.flac",
+                Some("曲.flac"),
+            ),
+            // Raw UTF-8 in the plain parameter
+            (
+                "attachment; filename=\"Motörhead.mp3\"",
+                Some("Motörhead.mp3"),
+            ),
+            // Without a file name
+            ("attachment", None),
+            ("inline; name=song", None),
+            ("attachment; filename=\"\"", None),
+            ("attachment; filename=\"  \"", None),
+        ] {
+            assert_eq!(file_name(header).as_deref(), expected, "{header}");
+        }
+    }
+
+    #[test]
+    fn extended_file_names_are_preferred() {
+        for header in [
+            "attachment; filename=\"fallback.mp3\"; filename*=UTF-8''Mot%C3%B6rhead.mp3",
+            "attachment; filename*=UTF-8''Mot%C3%B6rhead.mp3; filename=\"fallback.mp3\"",
+        ] {
+            assert_eq!(
+                file_name(header).as_deref(),
+                Some("Motörhead.mp3"),
+                "{header}"
+            );
+        }
+
+        // Unless they can't be decoded
+        for header in [
+            "attachment; filename=\"fallback.mp3\"; filename*=UTF-8''%FF%FE.mp3",
+            "attachment; filename=\"fallback.mp3\"; filename*=not-a-charset''song.mp3",
+            "attachment; filename=\"fallback.mp3\"; filename*=song.mp3",
+        ] {
+            assert_eq!(
+                file_name(header).as_deref(),
+                Some("fallback.mp3"),
+                "{header}"
+            );
+        }
+    }
+
+    #[test]
+    fn directories_are_removed() {
+        for (header, expected) in [
+            ("attachment; filename=\"../../x.mp3\"", Some("x.mp3")),
+            ("attachment; filename=\"/etc/passwd\"", Some("passwd")),
+            (r#"attachment; filename="..\\..\\x.mp3""#, Some("x.mp3")),
+            (
+                "attachment; filename*=UTF-8''..%2F..%2Fx.mp3",
+                Some("x.mp3"),
+            ),
+            ("attachment; filename*=UTF-8''..%5Cx.mp3", Some("x.mp3")),
+            ("attachment; filename=\"songs/\"", None),
+            ("attachment; filename=\"..\"", None),
+            ("attachment; filename=\"a/..\"", None),
+            ("attachment; filename=.", None),
+        ] {
+            assert_eq!(file_name(header).as_deref(), expected, "{header}");
+        }
+    }
+
+    #[test]
+    fn invalid_headers() {
+        for header in [
+            "",
+            "; filename=song.mp3",
+            "attachment; filename=",
+            "attachment; =song.mp3",
+            "attachment; filename=\"unterminated.mp3",
+        ] {
+            let hv = header::HeaderValue::from_bytes(header.as_bytes()).expect("Header value");
+            assert!(ContentDisposition::from_raw(&hv).is_err(), "{header}");
+        }
+    }
+}
@@ -0,0 +1,92 @@
+/// Decode the entities in text taken from an HTML page (eg. an attribute value)
+///
+/// Only the common named entities are known, unknown ones are kept as they are.
+pub fn unescape(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        res.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let decoded = rest.find(';').and_then(|end| {
+            let c = match &rest[1..end] {
+                "quot" => '"',
+                "apos" => '\'',
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "nbsp" => '\u{a0}',
+                x => {
+                    let code = x.strip_prefix('#')?;
+                    let code = match code.strip_prefix(['x', 'X']) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                        None => code.parse().ok()?,
+                    };
+                    char::from_u32(code)?
+                }
+            };
+
+            Some((c, end))
+        });
+
+        match decoded {
+            Some((c, end)) => {
+                res.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                res.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    res.push_str(rest);
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entities() {
+        for (text, expected) in [
+            ("&quot;a&quot; &amp; &apos;b&apos;", "\"a\" & 'b'"),
+            ("&lt;b&gt;bold&lt;/b&gt;", "<b>bold</b>"),
+            ("a&nbsp;b", "a\u{a0}b"),
+            ("&#39;&#x27;&#X27;", "'''"),
+            ("&#272;ur&#273;evdan", "Đurđevdan"),
+            ("&#x1F3B5;", "🎵"),
+            // Encoded entities are only decoded once
+            ("&amp;quot;", "&quot;"),
+        ] {
+            assert_eq!(unescape(text), expected, "{text}");
+        }
+    }
+
+    #[test]
+    fn text_that_isnt_an_entity() {
+        for text in [
+            "",
+            "plain text",
+            "AT&T",
+            "a & b",
+            "&unknown;",
+            "&;",
+            "&#;",
+            "&#xZZ;",
+            "&#1114112;",
+            "&#xD800;",
+            "trailing &",
+            "&amp",
+            "Đurđevdan & 曲",
+        ] {
+            assert_eq!(unescape(text), text, "{text}");
+        }
+
+        assert_eq!(unescape("& &amp;"), "& &");
+        assert_eq!(unescape("&&amp;"), "&&");
+    }
+}
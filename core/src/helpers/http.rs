@@ -0,0 +1,48 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use once_cell::sync::Lazy;
+use reqwest::{Client, ClientBuilder};
+
+use crate::config::Config;
+
+/// Timeout of the whole request (including the body) for API requests
+const API_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Idle connections are closed after this long
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const POOL_MAX_IDLE_PER_HOST: usize = 8;
+
+/// Client for API requests. Connections are pooled and shared between all the requests.
+pub static CLIENT: Lazy<Client> = Lazy::new(|| build(client_builder().timeout(API_TIMEOUT)));
+
+/// Clients for requests that need a different timeout, keyed by the timeout
+static CLIENTS_WITH_TIMEOUT: Lazy<Mutex<HashMap<Duration, Client>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Client for requests that can take longer (or shorter) than API requests, eg. file downloads.
+///
+/// The client is only built once for every timeout, so connections are still pooled.
+pub fn client_with_timeout(timeout: Duration) -> Client {
+    CLIENTS_WITH_TIMEOUT
+        .lock()
+        .expect("HTTP clients lock poisoned")
+        .entry(timeout)
+        .or_insert_with(|| build(client_builder().timeout(timeout)))
+        .clone()
+}
+
+/// Builder with the settings all clients should share, for clients that need more changes
+/// (eg. a different redirect policy).
+///
+/// Proxies are set using the `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY` environment variables.
+pub fn client_builder() -> ClientBuilder {
+    Client::builder()
+        .user_agent(&Config::global().user_agent)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+}
+
+fn build(builder: ClientBuilder) -> Client {
+    builder.build().expect("Failed to build HTTP client")
+}
@@ -0,0 +1,65 @@
+use std::{
+    hash::{BuildHasher, Hash, Hasher, RandomState},
+    process, thread, time,
+};
+
+/// Characters of the short IDs (lowercase RFC 4648 base32)
+const SHORT_ID_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+/// Length of the short IDs
+const SHORT_ID_LENGTH: usize = 6;
+/// Characters of the secret tokens (the ones Telegram allows in webhook secrets)
+const SECRET_TOKEN_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789_-";
+/// Length of the secret tokens
+const SECRET_TOKEN_LENGTH: usize = 32;
+
+fn now_ns() -> u128 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_nanos()
+}
+
+fn thread_id_hash() -> u64 {
+    let mut hasher = std::hash::DefaultHasher::new();
+    thread::current().id().hash(&mut hasher);
+    hasher.finish()
+}
+
+#[must_use]
+pub fn time_thread_id() -> String {
+    let thread_id = thread_id_hash();
+    let process_id = process::id();
+    let ns = now_ns();
+
+    let id = format!("{ns}-{process_id}-{thread_id}");
+
+    id
+}
+
+/// A short random ID that's easy for users to read out, eg. `k7f3a9`
+#[must_use]
+pub fn short_id() -> String {
+    let mut hash = RandomState::new().hash_one(time_thread_id());
+
+    let mut id = String::with_capacity(SHORT_ID_LENGTH);
+    for _ in 0..SHORT_ID_LENGTH {
+        id.push(SHORT_ID_ALPHABET[(hash % 32) as usize] as char);
+        hash /= 32;
+    }
+
+    id
+}
+
+/// A random token that's hard to guess, eg. for checking that requests come from Telegram
+#[must_use]
+pub fn secret_token() -> String {
+    let state = RandomState::new();
+
+    (0..SECRET_TOKEN_LENGTH)
+        .map(|i| {
+            let hash = state.hash_one((time_thread_id(), i));
+            SECRET_TOKEN_ALPHABET[(hash % 64) as usize] as char
+        })
+        .collect()
+}
@@ -0,0 +1,232 @@
+use std::{ffi::OsString, path::Path, process::Stdio};
+
+use serde::Deserialize;
+use tokio::process::Command;
+use tracing::{debug, trace, warn};
+
+use crate::{
+    config::Config,
+    helpers::temp_dir::TempDir,
+    processor::encoding::{Bitrate, OutputFormat},
+};
+
+/// Integrated loudness (in LUFS) the files are normalized to
+const TARGET_LOUDNESS: f64 = -14.0;
+/// Maximum true peak (in dBTP) of the normalized files
+const TARGET_TRUE_PEAK: f64 = -1.0;
+/// Loudness range (in LU) of the normalized files
+const TARGET_LOUDNESS_RANGE: f64 = 11.0;
+
+/// Loudness of a file as measured by the first pass of ffmpeg's `loudnorm` filter
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LoudnormStats {
+    #[serde(deserialize_with = "de_number")]
+    pub input_i: f64,
+    #[serde(deserialize_with = "de_number")]
+    pub input_tp: f64,
+    #[serde(deserialize_with = "de_number")]
+    pub input_lra: f64,
+    #[serde(deserialize_with = "de_number")]
+    pub input_thresh: f64,
+    #[serde(deserialize_with = "de_number")]
+    pub target_offset: f64,
+}
+impl LoudnormStats {
+    /// Parse the stats from the output of `loudnorm=print_format=json`.
+    ///
+    /// ffmpeg prints them at the very end of its log, so the last JSON object in it is used.
+    pub fn from_ffmpeg_output(output: &str) -> anyhow::Result<Self> {
+        let start = output
+            .rfind('{')
+            .ok_or_else(|| anyhow::anyhow!("no loudness stats found in the output"))?;
+        let end = output[start..]
+            .find('}')
+            .ok_or_else(|| anyhow::anyhow!("loudness stats in the output are incomplete"))?;
+
+        let stats = serde_json::from_str::<Self>(&output[start..=start + end])?;
+
+        Ok(stats)
+    }
+}
+
+/// Normalizes the loudness of audio files using ffmpeg's two-pass `loudnorm` filter
+pub struct Loudnorm;
+impl Loudnorm {
+    /// Normalize every file in place. Files that can't be normalized are left as they are.
+    ///
+    /// MP3 files are re-encoded with `bitrate`.
+    pub async fn normalize_all<P>(file_paths: &[P], bitrate: Bitrate)
+    where
+        P: AsRef<Path> + Send + Sync,
+    {
+        for file_path in file_paths {
+            let file_path = file_path.as_ref();
+
+            if let Err(e) = Self::normalize(file_path, bitrate).await {
+                warn!(?e, ?file_path, "Failed to normalize loudness");
+            }
+        }
+    }
+
+    #[tracing::instrument]
+    pub async fn normalize(file_path: &Path, bitrate: Bitrate) -> anyhow::Result<()> {
+        debug!("Normalizing loudness");
+
+        let stats = Self::measure(file_path).await?;
+        trace!(?stats, "Measured loudness");
+
+        let work_dir = TempDir::with_prefix("karaokify-loudnorm-").await?;
+        let output_path = work_dir
+            .path()
+            .join("normalized")
+            .with_extension(file_path.extension().unwrap_or_default());
+
+        let filter = format!(
+            "{}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:\
+             linear=true",
+            target_filter(),
+            stats.input_i,
+            stats.input_tp,
+            stats.input_lra,
+            stats.input_thresh,
+            stats.target_offset,
+        );
+
+        let codec_args = if OutputFormat::is_lossless_file(file_path) {
+            vec![]
+        } else {
+            bitrate.ffmpeg_args()
+        };
+
+        let cmd_status = Command::new(&Config::global().ffmpeg_path)
+            .args([OsString::from("-i"), file_path.as_os_str().to_os_string()])
+            .args(["-map", "0:a"])
+            // Keep the cover art (if any)
+            .args(["-map", "0:v?"])
+            .args(["-c:v", "copy"])
+            .args(["-af", &filter])
+            // The filter upsamples to 192 kHz
+            .args(["-ar", "44100"])
+            .args(codec_args)
+            .arg(&output_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await?;
+        trace!(status = ?cmd_status, "Normalize command finished");
+
+        if !cmd_status.success() {
+            anyhow::bail!("Command executed with exit code {:?}", cmd_status.code());
+        }
+
+        tokio::fs::copy(&output_path, file_path).await?;
+
+        Ok(())
+    }
+
+    /// Run the first (measuring) pass of the filter
+    async fn measure(file_path: &Path) -> anyhow::Result<LoudnormStats> {
+        let output = Command::new(&Config::global().ffmpeg_path)
+            .arg("-hide_banner")
+            .args([OsString::from("-i"), file_path.as_os_str().to_os_string()])
+            .args(["-map", "0:a"])
+            .args(["-af", &format!("{}:print_format=json", target_filter())])
+            .args(["-f", "null", "-"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            anyhow::bail!("Command executed with exit code {:?}", output.status.code());
+        }
+
+        LoudnormStats::from_ffmpeg_output(&String::from_utf8_lossy(&output.stderr))
+    }
+}
+
+fn target_filter() -> String {
+    format!("loudnorm=I={TARGET_LOUDNESS}:TP={TARGET_TRUE_PEAK}:LRA={TARGET_LOUDNESS_RANGE}")
+}
+
+/// The stats are printed as strings (eg. `"-23.54"`), and can be `"-inf"` for silent files
+fn de_number<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|x| x.is_finite())
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid number {value:?}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        std::fs::read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/loudnorm")
+                .join(name),
+        )
+        .expect("Fixture")
+    }
+
+    #[test]
+    fn stats() {
+        assert_eq!(
+            LoudnormStats::from_ffmpeg_output(&fixture("song.log")).expect("Stats"),
+            LoudnormStats {
+                input_i: -8.93,
+                input_tp: 0.47,
+                input_lra: 5.2,
+                input_thresh: -19.01,
+                target_offset: 0.03,
+            }
+        );
+    }
+
+    #[test]
+    fn silent_files_have_no_stats() {
+        assert!(LoudnormStats::from_ffmpeg_output(&fixture("silence.log")).is_err());
+    }
+
+    #[test]
+    fn broken_output() {
+        let output = fixture("song.log");
+
+        // ffmpeg was killed while printing the stats
+        let truncated = &output[..output.rfind("\"target_offset\"").expect("Offset")];
+        assert!(LoudnormStats::from_ffmpeg_output(truncated).is_err());
+
+        let without_offset = output.replace("\"target_offset\" : \"0.03\"", "\"x\" : \"0\"");
+        assert!(LoudnormStats::from_ffmpeg_output(&without_offset).is_err());
+
+        let not_a_number = output.replace("\"-8.93\"", "\"loud\"");
+        assert!(LoudnormStats::from_ffmpeg_output(&not_a_number).is_err());
+
+        assert!(LoudnormStats::from_ffmpeg_output("").is_err());
+        assert!(LoudnormStats::from_ffmpeg_output("Conversion failed!").is_err());
+    }
+
+    #[test]
+    fn numbers_with_whitespace() {
+        let output = fixture("song.log").replace("\"-8.93\"", "\" -8.93 \"");
+
+        assert!(
+            (LoudnormStats::from_ffmpeg_output(&output)
+                .expect("Stats")
+                .input_i
+                + 8.93)
+                .abs()
+                < f64::EPSILON
+        );
+    }
+}
@@ -0,0 +1,20 @@
+pub mod audio_meta;
+pub mod cover_art;
+pub mod domain;
+pub mod download;
+pub mod file_name;
+pub mod header;
+pub mod html;
+pub mod http;
+pub mod id;
+pub mod loudnorm;
+pub mod public_host;
+pub mod resolve_url;
+pub mod temp_cleanup;
+pub mod temp_dir;
+pub mod temp_file;
+#[cfg(test)]
+pub mod test_audio;
+#[cfg(test)]
+pub mod test_server;
+pub mod work_dir;
@@ -0,0 +1,214 @@
+//! Requests to URLs sent by users, which must only reach hosts on the public internet.
+//!
+//! Otherwise anyone could make the bot probe the network it runs in, eg. `localhost`, the
+//! private network or the metadata service of the cloud provider.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect::Policy,
+    Client, ClientBuilder,
+};
+use url::{Host, Url};
+
+use super::http::client_builder;
+
+/// How many redirects are followed before giving up
+const MAX_REDIRECTS: usize = 10;
+/// Timeout of the whole request for quick checks of the URLs
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Client for quick requests to the URLs sent by users, eg. checking what they link to
+pub static PUBLIC_CLIENT: Lazy<Client> = Lazy::new(|| {
+    public_client_builder()
+        .timeout(CHECK_TIMEOUT)
+        .build()
+        .expect("Failed to build HTTP client")
+});
+
+/// Builder of clients that only connect to public hosts, also when following redirects
+pub fn public_client_builder() -> ClientBuilder {
+    client_builder()
+        .dns_resolver(Arc::new(PublicResolver))
+        .redirect(Policy::custom(|attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+
+            match check_url(attempt.url()) {
+                Ok(()) => attempt.follow(),
+                Err(e) => attempt.error(e),
+            }
+        }))
+}
+
+/// Check that the URL is a web URL with a domain name. IP addresses aren't allowed since they
+/// aren't resolved, so they wouldn't be checked by the clients of [`public_client_builder`].
+pub fn check_url(url: &Url) -> anyhow::Result<()> {
+    if !matches!(url.scheme(), "http" | "https") {
+        anyhow::bail!("only web links are supported, not {}", url.scheme());
+    }
+
+    match url.host() {
+        Some(Host::Domain(_)) => Ok(()),
+        Some(Host::Ipv4(_) | Host::Ipv6(_)) => {
+            anyhow::bail!("links to IP addresses aren't allowed")
+        }
+        None => anyhow::bail!("the link has no host"),
+    }
+}
+
+/// Whether the address is reachable on the public internet, ie. it isn't a loopback, private,
+/// link-local (eg. the cloud metadata service), shared, multicast or otherwise reserved one
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => is_public_ipv6(ip),
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "This network"
+        || a == 0
+        // Shared address space of carrier-grade NATs
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments
+        || (a == 192 && b == 0 && ip.octets()[2] == 0)
+        // Benchmarking
+        || (a == 198 && (18..20).contains(&b))
+        // Reserved for future use
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    // Eg. `::ffff:127.0.0.1` reaches the IPv4 address
+    if let Some(ipv4) = ip.to_ipv4_mapped() {
+        return is_public_ipv4(ipv4);
+    }
+
+    let [first, second, ..] = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local
+        || (first & 0xfe00) == 0xfc00
+        // Link-local
+        || (first & 0xffc0) == 0xfe80
+        // Documentation
+        || (first == 0x2001 && second == 0x0db8)
+        // IPv4-compatible (deprecated) and NAT64 addresses can reach private IPv4 ones
+        || ip.segments()[..6].iter().all(|x| *x == 0)
+        || (first == 0x0064 && second == 0xff9b))
+}
+
+/// Resolves only domain names whose addresses are all public
+#[derive(Debug)]
+struct PublicResolver;
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .collect::<Vec<_>>();
+
+            if let Some(addr) = addrs.iter().find(|x| !is_public_ip(x.ip())) {
+                return Err(format!(
+                    "{} resolves to the non-public address {}",
+                    name.as_str(),
+                    addr.ip()
+                )
+                .into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses_are_public() {
+        let cases = [
+            ("1.1.1.1", true),
+            ("8.8.8.8", true),
+            ("172.32.0.1", true),
+            ("100.128.0.1", true),
+            ("2606:4700:4700::1111", true),
+            ("127.0.0.1", false),
+            ("127.1.2.3", false),
+            ("0.0.0.0", false),
+            ("10.1.2.3", false),
+            ("172.16.0.1", false),
+            ("172.31.255.255", false),
+            ("192.168.1.1", false),
+            ("169.254.169.254", false),
+            ("100.64.0.1", false),
+            ("192.0.0.8", false),
+            ("198.18.0.1", false),
+            ("224.0.0.1", false),
+            ("255.255.255.255", false),
+            ("240.0.0.1", false),
+            ("::", false),
+            ("::1", false),
+            ("::127.0.0.1", false),
+            ("::ffff:127.0.0.1", false),
+            ("::ffff:169.254.169.254", false),
+            ("64:ff9b::a00:1", false),
+            ("fc00::1", false),
+            ("fd12:3456::1", false),
+            ("fe80::1", false),
+            ("ff02::1", false),
+            ("2001:db8::1", false),
+        ];
+
+        for (ip, public) in cases {
+            let parsed = ip.parse::<IpAddr>().expect("Valid IP address");
+            assert_eq!(is_public_ip(parsed), public, "{ip}");
+        }
+    }
+
+    #[test]
+    fn only_web_urls_with_domains_are_allowed() {
+        let cases = [
+            ("https://example.com/song.mp3", true),
+            ("http://files.example.com:8080/song.mp3", true),
+            ("http://127.0.0.1/song.mp3", false),
+            ("http://2130706433/song.mp3", false),
+            ("http://0x7f.1/song.mp3", false),
+            ("http://[::1]/song.mp3", false),
+            ("http://169.254.169.254/latest/meta-data/", false),
+            ("ftp://example.com/song.mp3", false),
+            ("file:///etc/passwd", false),
+        ];
+
+        for (url, allowed) in cases {
+            let parsed = Url::parse(url).expect("Valid URL");
+            assert_eq!(check_url(&parsed).is_ok(), allowed, "{url}");
+        }
+    }
+
+    #[tokio::test]
+    async fn names_of_non_public_hosts_are_not_resolved() {
+        let name = "localhost".parse::<Name>().expect("Valid name");
+
+        assert!(PublicResolver.resolve(name).await.is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use std::{collections::HashSet, time::Duration};
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::{header, redirect::Policy, Client, StatusCode};
+use tracing::{debug, trace};
+use url::Url;
+
+use super::{
+    domain::DomainParser,
+    public_host::{self, public_client_builder},
+};
+
+/// How many redirects are followed before giving up
+const MAX_REDIRECTS: usize = 10;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Domains of song.link (Odesli) pages which link to the song on the different platforms
+const SONG_LINK_DOMAINS: &[&str] = &["song.link", "album.link", "odesli.co"];
+
+/// The links are sent by users, so only public hosts are requested
+static CLIENT: Lazy<Client> = Lazy::new(|| {
+    public_client_builder()
+        .redirect(Policy::none())
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("Failed to build HTTP client")
+});
+
+static PLATFORM_LINK_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"https://[^"'\s<>\\]+"#).expect("Invalid regex"));
+
+/// Follow the redirects of the URL (eg. of link shorteners) and return the final URL.
+///
+/// Only redirects to public hosts are followed.
+#[tracing::instrument(skip_all, fields(url = ?url.as_str()))]
+pub async fn resolve_url(url: &Url) -> anyhow::Result<Url> {
+    follow_redirects(&CLIENT, url).await
+}
+
+async fn follow_redirects(client: &Client, url: &Url) -> anyhow::Result<Url> {
+    let mut seen = HashSet::new();
+    let mut current = url.clone();
+
+    for _ in 0..MAX_REDIRECTS {
+        if !seen.insert(current.clone()) {
+            anyhow::bail!("redirect loop at {current}");
+        }
+        // The client only checks the domain names it resolves
+        public_host::check_url(&current)?;
+
+        let Some(next) = next_redirect(client, &current).await? else {
+            trace!(url = ?current.as_str(), "Resolved URL");
+            return Ok(current);
+        };
+        trace!(from = ?current.as_str(), to = ?next.as_str(), "Following redirect");
+
+        current = next;
+    }
+
+    anyhow::bail!("too many redirects")
+}
+
+/// The URL the request is redirected to, if any
+async fn next_redirect(client: &Client, url: &Url) -> anyhow::Result<Option<Url>> {
+    let mut resp = client.head(url.clone()).send().await?;
+
+    // Some servers don't support HEAD requests
+    if matches!(
+        resp.status(),
+        StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+    ) {
+        debug!(status = ?resp.status(), "HEAD request not supported, using GET");
+        resp = client.get(url.clone()).send().await?;
+    }
+
+    if !resp.status().is_redirection() {
+        return Ok(None);
+    }
+
+    let Some(location) = resp.headers().get(header::LOCATION) else {
+        return Ok(None);
+    };
+
+    Ok(Some(url.join(location.to_str()?)?))
+}
+
+/// Whether the URL is a song.link page (which only links to the song on other platforms)
+pub fn is_song_link(url: &Url) -> bool {
+    DomainParser::is_on(url, SONG_LINK_DOMAINS)
+}
+
+/// Links to the song on the different platforms from a song.link page
+#[tracing::instrument(skip_all, fields(url = ?url.as_str()))]
+pub async fn song_link_platform_urls(url: &Url) -> anyhow::Result<Vec<Url>> {
+    public_host::check_url(url)?;
+    let page = CLIENT
+        .get(url.clone())
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    let urls = platform_urls(&page);
+    trace!(?urls, "Found platform links");
+
+    Ok(urls)
+}
+
+/// The links to the other platforms on the song.link page
+fn platform_urls(page: &str) -> Vec<Url> {
+    let mut urls = vec![];
+    for m in PLATFORM_LINK_REGEX.find_iter(page) {
+        let Ok(platform_url) = Url::parse(&m.as_str().replace("&amp;", "&")) else {
+            continue;
+        };
+
+        if is_song_link(&platform_url) || urls.contains(&platform_url) {
+            continue;
+        }
+
+        urls.push(platform_url);
+    }
+
+    urls
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::helpers::http::client_builder;
+
+    /// Serve the redirects (by path) on `localhost`, other paths are `200 OK`. `PORT` in the
+    /// targets is replaced with the port of the server.
+    async fn redirect_server(redirects: &'static [(&'static str, &'static str)]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Listener bound");
+        let port = listener.local_addr().expect("Local address").port();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![0; 4096];
+                let read = stream.read(&mut request).await.unwrap_or_default();
+                let request = String::from_utf8_lossy(&request[..read]);
+                let path = request.split(' ').nth(1).unwrap_or_default();
+
+                let response = redirects.iter().find(|(x, _)| *x == path).map_or_else(
+                    || "HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n".to_string(),
+                    |(_, target)| {
+                        let target = target.replace("PORT", &port.to_string());
+                        format!(
+                            "HTTP/1.1 302 Found\r\nlocation: {target}\r\ncontent-length: 0\r\n\r\n"
+                        )
+                    },
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        format!("http://localhost:{port}")
+    }
+
+    /// A client that can reach the test server
+    fn local_client() -> Client {
+        client_builder()
+            .redirect(Policy::none())
+            .build()
+            .expect("Client built")
+    }
+
+    #[tokio::test]
+    async fn redirect_chains_are_followed() {
+        let base = redirect_server(&[
+            ("/short", "/middle"),
+            ("/middle", "http://localhost:PORT/song?id=1"),
+        ])
+        .await;
+        let url = Url::parse(&format!("{base}/short")).expect("Valid URL");
+
+        let resolved = follow_redirects(&local_client(), &url)
+            .await
+            .expect("Resolved URL");
+
+        assert_eq!(resolved.as_str(), format!("{base}/song?id=1"));
+    }
+
+    #[tokio::test]
+    async fn redirect_loops_are_detected() {
+        let base = redirect_server(&[("/a", "/b"), ("/b", "/a")]).await;
+        let url = Url::parse(&format!("{base}/a")).expect("Valid URL");
+
+        let e = follow_redirects(&local_client(), &url)
+            .await
+            .expect_err("Loop detected");
+
+        assert!(e.to_string().contains("redirect loop"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn redirects_to_ip_addresses_are_not_followed() {
+        let base = redirect_server(&[("/short", "http://127.0.0.1:PORT/admin")]).await;
+        let url = Url::parse(&format!("{base}/short")).expect("Valid URL");
+
+        let e = follow_redirects(&local_client(), &url)
+            .await
+            .expect_err("Redirect refused");
+
+        assert!(e.to_string().contains("IP addresses"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn non_public_hosts_are_not_resolved() {
+        let base = redirect_server(&[]).await;
+        let url = Url::parse(&format!("{base}/short")).expect("Valid URL");
+
+        assert!(resolve_url(&url).await.is_err());
+        assert!(song_link_platform_urls(&url).await.is_err());
+    }
+
+    #[test]
+    fn platform_links_are_found_on_song_link_pages() {
+        let page = r#"
+            <a href="https://open.spotify.com/track/abc?si=1&amp;utm=x">Spotify</a>
+            <a href="https://song.link/s/abc">Share</a>
+            <a href='https://music.youtube.com/watch?v=xyz'>YouTube Music</a>
+            <a href="https://open.spotify.com/track/abc?si=1&amp;utm=x">Spotify again</a>
+        "#;
+
+        let urls = platform_urls(page)
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            urls,
+            [
+                "https://open.spotify.com/track/abc?si=1&utm=x",
+                "https://music.youtube.com/watch?v=xyz",
+            ]
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use once_cell::sync::Lazy;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, trace, warn};
+
+use super::work_dir::WorkDir;
+use crate::config::Config;
+
+/// Temp files and directories that are in use (eg. by running jobs), so they're never removed
+static LIVE_PATHS: Lazy<Mutex<HashSet<PathBuf>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// All the temp files and directories of the bot start with this
+const TEMP_PREFIX: &str = "karaokify-";
+
+/// Removes the temp files and directories left behind when the bot was killed before it could
+/// clean them up
+pub struct TempCleanup;
+impl TempCleanup {
+    /// The path is in use until it's unregistered
+    pub fn register(path: &Path) {
+        LIVE_PATHS
+            .lock()
+            .expect("Temp paths lock poisoned")
+            .insert(path.to_path_buf());
+    }
+
+    pub fn unregister(path: &Path) {
+        LIVE_PATHS
+            .lock()
+            .expect("Temp paths lock poisoned")
+            .remove(path);
+    }
+
+    /// The temp files and directories that are in use
+    pub fn live_paths() -> Vec<PathBuf> {
+        LIVE_PATHS
+            .lock()
+            .expect("Temp paths lock poisoned")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Remove the temp files and directories older than `KARAOKIFY_TEMP_MAX_AGE_HOURS` that
+    /// aren't in use
+    pub async fn remove_stale() {
+        let max_age = Config::global().temp_max_age;
+
+        let res = tokio::task::spawn_blocking(move || Self::remove_stale_blocking(max_age)).await;
+
+        match res {
+            Ok(Ok((0, _))) => debug!("No stale temp files found"),
+            Ok(Ok((removed, bytes))) => info!(
+                removed,
+                reclaimed_mb = bytes / 1000 / 1000,
+                "Removed stale temp files"
+            ),
+            Ok(Err(e)) => warn!(?e, "Failed to remove stale temp files"),
+            Err(e) => warn!(?e, "Stale temp file cleanup panicked"),
+        }
+    }
+
+    /// Remove stale temp files every `interval`
+    pub async fn run_periodically(interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick is immediate and the cleanup already ran at startup
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+            Self::remove_stale().await;
+        }
+    }
+
+    /// Returns the number of removed entries and the bytes they took up
+    fn remove_stale_blocking(max_age: Duration) -> io::Result<(usize, u64)> {
+        let now = SystemTime::now();
+
+        let mut removed = 0;
+        let mut bytes = 0;
+        for entry in fs::read_dir(WorkDir::path())? {
+            let entry = entry?;
+            let path = entry.path();
+
+            if !entry.file_name().to_string_lossy().starts_with(TEMP_PREFIX) {
+                continue;
+            }
+
+            let is_live = LIVE_PATHS
+                .lock()
+                .expect("Temp paths lock poisoned")
+                .contains(&path);
+            if is_live {
+                trace!(?path, "Temp path is in use, skipping");
+                continue;
+            }
+
+            // Might've been removed in the meantime
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|x| now.duration_since(x).ok())
+                .unwrap_or_default();
+            if age < max_age {
+                continue;
+            }
+
+            let size = disk_usage(&path).unwrap_or_default();
+            let res = if metadata.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+
+            match res {
+                Ok(()) => {
+                    debug!(?path, ?age, size, "Removed stale temp path");
+                    removed += 1;
+                    bytes += size;
+                }
+                Err(e) => warn!(?e, ?path, "Failed to remove stale temp path"),
+            }
+        }
+
+        Ok((removed, bytes))
+    }
+}
+
+/// Total size of the file, or of all the files in the directory
+pub fn disk_usage(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+
+    let mut res = 0;
+    for entry in fs::read_dir(path)? {
+        res += disk_usage(&entry?.path()).unwrap_or_default();
+    }
+
+    Ok(res)
+}
@@ -0,0 +1,78 @@
+use std::{
+    ffi::OsString,
+    marker::Send,
+    path::{Path, PathBuf},
+};
+
+use tokio::{fs, runtime::RuntimeFlavor};
+
+use super::{id::time_thread_id, temp_cleanup::TempCleanup, work_dir::WorkDir};
+
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+    delete_on_drop: bool,
+}
+impl TempDir {
+    pub async fn new<T>(dir_name: T) -> Result<Self, std::io::Error>
+    where
+        T: Into<OsString> + Send,
+    {
+        let tmp_dir = WorkDir::path().join(dir_name.into());
+
+        TempCleanup::register(&tmp_dir);
+        if let Err(e) = fs::create_dir_all(&tmp_dir).await {
+            TempCleanup::unregister(&tmp_dir);
+            return Err(e);
+        }
+
+        Ok(Self {
+            path: tmp_dir,
+            delete_on_drop: true,
+        })
+    }
+
+    pub async fn with_prefix<T>(dir_name_prefix: T) -> Result<Self, std::io::Error>
+    where
+        T: Into<OsString> + Send,
+    {
+        let mut f: OsString = dir_name_prefix.into();
+        f.push(time_thread_id());
+        Self::new(f).await
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[allow(dead_code)]
+    pub fn no_delete_on_drop(&mut self) -> &mut Self {
+        self.delete_on_drop = false;
+        self
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let path = std::mem::take(&mut self.path);
+        if !self.delete_on_drop {
+            TempCleanup::unregister(&path);
+            return;
+        }
+
+        let remove = move || {
+            let _ = std::fs::remove_dir_all(&path);
+            TempCleanup::unregister(&path);
+        };
+
+        // The dir can contain gigabytes of intermediate files, deleting them would block the
+        // async runtime. A current-thread runtime (eg. in tests) cancels the blocking tasks that
+        // haven't started yet when it shuts down though, which would leave the dir behind.
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) if runtime.runtime_flavor() != RuntimeFlavor::CurrentThread => {
+                runtime.spawn_blocking(remove);
+            }
+            _ => remove(),
+        }
+    }
+}
@@ -5,7 +5,7 @@ use std::{
 
 use tokio::fs::File;
 
-use super::id::time_thread_id;
+use super::{id::time_thread_id, temp_cleanup::TempCleanup, work_dir::WorkDir};
 
 pub struct TempFile {
     path: PathBuf,
@@ -17,9 +17,16 @@ impl TempFile {
     where
         T: Into<OsString> + std::marker::Send,
     {
-        let tmp_dir = std::env::temp_dir();
-        let tmp_file = tmp_dir.join(file_name.into());
-        let file = File::create(&tmp_file).await?;
+        let tmp_file = WorkDir::path().join(file_name.into());
+
+        TempCleanup::register(&tmp_file);
+        let file = match File::create(&tmp_file).await {
+            Ok(x) => x,
+            Err(e) => {
+                TempCleanup::unregister(&tmp_file);
+                return Err(e);
+            }
+        };
 
         Ok(Self {
             path: tmp_file,
@@ -57,5 +64,6 @@ impl Drop for TempFile {
         if self.delete_on_drop {
             let _ = std::fs::remove_file(&self.path);
         }
+        TempCleanup::unregister(&self.path);
     }
 }
@@ -0,0 +1,38 @@
+//! Audio files generated with ffmpeg for the tests of the processors
+
+use std::{path::Path, process::Stdio};
+
+use tokio::process::Command;
+
+use crate::{config::Config, preflight::version_line};
+
+/// Whether ffmpeg and ffprobe can be run, checked the same way as on startup. The tests which
+/// need them are skipped without them.
+pub async fn has_ffmpeg() -> bool {
+    let config = Config::global();
+
+    let res = match version_line(&config.ffmpeg_path, &["-version"], "FFMPEG_PATH").await {
+        Ok(_) => version_line(&config.ffprobe_path, &["-version"], "FFPROBE_PATH").await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = &res {
+        eprintln!("Skipping the test, {e}");
+    }
+
+    res.is_ok()
+}
+
+/// Create the file from the output of the `lavfi` filter, eg. `sine=frequency=440:duration=5`
+pub async fn generate(file_path: &Path, filter: &str) {
+    let status = Command::new(&Config::global().ffmpeg_path)
+        .args(["-hide_banner", "-loglevel", "error"])
+        .args(["-f", "lavfi", "-i", filter])
+        .arg(file_path)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .status()
+        .await
+        .expect("ffmpeg ran");
+
+    assert!(status.success(), "Generating {filter:?} failed: {status}");
+}
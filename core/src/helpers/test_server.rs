@@ -0,0 +1,145 @@
+//! A local HTTP server for the tests of the downloads and the providers' APIs
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use futures::{Stream, StreamExt};
+use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
+use hyper::{
+    body::{Bytes, Frame, Incoming},
+    header::CONTENT_TYPE,
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use tokio::{net::TcpListener, task::JoinHandle};
+
+pub type Body = BoxBody<Bytes, Infallible>;
+
+/// A request the server received
+#[derive(Debug, Clone)]
+pub struct Received {
+    pub method: Method,
+    /// Eg. `/api?id=1`
+    pub path: String,
+    pub body: String,
+    /// Eg. `http://127.0.0.1:1234`, for responses that link back to the server
+    pub base_url: String,
+}
+
+/// Serves the responses of a function on a free port of localhost until it's dropped
+pub struct TestServer {
+    addr: SocketAddr,
+    received: Arc<Mutex<Vec<Received>>>,
+    task: JoinHandle<()>,
+}
+impl TestServer {
+    pub async fn start<F>(respond: F) -> Self
+    where
+        F: Fn(&Received) -> Response<Body> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Listening on localhost");
+        let addr = listener.local_addr().expect("Bound to an address");
+        let received = Arc::new(Mutex::new(vec![]));
+        let respond = Arc::new(respond);
+
+        let task = tokio::spawn({
+            let received = received.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let received = received.clone();
+                    let respond = respond.clone();
+
+                    tokio::spawn(async move {
+                        let service = service_fn(|req| {
+                            let received = received.clone();
+                            let respond = respond.clone();
+                            async move {
+                                let req = Self::receive(req, addr).await;
+                                received.lock().expect("Lock poisoned").push(req.clone());
+                                Ok::<_, Infallible>(respond(&req))
+                            }
+                        });
+
+                        let _ = http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await;
+                    });
+                }
+            }
+        });
+
+        Self {
+            addr,
+            received,
+            task,
+        }
+    }
+
+    async fn receive(req: Request<Incoming>, addr: SocketAddr) -> Received {
+        let method = req.method().clone();
+        let path = req
+            .uri()
+            .path_and_query()
+            .map_or_else(|| "/".to_string(), ToString::to_string);
+        let body = req
+            .into_body()
+            .collect()
+            .await
+            .map(|x| String::from_utf8_lossy(&x.to_bytes()).to_string())
+            .unwrap_or_default();
+
+        Received {
+            method,
+            path,
+            body,
+            base_url: format!("http://{addr}"),
+        }
+    }
+
+    /// The URL of `path` on the server, eg. `http://127.0.0.1:1234/api`
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.addr)
+    }
+
+    /// The requests received so far, in order
+    pub fn received(&self) -> Vec<Received> {
+        self.received.lock().expect("Lock poisoned").clone()
+    }
+}
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+pub fn response(status: StatusCode, body: impl Into<Bytes>) -> Response<Body> {
+    let mut res = Response::new(Full::new(body.into()).boxed());
+    *res.status_mut() = status;
+    res
+}
+
+/// A response with the chunks of `body`, sent as they're ready
+pub fn stream<S>(body: S) -> Response<Body>
+where
+    S: Stream<Item = Bytes> + Send + Sync + 'static,
+{
+    Response::new(BodyExt::boxed(StreamBody::new(
+        body.map(|x| Ok::<_, Infallible>(Frame::data(x))),
+    )))
+}
+
+pub fn json(body: impl Into<Bytes>) -> Response<Body> {
+    let mut res = response(StatusCode::OK, body);
+    res.headers_mut().insert(
+        CONTENT_TYPE,
+        "application/json".parse().expect("Valid header"),
+    );
+    res
+}
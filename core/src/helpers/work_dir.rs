@@ -0,0 +1,239 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use once_cell::sync::Lazy;
+use tracing::{debug, trace};
+
+use super::temp_cleanup::{disk_usage, TempCleanup};
+use crate::config::Config;
+
+/// Space reserved by each running job
+static RESERVATIONS: Lazy<Mutex<HashMap<u64, Reserved>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_RESERVATION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The intermediate files (eg. the WAV stems) take up a few times the size of the download
+const JOB_SIZE_RATIO: u64 = 5;
+
+/// Space reserved by a job
+#[derive(Debug, Clone, Copy)]
+struct Reserved {
+    /// How much the job is expected to need in total
+    estimate: u64,
+    /// How much the job's files take up the last time they were measured
+    written: u64,
+}
+impl Reserved {
+    /// The estimate can be off, so the job holds at least what it already wrote
+    fn bytes(self) -> u64 {
+        self.estimate.max(self.written)
+    }
+}
+
+/// Where the temp files are stored (`KARAOKIFY_WORK_DIR`)
+pub struct WorkDir;
+impl WorkDir {
+    pub fn path() -> &'static Path {
+        &Config::global().work_dir
+    }
+
+    /// Free space (in bytes) on the filesystem of the work dir
+    pub fn available_space() -> Result<u64, std::io::Error> {
+        fs2::available_space(Self::path())
+    }
+
+    /// Reserve the space a job might need, so the work dir doesn't grow over
+    /// `KARAOKIFY_WORK_DIR_MAX_GB`.
+    ///
+    /// Reservations are estimates, so the work dir is measured as well. Whichever is bigger is
+    /// assumed to be in use, since the files of the running jobs are part of both.
+    ///
+    /// Returns `None` if there's no space for the job right now.
+    pub async fn reserve() -> Option<Reservation> {
+        let config = Config::global();
+
+        Self::reserve_bytes(
+            config.max_download_size * JOB_SIZE_RATIO,
+            config.work_dir_max_size,
+        )
+        .await
+    }
+
+    async fn reserve_bytes(bytes: u64, max_size: Option<u64>) -> Option<Reservation> {
+        let Some(max_size) = max_size else {
+            return Some(Reservation { id: None });
+        };
+
+        let used = tokio::task::spawn_blocking(|| {
+            TempCleanup::live_paths()
+                .iter()
+                .map(|x| disk_usage(x).unwrap_or_default())
+                .sum::<u64>()
+        })
+        .await
+        .unwrap_or_default();
+
+        // Checked and reserved under the lock so concurrent jobs can't both take the last space
+        let mut reservations = RESERVATIONS.lock().expect("Reservations lock poisoned");
+        let reserved = reservations.values().map(|x| x.bytes()).sum::<u64>();
+        if used.max(reserved) + bytes > max_size {
+            drop(reservations);
+            debug!(used, reserved, bytes, max_size, "Work dir is full");
+            return None;
+        }
+
+        let id = NEXT_RESERVATION_ID.fetch_add(1, Ordering::Relaxed);
+        reservations.insert(
+            id,
+            Reserved {
+                estimate: bytes,
+                written: 0,
+            },
+        );
+        drop(reservations);
+        trace!(id, bytes, "Reserved work dir space");
+
+        Some(Reservation { id: Some(id) })
+    }
+}
+
+/// Space in the work dir reserved for a job, released when dropped.
+///
+/// Starts out as an estimate for the biggest possible download, and is updated with what the
+/// job actually writes as its files land.
+#[derive(Debug)]
+pub struct Reservation {
+    /// `None` if there's no size cap, so nothing is tracked
+    id: Option<u64>,
+}
+impl Reservation {
+    /// The song was downloaded into `dir`, so the estimate is based on its actual size instead of
+    /// the biggest possible download
+    pub async fn downloaded(&self, dir: &Path) {
+        if let Some(size) = self.measure(dir).await {
+            self.update(|x| {
+                x.estimate = size * JOB_SIZE_RATIO;
+                x.written = size;
+            });
+        }
+    }
+
+    /// Record what the job has written into `dir` so far (eg. once the stems land)
+    pub async fn written(&self, dir: &Path) {
+        if let Some(size) = self.measure(dir).await {
+            self.update(|x| x.written = size);
+        }
+    }
+
+    /// Size of the files in `dir`, if the space is tracked and they could be measured
+    async fn measure(&self, dir: &Path) -> Option<u64> {
+        self.id?;
+
+        let dir = dir.to_path_buf();
+        match tokio::task::spawn_blocking(move || disk_usage(&dir)).await {
+            Ok(Ok(x)) => Some(x),
+            res => {
+                debug!(?res, "Could not measure the files of the job");
+                None
+            }
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Reserved)) {
+        let Some(id) = self.id else {
+            return;
+        };
+
+        let mut reservations = RESERVATIONS.lock().expect("Reservations lock poisoned");
+        if let Some(reserved) = reservations.get_mut(&id) {
+            f(reserved);
+            trace!(id, ?reserved, "Updated work dir reservation");
+        }
+    }
+}
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            RESERVATIONS
+                .lock()
+                .expect("Reservations lock poisoned")
+                .remove(&id);
+            trace!(id, "Released work dir space");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::temp_dir::TempDir;
+
+    const MB: u64 = 1000 * 1000;
+
+    /// The reservations are global, so the tests can't run at the same time
+    static SERIAL: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn racing_reservations_stay_under_the_cap() {
+        let _serial = SERIAL.lock().await;
+        let tasks = (0..16)
+            .map(|_| tokio::spawn(WorkDir::reserve_bytes(100 * MB, Some(350 * MB))))
+            .collect::<Vec<_>>();
+
+        let mut reservations = vec![];
+        for task in tasks {
+            reservations.extend(task.await.expect("Task finished"));
+        }
+
+        assert_eq!(reservations.len(), 3);
+
+        // Released space can be reserved again
+        drop(reservations.pop());
+        let reservation = WorkDir::reserve_bytes(100 * MB, Some(350 * MB)).await;
+        assert!(reservation.is_some());
+        assert!(WorkDir::reserve_bytes(100 * MB, Some(350 * MB))
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn reservations_follow_the_written_files() {
+        let _serial = SERIAL.lock().await;
+        let dir = TempDir::with_prefix("karaokify-test-work-dir-")
+            .await
+            .expect("Directory created");
+        std::fs::write(dir.path().join("song.mp3"), vec![0; 1000]).expect("Song written");
+
+        let reservation = WorkDir::reserve_bytes(100 * MB, Some(10_000 * MB))
+            .await
+            .expect("Space reserved");
+        let reserved = || {
+            RESERVATIONS.lock().expect("Reservations lock poisoned")
+                [&reservation.id.expect("Tracked")]
+        };
+        assert_eq!(reserved().bytes(), 100 * MB);
+
+        // The download was much smaller than the biggest possible one
+        reservation.downloaded(dir.path()).await;
+        assert_eq!(reserved().bytes(), 1000 * JOB_SIZE_RATIO);
+
+        // The stems took up more than estimated
+        std::fs::write(dir.path().join("stems.wav"), vec![0; 9000]).expect("Stems written");
+        reservation.written(dir.path()).await;
+        assert_eq!(reserved().bytes(), 10_000);
+    }
+
+    #[tokio::test]
+    async fn nothing_is_tracked_without_a_cap() {
+        let reservation = WorkDir::reserve_bytes(100 * MB, None)
+            .await
+            .expect("Space reserved");
+
+        assert!(reservation.id.is_none());
+    }
+}
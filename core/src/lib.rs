@@ -0,0 +1,14 @@
+//! Downloading songs and splitting them into stems, without anything specific to the Telegram
+//! bot.
+//!
+//! [`downloader::Downloader`] downloads a song from a link with the first
+//! [`downloader::Handler`] that succeeds, and [`processor::demucs::DemucsProcessor`] splits it
+//! into stems. Progress is reported through `tokio::sync::watch` channels, and the settings are
+//! in [`config::Config`].
+
+pub mod config;
+pub mod downloader;
+pub mod events;
+pub mod helpers;
+pub mod preflight;
+pub mod processor;
@@ -0,0 +1,275 @@
+use std::{path::Path, process::Stdio};
+
+use tokio::process::Command;
+use tracing::{info, trace};
+
+use crate::{
+    config::{Config, DemucsDockerConfig},
+    processor::{demucs::DemucsProcessor, remote::RemoteSeparator},
+};
+
+/// Make sure the external programs the bot needs can be run and the work dir is usable, and
+/// pick the device demucs runs on.
+///
+/// Returns a description of the problem if any of them is missing or broken.
+pub async fn check() -> anyhow::Result<()> {
+    let config = Config::global();
+
+    let ffmpeg = version_line(&config.ffmpeg_path, &["-version"], "FFMPEG_PATH").await?;
+    info!(version = ?ffmpeg, "Found ffmpeg");
+
+    let ffprobe = version_line(&config.ffprobe_path, &["-version"], "FFPROBE_PATH").await?;
+    info!(version = ?ffprobe, "Found ffprobe");
+
+    if let Some(url) = &config.worker.url {
+        // Demucs only has to work on the worker
+        RemoteSeparator::new(url.clone()).check().await?;
+    } else if let Some(docker) = &config.demucs_docker {
+        check_docker(docker).await?;
+    } else {
+        // Demucs doesn't have a version flag, but the help only works if it's installed properly
+        version_line(&config.demucs_path, &["--help"], "DEMUCS_PATH").await?;
+        info!(path = ?config.demucs_path, "Found demucs");
+    }
+
+    tokio::fs::create_dir_all(&config.work_dir)
+        .await
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "could not create the work dir {:?} ({e}). Set KARAOKIFY_WORK_DIR to a writable \
+                 directory.",
+                config.work_dir
+            )
+        })?;
+    info!(path = ?config.work_dir, "Using work dir");
+
+    if config.worker.url.is_none() {
+        DemucsProcessor::probe_device().await?;
+    }
+
+    Ok(())
+}
+
+/// Make sure the Docker daemon is reachable and the demucs image exists
+async fn check_docker(docker: &DemucsDockerConfig) -> anyhow::Result<()> {
+    let server = version_line(
+        &docker.docker_path,
+        &["version", "--format", "{{.Server.Version}}"],
+        "DOCKER_PATH",
+    )
+    .await
+    .map_err(|e| anyhow::anyhow!("could not reach the Docker daemon: {e}"))?;
+    info!(version = ?server, "Found Docker");
+
+    // Inspecting the image doesn't pull it, so missing images are found before the first song
+    version_line(
+        &docker.docker_path,
+        &["image", "inspect", "--format", "{{.Id}}", &docker.image],
+        "DOCKER_PATH",
+    )
+    .await
+    .map_err(|e| {
+        anyhow::anyhow!(
+            "the demucs image {:?} doesn't exist, pull it or fix KARAOKIFY_DEMUCS_DOCKER_IMAGE: \
+             {e}",
+            docker.image
+        )
+    })?;
+    info!(image = docker.image, "Found demucs image");
+
+    Ok(())
+}
+
+/// Run the program with the arguments and return the first line of its output.
+///
+/// `path_env` is the environment variable which can be used to set the program's path.
+pub(crate) async fn version_line(
+    program: &Path,
+    args: &[&str],
+    path_env: &str,
+) -> anyhow::Result<String> {
+    trace!(?program, ?args, "Running program");
+
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "could not run {program:?} ({e}). Make sure it's installed and on the PATH or set \
+                 {path_env} to its location."
+            )
+        })?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "{program:?} {} exited with code {:?}: {}",
+            args.join(" "),
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, path::PathBuf};
+
+    use super::*;
+    use crate::helpers::temp_dir::TempDir;
+
+    /// A directory with fake executables, removed on drop
+    struct FakeBin(TempDir);
+    impl FakeBin {
+        async fn new() -> Self {
+            let dir = TempDir::with_prefix("karaokify-test-preflight-")
+                .await
+                .expect("Directory created");
+            Self(dir)
+        }
+
+        /// Create a shell script called `name`
+        fn script(&self, name: &str, script: &str, executable: bool) -> PathBuf {
+            let path = self.0.path().join(name);
+            std::fs::write(&path, format!("#!/bin/sh\n{script}\n")).expect("Script written");
+            let mode = if executable { 0o755 } else { 0o644 };
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))
+                .expect("Permissions set");
+
+            path
+        }
+    }
+
+    #[tokio::test]
+    async fn version_of_working_program() {
+        let bin = FakeBin::new().await;
+        let ffmpeg = bin.script(
+            "ffmpeg",
+            r#"echo "  ffmpeg version 7.0.1 Copyright (c) 2000-2024  "; echo "built with gcc""#,
+            true,
+        );
+
+        let version = version_line(&ffmpeg, &["-version"], "FFMPEG_PATH")
+            .await
+            .expect("Program works");
+
+        assert_eq!(version, "ffmpeg version 7.0.1 Copyright (c) 2000-2024");
+    }
+
+    #[tokio::test]
+    async fn version_of_silent_program() {
+        let bin = FakeBin::new().await;
+        let demucs = bin.script("demucs", "exit 0", true);
+
+        let version = version_line(&demucs, &["--help"], "DEMUCS_PATH")
+            .await
+            .expect("Program works");
+
+        assert_eq!(version, "");
+    }
+
+    #[tokio::test]
+    async fn failing_program() {
+        let bin = FakeBin::new().await;
+        let demucs = bin.script(
+            "demucs",
+            r#"echo "ModuleNotFoundError: No module named 'torch'" >&2; exit 1"#,
+            true,
+        );
+
+        let err = version_line(&demucs, &["--help"], "DEMUCS_PATH")
+            .await
+            .expect_err("Program fails")
+            .to_string();
+
+        assert!(err.contains("--help exited with code Some(1)"), "{err}");
+        assert!(err.contains("No module named 'torch'"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn missing_program() {
+        let bin = FakeBin::new().await;
+
+        let err = version_line(&bin.0.path().join("ffprobe"), &["-version"], "FFPROBE_PATH")
+            .await
+            .expect_err("Program is missing")
+            .to_string();
+
+        assert!(err.contains("could not run"), "{err}");
+        assert!(err.contains("set FFPROBE_PATH"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn program_that_is_not_executable() {
+        let bin = FakeBin::new().await;
+        let ffmpeg = bin.script("ffmpeg", "echo ffmpeg version 7.0.1", false);
+
+        let err = version_line(&ffmpeg, &["-version"], "FFMPEG_PATH")
+            .await
+            .expect_err("Program can't be run")
+            .to_string();
+
+        assert!(err.contains("could not run"), "{err}");
+        assert!(err.contains("set FFMPEG_PATH"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn docker_with_image() {
+        let bin = FakeBin::new().await;
+        let docker = bin.script(
+            "docker",
+            r#"case "$1 $2" in
+                "version --format") echo 27.1.1 ;;
+                "image inspect") [ "$5" = "demucs:latest" ] && echo sha256:1234 || exit 1 ;;
+                *) exit 2 ;;
+            esac"#,
+            true,
+        );
+        let config = |image: &str| DemucsDockerConfig {
+            image: image.to_string(),
+            docker_path: docker.clone(),
+            extra_args: vec![],
+        };
+
+        check_docker(&config("demucs:latest"))
+            .await
+            .expect("Image exists");
+
+        let err = check_docker(&config("demucs:missing"))
+            .await
+            .expect_err("Image is missing")
+            .to_string();
+        assert!(err.contains("\"demucs:missing\" doesn't exist"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn docker_without_daemon() {
+        let bin = FakeBin::new().await;
+        let docker = bin.script(
+            "docker",
+            r#"echo "Cannot connect to the Docker daemon" >&2; exit 1"#,
+            true,
+        );
+
+        let err = check_docker(&DemucsDockerConfig {
+            image: "demucs:latest".to_string(),
+            docker_path: docker,
+            extra_args: vec![],
+        })
+        .await
+        .expect_err("Daemon is down")
+        .to_string();
+
+        assert!(err.contains("could not reach the Docker daemon"), "{err}");
+        assert!(err.contains("Cannot connect"), "{err}");
+    }
+}
@@ -0,0 +1,295 @@
+use std::{f32::consts::PI, fmt, path::Path, process::Stdio, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::{debug, trace};
+
+use crate::config::Config;
+
+/// The song is decoded at this sample rate, which is plenty for the notes and the beats
+const SAMPLE_RATE: u32 = 11025;
+/// Only the start of longer songs is analyzed
+const MAX_DURATION: Duration = Duration::from_secs(600);
+
+/// Length of the frames the notes are measured in (about 0.75 s)
+const KEY_FRAME_LENGTH: usize = 8192;
+/// The notes that are measured, as MIDI note numbers (C3 to B5)
+const KEY_NOTES: std::ops::Range<u8> = 48..84;
+/// Frames quieter than this (RMS) are skipped, so silence doesn't count as any key
+const MIN_FRAME_RMS: f32 = 0.001;
+/// Key profiles of Krumhansl and Kessler, starting at the tonic
+const MAJOR_PROFILE: [f32; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f32; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B",
+];
+
+/// The loudness is measured every this many samples (about 12 ms) for finding the beats
+const TEMPO_HOP_LENGTH: usize = 128;
+/// The loudness is measured over this many samples, longer than the steps so that the beating of
+/// the notes against each other doesn't look like a rhythm
+const TEMPO_WINDOW_LENGTH: usize = 512;
+const MIN_BPM: f32 = 60.0;
+const MAX_BPM: f32 = 200.0;
+/// Tempos around this one are preferred, so a song isn't detected at half or double its tempo
+const PREFERRED_BPM: f32 = 120.0;
+/// The loudness increases are smoothed over this many steps, so a beat that falls between two
+/// steps still lines up with the next one
+const ONSET_SMOOTHING: usize = 5;
+
+/// The detected key and tempo of a song
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SongAnalysis {
+    pub key: Option<MusicalKey>,
+    pub bpm: Option<u16>,
+}
+impl SongAnalysis {
+    /// Detect the key and the tempo of the song.
+    ///
+    /// The song is decoded with ffmpeg and analyzed here: the key is the one whose profile
+    /// matches the notes that are played the most, and the tempo is the one that repeats the
+    /// most in the changes of the loudness.
+    #[tracing::instrument]
+    pub async fn analyze(file_path: &Path) -> anyhow::Result<Self> {
+        let output = Command::new(&Config::global().ffmpeg_path)
+            .arg("-hide_banner")
+            .args(["-t", &MAX_DURATION.as_secs().to_string()])
+            .arg("-i")
+            .arg(file_path)
+            .args(["-map", "0:a:0", "-ac", "1"])
+            .args(["-ar", &SAMPLE_RATE.to_string()])
+            .args(["-f", "f32le", "-"])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+        trace!(status = ?output.status, "Decode command finished");
+
+        if !output.status.success() {
+            anyhow::bail!("Command executed with exit code {:?}", output.status.code());
+        }
+
+        let samples = output
+            .stdout
+            .chunks_exact(4)
+            .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+            .collect::<Vec<_>>();
+
+        let res = tokio::task::spawn_blocking(move || Self {
+            key: detect_key(&samples),
+            bpm: detect_bpm(&samples),
+        })
+        .await?;
+        debug!(analysis = ?res, "Analyzed song");
+
+        if res.key.is_none() && res.bpm.is_none() {
+            anyhow::bail!("neither the key nor the tempo could be detected");
+        }
+
+        Ok(res)
+    }
+}
+impl fmt::Display for SongAnalysis {
+    /// Eg. `A minor, 128 BPM`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.key, self.bpm) {
+            (Some(key), Some(bpm)) => write!(f, "{key}, {bpm} BPM"),
+            (Some(key), None) => write!(f, "{key}"),
+            (None, Some(bpm)) => write!(f, "{bpm} BPM"),
+            (None, None) => write!(f, "unknown"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MusicalKey {
+    /// The note the key is built on, from 0 (C) to 11 (B)
+    pub tonic: u8,
+    pub minor: bool,
+}
+impl fmt::Display for MusicalKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = if self.minor { "minor" } else { "major" };
+
+        write!(f, "{} {mode}", NOTE_NAMES[usize::from(self.tonic % 12)])
+    }
+}
+
+/// The key whose profile correlates best with how much each note is played
+fn detect_key(samples: &[f32]) -> Option<MusicalKey> {
+    let window = (0..KEY_FRAME_LENGTH)
+        .map(|i| {
+            #[allow(clippy::cast_precision_loss)]
+            let x = i as f32 / KEY_FRAME_LENGTH as f32;
+            0.5f32.mul_add(-(2.0 * PI * x).cos(), 0.5)
+        })
+        .collect::<Vec<_>>();
+
+    let mut chroma = [0.0_f32; 12];
+    for frame in samples.chunks_exact(KEY_FRAME_LENGTH) {
+        #[allow(clippy::cast_precision_loss)]
+        let rms = (frame.iter().map(|x| x * x).sum::<f32>() / frame.len() as f32).sqrt();
+        if rms < MIN_FRAME_RMS {
+            continue;
+        }
+
+        let frame = frame
+            .iter()
+            .zip(&window)
+            .map(|(x, w)| x * w)
+            .collect::<Vec<_>>();
+        let mut frame_chroma = [0.0_f32; 12];
+        for note in KEY_NOTES {
+            frame_chroma[usize::from(note % 12)] += goertzel(&frame, note_frequency(note)).sqrt();
+        }
+
+        // Each frame counts the same, so the loud parts don't decide the key alone
+        let total = frame_chroma.iter().sum::<f32>();
+        if total > 0.0 {
+            for (sum, x) in chroma.iter_mut().zip(frame_chroma) {
+                *sum += x / total;
+            }
+        }
+    }
+
+    if chroma.iter().all(|x| *x == 0.0) {
+        return None;
+    }
+
+    (0..12_u8)
+        .flat_map(|tonic| [false, true].map(|minor| MusicalKey { tonic, minor }))
+        .map(|key| {
+            let profile = if key.minor {
+                MINOR_PROFILE
+            } else {
+                MAJOR_PROFILE
+            };
+            let rotated = std::array::from_fn::<_, 12, _>(|i| {
+                profile[(i + 12 - usize::from(key.tonic)) % 12]
+            });
+
+            (correlation(&chroma, &rotated), key)
+        })
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, key)| key)
+}
+
+/// The tempo whose beat period repeats the most in the increases of the loudness
+fn detect_bpm(samples: &[f32]) -> Option<u16> {
+    #[allow(clippy::cast_precision_loss)]
+    let frame_rate = SAMPLE_RATE as f32 / TEMPO_HOP_LENGTH as f32;
+
+    let log_energy = samples
+        .windows(TEMPO_WINDOW_LENGTH)
+        .step_by(TEMPO_HOP_LENGTH)
+        .map(|x| (x.iter().map(|x| x * x).sum::<f32>() + 1e-9).ln())
+        .collect::<Vec<_>>();
+    let onsets = log_energy
+        .windows(2)
+        .map(|x| (x[1] - x[0]).max(0.0))
+        .collect::<Vec<_>>();
+    #[allow(clippy::cast_precision_loss)]
+    let mean = onsets.iter().sum::<f32>() / onsets.len().max(1) as f32;
+    #[allow(clippy::cast_precision_loss)]
+    let onsets = onsets
+        .windows(ONSET_SMOOTHING)
+        .map(|x| x.iter().sum::<f32>() / ONSET_SMOOTHING as f32 - mean)
+        .collect::<Vec<_>>();
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let (min_lag, max_lag) = (
+        (60.0 * frame_rate / MAX_BPM).floor() as usize,
+        (60.0 * frame_rate / MIN_BPM).ceil() as usize,
+    );
+    if onsets.len() < max_lag * 4 {
+        return None;
+    }
+
+    // One more lag on each side so the peak can be interpolated
+    let autocorrelation = ((min_lag - 1)..=(max_lag + 1))
+        .map(|lag| {
+            let sum = onsets
+                .iter()
+                .zip(&onsets[lag..])
+                .map(|(a, b)| a * b)
+                .sum::<f32>();
+            #[allow(clippy::cast_precision_loss)]
+            let res = sum / (onsets.len() - lag) as f32;
+            res
+        })
+        .collect::<Vec<_>>();
+
+    let (i, _) = (1..autocorrelation.len() - 1)
+        .map(|i| {
+            #[allow(clippy::cast_precision_loss)]
+            let bpm = 60.0 * frame_rate / (min_lag - 1 + i) as f32;
+            // A log-normal weighting that halves the score an octave away from the preferred tempo
+            let weight = (-0.5 * (bpm / PREFERRED_BPM).log2().powi(2) / 0.72).exp();
+
+            (i, autocorrelation[i] * weight)
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .filter(|(_, x)| *x > 0.0)?;
+
+    // The peak is between the lags, which are too coarse for fast tempos
+    let (before, peak, after) = (
+        autocorrelation[i - 1],
+        autocorrelation[i],
+        autocorrelation[i + 1],
+    );
+    let curvature = 2.0f32.mul_add(-peak, before) + after;
+    let shift = if curvature < 0.0 {
+        (0.5 * (before - after) / curvature).clamp(-0.5, 0.5)
+    } else {
+        0.0
+    };
+
+    #[allow(clippy::cast_precision_loss)]
+    let lag = (min_lag - 1 + i) as f32 + shift;
+    let bpm = (60.0 * frame_rate / lag).round();
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    Some(bpm as u16)
+}
+
+/// Frequency of the MIDI note (A4 is note 69 at 440 Hz)
+fn note_frequency(note: u8) -> f32 {
+    440.0 * ((f32::from(note) - 69.0) / 12.0).exp2()
+}
+
+/// Power of the frequency in the frame
+fn goertzel(frame: &[f32], frequency: f32) -> f32 {
+    #[allow(clippy::cast_precision_loss)]
+    let coefficient = 2.0 * (2.0 * PI * frequency / SAMPLE_RATE as f32).cos();
+
+    let (mut s1, mut s2) = (0.0_f32, 0.0_f32);
+    for x in frame {
+        let s = x + coefficient * s1 - s2;
+        s2 = s1;
+        s1 = s;
+    }
+
+    (coefficient * s1)
+        .mul_add(-s2, s1.mul_add(s1, s2 * s2))
+        .max(0.0)
+}
+
+/// Pearson correlation of the two
+fn correlation(a: &[f32; 12], b: &[f32; 12]) -> f32 {
+    let mean_a = a.iter().sum::<f32>() / 12.0;
+    let mean_b = b.iter().sum::<f32>() / 12.0;
+
+    let (mut covariance, mut variance_a, mut variance_b) = (0.0, 0.0, 0.0);
+    for (a, b) in a.iter().zip(b) {
+        covariance += (a - mean_a) * (b - mean_b);
+        variance_a += (a - mean_a).powi(2);
+        variance_b += (b - mean_b).powi(2);
+    }
+
+    covariance / (variance_a * variance_b).sqrt().max(f32::EPSILON)
+}
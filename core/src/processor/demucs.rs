@@ -0,0 +1,970 @@
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use once_cell::sync::{Lazy, OnceCell};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Command,
+    sync::watch,
+};
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    config::Config,
+    events::{self, Event},
+    helpers::temp_dir::TempDir,
+    processor::{
+        demucs_backend::DemucsBackend,
+        encoding::Encoding,
+        ffmpeg::FfmpegProcessor,
+        pipeline::{self, Encode, GuideMix, Mix, Pipeline},
+        remote::RemoteSeparator,
+        separator::{self, StemSeparator},
+    },
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum DemucsModel {
+    #[default]
+    HTDemucs,
+    HTDemucsFt,
+    HTDemucs6s,
+    HDemucsMmi,
+    MDX,
+    MDXExtra,
+    MDXQ,
+}
+impl Display for DemucsModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HTDemucs => f.write_str("htdemucs"),
+            Self::HTDemucsFt => f.write_str("htdemucs_ft"),
+            Self::HTDemucs6s => f.write_str("htdemucs_6s"),
+            Self::HDemucsMmi => f.write_str("hdemucs_mmi"),
+            Self::MDX => f.write_str("mdx"),
+            Self::MDXExtra => f.write_str("mdx_extra"),
+            Self::MDXQ => f.write_str("mdx_q"),
+        }
+    }
+}
+
+impl FromStr for DemucsModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|x| x.to_string().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "unknown model {s:?}, expected one of: {}",
+                    Self::ALL.map(|x| x.to_string()).join(", ")
+                )
+            })
+    }
+}
+impl DemucsModel {
+    pub const ALL: [Self; 7] = [
+        Self::HTDemucs,
+        Self::HTDemucsFt,
+        Self::HTDemucs6s,
+        Self::HDemucsMmi,
+        Self::MDX,
+        Self::MDXExtra,
+        Self::MDXQ,
+    ];
+
+    /// Roughly how many times longer than the song itself processing takes with the model.
+    ///
+    /// The fine-tuned and MDX models are bags of four models so they're a lot slower.
+    pub const fn default_processing_ratio(self) -> f64 {
+        match self {
+            Self::HTDemucs | Self::HTDemucs6s | Self::HDemucsMmi => 1.5,
+            Self::HTDemucsFt | Self::MDX | Self::MDXExtra | Self::MDXQ => 6.0,
+        }
+    }
+
+    /// Longest segment (in seconds) the model can split at once. The transformer models can't
+    /// use longer segments than they were trained on.
+    const fn max_segment(self) -> Option<u32> {
+        match self {
+            Self::HTDemucs | Self::HTDemucsFt | Self::HTDemucs6s => Some(7),
+            Self::HDemucsMmi | Self::MDX | Self::MDXExtra | Self::MDXQ => None,
+        }
+    }
+}
+
+/// Progress of splitting a song into stems
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DemucsProgress {
+    /// How much of the current demucs run is done
+    pub percent: u8,
+    /// Demucs ran out of memory and is running again with smaller segments
+    pub reduced_memory: bool,
+}
+
+/// How a demucs run ended, along with the last lines of its output
+#[derive(Debug)]
+enum DemucsRun {
+    Exited(ExitStatus, Vec<String>),
+    /// Killed (eg. by the OOM killer) or reported running out of memory
+    OutOfMemory(Vec<String>),
+    /// Killed because it took too long
+    TimedOut,
+}
+
+/// What was read from the demucs output
+#[derive(Debug, Default)]
+struct DemucsOutput {
+    /// Demucs reported running out of memory
+    out_of_memory: bool,
+    /// The last [`STDERR_TAIL_LINES`] lines, without the progress bar
+    tail: VecDeque<String>,
+}
+
+/// Demucs failed, with the reason if it's one of the common ones.
+///
+/// Serializable so remote workers can send it to the bot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DemucsError {
+    reason: Option<String>,
+    code: Option<i32>,
+    /// The last lines of the demucs output
+    stderr: Vec<String>,
+}
+impl DemucsError {
+    fn new(code: Option<i32>, stderr: Vec<String>) -> Self {
+        let text = stderr.join("\n").to_lowercase();
+        let reason = FAILURE_REASONS
+            .iter()
+            .find(|(x, _)| text.contains(x))
+            .map(|(_, reason)| (*reason).to_string());
+
+        Self {
+            reason,
+            code,
+            stderr,
+        }
+    }
+
+    /// Whether the failure is one of the common ones, so the demucs output isn't needed to
+    /// understand it
+    pub const fn is_classified(&self) -> bool {
+        self.reason.is_some()
+    }
+
+    /// The end of the demucs output, at most [`STDERR_EXCERPT_CHARS`] long.
+    ///
+    /// Might contain paths and other details, so it's only meant for the admins.
+    pub fn stderr_excerpt(&self) -> String {
+        let mut res = VecDeque::new();
+        let mut len = 0;
+        for line in self.stderr.iter().rev().map(|x| x.trim_end()) {
+            len += line.chars().count() + 1;
+            if len > STDERR_EXCERPT_CHARS {
+                break;
+            }
+            res.push_front(line);
+        }
+
+        res.into_iter().collect::<Vec<_>>().join("\n")
+    }
+}
+impl Display for DemucsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.reason {
+            Some(reason) => f.write_str(reason),
+            None => write!(f, "Demucs exited with code {:?}", self.code),
+        }
+    }
+}
+impl std::error::Error for DemucsError {}
+
+/// Settings of a single demucs run
+#[derive(Debug, Clone, Copy, Default)]
+struct RunOptions {
+    /// Length of the segments (in seconds) the song is split in, shorter ones use less memory
+    segment: Option<u32>,
+    /// The run is killed after this long
+    timeout: Option<Duration>,
+    device: DemucsDevice,
+}
+
+/// Device demucs runs on (`KARAOKIFY_DEMUCS_DEVICE`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DemucsDevice {
+    /// Demucs uses CUDA if it's available and the CPU otherwise
+    #[default]
+    Auto,
+    Cuda,
+    Cpu,
+}
+impl Display for DemucsDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auto => f.write_str("auto"),
+            Self::Cuda => f.write_str("cuda"),
+            Self::Cpu => f.write_str("cpu"),
+        }
+    }
+}
+impl FromStr for DemucsDevice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "cuda" | "gpu" => Ok(Self::Cuda),
+            "cpu" => Ok(Self::Cpu),
+            _ => anyhow::bail!("unknown device {s:?}, expected one of: cuda, cpu, auto"),
+        }
+    }
+}
+
+/// The device passed to demucs, set by [`DemucsProcessor::probe_device`]. Differs from the
+/// configured one when CUDA was checked (`auto`) or doesn't work (fallback to the CPU).
+static DEVICE: OnceCell<DemucsDevice> = OnceCell::new();
+
+/// How long the device probe can take, including downloading the model
+const DEVICE_PROBE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Which stems the song should be split into
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)]
+pub enum StemMode {
+    /// Vocals and everything else
+    #[default]
+    TwoStem,
+    /// Vocals, drums, bass and other
+    FourStem,
+    /// Vocals, drums, bass, guitar, piano and other
+    SixStem,
+}
+impl StemMode {
+    /// The stems (other than vocals) that demucs outputs in this mode
+    const fn instrument_stems(self) -> &'static [&'static str] {
+        match self {
+            Self::TwoStem => &["no_vocals"],
+            Self::FourStem => &["drums", "bass", "other"],
+            Self::SixStem => &["drums", "bass", "guitar", "piano", "other"],
+        }
+    }
+
+    /// The model that should be used. Only `htdemucs_6s` supports splitting into six stems.
+    pub const fn model(self, requested: DemucsModel) -> DemucsModel {
+        match self {
+            Self::SixStem => DemucsModel::HTDemucs6s,
+            Self::TwoStem | Self::FourStem => requested,
+        }
+    }
+}
+impl FromStr for StemMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "2" => Ok(Self::TwoStem),
+            "4" => Ok(Self::FourStem),
+            "6" => Ok(Self::SixStem),
+            _ => anyhow::bail!("unsupported number of stems {s:?}, expected one of: 2, 4, 6"),
+        }
+    }
+}
+impl Display for StemMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TwoStem => f.write_str("2"),
+            Self::FourStem => f.write_str("4"),
+            Self::SixStem => f.write_str("6"),
+        }
+    }
+}
+
+/// Allowed volume (in dB) of the vocals in the guide mixes
+pub(super) const GUIDE_VOCALS_DB_RANGE: std::ops::RangeInclusive<i32> = -40..=0;
+
+/// How loud the vocals should be in the guide mix (instrumental with quiet vocals)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuideVocals {
+    /// Vocals at this volume (in dB)
+    Level(i32),
+    /// Two mixes, one with louder (-10 dB) and one with quieter (-20 dB) vocals
+    Both,
+}
+impl GuideVocals {
+    /// Volumes (in dB) of the vocals in each of the guide mixes
+    pub fn levels(self) -> Vec<i32> {
+        match self {
+            Self::Level(db) => vec![db],
+            Self::Both => vec![-10, -20],
+        }
+    }
+}
+impl FromStr for GuideVocals {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("both") {
+            return Ok(Self::Both);
+        }
+
+        let db = s
+            .parse::<i32>()
+            .ok()
+            .filter(|x| GUIDE_VOCALS_DB_RANGE.contains(x));
+        let Some(db) = db else {
+            anyhow::bail!(
+                "invalid guide vocals volume {s:?}, expected `both` or a number of dB between {} \
+                 and {}",
+                GUIDE_VOCALS_DB_RANGE.start(),
+                GUIDE_VOCALS_DB_RANGE.end()
+            );
+        };
+
+        Ok(Self::Level(db))
+    }
+}
+impl Display for GuideVocals {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Level(db) => write!(f, "{db}"),
+            Self::Both => f.write_str("both"),
+        }
+    }
+}
+
+/// Matches the percentage part of the progress bar demucs prints to stderr, eg.
+/// ` 42%|████▏     | 23.4/55.6 [00:10<00:14,  2.25seconds/s]`
+static PROGRESS_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?<percent>\d{1,3})%\|").expect("Invalid regex"));
+/// Errors of demucs (or `PyTorch`) running out of memory
+static OUT_OF_MEMORY_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)out of memory|MemoryError").expect("Invalid regex"));
+
+/// Segment lengths (in seconds) demucs is retried with if it runs out of memory
+pub(super) const REDUCED_MEMORY_SEGMENTS: [u32; 2] = [10, 5];
+
+/// How many of the last lines of the demucs output are kept for diagnosing failures
+const STDERR_TAIL_LINES: usize = 50;
+/// Longest part of the demucs output included in the admin report
+const STDERR_EXCERPT_CHARS: usize = 600;
+
+/// Human readable reasons of the common demucs failures, keyed by (lowercase) text in its
+/// output
+const FAILURE_REASONS: &[(&str, &str)] = &[
+    ("cuda out of memory", "The GPU ran out of memory"),
+    (
+        "not a supported file format",
+        "The file format isn't supported",
+    ),
+    (
+        "invalid data found when processing input",
+        "The file format isn't supported",
+    ),
+    ("could not load file", "The file format isn't supported"),
+    ("urlopen error", "The model couldn't be downloaded"),
+    ("http error", "The model couldn't be downloaded"),
+    ("connectionerror", "The model couldn't be downloaded"),
+    (
+        "temporary failure in name resolution",
+        "The model couldn't be downloaded",
+    ),
+    (
+        "couldn't find appropriate backend",
+        "No audio backend is installed for torchaudio",
+    ),
+    (
+        "no audio i/o backend",
+        "No audio backend is installed for torchaudio",
+    ),
+];
+
+pub struct DemucsProcessor;
+impl DemucsProcessor {
+    /// Split the song into stems.
+    ///
+    /// Progress of the separation (in percent) is reported through `progress` as it is parsed
+    /// from the demucs output.
+    ///
+    /// A guide mix (instrumental with quiet vocals) is created for each of the `guide_vocals`
+    /// volumes, along with the re-encoded song. Without `guide_vocals` only the stems are
+    /// created. All of the files are encoded using `encoding`.
+    #[tracing::instrument(skip(progress))]
+    pub async fn split_into_stems(
+        output_dir: &Path,
+        file_path: &Path,
+        demucs_model: DemucsModel,
+        stem_mode: StemMode,
+        guide_vocals: Option<GuideVocals>,
+        encoding: Encoding,
+        progress: Option<&watch::Sender<DemucsProgress>>,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        debug!("Splitting into stems");
+        let demucs_model = stem_mode.model(demucs_model);
+        let demucs_dir = TempDir::with_prefix("karaokify-demucs-").await?;
+
+        let started_at = Instant::now();
+        separator::global()
+            .separate(
+                file_path,
+                demucs_model,
+                stem_mode,
+                encoding,
+                demucs_dir.path(),
+                progress,
+            )
+            .await?;
+        events::emit(Event::DemucsFinished {
+            model: demucs_model,
+            duration: started_at.elapsed(),
+        });
+
+        let demucs_stems_dir = demucs_dir.path().join(demucs_model.to_string());
+
+        let file_base_name = {
+            let mut f = file_path.file_stem().unwrap_or_default().to_os_string();
+
+            if f.is_empty() {
+                f = OsString::from("song");
+            }
+
+            f
+        };
+        let output_path = |suffix: &str| {
+            output_dir.join({
+                let mut f = file_base_name.clone();
+                f.push(format!(".{suffix}.{}", encoding.extension()));
+                f
+            })
+        };
+        let demucs_stem_path =
+            |stem: &str| demucs_stems_dir.join(format!("{stem}.{}", encoding.demucs_extension()));
+
+        let vocals_path = output_path("vocals");
+        trace!(?vocals_path, "Exporting vocals to output directory");
+        Self::export(&demucs_stem_path("vocals"), &vocals_path, encoding).await?;
+
+        let music_path = output_path("music");
+        let mut instrument_paths = vec![];
+        if stem_mode == StemMode::TwoStem {
+            trace!(?music_path, "Exporting music to output directory");
+            Self::export(&demucs_stem_path("no_vocals"), &music_path, encoding).await?;
+        } else {
+            for stem in stem_mode.instrument_stems() {
+                let stem_path = output_path(stem);
+                trace!(?stem_path, "Exporting stem to output directory");
+                Self::export(&demucs_stem_path(stem), &stem_path, encoding).await?;
+                instrument_paths.push(stem_path);
+            }
+
+            trace!("Combining instrument stems to create music");
+            let combine = Mix {
+                inputs: instrument_paths.clone(),
+                output: music_path.clone(),
+                encoding,
+            };
+            pipeline::run_step(&combine, demucs_dir.path())
+                .await
+                .context("Failed to combine the instrument stems")?;
+        }
+
+        let Some(guide_vocals) = guide_vocals else {
+            let mut files = vec![vocals_path, music_path];
+            files.extend(instrument_paths);
+            return Ok(files);
+        };
+
+        let mut pipeline = Pipeline::new();
+        let guide_levels = guide_vocals.levels();
+        for db in &guide_levels {
+            // The volume is only added to the name if there's more than one guide mix
+            let guide_path = if guide_levels.len() > 1 {
+                output_path(&format!("music-with-quiet-vocals.{db}dB"))
+            } else {
+                output_path("music-with-quiet-vocals")
+            };
+
+            pipeline.push(GuideMix {
+                vocals: vocals_path.clone(),
+                music: music_path.clone(),
+                vocals_db: *db,
+                output: guide_path,
+                encoding,
+            });
+        }
+
+        let song_path = file_path.with_extension(encoding.extension());
+        pipeline.push(Encode {
+            input: file_path.to_path_buf(),
+            output: song_path.clone(),
+            encoding,
+        });
+
+        trace!(?pipeline, "Running post steps");
+        // The re-encoded song goes after the stems
+        let (song_paths, guide_paths) = pipeline
+            .run(demucs_dir.path())
+            .await
+            .into_iter()
+            .partition::<Vec<_>, _>(|x| *x == song_path);
+
+        let mut files = vec![vocals_path, music_path];
+        files.extend(guide_paths);
+        files.extend(instrument_paths);
+        files.extend(song_paths);
+
+        Ok(files)
+    }
+
+    /// Copy the file created by demucs to `output_path`, encoding it first if demucs couldn't
+    /// output it in the right format
+    async fn export(
+        demucs_path: &Path,
+        output_path: &Path,
+        encoding: Encoding,
+    ) -> anyhow::Result<()> {
+        if encoding.demucs_extension() == encoding.extension() {
+            tokio::fs::copy(demucs_path, output_path).await?;
+            return Ok(());
+        }
+
+        let encode = Encode {
+            input: demucs_path.to_path_buf(),
+            output: output_path.to_path_buf(),
+            encoding,
+        };
+        let scratch_dir = demucs_path.parent().unwrap_or_else(|| Path::new("."));
+
+        pipeline::run_step(&encode, scratch_dir).await
+    }
+
+    /// Run each of the models on a short silent file so demucs downloads and caches their
+    /// weights before the first real song is processed.
+    ///
+    /// Failures are only logged since the models will be fetched on first use anyway.
+    pub async fn warm_up(models: &[DemucsModel]) {
+        if models.is_empty() {
+            return;
+        }
+
+        // The worker warms up its own models
+        if Config::global().worker.url.is_some() {
+            debug!("Songs are split by a remote worker, not warming up models");
+            return;
+        }
+
+        info!(?models, "Warming up demucs models");
+
+        let warm_up_dir = match TempDir::with_prefix("karaokify-warm-up-").await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(?e, "Failed to create directory for warming up models");
+                return;
+            }
+        };
+
+        let silence_path = warm_up_dir.path().join("silence.mp3");
+        if let Err(e) = Self::generate_silence(&silence_path).await {
+            warn!(?e, "Failed to generate file for warming up models");
+            return;
+        }
+
+        for model in models {
+            info!(%model, "Warming up model");
+
+            match Self::run_demucs(
+                model,
+                StemMode::TwoStem,
+                Encoding::default(),
+                RunOptions {
+                    device: Self::device(),
+                    ..Default::default()
+                },
+                warm_up_dir.path(),
+                &silence_path,
+                None,
+            )
+            .await
+            {
+                Ok(DemucsRun::Exited(status, _)) if status.success() => {
+                    info!(%model, "Model warmed up");
+                }
+                Ok(run) => warn!(%model, ?run, "Failed to warm up model"),
+                Err(e) => warn!(%model, ?e, "Failed to warm up model"),
+            }
+        }
+
+        info!("Done warming up demucs models");
+    }
+
+    /// Check whether demucs can use CUDA by splitting a second of silence on it, and pick the
+    /// device the songs are processed on.
+    ///
+    /// If CUDA is configured but doesn't work, the CPU is used instead, unless
+    /// `KARAOKIFY_DEMUCS_DEVICE_STRICT` is set, in which case an error is returned.
+    pub async fn probe_device() -> anyhow::Result<()> {
+        let config = Config::global();
+
+        let device = match config.demucs_device {
+            DemucsDevice::Cpu => DemucsDevice::Cpu,
+            device => {
+                let cuda_works = Self::cuda_works().await?;
+                info!(cuda_works, "Checked whether demucs can use CUDA");
+
+                match (device, cuda_works) {
+                    (DemucsDevice::Cuda, false) if config.demucs_device_strict => {
+                        anyhow::bail!(
+                            "demucs can't use CUDA. Make sure the GPU drivers and a CUDA build of \
+                             PyTorch are installed, or set KARAOKIFY_DEMUCS_DEVICE to cpu or auto."
+                        );
+                    }
+                    (DemucsDevice::Cuda, false) => {
+                        warn!(
+                            "!!! Demucs can't use CUDA, falling back to the CPU. Processing will \
+                             be a lot slower !!!"
+                        );
+                        DemucsDevice::Cpu
+                    }
+                    // Passed explicitly so the Docker backend knows to give the container the GPU
+                    (_, true) => DemucsDevice::Cuda,
+                    (_, false) => DemucsDevice::Auto,
+                }
+            }
+        };
+
+        let _ = DEVICE.set(device);
+        info!(device = Self::device_description(), "Using demucs device");
+
+        Ok(())
+    }
+
+    /// The device passed to demucs
+    fn device() -> DemucsDevice {
+        DEVICE
+            .get()
+            .copied()
+            .unwrap_or_else(|| Config::global().demucs_device)
+    }
+
+    /// The device demucs runs on, for the status
+    pub fn device_description() -> String {
+        if Config::global().worker.url.is_some() {
+            return format!(
+                "{} (remote worker)",
+                RemoteSeparator::device().unwrap_or("unknown")
+            );
+        }
+
+        let Some(device) = DEVICE.get() else {
+            return "not checked yet".to_string();
+        };
+
+        match (Config::global().demucs_device, device) {
+            (DemucsDevice::Cuda, DemucsDevice::Cpu) => "cpu (CUDA doesn't work)".to_string(),
+            (DemucsDevice::Auto, DemucsDevice::Cuda) => "cuda (auto)".to_string(),
+            (DemucsDevice::Auto, _) => "cpu (auto, CUDA isn't available)".to_string(),
+            (_, device) => device.to_string(),
+        }
+    }
+
+    /// Run demucs on CUDA with a second of silence
+    async fn cuda_works() -> anyhow::Result<bool> {
+        let probe_dir = TempDir::with_prefix("karaokify-device-probe-").await?;
+        let silence_path = probe_dir.path().join("silence.mp3");
+        Self::generate_silence(&silence_path).await?;
+
+        let run = Self::run_demucs(
+            &DemucsModel::default(),
+            StemMode::TwoStem,
+            Encoding::default(),
+            RunOptions {
+                segment: None,
+                timeout: Some(DEVICE_PROBE_TIMEOUT),
+                device: DemucsDevice::Cuda,
+            },
+            probe_dir.path(),
+            &silence_path,
+            None,
+        )
+        .await?;
+
+        match run {
+            DemucsRun::Exited(status, _) if status.success() => Ok(true),
+            DemucsRun::Exited(_, stderr) | DemucsRun::OutOfMemory(stderr) => {
+                debug!(stderr = %stderr.join("\n"), "Demucs failed on CUDA");
+                Ok(false)
+            }
+            DemucsRun::TimedOut => Ok(false),
+        }
+    }
+
+    /// Generate a second of silence at `output_path`
+    async fn generate_silence(output_path: &Path) -> anyhow::Result<()> {
+        let cmd_status = Command::new(&Config::global().ffmpeg_path)
+            .args(["-f", "lavfi"])
+            .args(["-i", "anullsrc=r=44100:cl=stereo"])
+            .args(["-t", "1"])
+            .arg(output_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await?;
+        trace!(status = ?cmd_status, "Silence generation command finished");
+
+        if !cmd_status.success() {
+            anyhow::bail!("Command executed with exit code {:?}", cmd_status.code());
+        }
+
+        Ok(())
+    }
+
+    /// How long demucs gets to split the song, based on its duration
+    pub(super) async fn timeout(file_path: &Path) -> Duration {
+        let config = Config::global();
+
+        match FfmpegProcessor::duration(file_path).await {
+            Ok(duration) => duration
+                .mul_f64(config.demucs_timeout_ratio)
+                .max(config.demucs_min_timeout),
+            Err(e) => {
+                debug!(?e, "Could not get the song duration for the demucs timeout");
+                config.demucs_min_timeout
+            }
+        }
+    }
+
+    async fn run_demucs(
+        demucs_model: &DemucsModel,
+        stem_mode: StemMode,
+        encoding: Encoding,
+        options: RunOptions,
+        demucs_dir: &Path,
+        file_path: &Path,
+        progress: Option<&watch::Sender<DemucsProgress>>,
+    ) -> std::io::Result<DemucsRun> {
+        let mut args = vec!["--name".to_string(), demucs_model.to_string()];
+
+        if stem_mode == StemMode::TwoStem {
+            args.extend(["--two-stems".to_string(), "vocals".to_string()]);
+        }
+
+        if let Some(segment) = options.segment {
+            args.extend(["--segment".to_string(), segment.to_string()]);
+        }
+
+        // Demucs picks the device itself by default
+        if options.device != DemucsDevice::Auto {
+            args.extend(["--device".to_string(), options.device.to_string()]);
+        }
+
+        // WAV is the default output format
+        if let Some(x) = encoding.demucs_args() {
+            args.extend(x);
+        }
+
+        args.extend(["--filename".to_string(), "{stem}.{ext}".to_string()]);
+
+        let backend = DemucsBackend::global();
+        let (mut cmd, container) = backend.command(&args, options.device, demucs_dir, file_path)?;
+        let mut child = cmd
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stderr = child.stderr.take();
+
+        let run = async {
+            tokio::join!(child.wait(), async {
+                match stderr {
+                    Some(stderr) => Self::read_progress(stderr, progress).await,
+                    None => DemucsOutput::default(),
+                }
+            })
+        };
+        let res = match options.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, run).await,
+            None => Ok(run.await),
+        };
+
+        let Ok((status, output)) = res else {
+            child.kill().await?;
+            return Ok(DemucsRun::TimedOut);
+        };
+        let status = status?;
+        if let Some(container) = container {
+            container.exited();
+        }
+        let stderr = Vec::from(output.tail);
+
+        if output.out_of_memory || backend.was_killed(status) {
+            return Ok(DemucsRun::OutOfMemory(stderr));
+        }
+
+        Ok(DemucsRun::Exited(status, stderr))
+    }
+
+    /// Read the demucs output until it's closed and report any progress found in it.
+    ///
+    /// The progress bar is redrawn using carriage returns, so those are treated as line breaks.
+    ///
+    /// The rest of the output is kept for diagnosing failures.
+    async fn read_progress<R>(
+        mut output: R,
+        progress: Option<&watch::Sender<DemucsProgress>>,
+    ) -> DemucsOutput
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = [0_u8; 1024];
+        let mut line = Vec::new();
+        let mut res = DemucsOutput::default();
+
+        loop {
+            let n = match output.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    trace!(?e, "Failed to read demucs output");
+                    break;
+                }
+            };
+
+            for &c in &buf[..n] {
+                if c != b'\r' && c != b'\n' {
+                    line.push(c);
+                    continue;
+                }
+
+                let text = String::from_utf8_lossy(&line);
+                res.out_of_memory |= OUT_OF_MEMORY_REGEX.is_match(&text);
+
+                match Self::parse_progress(&text) {
+                    Some(percent) => {
+                        if let Some(progress) = progress {
+                            progress.send_modify(|x| x.percent = percent);
+                        }
+                    }
+                    None if !text.trim().is_empty() => {
+                        if res.tail.len() == STDERR_TAIL_LINES {
+                            res.tail.pop_front();
+                        }
+                        res.tail.push_back(text.into_owned());
+                    }
+                    None => {}
+                }
+
+                line.clear();
+            }
+        }
+
+        res
+    }
+
+    fn parse_progress(line: &str) -> Option<u8> {
+        PROGRESS_REGEX
+            .captures_iter(line)
+            .last()
+            .and_then(|x| x.name("percent"))
+            .and_then(|x| x.as_str().parse::<u8>().ok())
+            .filter(|x| *x <= 100)
+    }
+}
+
+/// Runs demucs on this machine, or in a Docker container on it
+#[derive(Debug)]
+pub struct LocalSeparator;
+#[async_trait::async_trait]
+impl StemSeparator for LocalSeparator {
+    /// Runs demucs with smaller segments (which use less memory) if it runs out of memory and
+    /// kills it if it takes too long
+    async fn separate(
+        &self,
+        file_path: &Path,
+        demucs_model: DemucsModel,
+        stem_mode: StemMode,
+        encoding: Encoding,
+        out_dir: &Path,
+        progress: Option<&watch::Sender<DemucsProgress>>,
+    ) -> anyhow::Result<()> {
+        let mut options = RunOptions {
+            segment: None,
+            timeout: Some(DemucsProcessor::timeout(file_path).await),
+            device: DemucsProcessor::device(),
+        };
+        let mut reduced_segments = REDUCED_MEMORY_SEGMENTS
+            .map(|x| demucs_model.max_segment().map_or(x, |max| x.min(max)))
+            .into_iter();
+
+        let (cmd_status, stderr) = loop {
+            info!(segment = ?options.segment, timeout = ?options.timeout, "Running demucs");
+
+            // Retried in case the command can't be started
+            let run = tryhard::retry_fn(|| {
+                DemucsProcessor::run_demucs(
+                    &demucs_model,
+                    stem_mode,
+                    encoding,
+                    options,
+                    out_dir,
+                    file_path,
+                    progress,
+                )
+            })
+            .retries(3)
+            .await?;
+
+            let stderr = match run {
+                DemucsRun::Exited(status, stderr) => break (status, stderr),
+                DemucsRun::TimedOut => {
+                    anyhow::bail!("Demucs didn't finish in {:?}", options.timeout);
+                }
+                DemucsRun::OutOfMemory(stderr) => stderr,
+            };
+
+            let Some(segment) = reduced_segments.find(|x| options.segment.map_or(true, |s| *x < s))
+            else {
+                warn!(stderr = %stderr.join("\n"), "Demucs ran out of memory");
+                let mut e = DemucsError::new(None, stderr);
+                e.reason.get_or_insert_with(|| {
+                    "Ran out of memory, even with reduced memory settings".to_string()
+                });
+                return Err(e.into());
+            };
+            warn!(
+                segment,
+                "Demucs ran out of memory, retrying with reduced memory settings"
+            );
+            options.segment = Some(segment);
+
+            if let Some(progress) = progress {
+                progress.send_replace(DemucsProgress {
+                    percent: 0,
+                    reduced_memory: true,
+                });
+            }
+        };
+
+        trace!(status = ?cmd_status, "Demucs command finished");
+
+        if !cmd_status.success() {
+            warn!(code = ?cmd_status.code(), stderr = %stderr.join("\n"), "Demucs failed");
+            return Err(DemucsError::new(cmd_status.code(), stderr).into());
+        }
+
+        Ok(())
+    }
+}
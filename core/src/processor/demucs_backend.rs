@@ -0,0 +1,166 @@
+use std::{
+    ffi::OsString,
+    os::unix::{fs::MetadataExt, process::ExitStatusExt},
+    path::Path,
+    process::{ExitStatus, Stdio},
+};
+
+use tokio::process::Command;
+use tracing::{debug, trace};
+
+use super::demucs::DemucsDevice;
+use crate::{
+    config::{Config, DemucsDockerConfig},
+    helpers::id::short_id,
+};
+
+/// Where the output directory is mounted in the container
+const CONTAINER_OUT_DIR: &str = "/data/out";
+/// Where the directory of the song is mounted in the container
+const CONTAINER_IN_DIR: &str = "/data/in";
+
+/// Exit code of `docker run` when the container was killed with `SIGKILL` (eg. by the OOM killer)
+const DOCKER_KILLED_CODE: i32 = 128 + 9;
+
+/// How demucs is run
+#[derive(Debug, Clone, Copy)]
+pub enum DemucsBackend {
+    /// The `demucs` executable (`DEMUCS_PATH`)
+    Local,
+    /// A Docker container with demucs installed (`KARAOKIFY_DEMUCS_DOCKER_IMAGE`)
+    Docker(&'static DemucsDockerConfig),
+}
+impl DemucsBackend {
+    /// The backend picked by the config
+    pub fn global() -> Self {
+        Config::global()
+            .demucs_docker
+            .as_ref()
+            .map_or(Self::Local, Self::Docker)
+    }
+
+    /// Command which runs demucs with the `args` on the song at `file_path`, writing the stems
+    /// into `out_dir`.
+    ///
+    /// For Docker the paths are mounted into the container. The command only controls the
+    /// `docker` client, so the returned [`Container`] has to be kept until demucs exits to make
+    /// sure the container is killed if the run is cancelled.
+    pub fn command(
+        self,
+        args: &[String],
+        device: DemucsDevice,
+        out_dir: &Path,
+        file_path: &Path,
+    ) -> std::io::Result<(Command, Option<Container>)> {
+        let docker = match self {
+            Self::Local => {
+                let mut cmd = Command::new(&Config::global().demucs_path);
+                cmd.args(args)
+                    .args([OsString::from("--out").as_os_str(), out_dir.as_os_str()])
+                    .arg(file_path);
+
+                return Ok((cmd, None));
+            }
+            Self::Docker(x) => x,
+        };
+
+        let out_dir = std::path::absolute(out_dir)?;
+        let file_path = std::path::absolute(file_path)?;
+        let (Some(in_dir), Some(file_name)) = (file_path.parent(), file_path.file_name()) else {
+            return Err(std::io::Error::other(format!(
+                "{} is not a file path",
+                file_path.display()
+            )));
+        };
+
+        // The stems have to be owned by the bot so it can delete them
+        let owner = std::fs::metadata(&out_dir)?;
+        let container = Container {
+            name: format!("karaokify-demucs-{}", short_id()),
+            docker_path: docker.docker_path.clone().into_os_string(),
+            running: true,
+        };
+
+        let mut cmd = Command::new(&docker.docker_path);
+        cmd.args(["run", "--rm", "--init", "--name", &container.name])
+            .args(["--user", &format!("{}:{}", owner.uid(), owner.gid())]);
+
+        if device == DemucsDevice::Cuda {
+            cmd.args(["--gpus", "all"]);
+        }
+
+        cmd.arg("--volume")
+            .arg(mount(&out_dir, CONTAINER_OUT_DIR, false))
+            .arg("--volume")
+            .arg(mount(in_dir, CONTAINER_IN_DIR, true))
+            .args(&docker.extra_args)
+            .arg(&docker.image)
+            .arg("demucs")
+            .args(args)
+            .args(["--out", CONTAINER_OUT_DIR])
+            .arg(Path::new(CONTAINER_IN_DIR).join(file_name));
+
+        trace!(?cmd, "Running demucs in Docker");
+
+        Ok((cmd, Some(container)))
+    }
+
+    /// Whether demucs was killed with `SIGKILL`, which is what the OOM killer uses
+    pub fn was_killed(self, status: ExitStatus) -> bool {
+        match self {
+            Self::Local => status.signal() == Some(9),
+            Self::Docker(_) => status.code() == Some(DOCKER_KILLED_CODE),
+        }
+    }
+}
+
+/// A running demucs container, killed when dropped unless it exited
+#[derive(Debug)]
+pub struct Container {
+    name: String,
+    docker_path: OsString,
+    running: bool,
+}
+impl Container {
+    /// Demucs exited and the container was removed, so there's nothing to kill
+    pub fn exited(mut self) {
+        self.running = false;
+    }
+}
+impl Drop for Container {
+    fn drop(&mut self) {
+        if !self.running {
+            return;
+        }
+
+        let name = std::mem::take(&mut self.name);
+        let docker_path = std::mem::take(&mut self.docker_path);
+        let kill = move || {
+            let res = std::process::Command::new(docker_path)
+                .args(["kill", &name])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+            debug!(?res, name, "Killed demucs container");
+        };
+
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(kill);
+            }
+            Err(_) => kill(),
+        }
+    }
+}
+
+/// `--volume` argument mounting the host directory into the container
+fn mount(host_path: &Path, container_path: &str, read_only: bool) -> OsString {
+    let mut res = host_path.as_os_str().to_os_string();
+    res.push(":");
+    res.push(container_path);
+    if read_only {
+        res.push(":ro");
+    }
+
+    res
+}
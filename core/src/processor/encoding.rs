@@ -0,0 +1,243 @@
+use std::{fmt::Display, path::Path, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// Allowed constant bitrates (in kbps) of MP3 files
+const CBR_RANGE: std::ops::RangeInclusive<u32> = 96..=320;
+/// Allowed VBR quality levels of MP3 files (`0` is the best)
+const VBR_RANGE: std::ops::RangeInclusive<u8> = 0..=9;
+
+/// Audio format of the created files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Mp3,
+    Flac,
+    Wav,
+}
+impl OutputFormat {
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Mp3 => "mp3",
+            Self::Flac => "flac",
+            Self::Wav => "wav",
+        }
+    }
+
+    /// Whether files of the format aren't compressed lossily (and are thus a lot larger)
+    pub fn is_lossless_file(file_path: &Path) -> bool {
+        file_path.extension().is_some_and(|x| {
+            [Self::Flac, Self::Wav]
+                .iter()
+                .any(|f| x.eq_ignore_ascii_case(f.extension()))
+        })
+    }
+}
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mp3" => Ok(Self::Mp3),
+            "flac" => Ok(Self::Flac),
+            "wav" => Ok(Self::Wav),
+            _ => anyhow::bail!("unsupported format {s:?}, expected one of: mp3, flac, wav"),
+        }
+    }
+}
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+/// Bitrate of MP3 files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Bitrate {
+    /// Constant bitrate (in kbps), eg. `320`
+    Cbr(u32),
+    /// Variable bitrate with the LAME quality level, eg. `v0`
+    Vbr(u8),
+}
+impl Bitrate {
+    /// Arguments which make ffmpeg encode MP3s with this bitrate
+    pub fn ffmpeg_args(self) -> Vec<String> {
+        match self {
+            Self::Cbr(kbps) => vec!["-b:a".to_string(), format!("{kbps}k")],
+            Self::Vbr(quality) => vec!["-q:a".to_string(), quality.to_string()],
+        }
+    }
+}
+impl Default for Bitrate {
+    fn default() -> Self {
+        Self::Cbr(256)
+    }
+}
+impl FromStr for Bitrate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+
+        if let Some(quality) = s.strip_prefix('v') {
+            return match quality.parse::<u8>() {
+                Ok(x) if VBR_RANGE.contains(&x) => Ok(Self::Vbr(x)),
+                _ => anyhow::bail!(
+                    "invalid VBR quality {s:?}, expected one of v{} (best) to v{}",
+                    VBR_RANGE.start(),
+                    VBR_RANGE.end()
+                ),
+            };
+        }
+
+        match s.trim_end_matches('k').parse::<u32>() {
+            Ok(x) if CBR_RANGE.contains(&x) => Ok(Self::Cbr(x)),
+            _ => anyhow::bail!(
+                "invalid bitrate {s:?}, expected a number between {} and {} (kbps) or a VBR \
+                 quality like `v0`",
+                CBR_RANGE.start(),
+                CBR_RANGE.end()
+            ),
+        }
+    }
+}
+impl Display for Bitrate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cbr(kbps) => write!(f, "{kbps}k"),
+            Self::Vbr(quality) => write!(f, "v{quality}"),
+        }
+    }
+}
+
+/// How the created files are encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Encoding {
+    pub format: OutputFormat,
+    /// Only used for MP3 files
+    pub bitrate: Bitrate,
+}
+impl Encoding {
+    pub const fn extension(self) -> &'static str {
+        self.format.extension()
+    }
+
+    /// Arguments which make ffmpeg encode the files like this
+    pub fn ffmpeg_args(self) -> Vec<String> {
+        match self.format {
+            OutputFormat::Mp3 => self.bitrate.ffmpeg_args(),
+            OutputFormat::Flac | OutputFormat::Wav => vec![],
+        }
+    }
+
+    /// Arguments which make demucs output files encoded like this.
+    ///
+    /// Demucs can only create constant bitrate MP3s, so `None` is returned for everything else.
+    /// It then outputs WAV files which have to be encoded afterwards.
+    pub fn demucs_args(self) -> Option<Vec<String>> {
+        match (self.format, self.bitrate) {
+            (OutputFormat::Mp3, Bitrate::Cbr(kbps)) => Some(vec![
+                "--mp3".to_string(),
+                "--mp3-bitrate".to_string(),
+                kbps.to_string(),
+            ]),
+            _ => None,
+        }
+    }
+
+    /// Extension of the files demucs outputs
+    pub fn demucs_extension(self) -> &'static str {
+        if self.demucs_args().is_some() {
+            OutputFormat::Mp3.extension()
+        } else {
+            OutputFormat::Wav.extension()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitrates() {
+        for (value, bitrate) in [
+            ("320", Bitrate::Cbr(320)),
+            ("320k", Bitrate::Cbr(320)),
+            ("192K", Bitrate::Cbr(192)),
+            (" 96 ", Bitrate::Cbr(96)),
+            ("v0", Bitrate::Vbr(0)),
+            ("V2", Bitrate::Vbr(2)),
+            ("v9", Bitrate::Vbr(9)),
+        ] {
+            assert_eq!(value.parse::<Bitrate>().expect(value), bitrate, "{value}");
+        }
+
+        for value in [
+            "", "k", "95", "321", "1000k", "-128", "128kbps", "v", "v10", "v-1", "vbr", "128.5",
+        ] {
+            assert!(value.parse::<Bitrate>().is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn bitrates_round_trip() {
+        for bitrate in [
+            Bitrate::Cbr(96),
+            Bitrate::Cbr(320),
+            Bitrate::Vbr(0),
+            Bitrate::Vbr(9),
+        ] {
+            assert_eq!(bitrate.to_string().parse::<Bitrate>().ok(), Some(bitrate));
+        }
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(
+            "FLAC".parse::<OutputFormat>().ok(),
+            Some(OutputFormat::Flac)
+        );
+        assert!("ogg".parse::<OutputFormat>().is_err());
+
+        assert!(OutputFormat::is_lossless_file(Path::new("song.FLAC")));
+        assert!(OutputFormat::is_lossless_file(Path::new("song.music.wav")));
+        assert!(!OutputFormat::is_lossless_file(Path::new("song.mp3")));
+        assert!(!OutputFormat::is_lossless_file(Path::new("flac")));
+    }
+
+    #[test]
+    fn encodings() {
+        let cbr = Encoding {
+            format: OutputFormat::Mp3,
+            bitrate: Bitrate::Cbr(192),
+        };
+        assert_eq!(cbr.ffmpeg_args(), ["-b:a", "192k"]);
+        assert_eq!(
+            cbr.demucs_args().as_deref(),
+            Some(
+                ["--mp3", "--mp3-bitrate", "192"]
+                    .map(String::from)
+                    .as_slice()
+            )
+        );
+        assert_eq!(cbr.demucs_extension(), "mp3");
+
+        // Demucs can't create VBR files
+        let vbr = Encoding {
+            format: OutputFormat::Mp3,
+            bitrate: Bitrate::Vbr(0),
+        };
+        assert_eq!(vbr.ffmpeg_args(), ["-q:a", "0"]);
+        assert_eq!(vbr.demucs_args(), None);
+        assert_eq!(vbr.demucs_extension(), "wav");
+
+        let flac = Encoding {
+            format: OutputFormat::Flac,
+            bitrate: Bitrate::Cbr(320),
+        };
+        assert!(flac.ffmpeg_args().is_empty());
+        assert_eq!(flac.demucs_args(), None);
+        assert_eq!(flac.extension(), "flac");
+    }
+}
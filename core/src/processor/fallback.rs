@@ -0,0 +1,54 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use tokio::process::Command;
+use tracing::{debug, trace};
+
+use crate::{config::Config, processor::encoding::Bitrate};
+
+/// Removes vocals by cancelling out everything that's panned to the center.
+///
+/// Much faster and more robust than demucs, but the quality is way worse (and it doesn't work
+/// for mono songs), so it's only used when demucs fails.
+pub struct FallbackProcessor;
+impl FallbackProcessor {
+    #[tracing::instrument]
+    pub async fn remove_center_channel(
+        output_dir: &Path,
+        file_path: &Path,
+        bitrate: Bitrate,
+    ) -> anyhow::Result<PathBuf> {
+        debug!("Removing center channel");
+
+        let output_path = output_dir.join({
+            let mut f = file_path.file_stem().unwrap_or_default().to_os_string();
+            if f.is_empty() {
+                f = OsString::from("song");
+            }
+            f.push(".music-fallback.mp3");
+            f
+        });
+
+        let cmd_status = Command::new(&Config::global().ffmpeg_path)
+            .args([OsString::from("-i"), file_path.as_os_str().to_os_string()])
+            .args(["-map", "0:a"])
+            .args(["-af", "pan=stereo|c0=c0-c1|c1=c1-c0"])
+            .args(bitrate.ffmpeg_args())
+            .arg(&output_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await?;
+        trace!(status = ?cmd_status, "Center channel removal command finished");
+
+        if !cmd_status.success() {
+            anyhow::bail!("Command executed with exit code {:?}", cmd_status.code());
+        }
+
+        Ok(output_path)
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use tokio::process::Command;
+use tracing::{debug, trace};
+
+use crate::{config::Config, helpers::temp_dir::TempDir};
+
+/// Bitrates (in kbps) to try when re-encoding a file to make it smaller
+const SHRINK_BITRATES: &[u32] = &[192, 128, 96];
+
+pub struct FfmpegProcessor;
+impl FfmpegProcessor {
+    /// Make sure the file is at most `max_size` bytes large.
+    ///
+    /// The file is first re-encoded with progressively lower bitrates. If even the lowest one
+    /// is too large, the file is split into parts (eg. `song.music.part1.mp3`) that each fit.
+    ///
+    /// Returns the path(s) of the file(s) which should be used instead of the original.
+    #[tracing::instrument]
+    pub async fn fit_to_size(file_path: &Path, max_size: u64) -> anyhow::Result<Vec<PathBuf>> {
+        if file_size(file_path).await? <= max_size {
+            return Ok(vec![file_path.to_path_buf()]);
+        }
+
+        let work_dir = TempDir::with_prefix("karaokify-ffmpeg-").await?;
+        let extension = file_path.extension().unwrap_or_default();
+
+        let mut smallest = None;
+        for bitrate in SHRINK_BITRATES {
+            let reencoded_path = work_dir
+                .path()
+                .join(format!("reencoded-{bitrate}k"))
+                .with_extension(extension);
+
+            debug!(?bitrate, "Re-encoding file to a lower bitrate");
+            Self::reencode(file_path, &reencoded_path, *bitrate).await?;
+
+            let size = file_size(&reencoded_path).await?;
+            trace!(?bitrate, ?size, "File re-encoded");
+
+            if size <= max_size {
+                trace!(
+                    ?reencoded_path,
+                    "Re-encoded file fits, copying over original"
+                );
+                tokio::fs::copy(&reencoded_path, file_path).await?;
+                return Ok(vec![file_path.to_path_buf()]);
+            }
+
+            smallest = Some((reencoded_path, size));
+        }
+
+        let Some((smallest_path, smallest_size)) = smallest else {
+            anyhow::bail!("No bitrates to re-encode the file with");
+        };
+
+        debug!(size = ?smallest_size, "File still too large, splitting into parts");
+        // Segments are split on frame boundaries so leave some headroom
+        let parts = smallest_size.div_ceil(max_size / 10 * 9).max(2);
+        let part_paths = Self::split_into_parts(&smallest_path, work_dir.path(), parts).await?;
+
+        let file_stem = file_path.file_stem().unwrap_or_default();
+        let mut files = vec![];
+        for (i, part_path) in part_paths.into_iter().enumerate() {
+            if file_size(&part_path).await? > max_size {
+                anyhow::bail!("file is too large even when split into {parts} parts");
+            }
+
+            let out_path = file_path.with_file_name({
+                let mut f = file_stem.to_os_string();
+                f.push(format!(".part{}.", i + 1));
+                f.push(extension);
+                f
+            });
+            trace!(?out_path, "Copying part to output directory");
+            tokio::fs::copy(&part_path, &out_path).await?;
+
+            files.push(out_path);
+        }
+
+        let _ = tokio::fs::remove_file(file_path).await;
+
+        Ok(files)
+    }
+
+    pub async fn reencode(
+        file_path: &Path,
+        output_path: &Path,
+        bitrate_kbps: u32,
+    ) -> anyhow::Result<()> {
+        let cmd_status = Command::new(&Config::global().ffmpeg_path)
+            .args([OsString::from("-i"), file_path.as_os_str().to_os_string()])
+            .args(["-map", "0:a"])
+            // Keep the cover art (if any)
+            .args(["-map", "0:v?"])
+            .args(["-c:v", "copy"])
+            .args(["-b:a", &format!("{bitrate_kbps}k")])
+            .arg(output_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await?;
+        trace!(status = ?cmd_status, "Re-encode command finished");
+
+        if !cmd_status.success() {
+            anyhow::bail!("Command executed with exit code {:?}", cmd_status.code());
+        }
+
+        Ok(())
+    }
+
+    /// Cut out the part of the file between `start` and `end`.
+    ///
+    /// The audio is copied as is if possible, but some containers don't support that so the
+    /// file is re-encoded to MP3 if copying fails.
+    ///
+    /// Returns the path of the trimmed file (eg. `song.trimmed.m4a`) in `output_dir`.
+    #[tracing::instrument]
+    pub async fn trim(
+        file_path: &Path,
+        output_dir: &Path,
+        start: Duration,
+        end: Duration,
+    ) -> anyhow::Result<PathBuf> {
+        let trimmed_path = |extension: &OsStr| {
+            output_dir.join({
+                let mut f = file_path.file_stem().unwrap_or_default().to_os_string();
+                if f.is_empty() {
+                    f = OsString::from("song");
+                }
+                f.push(".trimmed.");
+                f.push(extension);
+                f
+            })
+        };
+
+        let copy_path = trimmed_path(file_path.extension().unwrap_or_default());
+        let res = Self::run_trim(file_path, &copy_path, start, end, &["-c", "copy"]).await;
+        match res {
+            Ok(()) if file_size(&copy_path).await.unwrap_or_default() > 0 => {
+                return Ok(copy_path);
+            }
+            res => debug!(?res, "Failed to trim file without re-encoding it"),
+        }
+        let _ = tokio::fs::remove_file(&copy_path).await;
+
+        let reencoded_path = trimmed_path(OsStr::new("mp3"));
+        Self::run_trim(file_path, &reencoded_path, start, end, &["-b:a", "256k"]).await?;
+
+        Ok(reencoded_path)
+    }
+
+    async fn run_trim(
+        file_path: &Path,
+        output_path: &Path,
+        start: Duration,
+        end: Duration,
+        codec_args: &[&str],
+    ) -> anyhow::Result<()> {
+        let cmd_status = Command::new(&Config::global().ffmpeg_path)
+            .args(["-ss", &format!("{:.3}", start.as_secs_f64())])
+            .args(["-to", &format!("{:.3}", end.as_secs_f64())])
+            .args([OsString::from("-i"), file_path.as_os_str().to_os_string()])
+            .args(["-map", "0:a"])
+            .args(codec_args)
+            .arg(output_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await?;
+        trace!(status = ?cmd_status, "Trim command finished");
+
+        if !cmd_status.success() {
+            anyhow::bail!("Command executed with exit code {:?}", cmd_status.code());
+        }
+
+        Ok(())
+    }
+
+    /// Split the file into `parts` parts of (roughly) equal duration
+    pub async fn split_into_parts(
+        file_path: &Path,
+        output_dir: &Path,
+        parts: u64,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let duration = Self::duration(file_path).await?;
+        #[allow(clippy::cast_precision_loss)]
+        let segment_time = duration.as_secs_f64() / parts as f64;
+
+        let extension = file_path
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let segments_dir = output_dir.join("segments");
+        tokio::fs::create_dir_all(&segments_dir).await?;
+
+        let cmd_status = Command::new(&Config::global().ffmpeg_path)
+            .args([OsString::from("-i"), file_path.as_os_str().to_os_string()])
+            .args(["-f", "segment"])
+            .args(["-segment_time", &format!("{segment_time:.3}")])
+            .args(["-segment_start_number", "1"])
+            .args(["-c", "copy"])
+            .arg(segments_dir.join(format!("part%d.{extension}")))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await?;
+        trace!(status = ?cmd_status, "Split command finished");
+
+        if !cmd_status.success() {
+            anyhow::bail!("Command executed with exit code {:?}", cmd_status.code());
+        }
+
+        let mut files = vec![];
+        for i in 1.. {
+            let part_path = segments_dir.join(format!("part{i}.{extension}"));
+
+            if !tokio::fs::try_exists(&part_path).await.unwrap_or_default() {
+                break;
+            }
+
+            files.push(part_path);
+        }
+
+        if files.is_empty() {
+            anyhow::bail!("No parts were created");
+        }
+
+        Ok(files)
+    }
+
+    /// Extract the picture embedded in the song's tags as a JPEG
+    pub async fn extract_cover(file_path: &Path, output_path: &Path) -> anyhow::Result<()> {
+        let cmd_status = Command::new(&Config::global().ffmpeg_path)
+            .args([OsString::from("-i"), file_path.as_os_str().to_os_string()])
+            .args(["-map", "0:v:0"])
+            .args(["-frames:v", "1"])
+            .arg(output_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await?;
+        trace!(status = ?cmd_status, "Extract cover command finished");
+
+        if !cmd_status.success() {
+            anyhow::bail!("Command executed with exit code {:?}", cmd_status.code());
+        }
+
+        Ok(())
+    }
+
+    /// Create a small (at most 320x320) JPEG version of the image
+    pub async fn make_thumbnail(image_path: &Path, output_path: &Path) -> anyhow::Result<()> {
+        let cmd_status = Command::new(&Config::global().ffmpeg_path)
+            .args([OsString::from("-i"), image_path.as_os_str().to_os_string()])
+            .args(["-vf", "scale=320:320:force_original_aspect_ratio=decrease"])
+            .args(["-q:v", "5"])
+            .args(["-frames:v", "1"])
+            .arg(output_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await?;
+        trace!(status = ?cmd_status, "Thumbnail command finished");
+
+        if !cmd_status.success() {
+            anyhow::bail!("Command executed with exit code {:?}", cmd_status.code());
+        }
+
+        Ok(())
+    }
+
+    /// Embed the image into the file as its cover art
+    pub async fn embed_cover(file_path: &Path, image_path: &Path) -> anyhow::Result<()> {
+        let work_dir = TempDir::with_prefix("karaokify-ffmpeg-").await?;
+        let output_path = work_dir
+            .path()
+            .join("with-cover")
+            .with_extension(file_path.extension().unwrap_or_default());
+
+        let cmd_status = Command::new(&Config::global().ffmpeg_path)
+            .args([OsString::from("-i"), file_path.as_os_str().to_os_string()])
+            .args([OsString::from("-i"), image_path.as_os_str().to_os_string()])
+            .args(["-map", "0:a"])
+            .args(["-map", "1"])
+            .args(["-c", "copy"])
+            .args(["-id3v2_version", "3"])
+            .args(["-disposition:v", "attached_pic"])
+            .arg(&output_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await?;
+        trace!(status = ?cmd_status, "Embed cover command finished");
+
+        if !cmd_status.success() {
+            anyhow::bail!("Command executed with exit code {:?}", cmd_status.code());
+        }
+
+        tokio::fs::copy(&output_path, file_path).await?;
+
+        Ok(())
+    }
+
+    /// Get the duration of the media file using `ffprobe`
+    pub async fn duration(file_path: &Path) -> anyhow::Result<Duration> {
+        let output = Command::new(&Config::global().ffprobe_path)
+            .args(["-v", "error"])
+            .args(["-show_entries", "format=duration"])
+            .args(["-of", "default=noprint_wrappers=1:nokey=1"])
+            .arg(file_path)
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            anyhow::bail!("Command executed with exit code {:?}", output.status.code());
+        }
+
+        let duration = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse::<f64>()?;
+
+        Ok(Duration::try_from_secs_f64(duration)?)
+    }
+}
+
+async fn file_size(file_path: &Path) -> std::io::Result<u64> {
+    tokio::fs::metadata(file_path).await.map(|x| x.len())
+}
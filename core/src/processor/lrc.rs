@@ -0,0 +1,444 @@
+use std::{fmt::Write, time::Duration};
+
+/// The last line is shown for this long if the song's duration isn't known
+const LAST_LINE_DURATION: Duration = Duration::from_secs(5);
+/// Lines are hidden after this long, so a line before a long instrumental part doesn't stay
+/// on the screen for the whole part
+const MAX_LINE_DURATION: Duration = Duration::from_secs(10);
+/// The title is only shown before the lyrics if they start later than this
+const MIN_TITLE_DURATION: Duration = Duration::from_secs(2);
+
+/// Size of the video the subtitles are made for
+pub const VIDEO_WIDTH: u32 = 1280;
+pub const VIDEO_HEIGHT: u32 = 720;
+/// Distance of the previous and the next line from the current one (in the middle)
+const LINE_SPACING: u32 = 110;
+
+const ASS_STYLES: &str = "\
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, \
+Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, \
+Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: Current,DejaVu Sans,56,&H00FFFFFF,&H00FFFFFF,&H00000000,&H00000000,-1,0,0,0,100,100,0,0,\
+1,3,0,5,60,60,0,1
+Style: Other,DejaVu Sans,40,&H00808080,&H00808080,&H00000000,&H00000000,0,0,0,0,100,100,0,0,1,\
+2,0,5,60,60,0,1
+Style: Title,DejaVu Sans,48,&H0000D7FF,&H0000D7FF,&H00000000,&H00000000,-1,0,0,0,100,100,0,0,\
+1,3,0,5,60,60,0,1
+";
+
+/// A line of lyrics with the time it's sung at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LrcLine {
+    pub start: Duration,
+    /// Empty for the breaks between the lyrics (eg. instrumental parts)
+    pub text: String,
+}
+
+/// Parse lyrics in the LRC format, eg. `[00:12.34]Hello`.
+///
+/// Lines with several timestamps (eg. a repeated chorus) are added once for each of them and
+/// the `[offset:...]` tag is applied. Word timestamps of the enhanced format (eg.
+/// `<00:12.34>Hello`) are removed. The lines are sorted by their start.
+pub fn parse(lrc: &str) -> Vec<LrcLine> {
+    let mut offset_ms = 0_i64;
+    let mut res = vec![];
+
+    for line in lrc.lines() {
+        let mut rest = line.trim();
+        let mut starts = vec![];
+
+        while let Some((tag, after)) = rest.strip_prefix('[').and_then(|x| x.split_once(']')) {
+            if let Some(start) = parse_timestamp(tag) {
+                starts.push(start);
+            } else if let Some(value) = tag.strip_prefix("offset:") {
+                offset_ms = value.trim().parse().unwrap_or(offset_ms);
+            }
+            rest = after;
+        }
+
+        let text = strip_word_timestamps(rest);
+        for start in starts {
+            res.push((start, text.clone()));
+        }
+    }
+
+    // A positive offset makes the lyrics appear sooner
+    let mut res = res
+        .into_iter()
+        .map(|(start, text)| LrcLine {
+            start: shift(start, offset_ms.saturating_neg()),
+            text,
+        })
+        .collect::<Vec<_>>();
+    res.sort_by_key(|x| x.start);
+
+    res
+}
+
+/// Subtitles in the ASS format which show the current line in the middle of a
+/// [`VIDEO_WIDTH`]×[`VIDEO_HEIGHT`] video, with the previous line above and the next one below
+/// it.
+///
+/// The lines are moved `time_offset` earlier (eg. because only a part of the song starting at
+/// the offset is in the video), dropping the ones before the start. The title is shown until the
+/// lyrics start.
+pub fn to_ass(
+    lines: &[LrcLine],
+    title: &str,
+    time_offset: Duration,
+    duration: Option<Duration>,
+) -> String {
+    let lines = lines
+        .iter()
+        .filter_map(|x| {
+            Some(LrcLine {
+                start: x.start.checked_sub(time_offset)?,
+                text: x.text.clone(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut res = format!(
+        "[Script Info]\nScriptType: v4.00+\nPlayResX: {VIDEO_WIDTH}\nPlayResY: {VIDEO_HEIGHT}\n\
+         WrapStyle: 0\nScaledBorderAndShadow: yes\n\n{ASS_STYLES}\n[Events]\nFormat: Layer, \
+         Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n"
+    );
+
+    let first_start = lines.first().map_or(Duration::MAX, |x| x.start);
+    if first_start >= MIN_TITLE_DURATION {
+        let end = duration.map_or(first_start, |x| x.min(first_start));
+        push_event(&mut res, Duration::ZERO, end, "Title", None, title);
+    }
+
+    let center_y = VIDEO_HEIGHT / 2;
+    for (i, line) in lines.iter().enumerate() {
+        if line.text.is_empty() {
+            continue;
+        }
+
+        let next = lines.get(i + 1);
+        let end = next
+            .map(|x| x.start)
+            .or(duration)
+            .unwrap_or(line.start + LAST_LINE_DURATION)
+            .min(line.start + MAX_LINE_DURATION)
+            .min(duration.unwrap_or(Duration::MAX));
+        if end <= line.start {
+            continue;
+        }
+
+        push_event(&mut res, line.start, end, "Current", None, &line.text);
+
+        let previous = i.checked_sub(1).and_then(|x| lines.get(x));
+        if let Some(previous) = previous.filter(|x| !x.text.is_empty()) {
+            let y = center_y - LINE_SPACING;
+            push_event(&mut res, line.start, end, "Other", Some(y), &previous.text);
+        }
+        if let Some(next) = next.filter(|x| !x.text.is_empty()) {
+            let y = center_y + LINE_SPACING;
+            push_event(&mut res, line.start, end, "Other", Some(y), &next.text);
+        }
+    }
+
+    res
+}
+
+fn push_event(
+    ass: &mut String,
+    start: Duration,
+    end: Duration,
+    style: &str,
+    y: Option<u32>,
+    text: &str,
+) {
+    let position = y.map_or_else(String::new, |y| {
+        format!("{{\\pos({},{y})}}", VIDEO_WIDTH / 2)
+    });
+
+    let _ = writeln!(
+        ass,
+        "Dialogue: 0,{},{},{style},,0,0,0,,{position}{}",
+        format_ass_time(start),
+        format_ass_time(end),
+        escape_ass(text)
+    );
+}
+
+/// Parse an LRC timestamp, eg. `01:23.45`, `01:23.456` or `01:23`
+fn parse_timestamp(tag: &str) -> Option<Duration> {
+    let (minutes, seconds) = tag.trim().split_once(':')?;
+    let minutes = minutes.parse::<u64>().ok()?;
+    let (seconds, fraction) = seconds.split_once(['.', ':']).unwrap_or((seconds, ""));
+    if seconds.len() != 2 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let seconds = seconds.parse::<u64>().ok()?;
+
+    // The fraction is in hundredths (`.45`) or thousandths (`.456`) of a second
+    let millis = format!("{fraction:0<3}")[..3].parse::<u64>().ok()?;
+
+    Some(Duration::from_secs(minutes * 60 + seconds) + Duration::from_millis(millis))
+}
+
+/// Remove the word timestamps of the enhanced LRC format, eg. `<00:12.34>`
+fn strip_word_timestamps(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('<') {
+        res.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        match rest[1..].split_once('>') {
+            Some((tag, after)) if parse_timestamp(tag).is_some() => rest = after,
+            _ => {
+                res.push('<');
+                rest = &rest[1..];
+            }
+        }
+    }
+    res.push_str(rest);
+
+    res.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn shift(time: Duration, by_ms: i64) -> Duration {
+    let by = Duration::from_millis(by_ms.unsigned_abs());
+
+    if by_ms < 0 {
+        time.saturating_sub(by)
+    } else {
+        time + by
+    }
+}
+
+/// Format the time as `H:MM:SS.cc`, the ASS format with hundredths of a second
+fn format_ass_time(time: Duration) -> String {
+    let centis = time.as_millis() / 10;
+
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        centis / 360_000,
+        centis / 6000 % 60,
+        centis / 100 % 60,
+        centis % 100
+    )
+}
+
+/// Make sure the text isn't interpreted as ASS override tags (in braces) or line breaks
+fn escape_ass(text: &str) -> String {
+    text.replace('\\', "\u{29f5}")
+        .replace('{', "(")
+        .replace('}', ")")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(secs: f64, text: &str) -> LrcLine {
+        LrcLine {
+            start: Duration::from_secs_f64(secs),
+            text: text.to_string(),
+        }
+    }
+
+    fn events(ass: &str) -> Vec<&str> {
+        ass.lines()
+            .filter_map(|x| x.strip_prefix("Dialogue: 0,"))
+            .collect()
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(
+            parse_timestamp("01:23.45"),
+            Some(Duration::from_millis(83_450))
+        );
+        assert_eq!(
+            parse_timestamp("01:23.456"),
+            Some(Duration::from_millis(83_456))
+        );
+        assert_eq!(
+            parse_timestamp("01:23:45"),
+            Some(Duration::from_millis(83_450))
+        );
+        assert_eq!(parse_timestamp("01:23"), Some(Duration::from_secs(83)));
+        assert_eq!(
+            parse_timestamp(" 123:00.5 "),
+            Some(Duration::from_millis(7_380_500))
+        );
+        assert_eq!(parse_timestamp("1:2.34"), None);
+        assert_eq!(parse_timestamp("01:23.4x"), None);
+        assert_eq!(parse_timestamp("ar:Queen"), None);
+        assert_eq!(parse_timestamp("offset:500"), None);
+    }
+
+    #[test]
+    fn parse_lrc() {
+        let lrc = "\
+[ar:Queen]
+[ti:Bohemian Rhapsody]
+[00:00.64]Is this the real life?
+[00:04.12]  Is this   just fantasy?  
+
+[00:10.00][00:30.00]Chorus
+[00:20.00]
+not a lyrics line
+";
+
+        assert_eq!(
+            parse(lrc),
+            vec![
+                line(0.64, "Is this the real life?"),
+                line(4.12, "Is this just fantasy?"),
+                line(10.0, "Chorus"),
+                line(20.0, ""),
+                line(30.0, "Chorus"),
+            ]
+        );
+    }
+
+    #[test]
+    fn offsets() {
+        // The offset applies to the whole file, wherever the tag is
+        let lrc = "[00:01.00]First\n[offset:+1500]\n[00:10.00]Second";
+        assert_eq!(parse(lrc), vec![line(0.0, "First"), line(8.5, "Second")]);
+
+        let lrc = "[offset:-500]\n[00:01.00]First";
+        assert_eq!(parse(lrc), vec![line(1.5, "First")]);
+
+        let lrc = "[offset:soon]\n[00:01.00]First";
+        assert_eq!(parse(lrc), vec![line(1.0, "First")]);
+    }
+
+    #[test]
+    fn word_timestamps() {
+        assert_eq!(
+            parse("[00:01.00]<00:01.00>Is <00:01.50>this <00:02.00>real?<00:02.50>"),
+            vec![line(1.0, "Is this real?")]
+        );
+        assert_eq!(strip_word_timestamps("a <b> c"), "a <b> c");
+        assert_eq!(strip_word_timestamps("1 < 2 <00:01.00>"), "1 < 2");
+        assert_eq!(strip_word_timestamps("<<00:01.00>x"), "<x");
+    }
+
+    #[test]
+    fn ass_times() {
+        assert_eq!(format_ass_time(Duration::ZERO), "0:00:00.00");
+        assert_eq!(format_ass_time(Duration::from_millis(83_456)), "0:01:23.45");
+        assert_eq!(
+            format_ass_time(Duration::from_millis(3_723_990)),
+            "1:02:03.99"
+        );
+    }
+
+    #[test]
+    fn ass_escaping() {
+        assert_eq!(escape_ass(r"{\b1}Bold\N"), "(\u{29f5}b1)Bold\u{29f5}N");
+        assert_eq!(escape_ass("Plain text"), "Plain text");
+    }
+
+    #[test]
+    fn subtitles() {
+        let lines = [
+            line(5.0, "First"),
+            line(8.0, "Second"),
+            line(9.0, ""),
+            line(30.0, "{Third}"),
+        ];
+
+        let ass = to_ass(
+            &lines,
+            "Song",
+            Duration::ZERO,
+            Some(Duration::from_secs(32)),
+        );
+
+        assert!(ass.starts_with("[Script Info]\n"));
+        assert!(ass.contains("PlayResX: 1280\nPlayResY: 720\n"));
+        assert!(ass.contains(ASS_STYLES));
+        assert_eq!(
+            events(&ass),
+            [
+                "0:00:00.00,0:00:05.00,Title,,0,0,0,,Song",
+                "0:00:05.00,0:00:08.00,Current,,0,0,0,,First",
+                "0:00:05.00,0:00:08.00,Other,,0,0,0,,{\\pos(640,470)}Second",
+                "0:00:08.00,0:00:09.00,Current,,0,0,0,,Second",
+                "0:00:08.00,0:00:09.00,Other,,0,0,0,,{\\pos(640,250)}First",
+                "0:00:30.00,0:00:32.00,Current,,0,0,0,,(Third)",
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_dont_stay_too_long() {
+        let lines = [line(0.0, "First"), line(60.0, "Second")];
+
+        assert_eq!(
+            events(&to_ass(&lines, "Song", Duration::ZERO, None)),
+            [
+                "0:00:00.00,0:00:10.00,Current,,0,0,0,,First",
+                "0:00:00.00,0:00:10.00,Other,,0,0,0,,{\\pos(640,470)}Second",
+                "0:01:00.00,0:01:05.00,Current,,0,0,0,,Second",
+                "0:01:00.00,0:01:05.00,Other,,0,0,0,,{\\pos(640,250)}First",
+            ]
+        );
+
+        // Nothing is shown after the end of the song
+        let lines = [line(1.0, "First"), line(3.0, "Second")];
+        assert_eq!(
+            events(&to_ass(
+                &lines,
+                "Song",
+                Duration::ZERO,
+                Some(Duration::from_secs(3))
+            )),
+            [
+                "0:00:01.00,0:00:03.00,Current,,0,0,0,,First",
+                "0:00:01.00,0:00:03.00,Other,,0,0,0,,{\\pos(640,470)}Second",
+            ]
+        );
+    }
+
+    #[test]
+    fn time_offsets() {
+        let lines = [
+            line(10.0, "Before"),
+            line(65.0, "During"),
+            line(68.0, "After"),
+        ];
+
+        let ass = to_ass(
+            &lines,
+            "Song",
+            Duration::from_secs(60),
+            Some(Duration::from_secs(7)),
+        );
+
+        assert_eq!(
+            events(&ass),
+            [
+                "0:00:00.00,0:00:05.00,Title,,0,0,0,,Song",
+                "0:00:05.00,0:00:07.00,Current,,0,0,0,,During",
+                "0:00:05.00,0:00:07.00,Other,,0,0,0,,{\\pos(640,470)}After",
+            ]
+        );
+    }
+
+    #[test]
+    fn titles() {
+        // The lyrics start too soon for the title
+        let lines = [line(1.5, "First")];
+        let ass = to_ass(&lines, "Song", Duration::ZERO, Some(Duration::from_secs(3)));
+        assert_eq!(
+            events(&ass),
+            ["0:00:01.50,0:00:03.00,Current,,0,0,0,,First"]
+        );
+
+        // There are no lyrics in the video
+        let ass = to_ass(&[], "{Song}", Duration::ZERO, Some(Duration::from_secs(30)));
+        assert_eq!(events(&ass), ["0:00:00.00,0:00:30.00,Title,,0,0,0,,(Song)"]);
+    }
+}
@@ -0,0 +1,139 @@
+pub mod analysis;
+pub mod demucs;
+pub mod demucs_backend;
+pub mod encoding;
+pub mod fallback;
+pub mod ffmpeg;
+pub mod lrc;
+pub mod pipeline;
+pub mod postfx;
+pub mod remote;
+pub mod separator;
+pub mod video;
+pub mod vocal_detect;
+
+use std::path::Path;
+
+/// File name suffixes of the generated stems and what they contain
+const STEM_LABELS: &[(&str, &str)] = &[
+    ("vocals", "vocals"),
+    ("music", "instrumental"),
+    ("music-with-quiet-vocals", "instrumental with quiet vocals"),
+    ("music-fallback", "instrumental, lower quality fallback"),
+    ("drums", "drums"),
+    ("bass", "bass"),
+    ("guitar", "guitar"),
+    ("piano", "piano"),
+    ("other", "other instruments"),
+];
+
+/// Human readable label of the stem based on its file name (eg. `song.music.mp3` is
+/// `instrumental` and `song.music.-2st.85pct.mp3` is `instrumental, -2 semitones, 85% speed`).
+///
+/// Returns `None` for files that aren't stems (eg. the re-encoded song).
+pub fn stem_label(file_path: &Path) -> Option<String> {
+    let (suffix, effects) = parse_stem_name(file_path)?;
+    let label = suffix_label(&suffix)?;
+
+    Some(if effects.is_empty() {
+        label.to_string()
+    } else {
+        format!("{label}, {}", effects.join(", "))
+    })
+}
+
+/// Which stem the file is based on its file name, eg. `music` for both `song.music.mp3` and
+/// `song.music.-2st.mp3`.
+///
+/// Returns `None` for files that aren't stems (eg. the re-encoded song).
+pub fn stem_name(file_path: &Path) -> Option<String> {
+    parse_stem_name(file_path)
+        .map(|(suffix, _)| suffix)
+        .filter(|x| suffix_label(x).is_some())
+}
+
+/// Split the file name into the stem suffix and the labels of its modifiers
+fn parse_stem_name(file_path: &Path) -> Option<(String, Vec<String>)> {
+    let file_stem = file_path.file_stem()?.to_string_lossy();
+    // Skip the part number of files that were split into parts (eg. `song.music.part1.mp3`)
+    let mut file_stem = match file_stem.rsplit_once(".part") {
+        Some((base, part)) if part.parse::<u32>().is_ok() => base,
+        _ => &file_stem,
+    };
+
+    let mut effects = vec![];
+    loop {
+        let (rest, suffix) = file_stem.rsplit_once('.')?;
+
+        if let Some(effect) = modifier_label(suffix) {
+            effects.insert(0, effect);
+            file_stem = rest;
+            continue;
+        }
+
+        return Some((suffix.to_string(), effects));
+    }
+}
+
+/// Label of the suffixes added to stems that were modified, eg. `-2st` or `-10dB` (the
+/// volume of the vocals in a guide mix)
+fn modifier_label(suffix: &str) -> Option<String> {
+    postfx::effect_label(suffix).or_else(|| {
+        suffix
+            .strip_suffix("dB")
+            .and_then(|x| x.parse::<i32>().ok())
+            .map(|x| format!("vocals at {x} dB"))
+    })
+}
+
+fn suffix_label(suffix: &str) -> Option<&'static str> {
+    STEM_LABELS
+        .iter()
+        .find(|(x, _)| *x == suffix)
+        .map(|(_, label)| *label)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stem_labels() {
+        for (file_name, label, name) in [
+            ("Song.music.mp3", Some("instrumental"), Some("music")),
+            ("Song.vocals.flac", Some("vocals"), Some("vocals")),
+            (
+                "Song.music.-2st.mp3",
+                Some("instrumental, -2 semitones"),
+                Some("music"),
+            ),
+            (
+                "Song.music.+3st.85pct.mp3",
+                Some("instrumental, +3 semitones, 85% speed"),
+                Some("music"),
+            ),
+            (
+                "Song.music-with-quiet-vocals.-10dB.mp3",
+                Some("instrumental with quiet vocals, vocals at -10 dB"),
+                Some("music-with-quiet-vocals"),
+            ),
+            ("Song.music.part2.mp3", Some("instrumental"), Some("music")),
+            (
+                "Song.music.-2st.part1.mp3",
+                Some("instrumental, -2 semitones"),
+                Some("music"),
+            ),
+            // The title can contain dots as well
+            ("Mr. Brightside.drums.mp3", Some("drums"), Some("drums")),
+            // The re-encoded song isn't a stem
+            ("Song.mp3", None, None),
+            ("Mr. Brightside.mp3", None, None),
+            ("Song.music.partial.mp3", None, None),
+        ] {
+            let path = Path::new("/tmp/job").join(file_name);
+
+            assert_eq!(stem_label(&path).as_deref(), label, "{file_name}");
+            assert_eq!(stem_name(&path).as_deref(), name, "{file_name}");
+        }
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    ffi::OsString,
+    fmt::Debug,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use tokio::process::Command;
+use tracing::{debug, trace};
+
+use super::{demucs::GUIDE_VOCALS_DB_RANGE, encoding::Encoding};
+use crate::config::Config;
+
+/// A step run with ffmpeg on the files created by demucs, eg. mixing the vocals into the music
+pub trait PostStep: Debug + Send + Sync {
+    /// The files ffmpeg reads, in the order of its `-i` arguments
+    fn inputs(&self) -> Vec<&Path>;
+
+    /// The file the step creates
+    fn output(&self) -> &Path;
+
+    /// Arguments of ffmpeg between the inputs and the output, eg. the filter and the encoding
+    fn args(&self) -> anyhow::Result<Vec<OsString>>;
+}
+
+/// Steps which are run one after another, skipping the outputs of the ones that fail
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    steps: Vec<Box<dyn PostStep>>,
+}
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, step: impl PostStep + 'static) {
+        self.steps.push(Box::new(step));
+    }
+
+    /// Run all of the steps, using `scratch_dir` for the ones which overwrite their input.
+    ///
+    /// Returns the outputs of the steps which succeeded. Failures are only logged since the
+    /// other files are still useful without the output.
+    pub async fn run(&self, scratch_dir: &Path) -> Vec<PathBuf> {
+        let mut res = vec![];
+
+        for step in &self.steps {
+            match run_step(step.as_ref(), scratch_dir).await {
+                Ok(()) => res.push(step.output().to_path_buf()),
+                Err(e) => debug!(?e, ?step, "Post step failed, skipping its output"),
+            }
+        }
+
+        res
+    }
+}
+
+/// Run a single step, failing if ffmpeg does.
+///
+/// ffmpeg can't write to a file it's reading, so if the output is one of the inputs (eg. an
+/// MP3 re-encoded as MP3) the file is created in `scratch_dir` and copied over afterwards.
+pub async fn run_step(step: &dyn PostStep, scratch_dir: &Path) -> anyhow::Result<()> {
+    let output = step.output();
+    let ffmpeg_output = ffmpeg_output(step, scratch_dir);
+    let overwrites_input = ffmpeg_output != output;
+
+    let args = ffmpeg_args(step, &ffmpeg_output)?;
+    trace!(?step, ?args, "Running post step");
+
+    let cmd_status = Command::new(&Config::global().ffmpeg_path)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .status()
+        .await?;
+    trace!(status = ?cmd_status, "Post step command finished");
+
+    if !cmd_status.success() {
+        anyhow::bail!("Command executed with exit code {:?}", cmd_status.code());
+    }
+
+    if overwrites_input {
+        tokio::fs::copy(&ffmpeg_output, output).await?;
+    }
+
+    Ok(())
+}
+
+/// The file ffmpeg writes to, in `scratch_dir` if the step overwrites one of its inputs
+fn ffmpeg_output(step: &dyn PostStep, scratch_dir: &Path) -> PathBuf {
+    let output = step.output();
+
+    if step.inputs().contains(&output) {
+        scratch_dir.join(output.file_name().unwrap_or_default())
+    } else {
+        output.to_path_buf()
+    }
+}
+
+/// All of the arguments of the ffmpeg command running the step, writing to `output`
+pub fn ffmpeg_args(step: &dyn PostStep, output: &Path) -> anyhow::Result<Vec<OsString>> {
+    let mut res = vec![];
+    for input in step.inputs() {
+        res.push(OsString::from("-i"));
+        res.push(input.as_os_str().to_os_string());
+    }
+    res.extend(step.args()?);
+    res.push(output.as_os_str().to_os_string());
+
+    Ok(res)
+}
+
+/// Encode the file. The container is deduced from the extension of the output.
+#[derive(Debug)]
+pub struct Encode {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub encoding: Encoding,
+}
+impl PostStep for Encode {
+    fn inputs(&self) -> Vec<&Path> {
+        vec![&self.input]
+    }
+
+    fn output(&self) -> &Path {
+        &self.output
+    }
+
+    fn args(&self) -> anyhow::Result<Vec<OsString>> {
+        Ok(encoding_args(self.encoding))
+    }
+}
+
+/// Mix the files together at their original volume, eg. the instrument stems into the music
+#[derive(Debug)]
+pub struct Mix {
+    pub inputs: Vec<PathBuf>,
+    pub output: PathBuf,
+    pub encoding: Encoding,
+}
+impl PostStep for Mix {
+    fn inputs(&self) -> Vec<&Path> {
+        self.inputs.iter().map(PathBuf::as_path).collect()
+    }
+
+    fn output(&self) -> &Path {
+        &self.output
+    }
+
+    fn args(&self) -> anyhow::Result<Vec<OsString>> {
+        let filter = mix_filter(&vec![None; self.inputs.len()]);
+
+        Ok(filter_args(&filter, self.encoding))
+    }
+}
+
+/// Mix the vocals at `vocals_db` into the music, creating the music with quiet vocals
+#[derive(Debug)]
+pub struct GuideMix {
+    pub vocals: PathBuf,
+    pub music: PathBuf,
+    pub vocals_db: i32,
+    pub output: PathBuf,
+    pub encoding: Encoding,
+}
+impl PostStep for GuideMix {
+    fn inputs(&self) -> Vec<&Path> {
+        vec![&self.vocals, &self.music]
+    }
+
+    fn output(&self) -> &Path {
+        &self.output
+    }
+
+    fn args(&self) -> anyhow::Result<Vec<OsString>> {
+        let filter = guide_vocals_filter(self.vocals_db)?;
+
+        Ok(filter_args(&filter, self.encoding))
+    }
+}
+
+fn encoding_args(encoding: Encoding) -> Vec<OsString> {
+    encoding
+        .ffmpeg_args()
+        .into_iter()
+        .map(OsString::from)
+        .collect()
+}
+
+fn filter_args(filter: &str, encoding: Encoding) -> Vec<OsString> {
+    let mut res = vec![OsString::from("-filter_complex"), OsString::from(filter)];
+    res.extend(encoding_args(encoding));
+
+    res
+}
+
+/// Filter which mixes the inputs together, optionally changing the volume (in dB) of some of
+/// them
+fn mix_filter(volumes: &[Option<i32>]) -> String {
+    let volume_filters = volumes
+        .iter()
+        .enumerate()
+        .filter_map(|(i, volume)| volume.map(|v| format!("[{i}:a]volume={v}dB[a{i}];")))
+        .collect::<String>();
+    let mix_inputs = volumes
+        .iter()
+        .enumerate()
+        .map(|(i, volume)| match volume {
+            Some(_) => format!("[a{i}]"),
+            None => format!("[{i}:a]"),
+        })
+        .collect::<String>();
+
+    format!(
+        "{volume_filters}{mix_inputs}amix=inputs={n}:duration=longest:dropout_transition=0:\
+         normalize=0",
+        n = volumes.len()
+    )
+}
+
+/// Filter which mixes the vocals (first input) at `vocals_db` into the music (second input)
+fn guide_vocals_filter(vocals_db: i32) -> anyhow::Result<String> {
+    if !GUIDE_VOCALS_DB_RANGE.contains(&vocals_db) {
+        anyhow::bail!(
+            "guide vocals volume has to be between {} and {} dB, got {vocals_db}",
+            GUIDE_VOCALS_DB_RANGE.start(),
+            GUIDE_VOCALS_DB_RANGE.end()
+        );
+    }
+
+    Ok(mix_filter(&[Some(vocals_db), None]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::encoding::{Bitrate, OutputFormat};
+
+    const MP3: Encoding = Encoding {
+        format: OutputFormat::Mp3,
+        bitrate: Bitrate::Cbr(192),
+    };
+    const FLAC: Encoding = Encoding {
+        format: OutputFormat::Flac,
+        bitrate: Bitrate::Cbr(192),
+    };
+
+    fn args(step: &dyn PostStep) -> Vec<String> {
+        ffmpeg_args(step, step.output())
+            .expect("Valid step")
+            .into_iter()
+            .map(|x| x.to_string_lossy().to_string())
+            .collect()
+    }
+
+    /// A step whose arguments can't be built
+    #[derive(Debug)]
+    struct BrokenStep(PathBuf);
+    impl PostStep for BrokenStep {
+        fn inputs(&self) -> Vec<&Path> {
+            vec![]
+        }
+
+        fn output(&self) -> &Path {
+            &self.0
+        }
+
+        fn args(&self) -> anyhow::Result<Vec<OsString>> {
+            anyhow::bail!("broken")
+        }
+    }
+
+    #[test]
+    fn encode() {
+        let step = Encode {
+            input: "song.wav".into(),
+            output: "song.mp3".into(),
+            encoding: MP3,
+        };
+        assert_eq!(args(&step), ["-i", "song.wav", "-b:a", "192k", "song.mp3"]);
+
+        let step = Encode {
+            input: "song.wav".into(),
+            output: "song.flac".into(),
+            encoding: FLAC,
+        };
+        assert_eq!(args(&step), ["-i", "song.wav", "song.flac"]);
+    }
+
+    #[test]
+    fn overwritten_inputs() {
+        let step = Encode {
+            input: "/job/song.mp3".into(),
+            output: "/job/song.mp3".into(),
+            encoding: MP3,
+        };
+        assert_eq!(
+            ffmpeg_output(&step, Path::new("/scratch")),
+            Path::new("/scratch/song.mp3")
+        );
+
+        let step = Encode {
+            input: "/job/song.wav".into(),
+            output: "/job/song.mp3".into(),
+            encoding: MP3,
+        };
+        assert_eq!(
+            ffmpeg_output(&step, Path::new("/scratch")),
+            Path::new("/job/song.mp3")
+        );
+    }
+
+    #[test]
+    fn mix() {
+        let step = Mix {
+            inputs: vec!["drums.wav".into(), "bass.wav".into(), "other.wav".into()],
+            output: "music.mp3".into(),
+            encoding: MP3,
+        };
+
+        assert_eq!(
+            args(&step),
+            [
+                "-i",
+                "drums.wav",
+                "-i",
+                "bass.wav",
+                "-i",
+                "other.wav",
+                "-filter_complex",
+                "[0:a][1:a][2:a]amix=inputs=3:duration=longest:dropout_transition=0:normalize=0",
+                "-b:a",
+                "192k",
+                "music.mp3",
+            ]
+        );
+    }
+
+    #[test]
+    fn guide_mix() {
+        let step = GuideMix {
+            vocals: "vocals.flac".into(),
+            music: "music.flac".into(),
+            vocals_db: -12,
+            output: "music-with-quiet-vocals.flac".into(),
+            encoding: FLAC,
+        };
+
+        assert_eq!(
+            args(&step),
+            [
+                "-i",
+                "vocals.flac",
+                "-i",
+                "music.flac",
+                "-filter_complex",
+                "[0:a]volume=-12dB[a0];[a0][1:a]amix=inputs=2:duration=longest:\
+                 dropout_transition=0:normalize=0",
+                "music-with-quiet-vocals.flac",
+            ]
+        );
+    }
+
+    #[test]
+    fn guide_vocals_volumes() {
+        assert!(guide_vocals_filter(*GUIDE_VOCALS_DB_RANGE.start()).is_ok());
+        assert!(guide_vocals_filter(*GUIDE_VOCALS_DB_RANGE.end()).is_ok());
+        assert!(guide_vocals_filter(GUIDE_VOCALS_DB_RANGE.start() - 1).is_err());
+        assert!(guide_vocals_filter(GUIDE_VOCALS_DB_RANGE.end() + 1).is_err());
+    }
+
+    #[test]
+    fn mix_filters() {
+        assert_eq!(
+            mix_filter(&[None, Some(-6), Some(3)]),
+            "[1:a]volume=-6dB[a1];[2:a]volume=3dB[a2];[0:a][a1][a2]amix=inputs=3:\
+             duration=longest:dropout_transition=0:normalize=0"
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_steps_are_skipped() {
+        let mut pipeline = Pipeline::new();
+        pipeline.push(BrokenStep("first.mp3".into()));
+        pipeline.push(GuideMix {
+            vocals: "vocals.mp3".into(),
+            music: "music.mp3".into(),
+            vocals_db: 10,
+            output: "music-with-loud-vocals.mp3".into(),
+            encoding: MP3,
+        });
+        pipeline.push(BrokenStep("last.mp3".into()));
+
+        assert!(pipeline.run(Path::new("/nonexistent")).await.is_empty());
+    }
+}
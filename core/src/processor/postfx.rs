@@ -0,0 +1,315 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use tokio::{process::Command, sync::OnceCell};
+use tracing::{debug, trace};
+
+use crate::{config::Config, processor::encoding::Encoding};
+
+/// Sample rate of the stems created by demucs
+const STEM_SAMPLE_RATE: u32 = 44100;
+
+/// Whether the installed ffmpeg was built with the `rubberband` filter
+static HAS_RUBBERBAND: OnceCell<bool> = OnceCell::const_new();
+
+/// Effects applied to the stems after they're created
+pub struct PostFx;
+impl PostFx {
+    /// Transpose the file by `semitones` and/or change its tempo to `tempo_percent` of the
+    /// original in a single pass. Changing the pitch doesn't change the duration and vice versa.
+    ///
+    /// Uses the `rubberband` filter for the pitch if ffmpeg supports it since it sounds a lot
+    /// better, otherwise the pitch is changed by resampling and the tempo is corrected afterwards.
+    ///
+    /// Returns the path of the new file (in the same format), eg. `song.music.-2st.85pct.mp3`.
+    #[tracing::instrument]
+    pub async fn transform(
+        file_path: &Path,
+        semitones: Option<i8>,
+        tempo_percent: Option<u16>,
+        encoding: Encoding,
+    ) -> anyhow::Result<PathBuf> {
+        debug!("Applying effects");
+
+        // Only the pitch needs the rubberband filter
+        let rubberband = semitones.is_some() && Self::has_rubberband().await;
+        Self::transform_with(file_path, semitones, tempo_percent, encoding, rubberband).await
+    }
+
+    /// [`Self::transform`], using the `rubberband` filter if `rubberband` is set
+    async fn transform_with(
+        file_path: &Path,
+        semitones: Option<i8>,
+        tempo_percent: Option<u16>,
+        encoding: Encoding,
+        rubberband: bool,
+    ) -> anyhow::Result<PathBuf> {
+        let output_path = output_path(file_path, semitones, tempo_percent);
+        let filter = effects_filter(semitones, tempo_percent, rubberband);
+        trace!(?filter, "Using effects filter");
+
+        let cmd_status = Command::new(&Config::global().ffmpeg_path)
+            .args([OsString::from("-i"), file_path.as_os_str().to_os_string()])
+            .args(["-map", "0:a"])
+            .args(["-af", &filter])
+            .args(encoding.ffmpeg_args())
+            .arg(&output_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await?;
+        trace!(status = ?cmd_status, "Effects command finished");
+
+        if !cmd_status.success() {
+            anyhow::bail!("Command executed with exit code {:?}", cmd_status.code());
+        }
+
+        Ok(output_path)
+    }
+
+    async fn has_rubberband() -> bool {
+        *HAS_RUBBERBAND
+            .get_or_init(|| async {
+                let output = Command::new(&Config::global().ffmpeg_path)
+                    .args(["-hide_banner", "-filters"])
+                    .stdin(Stdio::null())
+                    .stderr(Stdio::null())
+                    .kill_on_drop(true)
+                    .output()
+                    .await;
+
+                let res = output.is_ok_and(|x| {
+                    String::from_utf8_lossy(&x.stdout)
+                        .split_whitespace()
+                        .any(|x| x == "rubberband")
+                });
+                debug!(available = ?res, "Checked for the rubberband filter");
+
+                res
+            })
+            .await
+    }
+}
+
+/// Path of the transformed file, eg. `song.music.-2st.85pct.mp3` for `song.music.mp3`
+fn output_path(file_path: &Path, semitones: Option<i8>, tempo_percent: Option<u16>) -> PathBuf {
+    file_path.with_file_name({
+        let mut f = file_path.file_stem().unwrap_or_default().to_os_string();
+        if let Some(semitones) = semitones {
+            f.push(format!(".{semitones:+}st"));
+        }
+        if let Some(tempo_percent) = tempo_percent {
+            f.push(format!(".{tempo_percent}pct"));
+        }
+        f.push(".");
+        f.push(file_path.extension().unwrap_or_default());
+        f
+    })
+}
+
+/// The ffmpeg audio filter that applies the effects, using the `rubberband` filter for the pitch
+/// if `rubberband` is set
+fn effects_filter(semitones: Option<i8>, tempo_percent: Option<u16>, rubberband: bool) -> String {
+    let pitch = semitones.map_or(1.0, |x| (f64::from(x) / 12.0).exp2());
+    let tempo = tempo_percent.map_or(1.0, |x| f64::from(x) / 100.0);
+
+    if semitones.is_none() {
+        atempo_chain(tempo)
+    } else if rubberband {
+        format!("rubberband=pitch={pitch:.6}:tempo={tempo:.6}")
+    } else {
+        format!(
+            "asetrate={rate:.0},aresample={STEM_SAMPLE_RATE},{tempo}",
+            rate = f64::from(STEM_SAMPLE_RATE) * pitch,
+            tempo = atempo_chain(tempo / pitch),
+        )
+    }
+}
+
+/// Chain of `atempo` filters that changes the tempo by `factor`.
+///
+/// A single filter only supports factors between 0.5 and 2.0 in older ffmpeg versions, so
+/// anything outside of that is split into multiple steps.
+#[allow(clippy::while_float)]
+fn atempo_chain(mut factor: f64) -> String {
+    let mut steps = vec![];
+
+    while factor > 2.0 {
+        steps.push(2.0);
+        factor /= 2.0;
+    }
+    while factor < 0.5 {
+        steps.push(0.5);
+        factor /= 0.5;
+    }
+    steps.push(factor);
+
+    steps
+        .iter()
+        .map(|x| format!("atempo={x:.6}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Human readable description of an effect from a transformed file's suffix, eg. `-2 semitones`
+/// for `-2st` or `85% speed` for `85pct`
+pub fn effect_label(suffix: &str) -> Option<String> {
+    if let Some(semitones) = suffix.strip_suffix("st").and_then(|x| x.parse::<i8>().ok()) {
+        return Some(format!("{semitones:+} semitones"));
+    }
+
+    suffix
+        .strip_suffix("pct")
+        .and_then(|x| x.parse::<u16>().ok())
+        .map(|x| format!("{x}% speed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        helpers::{temp_dir::TempDir, test_audio},
+        processor::{
+            encoding::{Bitrate, OutputFormat},
+            ffmpeg::FfmpegProcessor,
+        },
+    };
+
+    const WAV: Encoding = Encoding {
+        format: OutputFormat::Wav,
+        bitrate: Bitrate::Cbr(256),
+    };
+
+    /// The factors of the `atempo` filters in the chain
+    fn atempo_factors(chain: &str) -> Vec<f64> {
+        chain
+            .split(',')
+            .map(|x| {
+                x.strip_prefix("atempo=")
+                    .and_then(|x| x.parse().ok())
+                    .expect("atempo filter")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn output_paths() {
+        let path = Path::new("/tmp/job/Song.music.mp3");
+
+        assert_eq!(
+            output_path(path, Some(-2), None),
+            Path::new("/tmp/job/Song.music.-2st.mp3")
+        );
+        assert_eq!(
+            output_path(path, Some(3), Some(85)),
+            Path::new("/tmp/job/Song.music.+3st.85pct.mp3")
+        );
+        assert_eq!(
+            output_path(Path::new("/tmp/job/Song.music.flac"), None, Some(120)),
+            Path::new("/tmp/job/Song.music.120pct.flac")
+        );
+    }
+
+    #[test]
+    fn atempo_chains_stay_in_range() {
+        for factor in [0.25, 0.5, 0.85, 1.0, 1.5, 2.0, 3.0, 5.0] {
+            let factors = atempo_factors(&atempo_chain(factor));
+
+            assert!(
+                factors.iter().all(|x| (0.5..=2.0).contains(x)),
+                "{factor}: {factors:?}"
+            );
+            let product = factors.iter().product::<f64>();
+            assert!((product - factor).abs() < 1e-5, "{factor}: {factors:?}");
+        }
+
+        assert_eq!(atempo_chain(1.0), "atempo=1.000000");
+        assert_eq!(atempo_chain(3.0), "atempo=2.000000,atempo=1.500000");
+    }
+
+    #[test]
+    fn filters() {
+        // The tempo alone doesn't need rubberband
+        assert_eq!(effects_filter(None, Some(85), true), "atempo=0.850000");
+        assert_eq!(
+            effects_filter(Some(12), Some(50), true),
+            "rubberband=pitch=2.000000:tempo=0.500000"
+        );
+
+        // Resampling raises the pitch and speeds the song up, which the tempo makes up for
+        let filter = effects_filter(Some(12), None, false);
+        let (resample, tempo) = filter
+            .split_once(",aresample=44100,")
+            .expect("Resampling filter");
+        assert_eq!(resample, "asetrate=88200");
+        assert_eq!(atempo_factors(tempo), [0.5]);
+
+        let filter = effects_filter(Some(-2), Some(90), false);
+        let (resample, tempo) = filter
+            .split_once(",aresample=44100,")
+            .expect("Resampling filter");
+        let pitch = (-2.0_f64 / 12.0).exp2();
+        assert_eq!(resample, format!("asetrate={:.0}", 44100.0 * pitch));
+        let product = atempo_factors(tempo).iter().product::<f64>();
+        assert!((product - 0.9 / pitch).abs() < 1e-5, "{filter}");
+    }
+
+    /// Transpose a sine and make sure it's as long as before
+    async fn check_transposed_duration(rubberband: bool) {
+        let dir = TempDir::with_prefix("karaokify-test-postfx-")
+            .await
+            .expect("Directory created");
+        let path = dir.path().join("sine.wav");
+        test_audio::generate(&path, "sine=frequency=440:sample_rate=44100:duration=5").await;
+        let duration = FfmpegProcessor::duration(&path).await.expect("Duration");
+
+        for semitones in [-3, 5] {
+            let output = PostFx::transform_with(&path, Some(semitones), None, WAV, rubberband)
+                .await
+                .expect("Transposed");
+
+            assert_eq!(output, output_path(&path, Some(semitones), None));
+            let transposed = FfmpegProcessor::duration(&output).await.expect("Duration");
+            assert!(
+                (transposed.as_secs_f64() - duration.as_secs_f64()).abs() < 0.1,
+                "{semitones}: {transposed:?} instead of {duration:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn resampling_keeps_the_duration() {
+        if !test_audio::has_ffmpeg().await {
+            return;
+        }
+
+        check_transposed_duration(false).await;
+    }
+
+    #[tokio::test]
+    async fn rubberband_keeps_the_duration() {
+        if !test_audio::has_ffmpeg().await {
+            return;
+        }
+        if !PostFx::has_rubberband().await {
+            eprintln!("Skipping the test, ffmpeg doesn't have the rubberband filter");
+            return;
+        }
+
+        check_transposed_duration(true).await;
+    }
+
+    #[test]
+    fn effect_labels() {
+        assert_eq!(effect_label("-2st").as_deref(), Some("-2 semitones"));
+        assert_eq!(effect_label("+3st").as_deref(), Some("+3 semitones"));
+        assert_eq!(effect_label("85pct").as_deref(), Some("85% speed"));
+        assert_eq!(effect_label("music"), None);
+        assert_eq!(effect_label("st"), None);
+        assert_eq!(effect_label("-10dB"), None);
+    }
+}
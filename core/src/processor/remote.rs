@@ -0,0 +1,359 @@
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use anyhow::Context;
+use once_cell::sync::OnceCell;
+use reqwest::{multipart, Response};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, sync::watch, time::MissedTickBehavior};
+use tracing::{debug, info, trace};
+use url::Url;
+
+use super::{
+    demucs::{
+        DemucsError, DemucsModel, DemucsProcessor, DemucsProgress, StemMode,
+        REDUCED_MEMORY_SEGMENTS,
+    },
+    encoding::{Bitrate, Encoding, OutputFormat},
+    separator::StemSeparator,
+};
+use crate::{
+    config::Config,
+    helpers::http::{client_with_timeout, CLIENT},
+};
+
+/// Header with the shared secret (`KARAOKIFY_WORKER_SECRET`) the worker checks
+pub const SECRET_HEADER: &str = "x-karaokify-secret";
+
+/// How often the bot asks the worker how the job is doing
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// The job is given up on if this many status requests in a row fail
+const MAX_POLL_FAILURES: u32 = 5;
+/// Timeout of uploading the song and downloading each of the stems
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// The device the worker runs demucs on, as reported at startup
+static WORKER_DEVICE: OnceCell<String> = OnceCell::new();
+
+/// What the worker should do with the song, sent as the text fields of the multipart form
+/// along with the `file`
+#[derive(Debug, Clone, Copy)]
+pub struct JobParams {
+    pub demucs_model: DemucsModel,
+    pub stem_mode: StemMode,
+    pub encoding: Encoding,
+}
+impl JobParams {
+    fn fields(self) -> [(&'static str, String); 4] {
+        [
+            ("model", self.demucs_model.to_string()),
+            ("stems", self.stem_mode.to_string()),
+            ("format", self.encoding.format.to_string()),
+            ("bitrate", self.encoding.bitrate.to_string()),
+        ]
+    }
+
+    pub fn from_fields(fields: &HashMap<String, String>) -> anyhow::Result<Self> {
+        let field = |name: &str| {
+            fields
+                .get(name)
+                .map(String::as_str)
+                .with_context(|| format!("missing field {name:?}"))
+        };
+
+        Ok(Self {
+            demucs_model: field("model")?.parse()?,
+            stem_mode: field("stems")?.parse()?,
+            encoding: Encoding {
+                format: field("format")?.parse::<OutputFormat>()?,
+                bitrate: field("bitrate")?.parse::<Bitrate>()?,
+            },
+        })
+    }
+}
+
+/// Response of the worker to a new job
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreatedJob {
+    pub id: String,
+}
+
+/// How a job on the worker is doing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum WorkerJobStatus {
+    /// Waiting for a free processing slot on the worker
+    Queued,
+    Running {
+        percent: u8,
+        reduced_memory: bool,
+    },
+    /// The file names of the stems, which can be downloaded from the worker
+    Done {
+        stems: Vec<String>,
+    },
+    Failed {
+        error: String,
+        /// Set if demucs itself failed, so the bot can explain the failure like for local runs
+        demucs: Option<DemucsError>,
+    },
+}
+
+/// Response of the worker's `/health` endpoint
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WorkerHealth {
+    /// The device the worker runs demucs on, eg. `cuda`
+    pub device: String,
+}
+
+/// Sends the songs to a worker (`src/bin/worker.rs`) on another machine which runs demucs
+#[derive(Debug)]
+pub struct RemoteSeparator {
+    url: Url,
+    secret: String,
+}
+impl RemoteSeparator {
+    pub fn new(url: Url) -> Self {
+        let secret = Config::global()
+            .worker
+            .secret
+            .clone()
+            .expect("KARAOKIFY_WORKER_SECRET is not set");
+
+        Self { url, secret }
+    }
+
+    /// Make sure the worker is reachable and accepts the secret
+    pub async fn check(&self) -> anyhow::Result<()> {
+        let health = CLIENT
+            .get(self.endpoint("health"))
+            .header(SECRET_HEADER, &self.secret)
+            .send()
+            .await
+            .and_then(Response::error_for_status)
+            .with_context(|| format!("could not reach the worker at {}", self.url))?
+            .json::<WorkerHealth>()
+            .await?;
+        info!(url = %self.url, device = health.device, "Found worker");
+
+        let _ = WORKER_DEVICE.set(health.device);
+
+        Ok(())
+    }
+
+    /// The device the worker runs demucs on, if it was checked
+    pub fn device() -> Option<&'static str> {
+        WORKER_DEVICE.get().map(String::as_str)
+    }
+
+    fn endpoint(&self, path: &str) -> Url {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .expect("Worker URL can't be a base")
+            .pop_if_empty()
+            .extend(path.split('/'));
+
+        url
+    }
+
+    async fn create_job(&self, file_path: &Path, params: JobParams) -> anyhow::Result<String> {
+        let file_name = file_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let file = multipart::Part::bytes(tokio::fs::read(file_path).await?).file_name(file_name);
+
+        let mut form = multipart::Form::new().part("file", file);
+        for (name, value) in params.fields() {
+            form = form.text(name, value);
+        }
+
+        let job = client_with_timeout(TRANSFER_TIMEOUT)
+            .post(self.endpoint("jobs"))
+            .header(SECRET_HEADER, &self.secret)
+            .multipart(form)
+            .send()
+            .await
+            .and_then(Response::error_for_status)
+            .context("could not send the song to the worker")?
+            .json::<CreatedJob>()
+            .await?;
+
+        Ok(job.id)
+    }
+
+    /// Poll the job until it's done, forwarding its progress. Returns the file names of the
+    /// stems.
+    async fn wait(
+        &self,
+        job: &RemoteJob,
+        progress: Option<&watch::Sender<DemucsProgress>>,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut failures = 0;
+        loop {
+            interval.tick().await;
+
+            let res = CLIENT
+                .get(job.url.clone())
+                .header(SECRET_HEADER, &self.secret)
+                .send()
+                .await
+                .and_then(Response::error_for_status);
+            let status = match res {
+                Ok(x) => x.json::<WorkerJobStatus>().await?,
+                Err(e) if failures < MAX_POLL_FAILURES => {
+                    failures += 1;
+                    debug!(?e, failures, "Failed to get the job status from the worker");
+                    continue;
+                }
+                Err(e) => return Err(e).context("lost contact with the worker"),
+            };
+            failures = 0;
+            trace!(?status, "Got job status from the worker");
+
+            match status {
+                WorkerJobStatus::Queued => {}
+                WorkerJobStatus::Running {
+                    percent,
+                    reduced_memory,
+                } => {
+                    if let Some(progress) = progress {
+                        let new = DemucsProgress {
+                            percent,
+                            reduced_memory,
+                        };
+                        progress.send_if_modified(|x| std::mem::replace(x, new) != new);
+                    }
+                }
+                WorkerJobStatus::Done { stems } => return Ok(stems),
+                WorkerJobStatus::Failed {
+                    demucs: Some(e), ..
+                } => return Err(e.into()),
+                WorkerJobStatus::Failed { error, .. } => anyhow::bail!(error),
+            }
+        }
+    }
+
+    async fn download_stem(&self, job: &RemoteJob, stem: &str, dir: &Path) -> anyhow::Result<()> {
+        let mut url = job.url.clone();
+        url.path_segments_mut()
+            .expect("Worker URL can't be a base")
+            .extend(["stems", stem]);
+
+        let mut res = client_with_timeout(TRANSFER_TIMEOUT)
+            .get(url)
+            .header(SECRET_HEADER, &self.secret)
+            .send()
+            .await
+            .and_then(Response::error_for_status)
+            .with_context(|| format!("could not download the {stem} stem from the worker"))?;
+
+        let mut file = tokio::fs::File::create(dir.join(stem)).await?;
+        while let Some(chunk) = res.chunk().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+
+        Ok(())
+    }
+}
+#[async_trait::async_trait]
+impl StemSeparator for RemoteSeparator {
+    /// The worker retries and times out demucs like the local separator, so the job is only
+    /// given up on once it couldn't have finished even with all the retries
+    async fn separate(
+        &self,
+        file_path: &Path,
+        demucs_model: DemucsModel,
+        stem_mode: StemMode,
+        encoding: Encoding,
+        out_dir: &Path,
+        progress: Option<&watch::Sender<DemucsProgress>>,
+    ) -> anyhow::Result<()> {
+        let params = JobParams {
+            demucs_model,
+            stem_mode,
+            encoding,
+        };
+        #[allow(clippy::cast_possible_truncation)]
+        let timeout =
+            DemucsProcessor::timeout(file_path).await * (REDUCED_MEMORY_SEGMENTS.len() as u32 + 1);
+
+        let id = tryhard::retry_fn(|| self.create_job(file_path, params))
+            .retries(3)
+            .await?;
+        info!(id, url = %self.url, "Sent song to the worker");
+
+        let job = RemoteJob {
+            url: self.endpoint(&format!("jobs/{id}")),
+            secret: self.secret.clone(),
+            finished: false,
+        };
+
+        let stems = tokio::time::timeout(timeout, self.wait(&job, progress))
+            .await
+            .map_err(|_| anyhow::anyhow!("The worker didn't finish in {timeout:?}"))??;
+
+        let stems_dir = out_dir.join(demucs_model.to_string());
+        tokio::fs::create_dir_all(&stems_dir).await?;
+        for stem in &stems {
+            // The names are used as paths, so they must not point outside the directory
+            if Path::new(stem)
+                .file_name()
+                .map_or(true, |x| x != stem.as_str())
+            {
+                anyhow::bail!("The worker returned an invalid stem name {stem:?}");
+            }
+
+            tryhard::retry_fn(|| self.download_stem(&job, stem, &stems_dir))
+                .retries(3)
+                .await?;
+        }
+        trace!(?stems, "Downloaded stems from the worker");
+
+        job.delete().await;
+
+        Ok(())
+    }
+}
+
+/// A job on the worker, deleted (and cancelled if it's still running) when dropped
+struct RemoteJob {
+    url: Url,
+    secret: String,
+    finished: bool,
+}
+impl RemoteJob {
+    /// Remove the job and its files from the worker
+    async fn delete(mut self) {
+        self.finished = true;
+        delete_job(self.url.clone(), self.secret.clone()).await;
+    }
+}
+impl Drop for RemoteJob {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(delete_job(self.url.clone(), self.secret.clone()));
+        }
+    }
+}
+
+async fn delete_job(url: Url, secret: String) {
+    let res = CLIENT
+        .delete(url)
+        .header(SECRET_HEADER, secret)
+        .send()
+        .await
+        .and_then(Response::error_for_status);
+
+    if let Err(e) = res {
+        debug!(?e, "Failed to delete the job on the worker");
+    }
+}
@@ -0,0 +1,41 @@
+use std::path::Path;
+
+use once_cell::sync::Lazy;
+use tokio::sync::watch;
+
+use super::{
+    demucs::{DemucsModel, DemucsProgress, LocalSeparator, StemMode},
+    encoding::Encoding,
+    remote::RemoteSeparator,
+};
+use crate::config::Config;
+
+/// The separator picked by the config, the local one unless `KARAOKIFY_WORKER_URL` is set
+static SEPARATOR: Lazy<Box<dyn StemSeparator>> = Lazy::new(|| match &Config::global().worker.url {
+    Some(url) => Box::new(RemoteSeparator::new(url.clone())),
+    None => Box::new(LocalSeparator),
+});
+
+pub fn global() -> &'static dyn StemSeparator {
+    SEPARATOR.as_ref()
+}
+
+/// Splits songs into stems using demucs
+#[async_trait::async_trait]
+pub trait StemSeparator: std::fmt::Debug + Send + Sync {
+    /// Split the song at `file_path` into the stems of the `stem_mode`.
+    ///
+    /// The stems are put where demucs puts them, into `out_dir/{model}/{stem}.{ext}` (eg.
+    /// `htdemucs/vocals.mp3`), using the extension of [`Encoding::demucs_extension`].
+    ///
+    /// Progress of the separation is reported through `progress`.
+    async fn separate(
+        &self,
+        file_path: &Path,
+        demucs_model: DemucsModel,
+        stem_mode: StemMode,
+        encoding: Encoding,
+        out_dir: &Path,
+        progress: Option<&watch::Sender<DemucsProgress>>,
+    ) -> anyhow::Result<()>;
+}
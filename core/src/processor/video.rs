@@ -0,0 +1,109 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use tokio::process::Command;
+use tracing::{debug, trace};
+
+use super::lrc::{self, VIDEO_HEIGHT, VIDEO_WIDTH};
+use crate::config::Config;
+
+/// Bitrate of the audio of the video (in kbps)
+const AUDIO_BITRATE: u64 = 128;
+/// The lyrics on a plain background don't need more than this (in kbps)
+const MAX_VIDEO_BITRATE: u64 = 1000;
+/// Below this the text gets too blocky to read (in kbps)
+const MIN_VIDEO_BITRATE: u64 = 50;
+/// Part of the size limit that's used, since the bitrate is only an average
+const SIZE_LIMIT_MARGIN: f64 = 0.9;
+const BACKGROUND_COLOR: &str = "0x101018";
+const FRAME_RATE: u32 = 25;
+
+/// Renders karaoke videos with the lyrics over a plain background
+pub struct KaraokeVideo;
+impl KaraokeVideo {
+    /// Render a video of the `music` with the synced lyrics (in the LRC format) scrolling over
+    /// it, shown `lyrics_offset` earlier than in the LRC (eg. because the song was trimmed).
+    ///
+    /// The video is cut off after the configured maximum duration and its bitrate is chosen so
+    /// it fits into `max_size` bytes.
+    ///
+    /// Returns the path of the video, which is put next to the `music`.
+    #[tracing::instrument(skip(lrc))]
+    pub async fn render(
+        music: &Path,
+        lrc: &str,
+        title: &str,
+        lyrics_offset: Duration,
+        duration: Duration,
+        max_size: u64,
+    ) -> anyhow::Result<PathBuf> {
+        debug!("Rendering karaoke video");
+
+        let duration = duration.min(Config::global().max_video_duration);
+        let video_bitrate = video_bitrate(duration, max_size)
+            .ok_or_else(|| anyhow::anyhow!("the song is too long for the size limit"))?;
+
+        let dir = music.parent().unwrap_or_else(|| Path::new("."));
+        let lines = lrc::parse(lrc);
+        if lines.iter().all(|x| x.text.is_empty()) {
+            anyhow::bail!("the synced lyrics are empty");
+        }
+        let ass = lrc::to_ass(&lines, title, lyrics_offset, Some(duration));
+        // The subtitles filter is run in the directory, so the path doesn't have to be escaped
+        tokio::fs::write(dir.join("lyrics.ass"), ass).await?;
+
+        let output_path = music.with_extension("karaoke.mp4");
+        let cmd_status = Command::new(&Config::global().ffmpeg_path)
+            .current_dir(dir)
+            .args(["-f", "lavfi"])
+            .arg("-i")
+            .arg(format!(
+                "color=c={BACKGROUND_COLOR}:s={VIDEO_WIDTH}x{VIDEO_HEIGHT}:r={FRAME_RATE}"
+            ))
+            .arg("-i")
+            .arg(music)
+            .args(["-filter_complex", "[0:v]subtitles=filename=lyrics.ass[v]"])
+            .args(["-map", "[v]", "-map", "1:a:0"])
+            .args(["-t", &duration.as_secs_f64().to_string()])
+            .args([
+                "-c:v", "libx264", "-preset", "veryfast", "-pix_fmt", "yuv420p",
+            ])
+            .args(["-b:v", &format!("{video_bitrate}k")])
+            .args(["-maxrate", &format!("{video_bitrate}k")])
+            .args(["-bufsize", &format!("{}k", video_bitrate * 2)])
+            .args(["-c:a", "aac", "-b:a", &format!("{AUDIO_BITRATE}k")])
+            .args(["-movflags", "+faststart", "-shortest"])
+            .arg(&output_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await?;
+        trace!(status = ?cmd_status, "Video command finished");
+
+        if !cmd_status.success() {
+            anyhow::bail!("Command executed with exit code {:?}", cmd_status.code());
+        }
+
+        Ok(output_path)
+    }
+}
+
+/// Bitrate of the video (in kbps) so that a video that's `duration` long fits into `max_size`
+/// bytes. `None` if it can't with a readable quality.
+fn video_bitrate(duration: Duration, max_size: u64) -> Option<u64> {
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    let total =
+        (max_size as f64 * 8.0 * SIZE_LIMIT_MARGIN / 1000.0 / duration.as_secs_f64()) as u64;
+
+    let res = total.checked_sub(AUDIO_BITRATE)?.min(MAX_VIDEO_BITRATE);
+
+    (res >= MIN_VIDEO_BITRATE).then_some(res)
+}
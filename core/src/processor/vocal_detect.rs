@@ -0,0 +1,303 @@
+use std::{ffi::OsString, path::Path, process::Stdio, time::Duration};
+
+use tokio::process::Command;
+use tracing::{debug, trace};
+
+use super::ffmpeg::FfmpegProcessor;
+use crate::config::Config;
+
+/// Length of each of the parts of the song that are checked
+const WINDOW_LENGTH: Duration = Duration::from_secs(10);
+/// Where the checked parts are, as fractions of the duration of the song
+const WINDOW_POSITIONS: [f64; 3] = [0.25, 0.5, 0.75];
+/// Vocals are mixed into the center, so with them the voice band of the center is at least
+/// this much louder (in dB) than the voice band of the sides
+const MIN_VOCALS_CENTER_EXCESS_DB: f64 = 6.0;
+
+/// Splits the song into the center (`L+R`) and the sides (`L-R`), keeping only the frequencies
+/// of the voice. Mono songs are upmixed first, so their sides are silent.
+const VOICE_BAND_FILTER: &str = "aformat=channel_layouts=stereo,\
+                                 pan=stereo|c0=0.5*c0+0.5*c1|c1=0.5*c0-0.5*c1,\
+                                 highpass=f=300,lowpass=f=3000,astats";
+
+/// Cheap check for songs without vocals, so demucs isn't run on them for nothing
+pub struct VocalDetector;
+impl VocalDetector {
+    /// Whether the song looks like it doesn't have any vocals (eg. an instrumental or a classical
+    /// recording).
+    ///
+    /// A few parts of the song are checked for vocals by comparing the loudness of the voice
+    /// band in the center and on the sides. Songs where it can't be told (eg. mono or silent
+    /// ones) are assumed to have vocals.
+    #[tracing::instrument]
+    pub async fn looks_instrumental(
+        file_path: &Path,
+        duration: Option<Duration>,
+    ) -> anyhow::Result<bool> {
+        let duration = match duration {
+            Some(x) => Some(x),
+            None => FfmpegProcessor::duration(file_path).await.ok(),
+        };
+
+        let mut levels = vec![];
+        for start in window_starts(duration) {
+            levels.push(Self::voice_band_levels(file_path, start).await?);
+        }
+        let res = looks_instrumental(&levels);
+        debug!(?levels, instrumental = res, "Checked song for vocals");
+
+        Ok(res)
+    }
+
+    /// Loudness of the voice band in the center and on the sides of the part starting at `start`
+    async fn voice_band_levels(file_path: &Path, start: Duration) -> anyhow::Result<ChannelLevels> {
+        let output = Command::new(&Config::global().ffmpeg_path)
+            .arg("-hide_banner")
+            .args(["-ss", &start.as_secs_f64().to_string()])
+            .args(["-t", &WINDOW_LENGTH.as_secs_f64().to_string()])
+            .args([OsString::from("-i"), file_path.as_os_str().to_os_string()])
+            .args(["-map", "0:a:0"])
+            .args(["-af", VOICE_BAND_FILTER])
+            .args(["-f", "null", "-"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+        trace!(status = ?output.status, "Voice band command finished");
+
+        if !output.status.success() {
+            anyhow::bail!("Command executed with exit code {:?}", output.status.code());
+        }
+
+        parse_levels(&String::from_utf8_lossy(&output.stderr))
+            .ok_or_else(|| anyhow::anyhow!("ffmpeg didn't print the levels"))
+    }
+}
+
+/// RMS levels (in dB) of the voice band of a part of the song
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ChannelLevels {
+    center: f64,
+    side: f64,
+}
+impl ChannelLevels {
+    /// How much louder the center is than the sides, `None` if the part is silent
+    fn center_excess(self) -> Option<f64> {
+        self.center.is_finite().then_some(self.center - self.side)
+    }
+}
+
+/// Where the checked parts of the song start. The whole song is checked if it's short or its
+/// duration isn't known.
+fn window_starts(duration: Option<Duration>) -> Vec<Duration> {
+    let Some((duration, last_start)) =
+        duration.and_then(|x| Some((x, x.checked_sub(WINDOW_LENGTH)?)))
+    else {
+        return vec![Duration::ZERO];
+    };
+
+    WINDOW_POSITIONS
+        .iter()
+        .map(|x| {
+            duration
+                .mul_f64(*x)
+                .saturating_sub(WINDOW_LENGTH / 2)
+                .min(last_start)
+        })
+        .collect()
+}
+
+/// The per-channel RMS levels from the `astats` output
+fn parse_levels(stderr: &str) -> Option<ChannelLevels> {
+    let mut levels = [None, None];
+    let mut channel = None;
+
+    for line in stderr.lines() {
+        // Lines are prefixed with the filter name, eg. `[Parsed_astats_4 @ 0x1234] Channel: 1`
+        let line = line.split_once("] ").map_or(line, |(_, x)| x).trim();
+
+        if let Some(x) = line.strip_prefix("Channel:") {
+            channel = x.trim().parse::<usize>().ok();
+        } else if line == "Overall" {
+            channel = None;
+        } else if let Some(x) = line.strip_prefix("RMS level dB:") {
+            let level = levels.get_mut(channel.unwrap_or_default().wrapping_sub(1));
+            if let (Some(level), Ok(x)) = (level, x.trim().parse::<f64>()) {
+                *level = Some(x);
+            }
+        }
+    }
+
+    Some(ChannelLevels {
+        center: levels[0]?,
+        side: levels[1]?,
+    })
+}
+
+/// A song looks instrumental if none of the checked parts has its voice band in the center
+fn looks_instrumental(levels: &[ChannelLevels]) -> bool {
+    let excesses = levels
+        .iter()
+        .filter_map(|x| x.center_excess())
+        .collect::<Vec<_>>();
+
+    !excesses.is_empty() && excesses.iter().all(|x| *x < MIN_VOCALS_CENTER_EXCESS_DB)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::{temp_dir::TempDir, test_audio};
+
+    /// Length of the generated songs
+    const SONG_LENGTH: Duration = Duration::from_secs(30);
+
+    fn fixture_levels(name: &str) -> Option<ChannelLevels> {
+        let stderr = std::fs::read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/vocal_detect")
+                .join(name),
+        )
+        .expect("Fixture exists");
+
+        parse_levels(&stderr)
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!(
+            fixture_levels("vocals.log"),
+            Some(ChannelLevels {
+                center: -17.832_104,
+                side: -31.209_877,
+            })
+        );
+        assert_eq!(
+            fixture_levels("instrumental.log"),
+            Some(ChannelLevels {
+                center: -21.402_331,
+                side: -23.118_754,
+            })
+        );
+
+        let mono = fixture_levels("mono.log").expect("Levels");
+        assert!(mono.side.is_infinite());
+        let silent = fixture_levels("silent.log").expect("Levels");
+        assert_eq!(silent.center_excess(), None);
+    }
+
+    #[test]
+    fn missing_levels() {
+        assert_eq!(parse_levels(""), None);
+        assert_eq!(
+            parse_levels("Channel: 1\nRMS level dB: -20.0\nOverall\nRMS level dB: -19.0"),
+            None
+        );
+        assert_eq!(
+            parse_levels("Channel: 1\nRMS level dB: loud\nChannel: 2\nRMS level dB: -20.0"),
+            None
+        );
+    }
+
+    #[test]
+    fn songs_with_vocals() {
+        let vocals = fixture_levels("vocals.log").expect("Levels");
+        let instrumental = fixture_levels("instrumental.log").expect("Levels");
+
+        assert!(looks_instrumental(&[
+            instrumental,
+            instrumental,
+            instrumental
+        ]));
+        // A single part with vocals is enough
+        assert!(!looks_instrumental(&[instrumental, vocals, instrumental]));
+        assert!(!looks_instrumental(&[vocals]));
+    }
+
+    #[test]
+    fn songs_that_cant_be_told() {
+        let silent = fixture_levels("silent.log").expect("Levels");
+        let mono = fixture_levels("mono.log").expect("Levels");
+        let instrumental = fixture_levels("instrumental.log").expect("Levels");
+
+        assert!(!looks_instrumental(&[]));
+        assert!(!looks_instrumental(&[silent, silent]));
+        assert!(!looks_instrumental(&[mono]));
+        // Silent parts (eg. the intro) are ignored
+        assert!(looks_instrumental(&[silent, instrumental]));
+    }
+
+    /// Generate a song from the expressions of its channels and check it for vocals
+    async fn generated_looks_instrumental(name: &str, channels: &str) -> bool {
+        let dir = TempDir::with_prefix("karaokify-test-vocal-detect-")
+            .await
+            .expect("Directory created");
+        let path = dir.path().join(name);
+        test_audio::generate(
+            &path,
+            &format!(
+                "aevalsrc=exprs={channels}:sample_rate=44100:duration={}",
+                SONG_LENGTH.as_secs()
+            ),
+        )
+        .await;
+
+        let res = VocalDetector::looks_instrumental(&path, Some(SONG_LENGTH))
+            .await
+            .expect("Checked");
+        // The duration is probed if it isn't known
+        assert_eq!(
+            VocalDetector::looks_instrumental(&path, None)
+                .await
+                .expect("Checked"),
+            res
+        );
+
+        res
+    }
+
+    #[tokio::test]
+    async fn generated_songs() {
+        if !test_audio::has_ffmpeg().await {
+            return;
+        }
+
+        // A different sine on each side, so the sides are as loud as the center
+        let instrumental = "0.1*sin(2*PI*440*t)|0.1*sin(2*PI*554*t)";
+        assert!(generated_looks_instrumental("sines.wav", instrumental).await);
+
+        // A louder tone in the middle of the voice band, mixed into the center like vocals
+        let vocals = "0.1*sin(2*PI*440*t)+0.5*sin(2*PI*1000*t)|\
+                      0.1*sin(2*PI*554*t)+0.5*sin(2*PI*1000*t)";
+        assert!(!generated_looks_instrumental("vocals.wav", vocals).await);
+
+        // Mono songs can't be told apart
+        assert!(!generated_looks_instrumental("mono.wav", "0.1*sin(2*PI*440*t)").await);
+    }
+
+    #[test]
+    fn windows() {
+        let secs = |x: &[u64]| {
+            x.iter()
+                .map(|x| Duration::from_secs(*x))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            window_starts(Some(Duration::from_secs(200))),
+            secs(&[45, 95, 145])
+        );
+        // The windows end with the song
+        assert_eq!(
+            window_starts(Some(Duration::from_secs(20))),
+            secs(&[0, 5, 10])
+        );
+        assert_eq!(
+            window_starts(Some(Duration::from_secs(10))),
+            secs(&[0, 0, 0])
+        );
+        assert_eq!(window_starts(Some(Duration::from_secs(5))), secs(&[0]));
+        assert_eq!(window_starts(None), secs(&[0]));
+    }
+}
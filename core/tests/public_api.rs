@@ -0,0 +1,87 @@
+//! The crate used the way other services embed it, only through its public API
+
+use std::{path::Path, time::Duration};
+
+use karaokify_core::{
+    helpers::{
+        file_name::sanitize_file_name, header::content_disposition::ContentDisposition,
+        temp_dir::TempDir,
+    },
+    processor::{self, demucs::DemucsModel, lrc},
+};
+use reqwest::header::HeaderValue;
+
+#[test]
+fn content_disposition_prefers_the_extended_file_name() {
+    let header = HeaderValue::from_static(
+        "attachment; filename=\"fallback.mp3\"; filename*=UTF-8''%C5%A1um.mp3",
+    );
+
+    let disposition = ContentDisposition::from_raw(&header).expect("Valid header");
+
+    assert_eq!(disposition.get_filename(), Some("fallback.mp3"));
+    assert_eq!(disposition.get_file_name().as_deref(), Some("šum.mp3"));
+}
+
+#[test]
+fn file_names_are_sanitized() {
+    assert_eq!(sanitize_file_name("../../etc/passwd"), "_.._etc_passwd");
+    assert_eq!(sanitize_file_name("AC/DC - T.N.T.mp3"), "AC_DC - T.N.T.mp3");
+    assert_eq!(sanitize_file_name("...mp3"), "song.mp3");
+}
+
+#[test]
+fn stems_are_recognized_by_their_file_names() {
+    let instrumental = Path::new("song.music.mp3");
+    assert_eq!(processor::stem_name(instrumental).as_deref(), Some("music"));
+    assert_eq!(
+        processor::stem_label(instrumental).as_deref(),
+        Some("instrumental")
+    );
+
+    assert_eq!(processor::stem_name(Path::new("song.mp3")), None);
+}
+
+#[test]
+fn demucs_models_are_named_like_the_cli_expects() {
+    assert_eq!(DemucsModel::default().to_string(), "htdemucs");
+    assert_eq!(DemucsModel::HTDemucsFt.to_string(), "htdemucs_ft");
+}
+
+#[test]
+fn lrc_lyrics_are_parsed_in_order() {
+    let lines = lrc::parse("[00:05.00]Second\n[00:01.50]First\n[ar:Someone]");
+
+    assert_eq!(
+        lines,
+        vec![
+            lrc::LrcLine {
+                start: Duration::from_millis(1500),
+                text: "First".to_string(),
+            },
+            lrc::LrcLine {
+                start: Duration::from_secs(5),
+                text: "Second".to_string(),
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn temp_dirs_are_removed_when_dropped() {
+    let dir = TempDir::with_prefix("karaokify-core-test-")
+        .await
+        .expect("Temp dir created");
+    let path = dir.path().to_path_buf();
+    assert!(path.is_dir());
+
+    drop(dir);
+    // The files are removed in the background
+    for _ in 0..50 {
+        if !path.exists() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("Temp dir {path:?} wasn't removed");
+}
@@ -0,0 +1,118 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use teloxide::types::{ChatId, UserId};
+use tracing::{debug, trace};
+
+use crate::{config::Config, store::Store};
+
+const TREE_NAME: &str = "access";
+/// Users who aren't allowed are told that the bot is private at most this often
+const DENIED_REPLY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// When each user who isn't allowed was last told that the bot is private
+static DENIED_REPLIES: Lazy<Mutex<HashMap<UserId, Instant>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Whether a user or chat was allowed or blocked by an admin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccessRule {
+    Allowed,
+    Blocked,
+}
+
+/// Who can use the bot, from the config and the `/allow` and `/block` commands.
+///
+/// The bot is open to everyone unless some chats or users are allowed. Blocked users can't use
+/// it anywhere, and the admins can always use it.
+pub struct Access;
+impl Access {
+    /// Whether the user can use the bot in the chat
+    pub fn is_allowed(chat_id: ChatId, user_id: Option<UserId>) -> bool {
+        let config = Config::global();
+        let access = &config.access;
+
+        if user_id.is_some_and(|x| config.admin_ids.contains(&x)) {
+            return true;
+        }
+
+        let rules = match Self::rules() {
+            Ok(x) => x,
+            Err(e) => {
+                debug!(?e, "Failed to read access rules");
+                HashMap::new()
+            }
+        };
+        let user_rule = user_id.and_then(|x| rules.get(&Self::user_key(x)).copied());
+        let chat_rule = rules.get(&chat_id.0).copied();
+
+        let blocked = user_id.is_some_and(|x| access.blocked_users.contains(&x))
+            || user_rule == Some(AccessRule::Blocked)
+            || chat_rule == Some(AccessRule::Blocked);
+        if blocked {
+            return false;
+        }
+
+        let has_allow_list = !access.allowed_chats.is_empty()
+            || !access.allowed_users.is_empty()
+            || rules.values().any(|x| *x == AccessRule::Allowed);
+        if !has_allow_list {
+            return true;
+        }
+
+        user_id.is_some_and(|x| access.allowed_users.contains(&x))
+            || access.allowed_chats.contains(&chat_id)
+            || user_rule == Some(AccessRule::Allowed)
+            || chat_rule == Some(AccessRule::Allowed)
+    }
+
+    /// Whether the user should be told that the bot is private, so users who keep sending
+    /// songs aren't replied to every time
+    pub fn should_reply_denied(user_id: UserId) -> bool {
+        let now = Instant::now();
+        let mut replies = DENIED_REPLIES.lock().expect("Denied replies lock poisoned");
+        replies.retain(|_, x| now.duration_since(*x) < DENIED_REPLY_INTERVAL);
+
+        if replies.contains_key(&user_id) {
+            trace!(?user_id, "Already told the user that the bot is private");
+            return false;
+        }
+        replies.insert(user_id, now);
+
+        true
+    }
+
+    /// Allow or block the user (positive IDs) or group (negative IDs), replacing the previous
+    /// rule of the ID
+    pub fn set_rule(id: i64, rule: AccessRule) -> anyhow::Result<()> {
+        trace!(?id, ?rule, "Setting access rule");
+
+        Store::tree(TREE_NAME)?.insert(id.to_be_bytes(), serde_json::to_vec(&rule)?)?;
+
+        Ok(())
+    }
+
+    /// The rules set by the admins, keyed by the user or chat ID
+    fn rules() -> anyhow::Result<HashMap<i64, AccessRule>> {
+        Store::tree(TREE_NAME)?
+            .iter()
+            .map(|x| {
+                let (key, value) = x?;
+                let id = i64::from_be_bytes(key.as_ref().try_into()?);
+
+                Ok((id, serde_json::from_slice(&value)?))
+            })
+            .collect()
+    }
+
+    /// The ID of a user is the same as the one of their private chat with the bot
+    #[allow(clippy::cast_possible_wrap)]
+    const fn user_key(user_id: UserId) -> i64 {
+        user_id.0 as i64
+    }
+}
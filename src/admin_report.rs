@@ -0,0 +1,109 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use teloxide::{requests::Requester, types::ChatId};
+use tracing::{debug, trace};
+
+use crate::{bot::TelegramBot, config::Config, helpers::html};
+
+/// Identical reports are sent at most this often
+const REPEAT_INTERVAL: Duration = Duration::from_secs(600);
+
+/// When each report (by stage and error) was last sent
+static LAST_SENT: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Which part of processing a song failed
+#[derive(Debug, Clone, Copy)]
+pub enum FailedStage {
+    Download,
+    Processing,
+    Upload,
+    /// The job panicked
+    Internal,
+}
+impl Display for FailedStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Download => f.write_str("download"),
+            Self::Processing => f.write_str("processing"),
+            Self::Upload => f.write_str("upload"),
+            Self::Internal => f.write_str("internal error"),
+        }
+    }
+}
+
+/// Reports problems to the admin chat (`KARAOKIFY_ADMIN_CHAT_ID`)
+pub struct AdminReport;
+impl AdminReport {
+    /// Let the admins know that processing a song failed.
+    ///
+    /// The report is sent in the background so failing to send it can't affect the job.
+    pub fn job_failed(stage: FailedStage, song: impl Display, requester: &str, error: &str) {
+        let Some(chat_id) = Config::global().admin_chat_id else {
+            return;
+        };
+
+        if !Self::should_send(&format!("{stage}\n{error}")) {
+            trace!(?stage, "Identical report was sent recently, skipping");
+            return;
+        }
+
+        let text = format!(
+            "<b>Job failed</b> ({stage})\n\nSong: {}\nUser: {}\n\n<pre>{}</pre>",
+            html::escape_value(&song),
+            html::escape_value(requester),
+            html::escape_value(error),
+        );
+
+        Self::send(chat_id, text);
+    }
+
+    /// Let the admins know that a processed song couldn't be posted to the archive chat
+    pub fn archive_failed(song: &str, error: &str) {
+        let Some(chat_id) = Config::global().admin_chat_id else {
+            return;
+        };
+
+        if !Self::should_send(&format!("archive\n{error}")) {
+            trace!("Identical report was sent recently, skipping");
+            return;
+        }
+
+        let text = format!(
+            "<b>Posting to the archive chat failed</b>\n\nSong: {}\n\n<pre>{}</pre>",
+            html::escape_value(song),
+            html::escape_value(error),
+        );
+
+        Self::send(chat_id, text);
+    }
+
+    /// Send the report in the background
+    fn send(chat_id: ChatId, text: String) {
+        tokio::spawn(async move {
+            if let Err(e) = TelegramBot::instance().send_message(chat_id, text).await {
+                debug!(?e, "Failed to send admin report");
+            }
+        });
+    }
+
+    fn should_send(key: &str) -> bool {
+        let now = Instant::now();
+        let mut last_sent = LAST_SENT.lock().expect("Admin report lock poisoned");
+
+        last_sent.retain(|_, x| now.duration_since(*x) < REPEAT_INTERVAL);
+        if last_sent.contains_key(key) {
+            return false;
+        }
+
+        last_sent.insert(key.to_string(), now);
+        drop(last_sent);
+
+        true
+    }
+}
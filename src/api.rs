@@ -0,0 +1,551 @@
+//! Lets other programs (eg. a web app) submit songs without going through Telegram.
+//!
+//! `POST /jobs` with `{"url": "...", "model": "htdemucs_ft", "stems": 4}` (the model and the
+//! stems are optional) starts a job and returns its ID, `GET /jobs/{id}` returns its state and
+//! `GET /jobs/{id}/files/{name}` downloads the files once it's done. `DELETE /jobs/{id}` cancels
+//! a running job. Every request has to include the token (`KARAOKIFY_API_TOKEN`) as
+//! `Authorization: Bearer <token>`.
+//!
+//! The songs go through the same queues and processing as the ones sent to the bot, only the
+//! files are kept for downloading (for `KARAOKIFY_API_FILES_TTL_MINS`) instead of being uploaded.
+//! The jobs are tracked with the bot's jobs, so they're waited for (or cancelled) on shutdown.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use futures::{future::BoxFuture, FutureExt, TryStreamExt};
+use http_body_util::{combinators::BoxBody, BodyExt, Full, Limited, StreamBody};
+use hyper::{
+    body::{Bytes, Frame, Incoming},
+    header::{HeaderValue, AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use teloxide::RequestError;
+use tokio::{net::TcpListener, sync::watch, time::MissedTickBehavior};
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::{
+    config::Config,
+    helpers::{
+        header::content_disposition::ContentDisposition, html, id::short_id,
+        status_message::StatusMessage, temp_dir::TempDir,
+    },
+    jobs::{JobId, Jobs},
+    notifier::Notifier,
+    options::SongOptions,
+    result_sink::{Delivery, ResultSink},
+};
+
+/// The jobs by their ID
+static JOBS: Lazy<Mutex<HashMap<String, ApiJob>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(300);
+/// The JSON of a new job is tiny, anything larger is a mistake
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+type Body = BoxBody<Bytes, std::io::Error>;
+
+/// Processes a song submitted through the API like one sent to the bot, delivering the files
+/// with the submission's sink. Returns the reason shown to the client if it failed.
+pub type RunJob = fn(ApiSubmission) -> BoxFuture<'static, Result<(), String>>;
+
+/// A song submitted through the API
+#[derive(Debug)]
+pub struct ApiSubmission {
+    pub id: String,
+    pub url: Url,
+    pub options: SongOptions,
+    /// Status of the job, shown to the client while it's running
+    pub status: StatusMessage,
+    pub sink: ApiSink,
+}
+
+/// The body of `POST /jobs`
+#[derive(Debug, Deserialize)]
+struct NewJob {
+    url: String,
+    model: Option<String>,
+    stems: Option<u8>,
+}
+impl NewJob {
+    fn options(&self) -> anyhow::Result<SongOptions> {
+        let mut res = SongOptions::default();
+        if let Some(model) = &self.model {
+            res.model = model.parse()?;
+        }
+        if let Some(stems) = self.stems {
+            res.stem_mode = stems.to_string().parse()?;
+        }
+
+        Ok(res)
+    }
+}
+
+struct ApiJob {
+    /// ID of the job in the bot's jobs, used to cancel it
+    job_id: JobId,
+    state: JobState,
+    /// The latest status text of a running job
+    status: watch::Receiver<String>,
+    /// Holds the delivered files, deleted with the job
+    dir: TempDir,
+    finished_at: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum JobState {
+    Running {
+        status: String,
+    },
+    /// Contains the names of the files that can be downloaded
+    Done {
+        files: Vec<String>,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct CreatedJob {
+    id: String,
+}
+
+/// Keeps the files of a job submitted through the API until they're downloaded (or expire)
+#[derive(Debug)]
+pub struct ApiSink {
+    job_id: String,
+}
+#[async_trait::async_trait]
+impl ResultSink for ApiSink {
+    fn is_interactive(&self) -> bool {
+        false
+    }
+
+    async fn deliver(
+        &self,
+        msg: &mut dyn Notifier,
+        delivery: Delivery<'_>,
+    ) -> Result<Option<Vec<String>>, RequestError> {
+        msg.update_status(&msg.lang().text("keeping-files")).await?;
+
+        let Some(dir) = jobs().get(&self.job_id).map(|x| x.dir.path().to_path_buf()) else {
+            // The job is gone already, so nobody wants the files anymore
+            return Ok(None);
+        };
+
+        let mut names = vec![];
+        for file_path in delivery.files {
+            let Some(name) = file_path.file_name() else {
+                continue;
+            };
+
+            tokio::fs::rename(&file_path, dir.join(name)).await?;
+            names.push(name.to_string_lossy().to_string());
+        }
+
+        if let Some(job) = jobs().get_mut(&self.job_id) {
+            if let JobState::Done { files } = &mut job.state {
+                files.extend(names);
+            } else {
+                job.state = JobState::Done { files: names };
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// The HTTP server of the API
+pub struct ApiServer;
+impl ApiServer {
+    /// Start listening on `KARAOKIFY_API_ADDR`, if it's set. The submitted songs are processed
+    /// with `run_job`.
+    pub fn spawn(run_job: RunJob) {
+        let Some(config) = &Config::global().api else {
+            return;
+        };
+
+        tokio::spawn(Self::serve(config.listen_addr, run_job));
+        tokio::spawn(remove_expired_jobs(config.files_ttl));
+    }
+
+    async fn serve(addr: std::net::SocketAddr, run_job: RunJob) {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(?e, %addr, "Failed to start the API");
+                return;
+            }
+        };
+        info!(%addr, "Serving the API");
+
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    debug!(?e, "Failed to accept API connection");
+                    continue;
+                }
+            };
+
+            tokio::spawn(async move {
+                let service =
+                    service_fn(
+                        |req| async move { Ok::<_, Infallible>(respond(req, run_job).await) },
+                    );
+
+                let res = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+
+                if let Err(e) = res {
+                    debug!(?e, "Failed to serve API connection");
+                }
+            });
+        }
+    }
+}
+
+async fn respond(req: Request<Incoming>, run_job: RunJob) -> Response<Body> {
+    let Some(config) = &Config::global().api else {
+        return text_response(StatusCode::NOT_FOUND, "not found");
+    };
+
+    let authorized = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .is_some_and(|x| is_token(x.trim(), &config.token));
+    if !authorized {
+        return text_response(StatusCode::UNAUTHORIZED, "invalid token");
+    }
+
+    let method = req.method().clone();
+    let path = req.uri().path().trim_matches('/').to_string();
+    let segments = path.split('/').collect::<Vec<_>>();
+
+    match (method, segments.as_slice()) {
+        (Method::POST, ["jobs"]) => match create_job(req, run_job).await {
+            Ok(id) => json_response(StatusCode::ACCEPTED, &CreatedJob { id }),
+            Err(e) => {
+                debug!(?e, "Invalid API job");
+                text_response(StatusCode::BAD_REQUEST, format!("{e:#}"))
+            }
+        },
+        (Method::GET, ["jobs", id]) => {
+            let state = jobs().get(*id).map(|x| match &x.state {
+                JobState::Running { .. } => JobState::Running {
+                    status: html::to_plain_text(&x.status.borrow()),
+                },
+                state => state.clone(),
+            });
+            state.map_or_else(
+                || text_response(StatusCode::NOT_FOUND, "job not found"),
+                |x| json_response(StatusCode::OK, &x),
+            )
+        }
+        (Method::GET, ["jobs", id, "files", name]) => file_response(id, name).await,
+        (Method::DELETE, ["jobs", id]) => cancel_job(id),
+        _ => text_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+/// Compare the tokens in constant time, so the time it takes doesn't reveal how much of the
+/// token was guessed correctly
+fn is_token(token: &str, expected: &str) -> bool {
+    token.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+fn jobs() -> MutexGuard<'static, HashMap<String, ApiJob>> {
+    JOBS.lock().expect("API jobs lock poisoned")
+}
+
+/// Start processing the submitted song. Returns the ID of the job.
+async fn create_job(req: Request<Incoming>, run_job: RunJob) -> anyhow::Result<String> {
+    let body = Limited::new(req.into_body(), MAX_BODY_SIZE)
+        .collect()
+        .await
+        .map_err(|e| anyhow::anyhow!("could not read the body: {e}"))?
+        .to_bytes();
+    let new_job = serde_json::from_slice::<NewJob>(&body)?;
+
+    let url = Url::parse(new_job.url.trim())?;
+    if !matches!(url.scheme(), "http" | "https") {
+        anyhow::bail!("only http and https links are supported");
+    }
+    let options = new_job.options()?;
+
+    let id = short_id();
+    let dir = TempDir::with_prefix(format!("karaokify-api-{id}-")).await?;
+    let (status_tx, status_rx) = watch::channel("Waiting in queue...".to_string());
+    info!(id, %url, ?options, "API job created");
+
+    let submission = ApiSubmission {
+        id: id.clone(),
+        url,
+        options,
+        status: StatusMessage::detached(status_tx),
+        sink: ApiSink { job_id: id.clone() },
+    };
+
+    // Held while spawning so the job can't finish before it's inserted
+    let mut jobs = jobs();
+    let job_id = Jobs::spawn_detached(|cancel_token| run(run_job, submission, cancel_token));
+    jobs.insert(
+        id.clone(),
+        ApiJob {
+            job_id,
+            state: JobState::Running {
+                status: String::new(),
+            },
+            status: status_rx,
+            dir,
+            finished_at: None,
+        },
+    );
+    drop(jobs);
+
+    Ok(id)
+}
+
+async fn run(run_job: RunJob, submission: ApiSubmission, cancel_token: CancellationToken) {
+    let id = submission.id.clone();
+
+    let res = tokio::select! {
+        res = AssertUnwindSafe(run_job(submission)).catch_unwind() => res,
+
+        () = cancel_token.cancelled() => {
+            let reason = if Jobs::is_shutting_down() {
+                "cancelled because the server is shutting down"
+            } else {
+                "cancelled"
+            };
+            Ok(Err(reason.to_string()))
+        }
+    };
+    let error = match res {
+        Ok(Ok(())) => None,
+        Ok(Err(reason)) => Some(html::to_plain_text(&reason)),
+        Err(_) => Some("internal error".to_string()),
+    };
+
+    if let Some(job) = jobs().get_mut(&id) {
+        match error {
+            Some(error) => {
+                info!(id, error, "API job failed");
+                job.state = JobState::Failed { error };
+            }
+            // Nothing was delivered if the song didn't contain anything to process
+            None if matches!(job.state, JobState::Running { .. }) => {
+                job.state = JobState::Done { files: vec![] };
+            }
+            None => info!(id, "API job finished"),
+        }
+        job.finished_at = Some(Instant::now());
+    }
+}
+
+/// Cancel a running job
+fn cancel_job(id: &str) -> Response<Body> {
+    let job = jobs()
+        .get(id)
+        .map(|x| (x.job_id, matches!(x.state, JobState::Running { .. })));
+    let job_id = match job {
+        Some((job_id, true)) => job_id,
+        Some((_, false)) => return text_response(StatusCode::CONFLICT, "job isn't running"),
+        None => return text_response(StatusCode::NOT_FOUND, "job not found"),
+    };
+
+    if Jobs::cancel_by_id(job_id) {
+        info!(id, "Cancelling API job");
+        text_response(StatusCode::ACCEPTED, "cancelling")
+    } else {
+        text_response(StatusCode::CONFLICT, "job isn't running")
+    }
+}
+
+/// Stream one of the files of a finished job
+async fn file_response(id: &str, name: &str) -> Response<Body> {
+    let path = {
+        let jobs = jobs();
+        let Some(job) = jobs.get(id) else {
+            return text_response(StatusCode::NOT_FOUND, "job not found");
+        };
+        let JobState::Done { files } = &job.state else {
+            return text_response(StatusCode::CONFLICT, "job isn't done");
+        };
+        // Only the listed files can be downloaded, so the name can't point elsewhere
+        let Some(name) = files.iter().find(|x| *x == name) else {
+            return text_response(StatusCode::NOT_FOUND, "file not found");
+        };
+
+        let path = job.dir.path().join(name);
+        drop(jobs);
+
+        path
+    };
+
+    match open_file(&path).await {
+        Ok(x) => x,
+        Err(e) => {
+            warn!(?e, ?path, "Failed to open API job file");
+            text_response(StatusCode::INTERNAL_SERVER_ERROR, "could not read the file")
+        }
+    }
+}
+
+async fn open_file(path: &PathBuf) -> std::io::Result<Response<Body>> {
+    let file = tokio::fs::File::open(path).await?;
+    let size = file.metadata().await?.len();
+
+    let stream = ReaderStream::new(file).map_ok(Frame::data);
+    let mut res = Response::new(StreamBody::new(stream).boxed());
+    let headers = res.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(size));
+    if let Some(disposition) = file_disposition(path) {
+        headers.insert(CONTENT_DISPOSITION, disposition);
+    }
+
+    Ok(res)
+}
+
+fn file_disposition(path: &Path) -> Option<HeaderValue> {
+    let name = path.file_name()?.to_string_lossy();
+
+    HeaderValue::from_str(&ContentDisposition::attachment(name).to_string()).ok()
+}
+
+/// Delete the finished jobs (and their files) once they expire
+async fn remove_expired_jobs(files_ttl: Duration) {
+    let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        jobs().retain(|id, job| {
+            let expired = job.finished_at.is_some_and(|x| x.elapsed() > files_ttl);
+            if expired {
+                info!(id, "Deleting expired API job");
+            }
+
+            !expired
+        });
+    }
+}
+
+fn text_response(status: StatusCode, body: impl Into<Bytes>) -> Response<Body> {
+    let body = Full::new(body.into()).map_err(|x| match x {}).boxed();
+
+    let mut res = Response::new(body);
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+
+    res
+}
+
+fn json_response(status: StatusCode, body: &impl Serialize) -> Response<Body> {
+    let body = match serde_json::to_vec(body) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!(?e, "Failed to serialize response");
+            return text_response(StatusCode::INTERNAL_SERVER_ERROR, "serialization failed");
+        }
+    };
+
+    let mut res = text_response(status, body);
+    res.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        assert!(is_token("s3cret-token", "s3cret-token"));
+        assert!(!is_token("s3cret-tokem", "s3cret-token"));
+        assert!(!is_token("s3cret", "s3cret-token"));
+        assert!(!is_token("s3cret-token-and-more", "s3cret-token"));
+        assert!(!is_token("", "s3cret-token"));
+    }
+
+    #[tokio::test]
+    async fn cancelled_job_fails() {
+        fn never_finishes(_: ApiSubmission) -> BoxFuture<'static, Result<(), String>> {
+            futures::future::pending().boxed()
+        }
+
+        let id = "test-cancelled-job".to_string();
+        let (status_tx, status_rx) = watch::channel(String::new());
+        let submission = ApiSubmission {
+            id: id.clone(),
+            url: Url::parse("https://example.com/song.mp3").expect("Valid URL"),
+            options: SongOptions::default(),
+            status: StatusMessage::detached(status_tx),
+            sink: ApiSink { job_id: id.clone() },
+        };
+        let dir = TempDir::with_prefix("karaokify-test-api-")
+            .await
+            .expect("Temp dir created");
+
+        // The job never finishes on its own, so it can be inserted after spawning
+        let job_id =
+            Jobs::spawn_detached(|cancel_token| run(never_finishes, submission, cancel_token));
+        jobs().insert(
+            id.clone(),
+            ApiJob {
+                job_id,
+                state: JobState::Running {
+                    status: String::new(),
+                },
+                status: status_rx,
+                dir,
+                finished_at: None,
+            },
+        );
+
+        assert_eq!(cancel_job(&id).status(), StatusCode::ACCEPTED);
+
+        for _ in 0..100 {
+            if jobs().get(&id).is_some_and(|x| x.finished_at.is_some()) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let state = jobs().remove(&id).map(|x| x.state);
+        assert!(
+            matches!(&state, Some(JobState::Failed { error }) if error == "cancelled"),
+            "Unexpected state: {state:?}"
+        );
+
+        assert_eq!(cancel_job(&id).status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,113 @@
+use teloxide::{
+    payloads::SendAudioSetters,
+    requests::{Request, Requester},
+    types::{ChatId, InputFile, InputMedia, InputMediaAudio},
+};
+use tracing::{debug, trace};
+
+use crate::{
+    admin_report::AdminReport,
+    bot::TelegramBot,
+    config::Config,
+    helpers::{html, retry::retry_after},
+};
+
+/// Telegram allows at most this many files in a media group
+const MAX_MEDIA_GROUP_SIZE: usize = 10;
+
+/// A processed song that's posted to the archive chat
+#[derive(Debug, Clone)]
+pub struct ArchivedSong {
+    pub title: String,
+    /// Who requested the song, eg. "John Doe (@johndoe, 1234)"
+    pub requester: String,
+    /// The model the song was split with
+    pub model: String,
+    /// Telegram file IDs of the files that were uploaded to the requester
+    pub file_ids: Vec<String>,
+}
+
+/// Posts every processed song to the archive chat (`KARAOKIFY_ARCHIVE_CHAT_ID`) too, so it builds
+/// a library of the songs
+pub struct ArchiveChannel;
+impl ArchiveChannel {
+    /// Post the already uploaded files of the song to the archive chat, if it's configured.
+    ///
+    /// The files are posted in the background. Failures are only reported to the admin chat, so
+    /// they can't affect the job.
+    pub fn post(song: ArchivedSong) {
+        let Some(chat_id) = Config::global().archive_chat_id else {
+            return;
+        };
+
+        tokio::spawn(async move {
+            if let Err(e) = Self::send(chat_id, &song).await {
+                debug!(
+                    ?e,
+                    title = song.title,
+                    "Failed to post song to the archive chat"
+                );
+                AdminReport::archive_failed(&song.title, &e.to_string());
+            }
+        });
+    }
+
+    async fn send(chat_id: ChatId, song: &ArchivedSong) -> anyhow::Result<()> {
+        trace!(?song, "Posting song to the archive chat");
+        let caption = Self::caption(song);
+
+        for (i, chunk) in song.file_ids.chunks(MAX_MEDIA_GROUP_SIZE).enumerate() {
+            // Media groups need at least two files
+            if let [file_id] = chunk {
+                retry_after(|| {
+                    let mut request =
+                        TelegramBot::instance().send_audio(chat_id, InputFile::file_id(file_id));
+                    if i == 0 {
+                        request = request.caption(&caption);
+                    }
+                    request.send()
+                })
+                .await?;
+                continue;
+            }
+
+            let media_group = chunk
+                .iter()
+                .enumerate()
+                .map(|(j, file_id)| {
+                    let media = InputMediaAudio::new(InputFile::file_id(file_id));
+                    let media = if i == 0 && j == 0 {
+                        media.caption(&caption)
+                    } else {
+                        media
+                    };
+
+                    InputMedia::Audio(media)
+                })
+                .collect::<Vec<_>>();
+
+            retry_after(|| {
+                TelegramBot::instance()
+                    .send_media_group(chat_id, media_group.clone())
+                    .send()
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// The configured caption template with the placeholders (`{title}`, `{requester}`,
+    /// `{model}` and `{date}`) filled in
+    #[allow(clippy::literal_string_with_formatting_args)]
+    fn caption(song: &ArchivedSong) -> String {
+        let date = chrono::Utc::now().format("%Y-%m-%d %H:%M UTC").to_string();
+
+        Config::global()
+            .archive_caption
+            .replace("{title}", &html::escape_value(&song.title))
+            .replace("{requester}", &html::escape_value(&song.requester))
+            .replace("{model}", &html::escape_value(&song.model))
+            .replace("{date}", &date)
+    }
+}
@@ -0,0 +1,502 @@
+//! Splits songs into stems for a bot running on another machine.
+//!
+//! The bot (with `KARAOKIFY_WORKER_URL` set) uploads the song to `POST /jobs` as a multipart
+//! form, polls `GET /jobs/{id}` until it's done, downloads the stems from
+//! `GET /jobs/{id}/stems/{name}` and finally deletes the job with `DELETE /jobs/{id}`. Every
+//! request has to include the shared secret (`KARAOKIFY_WORKER_SECRET`).
+//!
+//! Demucs is run just like the bot runs it locally, so the same settings (eg.
+//! `KARAOKIFY_DEMUCS_DEVICE`) apply.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{HeaderValue, CONTENT_TYPE},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use karaokify::{
+    config::{check_config_file, Config, PipelineConfig},
+    helpers::{
+        header::content_disposition::ContentDisposition, id::short_id, log_format::init_log,
+        temp_dir::TempDir,
+    },
+    preflight,
+    processor::{
+        demucs::{DemucsError, DemucsProcessor, DemucsProgress, LocalSeparator},
+        remote::{CreatedJob, JobParams, WorkerHealth, WorkerJobStatus, SECRET_HEADER},
+        separator::StemSeparator,
+    },
+};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::{
+    net::TcpListener,
+    sync::{watch, Semaphore},
+    task::AbortHandle,
+    time::MissedTickBehavior,
+};
+use tracing::{debug, error, info, warn};
+
+/// The jobs by their ID
+static JOBS: Lazy<Mutex<HashMap<String, Job>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// Limits how many songs are split at the same time (`KARAOKIFY_MAX_CONCURRENT_PROCESSING`)
+static SLOTS: Lazy<Semaphore> =
+    Lazy::new(|| Semaphore::new(Config::global().max_concurrent_processing));
+
+/// Finished jobs are deleted after this long if the bot doesn't delete them (eg. because it
+/// was restarted)
+const FINISHED_JOB_MAX_AGE: Duration = Duration::from_secs(3600);
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(300);
+/// The form can be a bit larger than the song itself
+const MAX_FORM_OVERHEAD: u64 = 1000 * 1000;
+
+struct Job {
+    status: WorkerJobStatus,
+    /// Holds the song and the stems, deleted with the job
+    _dir: TempDir,
+    /// Where demucs puts the stems
+    stems_dir: PathBuf,
+    /// Aborting the task kills demucs
+    task: Option<AbortHandle>,
+    finished_at: Option<Instant>,
+}
+impl Drop for Job {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    match dotenvy::dotenv() {
+        Err(e) if e.not_found() => {}
+        Ok(_) => {}
+        Err(e) => {
+            panic!("Failed to load .env file: {}", e);
+        }
+    }
+
+    init_log();
+
+    let config = Config::global();
+    let worker = &PipelineConfig::global().worker;
+    check_config_file();
+    let Some(secret) = worker.secret.as_deref() else {
+        error!("KARAOKIFY_WORKER_SECRET must be set");
+        std::process::exit(1);
+    };
+
+    if let Err(e) = preflight::check().await {
+        error!("Preflight check failed: {e}");
+        std::process::exit(1);
+    }
+
+    tokio::spawn(DemucsProcessor::warm_up(&config.prewarm_models));
+    tokio::spawn(remove_expired_jobs());
+
+    let listener = match TcpListener::bind(worker.listen_addr).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!(?e, addr = %worker.listen_addr, "Failed to start the worker");
+            std::process::exit(1);
+        }
+    };
+    info!(
+        addr = %worker.listen_addr,
+        processing = config.max_concurrent_processing,
+        "Worker listening"
+    );
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                debug!(?e, "Failed to accept HTTP connection");
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            let service =
+                service_fn(|req| async move { Ok::<_, Infallible>(respond(req, secret).await) });
+
+            let res = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+
+            if let Err(e) = res {
+                debug!(?e, "Failed to serve HTTP connection");
+            }
+        });
+    }
+}
+
+async fn respond(req: Request<Incoming>, secret: &str) -> Response<Full<Bytes>> {
+    let authorized = req
+        .headers()
+        .get(SECRET_HEADER)
+        .is_some_and(|x| x.as_bytes() == secret.as_bytes());
+    if !authorized {
+        return text_response(StatusCode::UNAUTHORIZED, "invalid secret");
+    }
+
+    let method = req.method().clone();
+    let path = req.uri().path().trim_matches('/').to_string();
+    let segments = path.split('/').collect::<Vec<_>>();
+
+    match (method, segments.as_slice()) {
+        (Method::GET, ["health"]) => json_response(
+            StatusCode::OK,
+            &WorkerHealth {
+                device: DemucsProcessor::device_description(),
+            },
+        ),
+        (Method::POST, ["jobs"]) => match create_job(req).await {
+            Ok(id) => json_response(StatusCode::ACCEPTED, &CreatedJob { id }),
+            Err(e) => {
+                debug!(?e, "Invalid job");
+                text_response(StatusCode::BAD_REQUEST, format!("{e:#}"))
+            }
+        },
+        (Method::GET, ["jobs", id]) => {
+            let status = jobs().get(*id).map(|x| x.status.clone());
+            status.map_or_else(
+                || text_response(StatusCode::NOT_FOUND, "job not found"),
+                |x| json_response(StatusCode::OK, &x),
+            )
+        }
+        (Method::GET, ["jobs", id, "stems", stem]) => stem_response(id, stem).await,
+        (Method::DELETE, ["jobs", id]) => {
+            let removed = jobs().remove(*id);
+            if removed.is_none() {
+                return text_response(StatusCode::NOT_FOUND, "job not found");
+            }
+            info!(id, "Job deleted");
+
+            text_response(StatusCode::OK, "deleted")
+        }
+        _ => text_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+fn jobs() -> std::sync::MutexGuard<'static, HashMap<String, Job>> {
+    JOBS.lock().expect("Jobs lock poisoned")
+}
+
+/// Save the uploaded song and start splitting it. Returns the ID of the job.
+async fn create_job(req: Request<Incoming>) -> anyhow::Result<String> {
+    let boundary = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .and_then(multipart_boundary)
+        .ok_or_else(|| anyhow::anyhow!("expected a multipart/form-data body"))?;
+
+    let limit = PipelineConfig::global().max_download_size + MAX_FORM_OVERHEAD;
+    let body = Limited::new(
+        req.into_body(),
+        usize::try_from(limit).unwrap_or(usize::MAX),
+    )
+    .collect()
+    .await
+    .map_err(|e| anyhow::anyhow!("could not read the body: {e}"))?
+    .to_bytes();
+
+    let mut fields = HashMap::new();
+    let mut file = None;
+    for part in parse_multipart(&body, &boundary)? {
+        match part.file_name {
+            Some(file_name) if part.name == "file" => file = Some((file_name, part.data)),
+            _ => {
+                fields.insert(part.name, String::from_utf8_lossy(&part.data).to_string());
+            }
+        }
+    }
+    let params = JobParams::from_fields(&fields)?;
+    let (file_name, data) = file.ok_or_else(|| anyhow::anyhow!("missing the file"))?;
+
+    let dir = TempDir::with_prefix("karaokify-worker-").await?;
+    // Only the extension is kept, the rest of the name doesn't matter to demucs
+    let extension = Path::new(&file_name)
+        .extension()
+        .map(|x| x.to_string_lossy().to_string())
+        .filter(|x| x.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or_else(|| "audio".to_string());
+    let file_path = dir.path().join(format!("song.{extension}"));
+    tokio::fs::write(&file_path, &data).await?;
+    let out_dir = dir.path().join("stems");
+    tokio::fs::create_dir(&out_dir).await?;
+
+    let id = short_id();
+    info!(id, ?params, size = data.len(), "Job created");
+
+    jobs().insert(
+        id.clone(),
+        Job {
+            status: WorkerJobStatus::Queued,
+            _dir: dir,
+            stems_dir: out_dir.join(params.demucs_model.to_string()),
+            task: None,
+            finished_at: None,
+        },
+    );
+    let task = tokio::spawn(run_job(id.clone(), params, file_path, out_dir));
+    if let Some(job) = jobs().get_mut(&id) {
+        job.task = Some(task.abort_handle());
+    }
+
+    Ok(id)
+}
+
+async fn run_job(id: String, params: JobParams, file_path: PathBuf, out_dir: PathBuf) {
+    let _permit = SLOTS.acquire().await.expect("Semaphore closed");
+    set_status(&id, |x| {
+        *x = WorkerJobStatus::Running {
+            percent: 0,
+            reduced_memory: false,
+        };
+    });
+    info!(id, "Splitting song");
+
+    let (progress_tx, mut progress_rx) = watch::channel(DemucsProgress::default());
+    let separate = LocalSeparator.separate(
+        &file_path,
+        params.demucs_model,
+        params.stem_mode,
+        params.encoding,
+        &out_dir,
+        Some(&progress_tx),
+    );
+    tokio::pin!(separate);
+
+    let res = loop {
+        tokio::select! {
+            res = &mut separate => break res,
+
+            Ok(()) = progress_rx.changed() => {
+                let progress = *progress_rx.borrow_and_update();
+                set_status(&id, |x| {
+                    *x = WorkerJobStatus::Running {
+                        percent: progress.percent,
+                        reduced_memory: progress.reduced_memory,
+                    };
+                });
+            }
+        }
+    };
+
+    let status = match res {
+        Ok(()) => match stem_names(&out_dir.join(params.demucs_model.to_string())).await {
+            Ok(stems) => {
+                info!(id, ?stems, "Job finished");
+                WorkerJobStatus::Done { stems }
+            }
+            Err(e) => {
+                warn!(id, ?e, "Failed to list the stems");
+                WorkerJobStatus::Failed {
+                    error: format!("could not list the stems: {e}"),
+                    demucs: None,
+                }
+            }
+        },
+        Err(e) => {
+            warn!(id, ?e, "Job failed");
+            WorkerJobStatus::Failed {
+                error: format!("{e:#}"),
+                demucs: e.downcast_ref::<DemucsError>().cloned(),
+            }
+        }
+    };
+
+    if let Some(job) = jobs().get_mut(&id) {
+        job.status = status;
+        job.task = None;
+        job.finished_at = Some(Instant::now());
+    }
+}
+
+fn set_status(id: &str, update: impl FnOnce(&mut WorkerJobStatus)) {
+    if let Some(job) = jobs().get_mut(id) {
+        update(&mut job.status);
+    }
+}
+
+async fn stem_names(dir: &Path) -> std::io::Result<Vec<String>> {
+    let mut res = vec![];
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        res.push(entry.file_name().to_string_lossy().to_string());
+    }
+    res.sort();
+
+    Ok(res)
+}
+
+async fn stem_response(id: &str, stem: &str) -> Response<Full<Bytes>> {
+    let path = {
+        let jobs = jobs();
+        let Some(job) = jobs.get(id) else {
+            return text_response(StatusCode::NOT_FOUND, "job not found");
+        };
+        let WorkerJobStatus::Done { stems } = &job.status else {
+            return text_response(StatusCode::CONFLICT, "job isn't done");
+        };
+        // Only the listed stems can be downloaded, so the name can't point elsewhere
+        let Some(stem) = stems.iter().find(|x| *x == stem) else {
+            return text_response(StatusCode::NOT_FOUND, "stem not found");
+        };
+
+        let path = job.stems_dir.join(stem);
+        drop(jobs);
+
+        path
+    };
+
+    match tokio::fs::read(&path).await {
+        Ok(data) => {
+            let mut res = Response::new(Full::new(Bytes::from(data)));
+            res.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            );
+            res
+        }
+        Err(e) => {
+            warn!(?e, ?path, "Failed to read stem");
+            text_response(StatusCode::INTERNAL_SERVER_ERROR, "could not read the stem")
+        }
+    }
+}
+
+/// Delete the finished jobs the bot didn't delete itself
+async fn remove_expired_jobs() {
+    let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        jobs().retain(|id, job| {
+            let expired = job
+                .finished_at
+                .is_some_and(|x| x.elapsed() > FINISHED_JOB_MAX_AGE);
+            if expired {
+                info!(id, "Deleting expired job");
+            }
+
+            !expired
+        });
+    }
+}
+
+/// A part of a `multipart/form-data` body
+struct Part {
+    name: String,
+    file_name: Option<String>,
+    data: Bytes,
+}
+
+/// The boundary from a `multipart/form-data; boundary=...` content type
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    params.split(';').find_map(|x| {
+        let (name, value) = x.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+fn parse_multipart(body: &Bytes, boundary: &str) -> anyhow::Result<Vec<Part>> {
+    let delimiter = format!("--{boundary}");
+    let part_end = format!("\r\n{delimiter}");
+
+    let start = find(body, delimiter.as_bytes())
+        .ok_or_else(|| anyhow::anyhow!("missing multipart boundary"))?;
+    let mut rest = &body[start + delimiter.len()..];
+
+    let mut res = vec![];
+    // The last delimiter is followed by `--`
+    while !rest.starts_with(b"--") {
+        rest = rest
+            .strip_prefix(b"\r\n")
+            .ok_or_else(|| anyhow::anyhow!("invalid multipart delimiter"))?;
+
+        let headers_end =
+            find(rest, b"\r\n\r\n").ok_or_else(|| anyhow::anyhow!("missing multipart headers"))?;
+        let headers = std::str::from_utf8(&rest[..headers_end])?;
+        let content = &rest[headers_end + 4..];
+
+        let disposition = headers
+            .split("\r\n")
+            .find_map(|x| {
+                let (name, value) = x.split_once(':')?;
+                name.trim()
+                    .eq_ignore_ascii_case("content-disposition")
+                    .then(|| HeaderValue::from_str(value.trim()).ok())
+                    .flatten()
+            })
+            .ok_or_else(|| anyhow::anyhow!("multipart part without a Content-Disposition"))?;
+        let disposition = ContentDisposition::from_raw(&disposition)?;
+
+        let end = find(content, part_end.as_bytes())
+            .ok_or_else(|| anyhow::anyhow!("unterminated multipart part"))?;
+
+        res.push(Part {
+            name: disposition.get_name().unwrap_or_default().to_string(),
+            file_name: disposition.get_file_name(),
+            data: body.slice_ref(&content[..end]),
+        });
+        rest = &content[end + part_end.len()..];
+    }
+
+    Ok(res)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|x| x == needle)
+}
+
+fn text_response(status: StatusCode, body: impl Into<Bytes>) -> Response<Full<Bytes>> {
+    let mut res = Response::new(Full::new(body.into()));
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+
+    res
+}
+
+fn json_response(status: StatusCode, body: &impl Serialize) -> Response<Full<Bytes>> {
+    let body = match serde_json::to_vec(body) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!(?e, "Failed to serialize response");
+            return text_response(StatusCode::INTERNAL_SERVER_ERROR, "serialization failed");
+        }
+    };
+
+    let mut res = text_response(status, body);
+    res.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    res
+}
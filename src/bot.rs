@@ -1,8 +1,14 @@
 use once_cell::sync::OnceCell;
-use teloxide::{adaptors::trace, requests::RequesterExt};
+use teloxide::{
+    adaptors::{throttle::Limits, trace, Throttle},
+    requests::RequesterExt,
+};
 
-pub type TeloxideBot =
-    teloxide::adaptors::CacheMe<trace::Trace<teloxide::adaptors::DefaultParseMode<teloxide::Bot>>>;
+use crate::config::Config;
+
+pub type TeloxideBot = teloxide::adaptors::CacheMe<
+    trace::Trace<teloxide::adaptors::DefaultParseMode<Throttle<teloxide::Bot>>>,
+>;
 
 static TELEGRAM_BOT: OnceCell<TeloxideBot> = OnceCell::new();
 
@@ -10,10 +16,21 @@ pub struct TelegramBot;
 impl TelegramBot {
     pub fn instance() -> &'static TeloxideBot {
         TELEGRAM_BOT.get_or_init(|| {
-            teloxide::Bot::from_env()
+            let mut bot = teloxide::Bot::from_env();
+
+            if let Some(api_url) = &Config::global().telegram_api_url {
+                bot = bot.set_api_url(api_url.clone());
+            }
+
+            // Keeps the bot under Telegram's rate limits (eg. when editing many status messages)
+            bot.throttle(Limits::default())
                 .parse_mode(teloxide::types::ParseMode::Html)
                 .trace(trace::Settings::TRACE_EVERYTHING)
                 .cache_me()
         })
     }
+    /// The underlying bot without any adaptors (eg. for downloading files)
+    pub fn raw() -> &'static teloxide::Bot {
+        Self::instance().inner().inner().inner().inner()
+    }
 }
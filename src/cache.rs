@@ -0,0 +1,217 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace};
+use url::Url;
+
+use crate::{
+    config::Config,
+    options::SongOptions,
+    output_choice::OutputChoice,
+    processor::{analysis::SongAnalysis, encoding::OutputFormat},
+    store::Store,
+};
+
+const TREE_NAME: &str = "result_cache";
+
+/// Query parameters that only track where the link was shared from
+const TRACKING_PARAMS: &[&str] = &["si", "feature", "fbclid", "gclid", "igshid", "context"];
+
+/// The uploaded files of an already processed song
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedResult {
+    /// Telegram file IDs of the uploaded files
+    pub file_ids: Vec<String>,
+    /// The detected key and tempo of the song, shown along with the files
+    #[serde(default)]
+    pub analysis: Option<SongAnalysis>,
+    /// When the result was cached (as a UNIX timestamp)
+    created_at: u64,
+}
+
+/// Cache of processed songs so the same song with the same options doesn't have to be
+/// processed again
+pub struct ResultCache;
+impl ResultCache {
+    pub fn get(url: &Url, options: SongOptions) -> Option<CachedResult> {
+        let key = Self::key(url, options);
+
+        let res = Self::get_entry(&key);
+        let res = match res {
+            Ok(x) => x?,
+            Err(e) => {
+                debug!(?e, ?key, "Failed to read cached result");
+                return None;
+            }
+        };
+
+        if unix_now().saturating_sub(res.created_at) > Config::global().cache_max_age.as_secs() {
+            trace!(?key, "Cached result expired");
+            let _ = Store::tree(TREE_NAME).and_then(|x| Ok(x.remove(&key)?));
+            return None;
+        }
+
+        Some(res)
+    }
+
+    pub fn insert(
+        url: &Url,
+        options: SongOptions,
+        file_ids: Vec<String>,
+        analysis: Option<SongAnalysis>,
+    ) {
+        let key = Self::key(url, options);
+        let entry = CachedResult {
+            file_ids,
+            analysis,
+            created_at: unix_now(),
+        };
+
+        let res = Store::tree(TREE_NAME).and_then(|tree| {
+            tree.insert(&key, serde_json::to_vec(&entry)?)?;
+            Self::evict(&tree)
+        });
+
+        if let Err(e) = res {
+            debug!(?e, ?key, "Failed to cache result");
+        }
+    }
+
+    /// Remove the cached results of the URL (with any options).
+    ///
+    /// Returns how many results were removed.
+    pub fn forget(url: &Url) -> anyhow::Result<usize> {
+        let tree = Store::tree(TREE_NAME)?;
+
+        let keys = tree
+            .scan_prefix(Self::key_prefix(url))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        for key in &keys {
+            tree.remove(key)?;
+        }
+
+        Ok(keys.len())
+    }
+
+    fn get_entry(key: &str) -> anyhow::Result<Option<CachedResult>> {
+        let Some(value) = Store::tree(TREE_NAME)?.get(key)? else {
+            return Ok(None);
+        };
+
+        Ok(Some(serde_json::from_slice(&value)?))
+    }
+
+    /// Remove the expired entries and the oldest ones over the entry limit
+    fn evict(tree: &sled::Tree) -> anyhow::Result<()> {
+        let config = Config::global();
+        let now = unix_now();
+
+        let mut entries = vec![];
+        for item in tree {
+            let (key, value) = item?;
+
+            match serde_json::from_slice::<CachedResult>(&value) {
+                Ok(x) if now.saturating_sub(x.created_at) <= config.cache_max_age.as_secs() => {
+                    entries.push((x.created_at, key));
+                }
+                _ => {
+                    tree.remove(key)?;
+                }
+            }
+        }
+
+        if entries.len() > config.cache_max_entries {
+            entries.sort_unstable_by_key(|(created_at, _)| *created_at);
+            let excess = entries.len() - config.cache_max_entries;
+            for (_, key) in entries.into_iter().take(excess) {
+                tree.remove(key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Key of the song in the cache. Requests with the same key produce the same files.
+    pub fn key(url: &Url, options: SongOptions) -> String {
+        let mut key = format!(
+            "{}{}:{}",
+            Self::key_prefix(url),
+            options.model,
+            options.stem_mode
+        );
+
+        if let Some(trim) = options.trim {
+            key = format!("{key}:{}-{}", trim.start.as_secs(), trim.end.as_secs());
+        }
+
+        if let Some(pitch) = options.pitch {
+            key = format!("{key}:pitch{pitch:+}");
+        }
+
+        if let Some(tempo) = options.tempo {
+            key = format!("{key}:tempo{tempo}");
+        }
+
+        if let Some(guide_vocals) = options.guide_vocals {
+            key = format!("{key}:guide{guide_vocals}");
+        }
+
+        if options.loudnorm {
+            key = format!("{key}:loudnorm");
+        }
+
+        if options.zip {
+            key = format!("{key}:zip");
+        }
+
+        if options.format != OutputFormat::default() {
+            key = format!("{key}:{}", options.format);
+        }
+
+        if let Some(bitrate) = options.bitrate {
+            key = format!("{key}:{bitrate}");
+        }
+
+        let outputs = options.outputs.unwrap_or_default();
+        if outputs != OutputChoice::default() {
+            key = format!("{key}:outputs{outputs}");
+        }
+
+        key
+    }
+
+    fn key_prefix(url: &Url) -> String {
+        format!("{}\n", normalize_url(url))
+    }
+}
+
+/// Normalize the URL so that different links to the same song look the same, eg. without the
+/// fragment, tracking parameters or trailing slash
+fn normalize_url(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_fragment(None);
+
+    let query = url
+        .query_pairs()
+        .filter(|(k, _)| !k.starts_with("utm_") && !TRACKING_PARAMS.contains(&k.as_ref()))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<Vec<_>>();
+    if query.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(query);
+    }
+
+    let path = url.path().trim_end_matches('/').to_string();
+    url.set_path(&path);
+
+    url.to_string()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
@@ -0,0 +1,284 @@
+use serde::{Deserialize, Serialize};
+use teloxide::types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup};
+use tracing::{debug, trace};
+
+use crate::{
+    config::Config,
+    i18n::Lang,
+    options::SongOptions,
+    output_choice::OutputChoice,
+    processor::{
+        demucs::{DemucsModel, StemMode},
+        encoding::Bitrate,
+    },
+    store::Store,
+};
+
+const TREE_NAME: &str = "chat_settings";
+/// Prefix of the callback data of the buttons, eg. `settings:model`
+const CALLBACK_PREFIX: &str = "settings";
+
+/// Bitrates that can be chosen with the keyboard (others can still be set per song)
+const BITRATES: [Bitrate; 5] = [
+    Bitrate::Cbr(128),
+    Bitrate::Cbr(192),
+    Bitrate::Cbr(256),
+    Bitrate::Cbr(320),
+    Bitrate::Vbr(0),
+];
+
+/// One of the settings that can be changed with the keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    Model,
+    Stems,
+    Bitrate,
+    Outputs,
+    Status,
+    DeleteOriginal,
+}
+impl Setting {
+    const ALL: [Self; 6] = [
+        Self::Model,
+        Self::Stems,
+        Self::Bitrate,
+        Self::Outputs,
+        Self::Status,
+        Self::DeleteOriginal,
+    ];
+
+    const fn id(self) -> &'static str {
+        match self {
+            Self::Model => "model",
+            Self::Stems => "stems",
+            Self::Bitrate => "bitrate",
+            Self::Outputs => "outputs",
+            Self::Status => "status",
+            Self::DeleteOriginal => "delete-original",
+        }
+    }
+
+    fn label(self, lang: Lang) -> String {
+        lang.text(&format!("setting-{}", self.id()))
+    }
+}
+
+/// What a press of one of the keyboard buttons should do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingsAction {
+    /// Switch the setting to its next value
+    Change(Setting),
+    /// Go back to the global defaults
+    Reset,
+}
+
+/// Defaults of the songs sent in a chat. Options sent with the song override them, and the
+/// global config is used for the ones that aren't set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatSettings {
+    pub model: Option<DemucsModel>,
+    pub stem_mode: Option<StemMode>,
+    pub bitrate: Option<Bitrate>,
+    /// Which files are sent (asked for after the download if not set)
+    pub outputs: Option<OutputChoice>,
+    /// Language of the messages, the one of each user's app if not set
+    #[serde(default)]
+    pub language: Option<Lang>,
+    /// Whether the status message is edited into a summary of the job when it's done instead
+    /// of being deleted
+    #[serde(default)]
+    pub status_summary: Option<bool>,
+    /// Whether the messages with the links are deleted once the songs were delivered (only in
+    /// groups), they aren't if not set
+    #[serde(default)]
+    pub delete_original: Option<bool>,
+}
+impl ChatSettings {
+    /// The settings of the chat, the defaults if none were saved (or they can't be read)
+    pub fn get(chat_id: ChatId) -> Self {
+        let res = Store::tree(TREE_NAME).and_then(|tree| {
+            let Some(value) = tree.get(Self::key(chat_id))? else {
+                return Ok(None);
+            };
+
+            Ok(Some(serde_json::from_slice(&value)?))
+        });
+
+        match res {
+            Ok(x) => x.unwrap_or_default(),
+            Err(e) => {
+                debug!(?e, ?chat_id, "Failed to read chat settings");
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(self, chat_id: ChatId) -> anyhow::Result<()> {
+        trace!(?chat_id, settings = ?self, "Saving chat settings");
+        let tree = Store::tree(TREE_NAME)?;
+
+        // Chats using the defaults don't need an entry
+        if self == Self::default() {
+            tree.remove(Self::key(chat_id))?;
+        } else {
+            tree.insert(Self::key(chat_id), serde_json::to_vec(&self)?)?;
+        }
+
+        Ok(())
+    }
+
+    /// The options of songs sent without any
+    pub fn song_options(self) -> SongOptions {
+        let defaults = SongOptions::default();
+
+        SongOptions {
+            model: self.model.unwrap_or(defaults.model),
+            stem_mode: self.stem_mode.unwrap_or(defaults.stem_mode),
+            bitrate: self.bitrate,
+            outputs: self.outputs,
+            ..defaults
+        }
+    }
+
+    /// Whether the status message is kept as a summary of the job when it's done
+    pub fn status_summary(self) -> bool {
+        self.status_summary
+            .unwrap_or_else(|| Config::global().status_summary)
+    }
+
+    /// The settings after pressing the button of `setting`
+    #[must_use]
+    pub fn next(self, setting: Setting) -> Self {
+        match setting {
+            Setting::Model => Self {
+                model: next_value(self.model, &DemucsModel::ALL),
+                ..self
+            },
+            Setting::Stems => Self {
+                stem_mode: next_value(
+                    self.stem_mode,
+                    &[StemMode::TwoStem, StemMode::FourStem, StemMode::SixStem],
+                ),
+                ..self
+            },
+            Setting::Bitrate => Self {
+                bitrate: next_value(self.bitrate, &BITRATES),
+                ..self
+            },
+            Setting::Outputs => Self {
+                outputs: next_value(self.outputs, &OutputChoice::ALL),
+                ..self
+            },
+            Setting::Status => Self {
+                status_summary: next_value(self.status_summary, &[false, true]),
+                ..self
+            },
+            Setting::DeleteOriginal => Self {
+                delete_original: next_value(self.delete_original, &[true]),
+                ..self
+            },
+        }
+    }
+
+    /// Message listing the settings, shown along with the [`Self::keyboard`]
+    pub fn text(self, lang: Lang) -> String {
+        let settings = Setting::ALL
+            .map(|x| format!("{}: {}", x.label(lang), self.value_label(x, lang)))
+            .join("\n");
+
+        lang.format("settings", &[("settings", &settings)])
+    }
+
+    /// Keyboard with a button for each of the settings and one for going back to the defaults
+    pub fn keyboard(self, lang: Lang) -> InlineKeyboardMarkup {
+        let buttons = Setting::ALL
+            .map(|x| {
+                InlineKeyboardButton::callback(
+                    format!("{}: {}", x.label(lang), self.value_label(x, lang)),
+                    format!("{CALLBACK_PREFIX}:{}", x.id()),
+                )
+            })
+            .chunks(2)
+            .map(<[_]>::to_vec)
+            .chain([vec![InlineKeyboardButton::callback(
+                lang.text("settings-reset"),
+                format!("{CALLBACK_PREFIX}:reset"),
+            )]])
+            .collect::<Vec<_>>();
+
+        InlineKeyboardMarkup::new(buttons)
+    }
+
+    /// The action from the callback data of a keyboard button, if it's one of ours
+    pub fn parse_callback(callback_data: &str) -> Option<SettingsAction> {
+        let id = callback_data
+            .strip_prefix(CALLBACK_PREFIX)?
+            .strip_prefix(':')?;
+
+        if id == "reset" {
+            return Some(SettingsAction::Reset);
+        }
+
+        Setting::ALL
+            .into_iter()
+            .find(|x| x.id() == id)
+            .map(SettingsAction::Change)
+    }
+
+    fn value_label(self, setting: Setting, lang: Lang) -> String {
+        let value = match setting {
+            Setting::Model => self.model.map(|x| x.to_string()),
+            Setting::Stems => self.stem_mode.map(|x| x.to_string()),
+            Setting::Bitrate => self.bitrate.map(|x| x.to_string()),
+            Setting::Outputs => self.outputs.map(|x| x.label(lang)),
+            Setting::Status => self.status_summary.map(|x| status_label(x, lang)),
+            Setting::DeleteOriginal => self.delete_original.map(|x| on_off_label(x, lang)),
+        };
+
+        let default = |value: &(dyn std::fmt::Display + Sync)| {
+            lang.format("settings-default", &[("value", value)])
+        };
+        value.unwrap_or_else(|| match setting {
+            Setting::Model => default(&DemucsModel::default()),
+            Setting::Stems => default(&StemMode::default()),
+            Setting::Bitrate => default(&Config::global().mp3_bitrate),
+            Setting::Outputs => lang.text("settings-ask"),
+            Setting::Status => default(&status_label(Config::global().status_summary, lang)),
+            Setting::DeleteOriginal => default(&on_off_label(false, lang)),
+        })
+    }
+
+    fn key(chat_id: ChatId) -> String {
+        chat_id.to_string()
+    }
+}
+
+/// What happens to the status message when the job is done
+fn status_label(summary: bool, lang: Lang) -> String {
+    if summary {
+        lang.text("settings-status-summary")
+    } else {
+        lang.text("settings-status-delete")
+    }
+}
+
+fn on_off_label(value: bool, lang: Lang) -> String {
+    if value {
+        lang.text("settings-on")
+    } else {
+        lang.text("settings-off")
+    }
+}
+
+/// The value after `current` in `values`, going back to the default (`None`) after the last one
+fn next_value<T: Copy + PartialEq>(current: Option<T>, values: &[T]) -> Option<T> {
+    let Some(current) = current else {
+        return values.first().copied();
+    };
+
+    values
+        .iter()
+        .position(|x| *x == current)
+        .and_then(|i| values.get(i + 1))
+        .copied()
+}
@@ -0,0 +1,321 @@
+//! Processes a single song from the command line, eg. for debugging the pipeline without a bot
+//! token or a chat:
+//!
+//! ```text
+//! karaokify process <url or path> [--out <dir>] [--model <model>] [--stems <2|4|6>] [options...]
+//! ```
+//!
+//! The options are the same as the ones sent to the bot (eg. `0:45-2:10` or `pitch=-2`). Without
+//! a command the bot is started as usual.
+
+use std::path::{Path, PathBuf};
+
+use teloxide::RequestError;
+use tokio::sync::watch;
+use tracing::error;
+use url::Url;
+
+use crate::{
+    helpers::{html, id::short_id, status_message::StatusMessage},
+    notifier::Notifier,
+    options::SongOptions,
+    pipeline::Pipeline,
+    result_sink::{Delivery, ResultSink},
+    song_job::{process_song, JobContext, SongOutcome, SongRequester},
+    song_request::SongSource,
+};
+
+pub const USAGE: &str = "\
+Usage:
+  karaokify                   Run the Telegram bot
+  karaokify process <url or path> [--out <dir>] [--model <model>] [--stems <2|4|6>] [options...]
+                              Process a single song and write the files to the directory
+  karaokify help              Show this message";
+
+/// What the program was asked to do on the command line
+#[derive(Debug)]
+pub enum CliCommand {
+    /// Run the bot, the default
+    Bot,
+    /// Process a single song and exit
+    Process(ProcessArgs),
+    Help,
+}
+impl CliCommand {
+    /// Parse the arguments, without the name of the program
+    pub fn parse<I>(args: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter();
+
+        match args.next().as_deref() {
+            None => Ok(Self::Bot),
+            Some("process") => Ok(Self::Process(ProcessArgs::parse(args)?)),
+            Some("help" | "-h" | "--help") => Ok(Self::Help),
+            Some(x) => anyhow::bail!("unknown command {x:?}"),
+        }
+    }
+}
+
+/// Arguments of `karaokify process`
+#[derive(Debug)]
+pub struct ProcessArgs {
+    pub source: SongSource,
+    /// Where the processed files are written
+    pub out_dir: PathBuf,
+    pub options: SongOptions,
+}
+impl ProcessArgs {
+    fn parse<I>(args: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter();
+        let mut source = None;
+        let mut out_dir = PathBuf::from(".");
+        // Passed on as the options of the bot, eg. `--model x` is `model=x`
+        let mut options = vec![];
+
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                if source.is_none() {
+                    source = Some(arg);
+                } else {
+                    options.push(arg);
+                }
+                continue;
+            };
+
+            let (flag, value) = match flag.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), value.to_string()),
+                None => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("missing value of --{flag}"))?;
+                    (flag.to_string(), value)
+                }
+            };
+
+            match flag.as_str() {
+                "out" => out_dir = PathBuf::from(value),
+                "model" | "stems" => options.push(format!("{flag}={value}")),
+                _ => anyhow::bail!("unknown flag --{flag}"),
+            }
+        }
+
+        let Some(source) = source else {
+            anyhow::bail!("missing the URL or path of the song");
+        };
+
+        Ok(Self {
+            source: parse_source(&source)?,
+            out_dir,
+            options: SongOptions::parse(options.iter().map(String::as_str))?,
+        })
+    }
+}
+
+/// Process the song of `karaokify process` and write its files to the directory. The status
+/// messages are passed to `print_status` as plain text. Returns the exit code.
+pub async fn process<F>(args: ProcessArgs, pipeline: &dyn Pipeline, mut print_status: F) -> i32
+where
+    F: FnMut(&str) + Send + 'static,
+{
+    let ProcessArgs {
+        source,
+        out_dir,
+        mut options,
+    } = args;
+
+    let (status_tx, mut status_rx) = watch::channel(String::new());
+    let printer = tokio::spawn(async move {
+        while status_rx.changed().await.is_ok() {
+            let status = html::to_plain_text(&status_rx.borrow_and_update());
+            print_status(&status);
+        }
+    });
+    let mut msg = StatusMessage::detached(status_tx);
+
+    let requester = SongRequester {
+        user_id: None,
+        description: "CLI".to_string(),
+        job_id: short_id(),
+    };
+    let sink = DirSink::new(out_dir);
+
+    let job = JobContext {
+        requester: &requester,
+        sink: &sink,
+        pipeline,
+    };
+    let res = process_song(&mut msg, source, &mut options, &job).await;
+    // Lets the printer finish once it printed the last status
+    drop(msg);
+    let _ = printer.await;
+
+    match res {
+        Ok(SongOutcome::Processed { .. }) => 0,
+        Ok(SongOutcome::Failed(reason)) => {
+            eprintln!("{}", html::to_plain_text(&reason));
+            1
+        }
+        Err(e) => {
+            error!(?e, "Failed to process song");
+            1
+        }
+    }
+}
+
+/// Links are downloaded like the ones sent to the bot, anything else has to be a file
+fn parse_source(source: &str) -> anyhow::Result<SongSource> {
+    if let Ok(url) = Url::parse(source) {
+        if matches!(url.scheme(), "http" | "https") {
+            return Ok(SongSource::Url(url));
+        }
+    }
+
+    let path = Path::new(source);
+    if !path.is_file() {
+        anyhow::bail!("{source:?} is neither a link nor a file");
+    }
+
+    Ok(SongSource::Path(path.to_path_buf()))
+}
+
+/// Writes the files of the processed song into a directory
+#[derive(Debug)]
+pub struct DirSink {
+    dir: PathBuf,
+}
+impl DirSink {
+    pub const fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+#[async_trait::async_trait]
+impl ResultSink for DirSink {
+    fn is_interactive(&self) -> bool {
+        false
+    }
+
+    async fn deliver(
+        &self,
+        msg: &mut dyn Notifier,
+        delivery: Delivery<'_>,
+    ) -> Result<Option<Vec<String>>, RequestError> {
+        msg.update_status(&msg.lang().text("writing-files")).await?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        for file_path in delivery.files {
+            let Some(name) = file_path.file_name() else {
+                continue;
+            };
+            let out_path = self.dir.join(name);
+
+            // The directory can be on a different filesystem than the temp dir
+            if tokio::fs::rename(&file_path, &out_path).await.is_err() {
+                tokio::fs::copy(&file_path, &out_path).await?;
+                tokio::fs::remove_file(&file_path).await?;
+            }
+            println!("{}", out_path.display());
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::processor::demucs::{DemucsModel, StemMode};
+
+    fn parse(args: &[&str]) -> anyhow::Result<CliCommand> {
+        CliCommand::parse(args.iter().map(ToString::to_string))
+    }
+
+    fn process(args: &[&str]) -> ProcessArgs {
+        let mut all = vec!["process"];
+        all.extend(args);
+
+        match parse(&all) {
+            Ok(CliCommand::Process(x)) => x,
+            x => panic!("Expected a process command, got {x:?}"),
+        }
+    }
+
+    #[test]
+    fn commands() {
+        assert!(matches!(parse(&[]), Ok(CliCommand::Bot)));
+        for help in ["help", "-h", "--help"] {
+            assert!(matches!(parse(&[help]), Ok(CliCommand::Help)));
+        }
+        assert!(parse(&["bot"]).is_err());
+        assert!(parse(&["process"]).is_err());
+    }
+
+    #[test]
+    fn urls() {
+        let args = process(&["https://youtu.be/dQw4w9WgXcQ"]);
+
+        assert!(
+            matches!(&args.source, SongSource::Url(x) if x.as_str() == "https://youtu.be/dQw4w9WgXcQ")
+        );
+        assert_eq!(args.out_dir, PathBuf::from("."));
+        assert_eq!(args.options, SongOptions::default());
+    }
+
+    #[test]
+    fn files() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+
+        let args = process(&[path]);
+        assert!(matches!(&args.source, SongSource::Path(x) if x == Path::new(path)));
+
+        assert!(parse(&["process", "/no/such/song.mp3"]).is_err());
+        // Only links are downloaded
+        assert!(parse(&["process", "ftp://example.com/song.mp3"]).is_err());
+    }
+
+    #[test]
+    fn flags() {
+        let args = process(&[
+            "--out",
+            "/tmp/songs",
+            "https://youtu.be/dQw4w9WgXcQ",
+            "--model=htdemucs_ft",
+            "--stems",
+            "4",
+            "0:45-2:10",
+            "zip",
+        ]);
+
+        assert_eq!(args.out_dir, PathBuf::from("/tmp/songs"));
+        assert_eq!(args.options.model, DemucsModel::HTDemucsFt);
+        assert_eq!(args.options.stem_mode, StemMode::FourStem);
+        let trim = args.options.trim.expect("Trimmed");
+        assert_eq!(trim.start, Duration::from_secs(45));
+        assert_eq!(trim.end, Duration::from_secs(130));
+        assert!(args.options.zip);
+
+        // The last value wins
+        let args = process(&["https://youtu.be/x", "--out=a", "--out=b"]);
+        assert_eq!(args.out_dir, PathBuf::from("b"));
+    }
+
+    #[test]
+    fn invalid_flags() {
+        for args in [
+            &["process", "https://youtu.be/x", "--out"][..],
+            &["process", "https://youtu.be/x", "--verbose", "yes"],
+            &["process", "https://youtu.be/x", "--stems", "3"],
+            &["process", "https://youtu.be/x", "--model=nope"],
+            &["process", "https://youtu.be/x", "pitch=99"],
+            &["process", "--out", "dir"],
+        ] {
+            assert!(parse(args).is_err(), "{args:?}");
+        }
+    }
+}
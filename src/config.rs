@@ -0,0 +1,318 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+/// The settings of downloading and processing the songs
+pub use karaokify_core::config::{check_config_file, Config as PipelineConfig};
+use karaokify_core::config::{env_var, env_var_list, env_var_pairs, env_var_positive};
+use once_cell::sync::OnceCell;
+use teloxide::types::{ChatId, UserId};
+use url::Url;
+
+use crate::{
+    helpers::{id::secret_token, log_format::LogFormat},
+    processor::{
+        demucs::{DemucsModel, GuideVocals},
+        encoding::Bitrate,
+    },
+};
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+const DEFAULT_ARCHIVE_CAPTION: &str =
+    "<b>{title}</b>\nRequested by {requester}\nModel: {model}\n{date}";
+
+#[derive(Debug)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Config {
+    /// How many songs can be downloaded at the same time
+    pub max_concurrent_downloads: usize,
+    /// How many songs can be processed (split into stems) at the same time
+    pub max_concurrent_processing: usize,
+    /// URL of the Telegram Bot API server (eg. a self-hosted `telegram-bot-api`)
+    pub telegram_api_url: Option<Url>,
+    /// Maximum size of a single upload request to Telegram (in bytes)
+    pub max_payload_size: u64,
+    /// Maximum number of tracks that are processed from an album or playlist
+    pub max_playlist_tracks: usize,
+    /// Maximum number of links that are looked at in a single message, supported or not
+    pub max_message_links: usize,
+    /// Whether songs are searched for by name when the message doesn't contain a link
+    pub search: bool,
+    /// Minimum time between two songs submitted by the same user
+    pub min_submission_interval: Option<Duration>,
+    /// Maximum number of songs a user can submit in 24 hours
+    pub daily_quota: Option<usize>,
+    /// Users that aren't affected by the rate limits
+    pub admin_ids: Vec<UserId>,
+    /// Chat where failed jobs are reported
+    pub admin_chat_id: Option<ChatId>,
+    /// Chat (eg. a private channel) where every processed song is posted too
+    pub archive_chat_id: Option<ChatId>,
+    /// Caption of the songs posted to the archive chat, with the `{title}`, `{requester}`,
+    /// `{model}` and `{date}` placeholders
+    pub archive_caption: String,
+    /// Group chats where the bot handles every message, not just the ones mentioning it
+    pub group_chat_ids: Vec<ChatId>,
+    /// Directory where persistent data (eg. the result cache) is stored
+    pub data_dir: PathBuf,
+    /// Maximum number of processed songs that are kept in the cache
+    pub cache_max_entries: usize,
+    /// Maximum number of jobs that are kept in the history of each user
+    pub history_max_entries: usize,
+    /// How long processed songs are kept in the cache
+    pub cache_max_age: Duration,
+    /// How long running jobs get to finish when the bot is shutting down
+    pub shutdown_grace_period: Duration,
+    /// Where the processed files are kept instead of being deleted with the temp files, if
+    /// anywhere
+    pub output_dir: Option<OutputDirConfig>,
+    /// How often the leftover temp files are removed (besides at startup)
+    pub temp_cleanup_interval: Option<Duration>,
+    /// Demucs models that are run once at startup so their weights are downloaded and cached
+    pub prewarm_models: Vec<DemucsModel>,
+    /// How many times longer than the song processing takes with each model (eg.
+    /// `htdemucs=1.5,htdemucs_ft=6`). Used for the initial processing time estimates.
+    pub processing_ratios: Vec<(DemucsModel, f64)>,
+    /// How loud the vocals are in the guide mix unless the user asks for something else
+    pub guide_vocals: GuideVocals,
+    /// Whether the loudness of all the files is normalized before they're uploaded
+    pub loudnorm: bool,
+    /// Bitrate of MP3 files unless the user asks for something else
+    pub mp3_bitrate: Bitrate,
+    /// Whether a short preview of the instrumental is sent before the whole song is processed
+    pub preview: bool,
+    /// Whether songs are checked for vocals before they're processed, so the user can skip the
+    /// ones that look instrumental already
+    pub instrumental_check: bool,
+    /// Whether the lyrics of the song are looked up on LRCLIB and sent along with the files
+    pub lyrics: bool,
+    /// Whether the key and tempo of the song are detected and sent along with the files
+    pub analysis: bool,
+    /// Whether the status message is edited into a summary of the job when it's done instead
+    /// of being deleted, unless the chat changed it in its settings
+    pub status_summary: bool,
+    /// Songs aren't downloaded if there's less free space than this (in bytes) for temp files
+    pub min_free_disk_space: u64,
+    /// Address the Prometheus metrics are served on, nothing is served if it's not set
+    pub metrics_addr: Option<SocketAddr>,
+    /// How the log lines are written
+    pub log_format: LogFormat,
+    /// Address the health checks are served on, can be the same as `metrics_addr`
+    pub health_addr: Option<SocketAddr>,
+    /// The bot is considered hung if it wasn't in contact with Telegram for this long
+    pub health_max_silence: Duration,
+    /// The API for submitting songs without Telegram, if it's enabled
+    pub api: Option<ApiConfig>,
+    /// Who can use the bot, besides the ones allowed or blocked with the admin commands
+    pub access: AccessConfig,
+    /// Where the updates are received if Telegram sends them instead of them being polled for
+    pub webhook: Option<WebhookConfig>,
+}
+
+/// Settings of the API for submitting songs without Telegram
+#[derive(Debug)]
+pub struct ApiConfig {
+    /// Where the API listens (`KARAOKIFY_API_ADDR`)
+    pub listen_addr: SocketAddr,
+    /// Has to be sent as a bearer token with every request (`KARAOKIFY_API_TOKEN`)
+    pub token: String,
+    /// How long the files of a finished job can be downloaded (`KARAOKIFY_API_FILES_TTL_MINS`)
+    pub files_ttl: Duration,
+}
+impl ApiConfig {
+    fn from_env() -> Option<Self> {
+        let listen_addr = env_var("KARAOKIFY_API_ADDR")?;
+        let token = env_var("KARAOKIFY_API_TOKEN");
+        assert!(
+            token.is_some(),
+            "KARAOKIFY_API_TOKEN must be set if KARAOKIFY_API_ADDR is"
+        );
+
+        Some(Self {
+            listen_addr,
+            token: token?,
+            files_ttl: Duration::from_secs(
+                env_var_positive("KARAOKIFY_API_FILES_TTL_MINS").unwrap_or(60) as u64 * 60,
+            ),
+        })
+    }
+}
+/// Settings of receiving the updates with a webhook instead of polling for them
+#[derive(Debug)]
+pub struct WebhookConfig {
+    /// Public HTTPS URL Telegram sends the updates to (`KARAOKIFY_WEBHOOK_URL`)
+    pub url: Url,
+    /// Where the updates are received, eg. behind a reverse proxy (`KARAOKIFY_WEBHOOK_ADDR`).
+    /// Has to be different from the metrics and health check addresses.
+    pub listen_addr: SocketAddr,
+    /// Sent along with every update so nobody else can send fake ones
+    /// (`KARAOKIFY_WEBHOOK_SECRET`, a random one if it's not set)
+    pub secret: String,
+}
+impl WebhookConfig {
+    fn from_env() -> Option<Self> {
+        let url = env_var("KARAOKIFY_WEBHOOK_URL");
+        let listen_addr = env_var("KARAOKIFY_WEBHOOK_ADDR");
+        assert_eq!(
+            url.is_some(),
+            listen_addr.is_some(),
+            "KARAOKIFY_WEBHOOK_URL and KARAOKIFY_WEBHOOK_ADDR must be set together"
+        );
+
+        let secret = env_var("KARAOKIFY_WEBHOOK_SECRET").unwrap_or_else(secret_token);
+        assert!(
+            (1..=256).contains(&secret.len())
+                && secret
+                    .bytes()
+                    .all(|x| x.is_ascii_alphanumeric() || matches!(x, b'_' | b'-')),
+            "KARAOKIFY_WEBHOOK_SECRET must be 1-256 characters long and only contain A-Z, a-z, \
+             0-9, _ and -"
+        );
+
+        Some(Self {
+            url: url?,
+            listen_addr: listen_addr?,
+            secret,
+        })
+    }
+}
+
+/// Who can use the bot. It's open to everyone unless there are allowed chats or users.
+#[derive(Debug)]
+pub struct AccessConfig {
+    /// Chats where anybody can use the bot (`KARAOKIFY_ALLOWED_CHATS`)
+    pub allowed_chats: Vec<ChatId>,
+    /// Users who can use the bot in any chat (`KARAOKIFY_ALLOWED_USERS`)
+    pub allowed_users: Vec<UserId>,
+    /// Users who can't use the bot, even in allowed chats (`KARAOKIFY_BLOCKED_USERS`)
+    pub blocked_users: Vec<UserId>,
+}
+impl AccessConfig {
+    fn from_env() -> Self {
+        let users = |name| env_var_list::<u64>(name).into_iter().map(UserId).collect();
+
+        Self {
+            allowed_chats: env_var_list::<i64>("KARAOKIFY_ALLOWED_CHATS")
+                .into_iter()
+                .map(ChatId)
+                .collect(),
+            allowed_users: users("KARAOKIFY_ALLOWED_USERS"),
+            blocked_users: users("KARAOKIFY_BLOCKED_USERS"),
+        }
+    }
+}
+
+/// Settings of keeping the processed files on disk
+#[derive(Debug)]
+pub struct OutputDirConfig {
+    /// Directory the files are moved into (`KARAOKIFY_OUTPUT_DIR`)
+    pub path: PathBuf,
+    /// The oldest kept files are removed when the directory gets larger than this
+    /// (`KARAOKIFY_OUTPUT_DIR_MAX_GB`)
+    pub max_size: Option<u64>,
+}
+impl OutputDirConfig {
+    fn from_env() -> Option<Self> {
+        Some(Self {
+            path: env_var("KARAOKIFY_OUTPUT_DIR")?,
+            max_size: env_var_positive("KARAOKIFY_OUTPUT_DIR_MAX_GB")
+                .map(|x| x as u64 * 1000 * 1000 * 1000),
+        })
+    }
+}
+impl Config {
+    pub fn global() -> &'static Self {
+        CONFIG.get_or_init(Self::from_env)
+    }
+
+    fn from_env() -> Self {
+        let max_concurrent_jobs = env_var_positive("KARAOKIFY_MAX_CONCURRENT_JOBS").unwrap_or(1);
+        let max_payload_mb = env_var_positive("KARAOKIFY_MAX_PAYLOAD_MB").unwrap_or(50);
+        Self {
+            max_concurrent_downloads: env_var_positive("KARAOKIFY_MAX_CONCURRENT_DOWNLOADS")
+                .unwrap_or(max_concurrent_jobs),
+            max_concurrent_processing: env_var_positive("KARAOKIFY_MAX_CONCURRENT_PROCESSING")
+                .unwrap_or(max_concurrent_jobs),
+            telegram_api_url: env_var("TELEGRAM_API_URL"),
+            max_payload_size: max_payload_mb as u64 * 1000 * 1000,
+            max_playlist_tracks: env_var_positive("KARAOKIFY_MAX_PLAYLIST_TRACKS").unwrap_or(10),
+            max_message_links: env_var_positive("KARAOKIFY_MAX_MESSAGE_LINKS").unwrap_or(5),
+            search: env_var("KARAOKIFY_SEARCH").unwrap_or_default(),
+            min_submission_interval: env_var_positive("KARAOKIFY_MIN_INTERVAL_SECS")
+                .map(|x| Duration::from_secs(x as u64)),
+            daily_quota: env_var_positive("KARAOKIFY_DAILY_QUOTA"),
+            admin_ids: env_var_list::<u64>("KARAOKIFY_ADMIN_IDS")
+                .into_iter()
+                .map(UserId)
+                .collect(),
+            admin_chat_id: env_var("KARAOKIFY_ADMIN_CHAT_ID").map(ChatId),
+            archive_chat_id: env_var("KARAOKIFY_ARCHIVE_CHAT_ID").map(ChatId),
+            archive_caption: archive_caption_from_env(),
+            group_chat_ids: env_var_list::<i64>("KARAOKIFY_GROUP_CHAT_IDS")
+                .into_iter()
+                .map(ChatId)
+                .collect(),
+            data_dir: env_var("KARAOKIFY_DATA_DIR").unwrap_or_else(|| PathBuf::from("data")),
+            cache_max_entries: env_var_positive("KARAOKIFY_CACHE_MAX_ENTRIES").unwrap_or(1000),
+            history_max_entries: env_var_positive("KARAOKIFY_HISTORY_MAX_ENTRIES").unwrap_or(20),
+            cache_max_age: Duration::from_secs(
+                env_var_positive("KARAOKIFY_CACHE_MAX_AGE_DAYS").unwrap_or(30) as u64 * 86_400,
+            ),
+            shutdown_grace_period: Duration::from_secs(
+                env_var("KARAOKIFY_SHUTDOWN_GRACE_SECS").unwrap_or(60),
+            ),
+            output_dir: OutputDirConfig::from_env(),
+            temp_cleanup_interval: env_var_positive("KARAOKIFY_TEMP_CLEANUP_INTERVAL_HOURS")
+                .map(|x| Duration::from_secs(x as u64 * 60 * 60)),
+            prewarm_models: env_var_list("KARAOKIFY_PREWARM_MODELS"),
+            processing_ratios: processing_ratios_from_env(),
+            guide_vocals: env_var("KARAOKIFY_GUIDE_VOCALS_DB").unwrap_or(GuideVocals::Level(-20)),
+            loudnorm: env_var("KARAOKIFY_LOUDNORM").unwrap_or_default(),
+            mp3_bitrate: env_var("KARAOKIFY_MP3_BITRATE").unwrap_or_default(),
+            preview: env_var("KARAOKIFY_PREVIEW").unwrap_or(true),
+            instrumental_check: env_var("KARAOKIFY_INSTRUMENTAL_CHECK").unwrap_or(true),
+            lyrics: env_var("KARAOKIFY_LYRICS").unwrap_or(false),
+            analysis: env_var("KARAOKIFY_ANALYSIS").unwrap_or(false),
+            status_summary: env_var("KARAOKIFY_STATUS_SUMMARY").unwrap_or(false),
+            min_free_disk_space: env_var("KARAOKIFY_MIN_FREE_DISK_MB").unwrap_or(2000)
+                * 1000
+                * 1000,
+            metrics_addr: env_var("KARAOKIFY_METRICS_ADDR"),
+            log_format: env_var("KARAOKIFY_LOG_FORMAT").unwrap_or_default(),
+            health_addr: env_var("KARAOKIFY_HEALTH_ADDR"),
+            health_max_silence: Duration::from_secs(
+                env_var_positive("KARAOKIFY_HEALTH_MAX_SILENCE_MINS").unwrap_or(5) as u64 * 60,
+            ),
+            api: ApiConfig::from_env(),
+            access: AccessConfig::from_env(),
+            webhook: WebhookConfig::from_env(),
+        }
+    }
+
+    /// The configured processing time ratio for the model, or its default one
+    pub fn processing_ratio(&self, model: DemucsModel) -> f64 {
+        self.processing_ratios
+            .iter()
+            .find(|(x, _)| *x == model)
+            .map_or_else(|| model.default_processing_ratio(), |(_, ratio)| *ratio)
+    }
+}
+
+/// Environment variables can't easily contain line breaks, so `\n` is one
+fn archive_caption_from_env() -> String {
+    env_var::<String>("KARAOKIFY_ARCHIVE_CAPTION").map_or_else(
+        || DEFAULT_ARCHIVE_CAPTION.to_string(),
+        |x| x.replace("\\n", "\n"),
+    )
+}
+
+fn processing_ratios_from_env() -> Vec<(DemucsModel, f64)> {
+    let processing_ratios = env_var_pairs("KARAOKIFY_PROCESSING_RATIOS");
+    for (model, ratio) in &processing_ratios {
+        assert!(
+            *ratio > 0.0,
+            "KARAOKIFY_PROCESSING_RATIOS ratio for {model} must be greater than 0"
+        );
+    }
+
+    processing_ratios
+}
@@ -0,0 +1,114 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use tracing::trace;
+
+use crate::{
+    config::Config,
+    helpers::duration::{format_estimate, format_timestamp},
+    i18n::Lang,
+    processor::demucs::DemucsModel,
+};
+
+/// How many of the latest processing time ratios are averaged for each model
+const MAX_SAMPLES: usize = 20;
+
+/// Latest ratios of processing time to song duration for each model, oldest first
+static SAMPLES: Lazy<Mutex<HashMap<DemucsModel, VecDeque<f64>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Estimate of how long processing a song will take.
+///
+/// Starts out with the configured ratio for the model and corrects itself using the rolling
+/// average of how long songs actually took to process.
+#[derive(Debug)]
+pub struct ProcessingEta {
+    model: DemucsModel,
+    song_duration: Option<Duration>,
+    estimate: Option<Duration>,
+    started_at: Instant,
+    started_at_clock: String,
+}
+impl ProcessingEta {
+    /// Start estimating processing of a song that's `song_duration` long
+    pub fn start(model: DemucsModel, song_duration: Option<Duration>) -> Self {
+        let estimate = song_duration.map(|x| x.mul_f64(Self::ratio(model)));
+        trace!(
+            ?model,
+            ?song_duration,
+            ?estimate,
+            "Estimated processing time"
+        );
+
+        Self {
+            model,
+            song_duration,
+            estimate,
+            started_at: Instant::now(),
+            started_at_clock: chrono::Local::now().format("%H:%M").to_string(),
+        }
+    }
+
+    /// Remember how long processing took so future estimates are more accurate
+    pub fn finish(self) {
+        let Some(song_duration) = self.song_duration.filter(|x| !x.is_zero()) else {
+            return;
+        };
+
+        let ratio = self.started_at.elapsed().as_secs_f64() / song_duration.as_secs_f64();
+        trace!(model = ?self.model, ?ratio, "Recording processing time ratio");
+
+        let mut samples = SAMPLES.lock().expect("Processing samples lock poisoned");
+        let model_samples = samples.entry(self.model).or_default();
+        model_samples.push_back(ratio);
+        if model_samples.len() > MAX_SAMPLES {
+            model_samples.pop_front();
+        }
+        drop(samples);
+    }
+
+    /// Status text for the user, eg. `Processing song (3:45)... 42%` followed by the ETA.
+    ///
+    /// Falls back to a generic message if the song duration isn't known.
+    pub fn status_text(&self, percent: Option<u8>, lang: Lang) -> String {
+        let percent = percent.map(|x| format!(" {x}%")).unwrap_or_default();
+
+        let (Some(song_duration), Some(estimate)) = (self.song_duration, self.estimate) else {
+            return lang.format("processing-unknown-eta", &[("percent", &percent)]);
+        };
+
+        let remaining = estimate.saturating_sub(self.started_at.elapsed());
+        let eta = if remaining.is_zero() {
+            lang.text("eta-almost-done")
+        } else {
+            lang.format("eta", &[("estimate", &format_estimate(remaining))])
+        };
+
+        lang.format(
+            "processing",
+            &[
+                ("duration", &format_timestamp(song_duration)),
+                ("percent", &percent),
+                ("eta", &eta),
+                ("started", &self.started_at_clock),
+            ],
+        )
+    }
+
+    /// Expected ratio of processing time to song duration for the model
+    fn ratio(model: DemucsModel) -> f64 {
+        let samples = SAMPLES.lock().expect("Processing samples lock poisoned");
+        #[allow(clippy::cast_precision_loss)]
+        let average = samples
+            .get(&model)
+            .filter(|x| !x.is_empty())
+            .map(|x| x.iter().sum::<f64>() / x.len() as f64);
+        drop(samples);
+
+        average.unwrap_or_else(|| Config::global().processing_ratio(model))
+    }
+}
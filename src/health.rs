@@ -0,0 +1,67 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use teloxide::requests::Requester;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, trace};
+
+use crate::{bot::TelegramBot, config::Config};
+
+/// Last time the bot got something from Telegram or successfully sent something to it
+static LAST_CONTACT: Lazy<Mutex<Instant>> = Lazy::new(|| Mutex::new(Instant::now()));
+/// Whether the startup checks succeeded
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// How often Telegram is called to check that the bot can still reach it
+const SELF_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Liveness and readiness of the bot, served on `/healthz` and `/readyz` if
+/// `KARAOKIFY_HEALTH_ADDR` is set
+pub struct Health;
+impl Health {
+    /// The bot got an update from Telegram or a request to it succeeded
+    pub fn record_contact() {
+        *LAST_CONTACT.lock().expect("Health lock poisoned") = Instant::now();
+    }
+
+    /// The startup checks (programs and Telegram) succeeded
+    pub fn set_ready() {
+        READY.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_ready() -> bool {
+        READY.load(Ordering::Relaxed)
+    }
+
+    /// Whether the bot was in contact with Telegram recently
+    pub fn is_alive() -> bool {
+        LAST_CONTACT.lock().expect("Health lock poisoned").elapsed()
+            <= Config::global().health_max_silence
+    }
+
+    /// Periodically call Telegram so quiet periods without updates aren't mistaken for a hung
+    /// bot
+    pub async fn self_check() {
+        let mut interval = tokio::time::interval(SELF_CHECK_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            // The adapted bot caches `getMe`, so it wouldn't actually call Telegram
+            match TelegramBot::raw().get_me().await {
+                Ok(_) => {
+                    trace!("Health self-check succeeded");
+                    Self::record_contact();
+                }
+                Err(e) => debug!(?e, "Health self-check failed"),
+            }
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::path::{Path, PathBuf};
+
+use tracing::trace;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+/// Creates archives of the processed files
+pub struct Archive;
+impl Archive {
+    /// Package the files into a zip at `zip_path`, using their file names as the names in the
+    /// archive.
+    ///
+    /// The files aren't compressed since audio barely gets any smaller anyway.
+    #[tracing::instrument(skip(file_paths))]
+    pub async fn create_zip(zip_path: &Path, file_paths: &[PathBuf]) -> anyhow::Result<()> {
+        trace!(?file_paths, "Creating zip");
+
+        let zip_path = zip_path.to_path_buf();
+        let file_paths = file_paths.to_vec();
+        tokio::task::spawn_blocking(move || {
+            let mut zip = ZipWriter::new(std::fs::File::create(zip_path)?);
+            let options = SimpleFileOptions::default()
+                .compression_method(CompressionMethod::Stored)
+                .large_file(true);
+
+            for file_path in file_paths {
+                let file_name = file_path
+                    .file_name()
+                    .ok_or_else(|| anyhow::anyhow!("file {file_path:?} has no name"))?
+                    .to_string_lossy()
+                    .to_string();
+
+                zip.start_file(file_name, options)?;
+                std::io::copy(&mut std::fs::File::open(&file_path)?, &mut zip)?;
+            }
+
+            zip.finish()?;
+
+            Ok(())
+        })
+        .await?
+    }
+}
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+/// Format the duration for the user, eg. `2h 5m` or `30s`
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs().max(1);
+    let (days, hours, mins, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+
+    match (days, hours, mins) {
+        (0, 0, 0) => format!("{secs}s"),
+        (0, 0, _) => format!("{mins}m {secs}s"),
+        (0, _, _) => format!("{hours}h {mins}m"),
+        _ => format!("{days}d {hours}h"),
+    }
+}
+
+/// Format the duration like a timestamp, eg. `3:45` or `1:02:03`
+pub fn format_timestamp(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, mins, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+    if hours > 0 {
+        format!("{hours}:{mins:02}:{secs:02}")
+    } else {
+        format!("{mins}:{secs:02}")
+    }
+}
+
+/// Parse a timestamp like `3:45` or `1:02:03`
+pub fn parse_timestamp(timestamp: &str) -> Option<Duration> {
+    let mut parts = timestamp.split(':').rev();
+    let secs = parts.next()?.parse::<u64>().ok()?;
+    let mins = parts.next()?.parse::<u64>().ok()?;
+    let hours = parts.next().map_or(Some(0), |x| x.parse::<u64>().ok())?;
+
+    if parts.next().is_some() || secs >= 60 || (hours > 0 && mins >= 60) {
+        return None;
+    }
+
+    Some(Duration::from_secs(hours * 3600 + mins * 60 + secs))
+}
+
+/// Format the duration as a rough estimate, eg. `~7 min` or `~1h 20m`
+pub fn format_estimate(duration: Duration) -> String {
+    let mins = duration.as_secs().div_ceil(60).max(1);
+
+    if mins < 60 {
+        format!("~{mins} min")
+    } else {
+        format!("~{}h {}m", mins / 60, mins % 60)
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt::Display;
+
+pub use karaokify_core::helpers::html::unescape;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// Maximum length (in characters) of a Telegram message
+pub const MAX_MESSAGE_LENGTH: usize = 4096;
+
+/// Maximum length of a single interpolated value (eg. an error reason), so that a few of them
+/// still fit into a message
+const MAX_VALUE_LENGTH: usize = 1000;
+
+static TAG: Lazy<Regex> = Lazy::new(|| Regex::new(r"<[^>]*>").expect("Invalid regex"));
+
+/// Escape the text so it can be safely put into an HTML formatted message
+pub fn escape(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '&' => res.push_str("&amp;"),
+            c => res.push(c),
+        }
+    }
+
+    res
+}
+
+/// Shorten the text to at most `max_chars` characters (including the trailing ellipsis)
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+
+    let mut res = text
+        .chars()
+        .take(max_chars.saturating_sub(1))
+        .collect::<String>();
+    res.push('…');
+
+    res
+}
+
+/// Escape user-controlled text (eg. file names or errors from remote services) for a message,
+/// shortening it if it's too long
+pub fn escape_value(text: impl Display) -> String {
+    escape(&truncate(&text.to_string(), MAX_VALUE_LENGTH))
+}
+
+/// The text of an HTML formatted message without the formatting, eg. for showing a status
+/// outside of Telegram
+pub fn to_plain_text(text: &str) -> String {
+    unescape(&TAG.replace_all(text, "")).trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nasty_file_names() {
+        for (text, escaped) in [
+            ("<b>lol.mp3", "&lt;b&gt;lol.mp3"),
+            ("</code>.music.mp3", "&lt;/code&gt;.music.mp3"),
+            ("Tom & Jerry.mp3", "Tom &amp; Jerry.mp3"),
+            ("&amp;.mp3", "&amp;amp;.mp3"),
+            ("line\nbreak<br>.mp3", "line\nbreak&lt;br&gt;.mp3"),
+            (
+                "<a href=\"https://evil.example\">x</a>",
+                "&lt;a href=\"https://evil.example\"&gt;x&lt;/a&gt;",
+            ),
+            (
+                "Motörhead — Ace of Spades 🂡.flac",
+                "Motörhead — Ace of Spades 🂡.flac",
+            ),
+            ("", ""),
+        ] {
+            assert_eq!(escape(text), escaped, "{text:?}");
+            assert_eq!(unescape(&escape(text)), text, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn truncate_at_char_boundaries() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("exactly10!", 10), "exactly10!");
+        assert_eq!(truncate("a bit too long", 10), "a bit too…");
+        // Multibyte characters are counted as one and never split
+        assert_eq!(truncate("žšćčđžšćčđž", 10), "žšćčđžšćč…");
+        assert_eq!(truncate("🎤🎤🎤🎤", 3), "🎤🎤…");
+        assert_eq!(truncate("e\u{301}e\u{301}e\u{301}", 4), "e\u{301}e…");
+        assert_eq!(truncate("anything", 1), "…");
+        assert_eq!(truncate("anything", 0), "…");
+    }
+
+    #[test]
+    fn values_are_truncated_before_escaping() {
+        let value = escape_value("<".repeat(MAX_VALUE_LENGTH * 2));
+
+        // The entities aren't cut in half
+        assert_eq!(value, format!("{}…", "&lt;".repeat(MAX_VALUE_LENGTH - 1)));
+        assert_eq!(escape_value(42), "42");
+        assert_eq!(escape_value("a < b"), "a &lt; b");
+    }
+
+    #[test]
+    fn plain_text() {
+        assert_eq!(
+            to_plain_text("<b>Downloading</b> <i>Tom &amp; Jerry</i>: 50%\n"),
+            "Downloading Tom & Jerry: 50%"
+        );
+        assert_eq!(to_plain_text(&escape("<b>lol")), "<b>lol");
+        assert_eq!(
+            to_plain_text("<a href=\"https://example.com\">link</a> &lt;3"),
+            "link <3"
+        );
+    }
+}
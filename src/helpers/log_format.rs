@@ -0,0 +1,170 @@
+use std::{fmt, str::FromStr};
+
+use chrono::{SecondsFormat, Utc};
+use serde_json::{Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span::Record,
+    Event, Subscriber,
+};
+use tracing_subscriber::{
+    field::RecordFields,
+    filter::Builder as TracingFilterBuilder,
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+};
+
+use crate::config::Config;
+
+/// How the log lines are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable, with colours
+    #[default]
+    Pretty,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => anyhow::bail!("unknown log format {s:?}, expected one of: pretty, json"),
+        }
+    }
+}
+
+/// Log to stdout in the `KARAOKIFY_LOG_FORMAT`, at the levels set by `RUST_LOG` (`info` by
+/// default)
+pub fn init_log() {
+    let builder = tracing_subscriber::fmt().with_env_filter(
+        TracingFilterBuilder::default()
+            .with_default_directive(LevelFilter::INFO.into())
+            .from_env_lossy(),
+    );
+
+    match Config::global().log_format {
+        LogFormat::Pretty => builder.with_ansi(true).finish().init(),
+        LogFormat::Json => builder
+            .with_ansi(false)
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .finish()
+            .init(),
+    }
+}
+
+/// Writes each event as a JSON object with the fields of the event and of the spans it's in
+pub struct JsonFormat;
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let meta = event.metadata();
+
+        let mut fields = JsonVisitor::default();
+        event.record(&mut fields);
+        let message = fields.0.remove("message");
+
+        let mut res = Map::new();
+        res.insert(
+            "timestamp".into(),
+            Utc::now()
+                .to_rfc3339_opts(SecondsFormat::Micros, true)
+                .into(),
+        );
+        res.insert("level".into(), meta.level().as_str().into());
+        res.insert("target".into(), meta.target().into());
+        if let Some(message) = message {
+            res.insert("message".into(), message);
+        }
+        res.insert("fields".into(), Value::Object(fields.0));
+
+        if let Some(scope) = ctx.event_scope() {
+            let spans = scope
+                .from_root()
+                .map(|span| {
+                    let mut res = span
+                        .extensions()
+                        .get::<FormattedFields<N>>()
+                        .and_then(|x| serde_json::from_str::<Map<String, Value>>(&x.fields).ok())
+                        .unwrap_or_default();
+                    res.insert("name".into(), span.name().into());
+
+                    Value::Object(res)
+                })
+                .collect();
+            res.insert("spans".into(), Value::Array(spans));
+        }
+
+        writeln!(writer, "{}", Value::Object(res))
+    }
+}
+
+/// Formats the fields of spans as JSON objects, so [`JsonFormat`] can include them
+pub struct JsonFields;
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut res = JsonVisitor::default();
+        fields.record(&mut res);
+
+        write!(writer, "{}", Value::Object(res.0))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &Record<'_>,
+    ) -> fmt::Result {
+        let mut res = JsonVisitor(serde_json::from_str(&current.fields).unwrap_or_default());
+        fields.record(&mut res);
+        current.fields = Value::Object(res.0).to_string();
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct JsonVisitor(Map<String, Value>);
+impl Visit for JsonVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{value:?}").into());
+    }
+}
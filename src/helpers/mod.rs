@@ -1,7 +1,13 @@
-pub mod domain;
-pub mod download;
-pub mod header;
-pub mod id;
+// The helpers of the pipeline are in `karaokify-core`, the ones here are only used by the bot
+pub use karaokify_core::helpers::*;
+
+pub mod archive;
+pub mod duration;
+pub mod html;
+pub mod log_format;
+pub mod output_dir;
+pub mod retry;
 pub mod status_message;
-pub mod temp_dir;
-pub mod temp_file;
+pub mod telegram_file;
+pub mod topic;
+pub mod url_normalize;
@@ -0,0 +1,155 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use tracing::{debug, info, trace, warn};
+
+use super::{file_name::sanitize_file_name, temp_cleanup::disk_usage};
+use crate::config::Config;
+
+/// Held while files are moved into the directory or it's pruned, so concurrent jobs don't prune
+/// each other's files while they're being moved
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Keeps the processed files of the jobs in `KARAOKIFY_OUTPUT_DIR` instead of deleting them
+/// along with the temp directory of the job
+pub struct OutputDir;
+impl OutputDir {
+    /// Move the files into `<output dir>/<date>/<title>-<job id>/` if the output directory is
+    /// configured, then remove the oldest jobs if the directory is over its size limit.
+    ///
+    /// The user already has the files, so failures are only logged.
+    pub async fn persist(file_paths: &[PathBuf], title: &str, job_id: &str) {
+        let Some(config) = &Config::global().output_dir else {
+            return;
+        };
+        if file_paths.is_empty() {
+            return;
+        }
+
+        let file_paths = file_paths.to_vec();
+        let name = format!("{}-{job_id}", sanitize_file_name(title));
+        let date = chrono::Local::now().format("%Y-%m-%d").to_string();
+
+        let res = tokio::task::spawn_blocking(move || {
+            let _lock = LOCK.lock().expect("Output dir lock poisoned");
+
+            let dir = move_files(&file_paths, &config.path.join(date), &name)?;
+            if let Some(max_size) = config.max_size {
+                prune(&config.path, max_size, &dir)?;
+            }
+
+            Ok::<_, io::Error>(dir)
+        })
+        .await;
+
+        match res {
+            Ok(Ok(dir)) => info!(?dir, "Kept processed files in the output directory"),
+            Ok(Err(e)) => warn!(?e, "Failed to keep processed files"),
+            Err(e) => warn!(?e, "Keeping processed files panicked"),
+        }
+    }
+}
+
+/// Move the files into a new directory called `name` in `parent`.
+///
+/// A number is added to the name if the directory exists already, eg. `Song-abc123-2`.
+fn move_files(file_paths: &[PathBuf], parent: &Path, name: &str) -> io::Result<PathBuf> {
+    fs::create_dir_all(parent)?;
+
+    let mut dir = parent.join(name);
+    let mut n = 1;
+    loop {
+        match fs::create_dir(&dir) {
+            Ok(()) => break,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                n += 1;
+                dir = parent.join(format!("{name}-{n}"));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    for file_path in file_paths {
+        let Some(file_name) = file_path.file_name() else {
+            continue;
+        };
+
+        move_file(file_path, &dir.join(file_name))?;
+    }
+
+    Ok(dir)
+}
+
+/// Rename the file, copying it instead if it's on a different filesystem (eg. a network share)
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    trace!(?from, ?to, "Renaming failed, copying the file instead");
+    fs::copy(from, to)?;
+    fs::remove_file(from)
+}
+
+/// Remove the oldest job directories until the output directory is at most `max_size` bytes,
+/// never removing the one that was just created
+fn prune(root: &Path, max_size: u64, keep: &Path) -> io::Result<()> {
+    let mut jobs = vec![];
+    for date_dir in fs::read_dir(root)? {
+        let date_dir = date_dir?.path();
+        if !date_dir.is_dir() {
+            continue;
+        }
+
+        for job_dir in fs::read_dir(&date_dir)? {
+            let job_dir = job_dir?;
+            let modified = job_dir
+                .metadata()
+                .and_then(|x| x.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            let path = job_dir.path();
+            let size = disk_usage(&path).unwrap_or_default();
+
+            jobs.push((modified, path, size));
+        }
+    }
+
+    let mut total = jobs.iter().map(|(_, _, size)| size).sum::<u64>();
+    jobs.sort_by_key(|(modified, _, _)| *modified);
+
+    for (_, path, size) in jobs {
+        if total <= max_size {
+            break;
+        }
+        if path == keep {
+            continue;
+        }
+
+        let res = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        match res {
+            Ok(()) => {
+                debug!(
+                    ?path,
+                    size, "Removed old output to stay under the size limit"
+                );
+                total = total.saturating_sub(size);
+            }
+            Err(e) => warn!(?e, ?path, "Failed to remove old output"),
+        }
+
+        // Empty date directories aren't needed anymore
+        if let Some(date_dir) = path.parent() {
+            let _ = fs::remove_dir(date_dir);
+        }
+    }
+
+    Ok(())
+}
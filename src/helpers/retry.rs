@@ -0,0 +1,186 @@
+use std::{future::Future, time::Duration};
+
+use teloxide::RequestError;
+use tracing::debug;
+
+use crate::metrics::Metrics;
+
+/// How many times a request is retried after Telegram tells us to slow down
+const MAX_RETRIES: usize = 3;
+/// How many times a request is sent in total if it keeps failing because of the network
+const MAX_ATTEMPTS: u32 = 3;
+/// How long to wait before sending the request again, multiplied by the failed attempts
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Run the request, waiting and retrying it if Telegram responds with `RetryAfter`.
+///
+/// Other errors are returned immediately.
+pub async fn retry_after<F, Fut, T>(mut request: F) -> Result<T, RequestError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RequestError>>,
+{
+    let mut retries = 0;
+
+    loop {
+        match request().await {
+            Err(RequestError::RetryAfter(wait)) if retries < MAX_RETRIES => {
+                retries += 1;
+                debug!(?wait, ?retries, "Rate limited by Telegram, retrying");
+                tokio::time::sleep(wait).await;
+            }
+
+            res => {
+                if let Err(e) = &res {
+                    Metrics::telegram_error(e);
+                }
+                return res;
+            }
+        }
+    }
+}
+
+/// Run the request like [`retry_after`], also sending it again (up to [`MAX_ATTEMPTS`] times in
+/// total) if it fails because of the network, eg. when the connection drops during an upload.
+///
+/// Other errors (eg. a file that's too large) are returned immediately.
+pub async fn retry_request<F, Fut, T>(mut request: F) -> Result<T, RequestError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RequestError>>,
+{
+    let mut attempts = 1;
+
+    loop {
+        match retry_after(&mut request).await {
+            Err(RequestError::Network(e)) if attempts < MAX_ATTEMPTS => {
+                debug!(
+                    ?e,
+                    ?attempts,
+                    "Request failed because of the network, retrying"
+                );
+            }
+            Err(RequestError::Io(e)) if attempts < MAX_ATTEMPTS => {
+                debug!(
+                    ?e,
+                    ?attempts,
+                    "Request failed because of the network, retrying"
+                );
+            }
+
+            res => return res,
+        }
+
+        tokio::time::sleep(RETRY_DELAY * attempts).await;
+        attempts += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex};
+
+    use tokio::time::Instant;
+
+    use super::*;
+
+    /// A request that responds with the results in order, counting how many times it was sent
+    struct FakeRequest {
+        results: Mutex<VecDeque<Result<u32, RequestError>>>,
+        sent: Mutex<usize>,
+    }
+    impl FakeRequest {
+        fn new(results: impl IntoIterator<Item = Result<u32, RequestError>>) -> Self {
+            Self {
+                results: Mutex::new(results.into_iter().collect()),
+                sent: Mutex::new(0),
+            }
+        }
+
+        fn send(&self) -> std::future::Ready<Result<u32, RequestError>> {
+            *self.sent.lock().expect("Lock poisoned") += 1;
+            std::future::ready(
+                self.results
+                    .lock()
+                    .expect("Lock poisoned")
+                    .pop_front()
+                    .expect("Request sent too many times"),
+            )
+        }
+
+        fn sent(&self) -> usize {
+            *self.sent.lock().expect("Lock poisoned")
+        }
+    }
+
+    fn retry_after_secs(secs: u64) -> Result<u32, RequestError> {
+        Err(RequestError::RetryAfter(Duration::from_secs(secs)))
+    }
+
+    fn io_error() -> Result<u32, RequestError> {
+        Err(RequestError::Io(std::io::Error::from(
+            std::io::ErrorKind::ConnectionReset,
+        )))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_as_long_as_told() {
+        let request = FakeRequest::new([retry_after_secs(5), retry_after_secs(7), Ok(1)]);
+        let start = Instant::now();
+
+        assert_eq!(retry_after(|| request.send()).await.expect("Sent"), 1);
+
+        assert_eq!(request.sent(), 3);
+        assert_eq!(start.elapsed(), Duration::from_secs(12));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_the_retries() {
+        let request = FakeRequest::new((0..=MAX_RETRIES).map(|_| retry_after_secs(1)));
+
+        let res = retry_after(|| request.send()).await;
+
+        assert!(matches!(res, Err(RequestError::RetryAfter(_))));
+        assert_eq!(request.sent(), MAX_RETRIES + 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn other_errors_are_returned_immediately() {
+        let request = FakeRequest::new([Err(RequestError::MigrateToChatId(-100)), Ok(1)]);
+
+        let res = retry_after(|| request.send()).await;
+
+        assert!(matches!(res, Err(RequestError::MigrateToChatId(_))));
+        assert_eq!(request.sent(), 1);
+
+        // Network errors are only retried by `retry_request`
+        let request = FakeRequest::new([io_error(), Ok(1)]);
+        assert!(matches!(
+            retry_after(|| request.send()).await,
+            Err(RequestError::Io(_))
+        ));
+        assert_eq!(request.sent(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn network_errors_are_retried() {
+        let request = FakeRequest::new([io_error(), retry_after_secs(1), io_error(), Ok(1)]);
+        let start = Instant::now();
+
+        assert_eq!(retry_request(|| request.send()).await.expect("Sent"), 1);
+
+        assert_eq!(request.sent(), 4);
+        // Waited for the rate limit and longer after each failed attempt
+        assert_eq!(
+            start.elapsed(),
+            Duration::from_secs(1) + RETRY_DELAY + RETRY_DELAY * 2
+        );
+
+        let request = FakeRequest::new((0..MAX_ATTEMPTS).map(|_| io_error()));
+        assert!(matches!(
+            retry_request(|| request.send()).await,
+            Err(RequestError::Io(_))
+        ));
+        assert_eq!(request.sent(), MAX_ATTEMPTS as usize);
+    }
+}
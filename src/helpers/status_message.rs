@@ -1,27 +1,63 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
 use teloxide::{
     payloads::{EditMessageTextSetters, SendMessageSetters},
-    requests::Requester,
-    types::{ChatId, Message, MessageId},
+    requests::{Request, Requester},
+    types::{ChatId, InlineKeyboardMarkup, Message, MessageId},
+    ApiError, RequestError,
 };
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::debug;
 
-use crate::bot::TelegramBot;
+use super::{
+    retry::retry_after,
+    topic::{topic_id, InTopic},
+};
+use crate::{bot::TelegramBot, i18n::Lang, in_flight::InFlightStatus};
+
+/// The status message is edited at most this often. Only the latest text is sent.
+const EDIT_INTERVAL: Duration = Duration::from_secs(3);
 
 #[derive(Debug, Clone)]
-#[allow(clippy::struct_field_names)]
 pub struct StatusMessage {
     chat_id: ChatId,
     msg_id: MessageId,
-    reply_msg_id: Option<MessageId>,
+    /// The forum topic the messages are sent in
+    topic_id: Option<i32>,
+    /// Language of the messages about the song
+    lang: Lang,
+    header: Option<String>,
+    mirror: Option<InFlightStatus>,
+    editor: Option<Arc<StatusEditor>>,
+    /// Receives the updates instead of a Telegram message, if the job doesn't come from a chat
+    detached: Option<Arc<watch::Sender<String>>>,
 }
 impl StatusMessage {
-    const fn new(chat_id: ChatId, msg_id: MessageId) -> Self {
+    const fn new(chat_id: ChatId, msg_id: MessageId, topic_id: Option<i32>) -> Self {
         Self {
             chat_id,
             msg_id,
-            reply_msg_id: None,
+            topic_id,
+            lang: Lang::En,
+            header: None,
+            mirror: None,
+            editor: None,
+            detached: None,
         }
     }
 
+    /// A status that isn't shown in any chat, eg. for jobs submitted through the API. The
+    /// updates are sent to `status` instead.
+    pub fn detached(status: watch::Sender<String>) -> Self {
+        let mut res = Self::new(ChatId(0), MessageId(0), None);
+        res.detached = Some(Arc::new(status));
+
+        res
+    }
+
     pub const fn chat_id(&self) -> ChatId {
         self.chat_id
     }
@@ -30,54 +66,149 @@ impl StatusMessage {
         self.msg_id
     }
 
-    pub const fn from_message(msg: &Message) -> Self {
-        Self::new(msg.chat.id, msg.id)
+    /// The forum topic the messages about the song have to be sent in
+    pub const fn topic_id(&self) -> Option<i32> {
+        self.topic_id
     }
 
-    pub async fn update_message(&mut self, text: &str) -> Result<(), teloxide::RequestError> {
-        for _ in 0..3 {
-            match self.reply_msg_id {
-                Some(reply_id) => {
-                    let res = TelegramBot::instance()
-                        .edit_message_text(self.chat_id, reply_id, text)
-                        .disable_web_page_preview(true)
-                        .await;
+    /// Receives the updates instead of a Telegram message, if the status is detached
+    pub(crate) fn detached_status(&self) -> Option<&watch::Sender<String>> {
+        self.detached.as_deref()
+    }
 
-                    if matches!(
-                        res,
-                        Err(teloxide::RequestError::Api(
-                            teloxide::ApiError::MessageToEditNotFound
-                        ))
-                    ) {
-                        self.reply_msg_id = None;
-                        continue;
-                    }
+    /// The ID of the status message itself, if it was already sent
+    pub fn status_msg_id(&self) -> Option<MessageId> {
+        self.editor.as_ref().map(|x| x.status_msg_id())
+    }
 
-                    return Ok(());
-                }
-                None => {
-                    let status_msg = TelegramBot::instance()
-                        .send_message(self.chat_id, text)
-                        .reply_to_message_id(self.msg_id)
-                        .allow_sending_without_reply(true)
-                        .await?;
+    pub fn from_message(msg: &Message) -> Self {
+        let mut res = Self::new(msg.chat.id, msg.id, topic_id(msg));
+        res.lang = Lang::of_message(msg);
 
-                    self.reply_msg_id = Some(status_msg.id);
+        res
+    }
 
-                    return Ok(());
-                }
-            }
+    /// Use a message that was already sent as the status message, eg. one with a keyboard that
+    /// started a new job
+    pub fn from_existing(status_msg: &Message, reply_to_id: MessageId) -> Self {
+        let chat_id = status_msg.chat.id;
+        let topic_id = topic_id(status_msg);
+
+        let mut res = Self::new(chat_id, reply_to_id, topic_id);
+        res.lang = Lang::for_chat(chat_id, None);
+        res.editor = Some(Arc::new(StatusEditor::spawn(
+            chat_id,
+            reply_to_id,
+            topic_id,
+            status_msg.id,
+            StatusContent {
+                text: String::new(),
+                keyboard: None,
+            },
+        )));
+
+        res
+    }
+
+    pub const fn lang(&self) -> Lang {
+        self.lang
+    }
+
+    /// Send the messages in another language, eg. the one of the user who pressed a button
+    pub const fn set_lang(&mut self, lang: Lang) {
+        self.lang = lang;
+    }
+
+    /// Set text that is shown above every status update (eg. "Track 3/12")
+    pub fn set_header(&mut self, header: Option<String>) {
+        self.header = header;
+    }
+
+    /// Also publish every status update to the requests waiting for the same song
+    pub fn set_mirror(&mut self, mirror: Option<InFlightStatus>) {
+        self.mirror = mirror;
+    }
+
+    /// Show the text in the status message.
+    ///
+    /// Only the first call waits for the message to be sent. Later edits are sent in the
+    /// background and coalesced so Telegram doesn't get flooded with edits.
+    pub async fn update_message(&mut self, text: &str) -> Result<(), RequestError> {
+        self.update(text, None).await
+    }
+
+    /// Show the text in the status message with the keyboard below it.
+    ///
+    /// The keyboard is removed by the next update.
+    pub async fn update_message_with_keyboard(
+        &mut self,
+        text: &str,
+        keyboard: InlineKeyboardMarkup,
+    ) -> Result<(), RequestError> {
+        self.update(text, Some(keyboard)).await
+    }
+
+    async fn update(
+        &mut self,
+        text: &str,
+        keyboard: Option<InlineKeyboardMarkup>,
+    ) -> Result<(), RequestError> {
+        if let Some(mirror) = &self.mirror {
+            mirror.publish(text);
+        }
+
+        let text = self
+            .header
+            .as_ref()
+            .map_or_else(|| text.to_string(), |header| format!("{header}\n\n{text}"));
+
+        if let Some(detached) = &self.detached {
+            detached.send_replace(text);
+            return Ok(());
+        }
+
+        let content = StatusContent { text, keyboard };
+
+        if let Some(editor) = &self.editor {
+            editor.content.send_replace(content);
+            return Ok(());
         }
 
-        Err(teloxide::RequestError::Api(
-            teloxide::ApiError::MessageNotModified,
-        ))
+        let status_msg_id =
+            send_status_message(self.chat_id, self.msg_id, self.topic_id, &content).await?;
+        self.editor = Some(Arc::new(StatusEditor::spawn(
+            self.chat_id,
+            self.msg_id,
+            self.topic_id,
+            status_msg_id,
+            content,
+        )));
+
+        Ok(())
     }
 
-    pub async fn delete_message(&mut self) -> Result<(), teloxide::RequestError> {
-        if let Some(id) = self.reply_msg_id {
+    /// Wait until the latest text is shown in the status message
+    pub async fn flush(self) {
+        let Some(editor) = self.editor else {
+            return;
+        };
+        // Somebody else can still update the message
+        let Ok(editor) = Arc::try_unwrap(editor) else {
+            return;
+        };
+
+        let StatusEditor { content, task, .. } = editor;
+        drop(content);
+        let _ = task.await;
+    }
+
+    /// Delete the status message. Pending edits are discarded.
+    pub async fn delete_message(&self) -> Result<(), RequestError> {
+        if let Some(editor) = &self.editor {
+            editor.task.abort();
+
             TelegramBot::instance()
-                .delete_message(self.chat_id, id)
+                .delete_message(self.chat_id, editor.status_msg_id())
                 .await?;
         }
 
@@ -96,3 +227,128 @@ impl<'a> From<&'a Message> for StatusMessage {
         Self::from_message(msg)
     }
 }
+
+/// What's shown in the status message
+#[derive(Debug, Clone)]
+struct StatusContent {
+    text: String,
+    keyboard: Option<InlineKeyboardMarkup>,
+}
+
+/// Background task that edits the status message with the latest text
+#[derive(Debug)]
+struct StatusEditor {
+    content: watch::Sender<StatusContent>,
+    /// Can change if the message gets deleted and has to be sent again
+    status_msg_id: Arc<Mutex<MessageId>>,
+    task: JoinHandle<()>,
+}
+impl StatusEditor {
+    fn spawn(
+        chat_id: ChatId,
+        reply_to_id: MessageId,
+        topic_id: Option<i32>,
+        status_msg_id: MessageId,
+        content: StatusContent,
+    ) -> Self {
+        let (content, mut content_rx) = watch::channel(content);
+        let status_msg_id = Arc::new(Mutex::new(status_msg_id));
+
+        let task = tokio::spawn({
+            let status_msg_id = status_msg_id.clone();
+
+            async move {
+                // Finishes once the status message is dropped and the last text is sent
+                while content_rx.changed().await.is_ok() {
+                    let content = content_rx.borrow_and_update().clone();
+
+                    if let Err(e) = edit_status_message(
+                        chat_id,
+                        reply_to_id,
+                        topic_id,
+                        &status_msg_id,
+                        &content,
+                    )
+                    .await
+                    {
+                        debug!(?e, "Failed to update status message");
+                    }
+
+                    tokio::time::sleep(EDIT_INTERVAL).await;
+                }
+            }
+        });
+
+        Self {
+            content,
+            status_msg_id,
+            task,
+        }
+    }
+
+    fn status_msg_id(&self) -> MessageId {
+        *self
+            .status_msg_id
+            .lock()
+            .expect("Status message lock poisoned")
+    }
+}
+
+async fn send_status_message(
+    chat_id: ChatId,
+    reply_to_id: MessageId,
+    topic_id: Option<i32>,
+    content: &StatusContent,
+) -> Result<MessageId, RequestError> {
+    let status_msg = retry_after(|| {
+        let mut request = TelegramBot::instance()
+            .send_message(chat_id, &content.text)
+            .reply_to_message_id(reply_to_id)
+            .allow_sending_without_reply(true)
+            .in_topic(topic_id);
+        if let Some(keyboard) = &content.keyboard {
+            request = request.reply_markup(keyboard.clone());
+        }
+
+        request.send()
+    })
+    .await?;
+
+    Ok(status_msg.id)
+}
+
+async fn edit_status_message(
+    chat_id: ChatId,
+    reply_to_id: MessageId,
+    topic_id: Option<i32>,
+    status_msg_id: &Mutex<MessageId>,
+    content: &StatusContent,
+) -> Result<(), RequestError> {
+    let msg_id = *status_msg_id.lock().expect("Status message lock poisoned");
+
+    let res = retry_after(|| {
+        // Leaving out the keyboard removes it
+        let mut request = TelegramBot::instance()
+            .edit_message_text(chat_id, msg_id, &content.text)
+            .disable_web_page_preview(true);
+        if let Some(keyboard) = &content.keyboard {
+            request = request.reply_markup(keyboard.clone());
+        }
+
+        request.send()
+    })
+    .await;
+
+    match res {
+        Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => Ok(()),
+
+        // The user deleted the status message, send a new one
+        Err(RequestError::Api(ApiError::MessageToEditNotFound)) => {
+            let new_id = send_status_message(chat_id, reply_to_id, topic_id, content).await?;
+            *status_msg_id.lock().expect("Status message lock poisoned") = new_id;
+            Ok(())
+        }
+
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,112 @@
+use std::path::{Path, PathBuf};
+
+use teloxide::{net::Download, requests::Requester, types::Message};
+use tokio::{fs::File, io::AsyncWriteExt};
+use tracing::{debug, trace};
+
+use crate::bot::TelegramBot;
+
+/// Maximum size of a file that bots can download from the Telegram Bot API.
+pub const MAX_DOWNLOAD_SIZE: u64 = {
+    let kb = 1000;
+    let mb = kb * 1000;
+
+    20 * mb
+};
+
+/// An audio file sent directly to the bot (as audio, voice or an audio document).
+#[derive(Debug, Clone)]
+pub struct TelegramFile {
+    id: String,
+    name: Option<String>,
+    size: u64,
+}
+impl TelegramFile {
+    pub fn from_message(msg: &Message) -> Option<Self> {
+        if let Some(audio) = msg.audio() {
+            return Some(Self {
+                id: audio.file.id.clone(),
+                name: audio.file_name.clone(),
+                size: audio.file.size.into(),
+            });
+        }
+
+        if let Some(voice) = msg.voice() {
+            return Some(Self {
+                id: voice.file.id.clone(),
+                name: None,
+                size: voice.file.size.into(),
+            });
+        }
+
+        if let Some(document) = msg.document() {
+            let is_audio = document
+                .mime_type
+                .as_ref()
+                .is_some_and(|x| x.type_() == "audio");
+
+            if !is_audio {
+                return None;
+            }
+
+            return Some(Self {
+                id: document.file.id.clone(),
+                name: document.file_name.clone(),
+                size: document.file.size.into(),
+            });
+        }
+
+        None
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    #[tracing::instrument(skip(self), fields(id = ?self.id, size = ?self.size))]
+    pub async fn download(&self, download_dir: &Path) -> anyhow::Result<PathBuf> {
+        if self.size > MAX_DOWNLOAD_SIZE {
+            anyhow::bail!(
+                "File is too large ({:.1} MB). Telegram only allows bots to download files up \
+                 to {} MB.",
+                self.size_mb(),
+                MAX_DOWNLOAD_SIZE / 1000 / 1000
+            );
+        }
+
+        debug!("Getting file info");
+        let file = TelegramBot::instance().get_file(&self.id).await?;
+        trace!(?file, "Got file info");
+
+        let file_name = self
+            .name
+            .as_deref()
+            .and_then(|x| Path::new(x).file_name())
+            .map_or_else(
+                || {
+                    Path::new(&file.path)
+                        .file_name()
+                        .map_or_else(|| "song".into(), std::ffi::OsStr::to_os_string)
+                },
+                std::ffi::OsStr::to_os_string,
+            );
+        let download_path = download_dir.join(file_name);
+
+        trace!(?download_path, "Downloading file");
+        {
+            let mut out_file = File::create(&download_path).await?;
+            TelegramBot::raw()
+                .download_file(&file.path, &mut out_file)
+                .await?;
+            out_file.flush().await?;
+        }
+        debug!(?download_path, "File downloaded");
+
+        Ok(download_path)
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn size_mb(&self) -> f64 {
+        self.size as f64 / 1000.0 / 1000.0
+    }
+}
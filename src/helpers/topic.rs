@@ -0,0 +1,62 @@
+use teloxide::{
+    payloads::{SendAudio, SendDocument, SendMediaGroup, SendMessage, SendVideo},
+    requests::HasPayload,
+    types::{Message, MessageCommon, MessageKind},
+};
+
+/// The forum topic the message was sent in, if the chat is a forum
+pub const fn topic_id(msg: &Message) -> Option<i32> {
+    match &msg.kind {
+        MessageKind::Common(MessageCommon {
+            is_topic_message: true,
+            ..
+        }) => msg.thread_id,
+        _ => None,
+    }
+}
+
+/// Payloads of requests that send a message, which can be sent into a forum topic
+pub trait TopicPayload {
+    fn set_topic_id(&mut self, topic_id: Option<i32>);
+}
+impl TopicPayload for SendMessage {
+    fn set_topic_id(&mut self, topic_id: Option<i32>) {
+        self.message_thread_id = topic_id;
+    }
+}
+impl TopicPayload for SendAudio {
+    fn set_topic_id(&mut self, topic_id: Option<i32>) {
+        self.message_thread_id = topic_id;
+    }
+}
+impl TopicPayload for SendDocument {
+    fn set_topic_id(&mut self, topic_id: Option<i32>) {
+        self.message_thread_id = topic_id;
+    }
+}
+impl TopicPayload for SendVideo {
+    fn set_topic_id(&mut self, topic_id: Option<i32>) {
+        self.message_thread_id = topic_id;
+    }
+}
+impl TopicPayload for SendMediaGroup {
+    fn set_topic_id(&mut self, topic_id: Option<i32>) {
+        self.message_thread_id = topic_id;
+    }
+}
+
+pub trait InTopic {
+    /// Send the message into the forum topic (see [`topic_id`]), or outside of topics if `None`
+    #[must_use]
+    fn in_topic(self, topic_id: Option<i32>) -> Self;
+}
+impl<R> InTopic for R
+where
+    R: HasPayload,
+    R::Payload: TopicPayload,
+{
+    fn in_topic(mut self, topic_id: Option<i32>) -> Self {
+        self.payload_mut().set_topic_id(topic_id);
+        self
+    }
+}
@@ -0,0 +1,199 @@
+use std::time::Duration;
+
+use url::Url;
+
+use super::domain::DomainParser;
+
+/// Path prefixes of `YouTube` links that are followed by the video ID, eg. `/shorts/<id>`
+const YOUTUBE_ID_PATH_PREFIXES: &[&str] = &["shorts", "embed", "live", "v", "e"];
+
+/// A link in the form the handlers expect
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NormalizedUrl {
+    pub url: Url,
+    /// Where the link wants playback to start, eg. from `t=90` on `YouTube`
+    pub start: Option<Duration>,
+}
+
+/// Canonicalize the URL so the handlers (and the result cache) see a single form of it.
+///
+/// `YouTube` links (`youtu.be`, `music.youtube.com`, Shorts, embeds, ...) become
+/// `https://www.youtube.com/watch?v=<id>` without any playlist or tracking parameters. Other
+/// links are returned as they are.
+pub fn normalize_url(url: &Url) -> NormalizedUrl {
+    youtube_video(url).map_or_else(
+        || NormalizedUrl {
+            url: url.clone(),
+            start: None,
+        },
+        |(id, start)| NormalizedUrl {
+            url: Url::parse(&format!("https://www.youtube.com/watch?v={id}"))
+                .expect("Invalid YouTube URL"),
+            start,
+        },
+    )
+}
+
+/// The ID and start offset of the `YouTube` video the URL links to
+fn youtube_video(url: &Url) -> Option<(String, Option<Duration>)> {
+    let domain_root = DomainParser::get_domain_root(url)?;
+    let mut segments = url.path_segments()?.filter(|x| !x.is_empty());
+
+    let id = match domain_root {
+        "youtu.be" => segments.next().map(ToString::to_string),
+        "youtube.com" | "youtube-nocookie.com" => match segments.next() {
+            Some("watch") => query_param(url, "v"),
+            Some(prefix) if YOUTUBE_ID_PATH_PREFIXES.contains(&prefix) => {
+                segments.next().map(ToString::to_string)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+    .filter(|x| is_youtube_id(x))?;
+
+    let start = query_param(url, "t")
+        .or_else(|| query_param(url, "start"))
+        .and_then(|x| parse_offset(&x));
+
+    Some((id, start))
+}
+
+fn query_param(url: &Url, name: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.into_owned())
+}
+
+/// `YouTube` video IDs are 11 characters of URL-safe base64
+fn is_youtube_id(id: &str) -> bool {
+    id.len() == 11
+        && id
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+}
+
+/// Parse a `YouTube` time offset, eg. `90`, `90s` or `1h2m3s`
+fn parse_offset(offset: &str) -> Option<Duration> {
+    if let Ok(secs) = offset.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let mut secs = 0;
+    let mut number = String::new();
+    for c in offset.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let multiplier = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+        secs += number.parse::<u64>().ok()? * multiplier;
+        number.clear();
+    }
+
+    if !number.is_empty() || offset.is_empty() {
+        return None;
+    }
+
+    Some(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WATCH_URL: &str = "https://www.youtube.com/watch?v=dQw4w9WgXcQ";
+
+    fn normalized(url: &str) -> NormalizedUrl {
+        normalize_url(&Url::parse(url).expect("Valid URL"))
+    }
+
+    #[test]
+    fn youtube_links() {
+        for (url, start) in [
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ", None),
+            ("https://youtube.com/watch?v=dQw4w9WgXcQ", None),
+            ("http://m.youtube.com/watch?v=dQw4w9WgXcQ&feature=share", None),
+            ("https://music.youtube.com/watch?v=dQw4w9WgXcQ&si=abc", None),
+            (
+                "https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PLx0sYbCqOb8TBPRdmBHs5Iftvv9TPboYG&index=3",
+                None,
+            ),
+            (
+                "https://www.youtube.com/watch?list=PLx0sYbCqOb8TBPRdmBHs5Iftvv9TPboYG&v=dQw4w9WgXcQ",
+                None,
+            ),
+            ("https://youtu.be/dQw4w9WgXcQ", None),
+            ("https://youtu.be/dQw4w9WgXcQ?si=tracking", None),
+            ("https://youtu.be/dQw4w9WgXcQ?t=90", Some(90)),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=1m30s", Some(90)),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=1h2m3s", Some(3723)),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=45s", Some(45)),
+            ("https://www.youtube.com/shorts/dQw4w9WgXcQ", None),
+            ("https://youtube.com/shorts/dQw4w9WgXcQ?feature=share", None),
+            ("https://www.youtube.com/embed/dQw4w9WgXcQ?start=30", Some(30)),
+            ("https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ", None),
+            ("https://www.youtube.com/live/dQw4w9WgXcQ", None),
+            ("https://www.youtube.com/v/dQw4w9WgXcQ", None),
+            // Invalid offsets are ignored
+            ("https://youtu.be/dQw4w9WgXcQ?t=soon", None),
+            ("https://youtu.be/dQw4w9WgXcQ?t=1m30", None),
+        ] {
+            let res = normalized(url);
+
+            assert_eq!(res.url.as_str(), WATCH_URL, "{url}");
+            assert_eq!(res.start, start.map(Duration::from_secs), "{url}");
+        }
+    }
+
+    #[test]
+    fn other_links_are_kept() {
+        for url in [
+            "https://open.spotify.com/track/4cOdK2wGLETKBW3PvgPWqT?si=x",
+            "https://www.youtube.com/playlist?list=PLx0sYbCqOb8TBPRdmBHs5Iftvv9TPboYG",
+            "https://www.youtube.com/@channel",
+            "https://www.youtube.com/watch",
+            "https://www.youtube.com/watch?v=short",
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ!",
+            "https://youtu.be/",
+            "https://youtube.com.evil.com/watch?v=dQw4w9WgXcQ",
+            "https://notyoutube.com/watch?v=dQw4w9WgXcQ",
+            "https://evil.com/youtu.be/dQw4w9WgXcQ",
+        ] {
+            let res = normalized(url);
+
+            assert_eq!(res.url.as_str(), Url::parse(url).expect("URL").as_str());
+            assert_eq!(res.start, None, "{url}");
+        }
+    }
+
+    #[test]
+    fn offsets() {
+        for (offset, secs) in [
+            ("0", Some(0)),
+            ("90", Some(90)),
+            ("90s", Some(90)),
+            ("2m", Some(120)),
+            ("1h", Some(3600)),
+            ("1h2m3s", Some(3723)),
+            ("", None),
+            ("-5", None),
+            ("1.5", None),
+            ("5x", None),
+            ("m", None),
+            ("99999999999999999999", None),
+        ] {
+            assert_eq!(
+                parse_offset(offset),
+                secs.map(Duration::from_secs),
+                "{offset}"
+            );
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use teloxide::types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup, UserId};
+use tracing::{debug, trace};
+
+use crate::{config::Config, store::Store};
+
+const TREE_NAME: &str = "history";
+/// Prefix of the callback data of the buttons, eg. `history:12`
+const CALLBACK_PREFIX: &str = "history";
+
+/// How a job of the user ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobStatus {
+    Done,
+    Failed,
+}
+
+/// A song the user requested
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub title: String,
+    pub status: JobStatus,
+    /// Telegram file IDs of the uploaded files, if all of them can be sent again
+    pub file_ids: Option<Vec<String>>,
+    /// Chat the song was requested in
+    pub chat_id: ChatId,
+    /// When the job ended (as a UNIX timestamp)
+    pub created_at: u64,
+}
+impl HistoryEntry {
+    /// Whether the entry can be shown in the chat. Songs requested in a group are only shown in
+    /// that group or in the private chat with the user, so they aren't leaked to other groups.
+    pub fn is_visible_in(&self, chat_id: ChatId) -> bool {
+        chat_id.is_user() || self.chat_id == chat_id
+    }
+
+    /// When the job ended, eg. `2024-07-21 18:30 UTC`
+    pub fn date(&self) -> String {
+        i64::try_from(self.created_at)
+            .ok()
+            .and_then(|x| chrono::DateTime::from_timestamp(x, 0))
+            .map_or_else(
+                || "unknown date".to_string(),
+                |x| x.format("%Y-%m-%d %H:%M UTC").to_string(),
+            )
+    }
+}
+
+/// The recent jobs of each user, so they can get the files again without resending the song
+pub struct History;
+impl History {
+    /// Remember the job of the user, forgetting their oldest ones over the entry limit
+    pub fn record(
+        user_id: UserId,
+        chat_id: ChatId,
+        title: String,
+        status: JobStatus,
+        file_ids: Option<Vec<String>>,
+    ) {
+        let res = Store::generate_id().and_then(|id| {
+            let entry = HistoryEntry {
+                id,
+                title,
+                status,
+                file_ids,
+                chat_id,
+                created_at: unix_now(),
+            };
+            trace!(?user_id, ?entry, "Recording job in history");
+
+            let tree = Store::tree(TREE_NAME)?;
+            tree.insert(Self::key(user_id, id), serde_json::to_vec(&entry)?)?;
+            Self::evict(&tree, user_id)
+        });
+
+        if let Err(e) = res {
+            debug!(?e, ?user_id, "Failed to record job in history");
+        }
+    }
+
+    /// The latest jobs of the user, newest first
+    pub fn list(user_id: UserId, limit: usize) -> anyhow::Result<Vec<HistoryEntry>> {
+        Store::tree(TREE_NAME)?
+            .scan_prefix(Self::key_prefix(user_id))
+            .values()
+            .rev()
+            .take(limit)
+            .map(|x| Ok(serde_json::from_slice(&x?)?))
+            .collect()
+    }
+
+    pub fn get(user_id: UserId, id: u64) -> anyhow::Result<Option<HistoryEntry>> {
+        let Some(value) = Store::tree(TREE_NAME)?.get(Self::key(user_id, id))? else {
+            return Ok(None);
+        };
+
+        Ok(Some(serde_json::from_slice(&value)?))
+    }
+
+    /// Keyboard with a button to get the files of each of the entries again
+    pub fn keyboard(entries: &[HistoryEntry]) -> InlineKeyboardMarkup {
+        let buttons = entries
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.file_ids.is_some())
+            .map(|(i, x)| {
+                vec![InlineKeyboardButton::callback(
+                    format!("{}. {}", i + 1, x.title),
+                    format!("{CALLBACK_PREFIX}:{}", x.id),
+                )]
+            });
+
+        InlineKeyboardMarkup::new(buttons)
+    }
+
+    /// The entry ID from the callback data of a keyboard button, if it's one of ours
+    pub fn parse_callback(callback_data: &str) -> Option<u64> {
+        callback_data
+            .strip_prefix(CALLBACK_PREFIX)?
+            .strip_prefix(':')?
+            .parse()
+            .ok()
+    }
+
+    /// Remove the oldest entries of the user over the entry limit
+    fn evict(tree: &sled::Tree, user_id: UserId) -> anyhow::Result<()> {
+        let keys = tree
+            .scan_prefix(Self::key_prefix(user_id))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Keys are ordered by the entry ID, so the oldest entries are first
+        let excess = keys
+            .len()
+            .saturating_sub(Config::global().history_max_entries);
+        for key in keys.into_iter().take(excess) {
+            tree.remove(key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Entries of a user are next to each other, ordered by their ID
+    fn key(user_id: UserId, id: u64) -> String {
+        format!("{}{id:020}", Self::key_prefix(user_id))
+    }
+
+    fn key_prefix(user_id: UserId) -> String {
+        format!("{user_id}:")
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
@@ -0,0 +1,157 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{HeaderValue, CONTENT_TYPE},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+
+use crate::{config::Config, health::Health, metrics::Metrics};
+
+/// Reads the number of songs waiting in each queue
+pub type QueueDepths = fn() -> Vec<(&'static str, usize)>;
+
+/// What a listener serves
+#[derive(Debug, Clone, Copy, Default)]
+struct Endpoints {
+    /// `/metrics`
+    metrics: bool,
+    /// `/healthz` and `/readyz`
+    health: bool,
+}
+
+/// The HTTP server for the metrics and health checks
+pub struct HttpServer;
+impl HttpServer {
+    /// Start listening on `KARAOKIFY_METRICS_ADDR` and `KARAOKIFY_HEALTH_ADDR`, if they're set.
+    ///
+    /// A single listener serves everything if both are the same address.
+    pub fn spawn(queue_depths: QueueDepths) {
+        let config = Config::global();
+
+        let mut listeners: Vec<(SocketAddr, Endpoints)> = vec![];
+        let addrs = [
+            (
+                config.metrics_addr,
+                Endpoints {
+                    metrics: true,
+                    health: false,
+                },
+            ),
+            (
+                config.health_addr,
+                Endpoints {
+                    metrics: false,
+                    health: true,
+                },
+            ),
+        ];
+        for (addr, endpoints) in addrs {
+            let Some(addr) = addr else {
+                continue;
+            };
+
+            match listeners.iter_mut().find(|(x, _)| *x == addr) {
+                Some((_, existing)) => {
+                    existing.metrics |= endpoints.metrics;
+                    existing.health |= endpoints.health;
+                }
+                None => listeners.push((addr, endpoints)),
+            }
+        }
+
+        for (addr, endpoints) in listeners {
+            tokio::spawn(Self::serve(addr, endpoints, queue_depths));
+        }
+    }
+
+    async fn serve(addr: SocketAddr, endpoints: Endpoints, queue_depths: QueueDepths) {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(?e, %addr, "Failed to start the HTTP server");
+                return;
+            }
+        };
+        info!(%addr, ?endpoints, "Serving HTTP");
+
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    debug!(?e, "Failed to accept HTTP connection");
+                    continue;
+                }
+            };
+
+            tokio::spawn(async move {
+                let service = service_fn(|req| async move {
+                    Ok::<_, Infallible>(Self::respond(&req, endpoints, queue_depths))
+                });
+
+                let res = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+
+                if let Err(e) = res {
+                    debug!(?e, "Failed to serve HTTP connection");
+                }
+            });
+        }
+    }
+
+    fn respond(
+        req: &Request<Incoming>,
+        endpoints: Endpoints,
+        queue_depths: QueueDepths,
+    ) -> Response<Full<Bytes>> {
+        if req.method() != Method::GET {
+            return text_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+        }
+
+        match req.uri().path() {
+            "/metrics" if endpoints.metrics => {
+                let mut res = text_response(StatusCode::OK, Metrics::render(&queue_depths()));
+                res.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/plain; version=0.0.4"),
+                );
+                res
+            }
+
+            "/healthz" if endpoints.health && Health::is_alive() => {
+                text_response(StatusCode::OK, "ok")
+            }
+            "/healthz" if endpoints.health => text_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "no contact with Telegram recently",
+            ),
+
+            "/readyz" if endpoints.health && Health::is_ready() => {
+                text_response(StatusCode::OK, "ok")
+            }
+            "/readyz" if endpoints.health => {
+                text_response(StatusCode::SERVICE_UNAVAILABLE, "starting up")
+            }
+
+            _ => text_response(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+fn text_response(status: StatusCode, body: impl Into<Bytes>) -> Response<Full<Bytes>> {
+    let mut res = Response::new(Full::new(body.into()));
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+
+    res
+}
@@ -0,0 +1,219 @@
+//! Translations of the messages sent to the users.
+//!
+//! Each language has a file in `locales/` with a `key = text` line for each message. Placeholders
+//! like `{position}` are replaced with the arguments, `\n` is a line break, and a `\` at the end
+//! of a line continues the text on the next one. Messages that aren't translated are sent in
+//! English.
+
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use teloxide::types::{ChatId, Message, User};
+use tracing::{debug, warn};
+
+use crate::chat_settings::ChatSettings;
+
+/// The files are part of the binary, so there's nothing else to deploy
+const LOCALES: [(Lang, &str); 2] = [
+    (Lang::En, include_str!("../locales/en.txt")),
+    (Lang::Hr, include_str!("../locales/hr.txt")),
+];
+
+static TRANSLATIONS: Lazy<HashMap<Lang, HashMap<&'static str, String>>> = Lazy::new(|| {
+    LOCALES
+        .into_iter()
+        .map(|(lang, text)| (lang, parse_locale(text)))
+        .collect()
+});
+
+/// Language of the messages sent to the users
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Lang {
+    #[default]
+    En,
+    Hr,
+}
+impl Lang {
+    pub const ALL: [Self; 2] = [Self::En, Self::Hr];
+
+    /// The IETF language tag, as used by Telegram
+    pub const fn code(self) -> &'static str {
+        match self {
+            Self::En => "en",
+            Self::Hr => "hr",
+        }
+    }
+
+    /// Name of the language in the language itself
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::En => "English",
+            Self::Hr => "Hrvatski",
+        }
+    }
+
+    /// The language of the user's Telegram app (eg. `hr` or `en-US`), if it's supported
+    pub fn from_language_code(code: &str) -> Option<Self> {
+        let code = code.split(['-', '_']).next().unwrap_or(code);
+
+        Self::ALL
+            .into_iter()
+            .find(|x| x.code().eq_ignore_ascii_case(code))
+    }
+
+    /// The language the messages in the chat are sent in: the one chosen with `/language`, or
+    /// the one of the user's app
+    pub fn for_chat(chat_id: ChatId, user: Option<&User>) -> Self {
+        ChatSettings::get(chat_id)
+            .language
+            .or_else(|| {
+                user.and_then(|x| x.language_code.as_deref())
+                    .and_then(Self::from_language_code)
+            })
+            .unwrap_or_default()
+    }
+
+    /// The language the replies to the message are sent in
+    pub fn of_message(msg: &Message) -> Self {
+        Self::for_chat(msg.chat.id, msg.from())
+    }
+
+    /// The message in this language, or in English if it isn't translated
+    pub fn text(self, key: &str) -> String {
+        self.format(key, &[])
+    }
+
+    /// The message with the placeholders (eg. `{position}`) replaced by the arguments
+    pub fn format(self, key: &str, args: &[(&str, &(dyn Display + Sync))]) -> String {
+        let Some(template) = self.get(key).or_else(|| Self::En.get(key)) else {
+            debug!(?key, "Message is missing from the English locale");
+            return key.to_string();
+        };
+
+        args.iter()
+            .fold(template.to_string(), |text, (name, value)| {
+                text.replace(&format!("{{{name}}}"), &value.to_string())
+            })
+    }
+
+    /// The message in this language only, without falling back to English
+    pub fn get(self, key: &str) -> Option<&'static str> {
+        TRANSLATIONS.get(&self)?.get(key).map(String::as_str)
+    }
+}
+impl FromStr for Lang {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_language_code(s).ok_or_else(|| {
+            anyhow::anyhow!(
+                "unsupported language {s:?}, expected one of: {}",
+                Self::ALL.map(Self::code).join(", ")
+            )
+        })
+    }
+}
+impl Display for Lang {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// Read the `key = text` lines of a locale file
+fn parse_locale(text: &'static str) -> HashMap<&'static str, String> {
+    let mut res = HashMap::new();
+    let mut lines = text.lines();
+
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            warn!(?line, "Invalid line in locale");
+            continue;
+        };
+
+        let mut value = value.trim().to_string();
+        while let Some(start) = value.strip_suffix('\\') {
+            let start = start.to_string();
+            let next = lines.next().unwrap_or_default().trim();
+            value = format!("{start}{next}");
+        }
+
+        res.insert(key.trim(), value.replace("\\n", "\n"));
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use regex::Regex;
+
+    use super::*;
+
+    /// The keys of the messages used in the source files in `dir`
+    fn used_keys(dir: &Path, keys: &mut Vec<(String, String)>) {
+        let regex = Regex::new(r#"\.(?:text|format)\(\s*"([a-z0-9-]+)""#).expect("Invalid regex");
+
+        for entry in std::fs::read_dir(dir).expect("Source dir readable") {
+            let path = entry.expect("Source dir entry readable").path();
+            if path.is_dir() {
+                used_keys(&path, keys);
+                continue;
+            }
+            if path.extension().map_or(true, |x| x != "rs") {
+                continue;
+            }
+
+            let source = std::fs::read_to_string(&path).expect("Source file readable");
+            keys.extend(
+                regex
+                    .captures_iter(&source)
+                    .map(|x| (path.display().to_string(), x[1].to_string())),
+            );
+        }
+    }
+
+    #[test]
+    fn translations_exist_in_english() {
+        let english = &TRANSLATIONS[&Lang::En];
+
+        for (lang, messages) in TRANSLATIONS.iter() {
+            for key in messages.keys() {
+                // The English command descriptions are part of the command list itself
+                if key.starts_with("command-") {
+                    continue;
+                }
+
+                assert!(
+                    english.contains_key(key),
+                    "{key:?} ({lang}) is missing from English, it's either misspelled or not \
+                     used anymore"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn used_messages_exist_in_english() {
+        let mut keys = vec![];
+        used_keys(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("src"),
+            &mut keys,
+        );
+        assert!(!keys.is_empty());
+
+        for (file, key) in keys {
+            assert!(
+                TRANSLATIONS[&Lang::En].contains_key(key.as_str()),
+                "{key:?} (used in {file}) is missing from English"
+            );
+        }
+    }
+}
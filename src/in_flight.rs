@@ -0,0 +1,102 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use once_cell::sync::Lazy;
+use tokio::sync::watch;
+use tracing::trace;
+
+/// Songs that are currently being downloaded or processed, keyed by the cache key of the request
+static IN_FLIGHT: Lazy<Mutex<HashMap<String, watch::Receiver<InFlightState>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone)]
+pub enum InFlightState {
+    /// The song is still being processed. Contains the latest status text.
+    Running(String),
+    /// The song was processed. Contains the file IDs of the uploaded files if it succeeded.
+    Finished(Option<Vec<String>>),
+}
+
+pub enum InFlightJoin {
+    /// Nobody is processing the song yet, so the caller should process it
+    Leader(InFlightGuard),
+    /// Somebody is already processing the song, the caller can wait for the results
+    Follower(watch::Receiver<InFlightState>),
+}
+
+/// Tracks identical requests so the same song isn't processed multiple times at once
+pub struct InFlight;
+impl InFlight {
+    pub fn join(key: String) -> InFlightJoin {
+        let mut in_flight = IN_FLIGHT.lock().expect("In-flight lock poisoned");
+
+        if let Some(rx) = in_flight.get(&key) {
+            trace!(?key, "Song is already in flight");
+            return InFlightJoin::Follower(rx.clone());
+        }
+
+        let (tx, rx) = watch::channel(InFlightState::Running(String::new()));
+        in_flight.insert(key.clone(), rx);
+        drop(in_flight);
+        trace!(?key, "Song is now in flight");
+
+        InFlightJoin::Leader(InFlightGuard {
+            key,
+            status: InFlightStatus(Arc::new(tx)),
+        })
+    }
+}
+
+/// Publishes the status of the leading request to the waiting ones
+#[derive(Debug, Clone)]
+pub struct InFlightStatus(Arc<watch::Sender<InFlightState>>);
+impl InFlightStatus {
+    pub fn publish(&self, text: &str) {
+        self.0.send_if_modified(|state| match state {
+            InFlightState::Running(x) => {
+                text.clone_into(x);
+                true
+            }
+            InFlightState::Finished(_) => false,
+        });
+    }
+}
+
+/// Held by the request that processes the song.
+///
+/// If it's dropped without calling [`InFlightGuard::finish`] (eg. because the processing failed
+/// or was cancelled), the waiting requests are told to process the song themselves.
+pub struct InFlightGuard {
+    key: String,
+    status: InFlightStatus,
+}
+impl InFlightGuard {
+    pub fn status(&self) -> InFlightStatus {
+        self.status.clone()
+    }
+
+    pub fn finish(self, file_ids: Option<Vec<String>>) {
+        self.status
+            .0
+            .send_replace(InFlightState::Finished(file_ids));
+    }
+}
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT
+            .lock()
+            .expect("In-flight lock poisoned")
+            .remove(&self.key);
+
+        self.status.0.send_if_modified(|state| match state {
+            InFlightState::Running(_) => {
+                *state = InFlightState::Finished(None);
+                true
+            }
+            InFlightState::Finished(_) => false,
+        });
+        trace!(key = ?self.key, "Song no longer in flight");
+    }
+}
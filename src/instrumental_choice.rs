@@ -0,0 +1,135 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, UserId};
+use tokio::sync::oneshot;
+use tracing::trace;
+
+use crate::i18n::Lang;
+
+/// Prefix of the callback data of the buttons, eg. `instrumental:12:process`
+const CALLBACK_PREFIX: &str = "instrumental";
+
+/// Songs waiting for the user to decide, keyed by the ID in the callback data
+static PENDING: Lazy<Mutex<HashMap<u64, PendingEntry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_PENDING_ID: AtomicU64 = AtomicU64::new(1);
+
+/// What to do with a song that looks instrumental already
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InstrumentalChoice {
+    #[default]
+    Skip,
+    Process,
+}
+impl InstrumentalChoice {
+    const ALL: [Self; 2] = [Self::Process, Self::Skip];
+
+    const fn id(self) -> &'static str {
+        match self {
+            Self::Skip => "skip",
+            Self::Process => "process",
+        }
+    }
+
+    fn label(self, lang: Lang) -> String {
+        lang.text(&format!("instrumental-{}", self.id()))
+    }
+
+    /// Wait for the user to decide using the keyboard
+    pub fn ask(user_id: Option<UserId>) -> PendingInstrumentalChoice {
+        let id = NEXT_PENDING_ID.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+
+        PENDING
+            .lock()
+            .expect("Pending instrumental choices lock poisoned")
+            .insert(id, PendingEntry { user_id, tx });
+        trace!(?id, "Waiting for instrumental choice");
+
+        PendingInstrumentalChoice { id, rx }
+    }
+
+    /// Parse the callback data of one of the keyboard buttons
+    pub fn parse_callback(data: &str) -> Option<(u64, Self)> {
+        let (id, choice) = data
+            .strip_prefix(CALLBACK_PREFIX)?
+            .strip_prefix(':')?
+            .split_once(':')?;
+        let choice = Self::ALL.into_iter().find(|x| x.id() == choice)?;
+
+        Some((id.parse().ok()?, choice))
+    }
+
+    /// Handle a press of one of the keyboard buttons by `user_id`.
+    ///
+    /// Returns the reason shown to the user if the choice couldn't be made.
+    pub fn choose(id: u64, choice: Self, user_id: UserId, lang: Lang) -> anyhow::Result<()> {
+        let mut pending = PENDING
+            .lock()
+            .expect("Pending instrumental choices lock poisoned");
+        let Some(entry) = pending.get(&id) else {
+            anyhow::bail!(lang.text("choice-expired"));
+        };
+        if entry.user_id.is_some_and(|x| x != user_id) {
+            anyhow::bail!(lang.text("choice-not-yours"));
+        }
+        let entry = pending.remove(&id).expect("Pending choice should exist");
+        drop(pending);
+
+        trace!(?id, ?choice, "Instrumental choice made");
+        let _ = entry.tx.send(choice);
+
+        Ok(())
+    }
+}
+
+struct PendingEntry {
+    /// Only this user can choose (anybody can if the song wasn't sent by a user)
+    user_id: Option<UserId>,
+    tx: oneshot::Sender<InstrumentalChoice>,
+}
+
+/// A choice the user can make using the keyboard. It expires when this is dropped.
+#[derive(Debug)]
+pub struct PendingInstrumentalChoice {
+    id: u64,
+    rx: oneshot::Receiver<InstrumentalChoice>,
+}
+impl PendingInstrumentalChoice {
+    pub fn keyboard(&self, lang: Lang) -> InlineKeyboardMarkup {
+        let buttons = InstrumentalChoice::ALL.map(|x| {
+            InlineKeyboardButton::callback(
+                x.label(lang),
+                format!("{CALLBACK_PREFIX}:{}:{}", self.id, x.id()),
+            )
+        });
+
+        InlineKeyboardMarkup::new([buttons])
+    }
+
+    /// Wait for the choice, skipping the song if none is made in time
+    pub async fn wait(mut self, timeout: Duration) -> InstrumentalChoice {
+        match tokio::time::timeout(timeout, &mut self.rx).await {
+            Ok(Ok(choice)) => choice,
+            _ => {
+                trace!(id = ?self.id, "No instrumental choice made, using the default");
+                InstrumentalChoice::default()
+            }
+        }
+    }
+}
+impl Drop for PendingInstrumentalChoice {
+    fn drop(&mut self) {
+        PENDING
+            .lock()
+            .expect("Pending instrumental choices lock poisoned")
+            .remove(&self.id);
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use teloxide::types::{ChatId, MessageId, UserId};
+use tokio::{sync::Notify, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, trace, warn};
+
+pub type JobId = u64;
+
+static JOBS: Lazy<Mutex<BTreeMap<JobId, Job>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(1);
+/// Notified whenever a job finishes
+static JOB_FINISHED: Notify = Notify::const_new();
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// How long cancelled jobs get to clean up (eg. update their status messages) on shutdown
+const SHUTDOWN_CLEANUP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Who requested the job and where
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::struct_field_names)]
+pub struct JobOrigin {
+    pub chat_id: ChatId,
+    pub user_id: Option<UserId>,
+    /// The message the user sent to start the job
+    pub msg_id: MessageId,
+    /// The message the bot uses to report the job status
+    pub status_msg_id: Option<MessageId>,
+}
+impl JobOrigin {
+    fn matches_message(&self, msg_id: MessageId) -> bool {
+        self.msg_id == msg_id || self.status_msg_id == Some(msg_id)
+    }
+}
+
+#[derive(Debug)]
+struct Job {
+    /// Not set for jobs submitted through the API
+    origin: Option<JobOrigin>,
+    cancel_token: CancellationToken,
+    #[allow(dead_code)]
+    handle: JoinHandle<()>,
+}
+
+/// Removes the job from the registry when the job task finishes (or panics)
+struct JobGuard(JobId);
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        trace!(job = ?self.0, "Removing job");
+        JOBS.lock().expect("Jobs lock poisoned").remove(&self.0);
+        JOB_FINISHED.notify_waiters();
+    }
+}
+
+pub struct Jobs;
+impl Jobs {
+    /// Spawn a new tracked job.
+    ///
+    /// The job is given a cancellation token and should stop as soon as possible once it gets
+    /// cancelled.
+    pub fn spawn<F, Fut>(origin: JobOrigin, job: F) -> JobId
+    where
+        F: FnOnce(CancellationToken) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self::spawn_with_origin(Some(origin), job)
+    }
+
+    /// Spawn a new tracked job that wasn't requested in a chat (eg. one submitted through the
+    /// API). It can only be cancelled by its ID.
+    pub fn spawn_detached<F, Fut>(job: F) -> JobId
+    where
+        F: FnOnce(CancellationToken) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self::spawn_with_origin(None, job)
+    }
+
+    fn spawn_with_origin<F, Fut>(origin: Option<JobOrigin>, job: F) -> JobId
+    where
+        F: FnOnce(CancellationToken) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let id = NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed);
+        let cancel_token = CancellationToken::new();
+        let fut = job(cancel_token.clone());
+
+        // Hold the lock while spawning so the job can't remove itself before it's inserted
+        let mut jobs = JOBS.lock().expect("Jobs lock poisoned");
+
+        let handle = tokio::task::spawn(async move {
+            let _guard = JobGuard(id);
+
+            fut.await;
+        });
+
+        trace!(job = ?id, ?origin, "Job spawned");
+        jobs.insert(
+            id,
+            Job {
+                origin,
+                cancel_token,
+                handle,
+            },
+        );
+
+        id
+    }
+
+    /// Number of jobs that are waiting or being processed
+    pub fn len() -> usize {
+        JOBS.lock().expect("Jobs lock poisoned").len()
+    }
+
+    /// Cancel a job of the user in the chat.
+    ///
+    /// If `msg_id` is set, the job that was started by (or reports its status in) that message is
+    /// cancelled, otherwise the latest job of the user in the chat is cancelled.
+    ///
+    /// Returns whether a job was cancelled.
+    pub fn cancel(chat_id: ChatId, user_id: Option<UserId>, msg_id: Option<MessageId>) -> bool {
+        let jobs = JOBS.lock().expect("Jobs lock poisoned");
+
+        let cancel_token = jobs
+            .iter()
+            .rev()
+            .filter(|(_, job)| !job.cancel_token.is_cancelled())
+            .filter_map(|(id, job)| job.origin.map(|origin| (id, job, origin)))
+            .filter(|(_, _, origin)| origin.chat_id == chat_id)
+            .filter(|(_, _, origin)| user_id.is_none() || origin.user_id == user_id)
+            .find(|(_, _, origin)| msg_id.map_or(true, |msg_id| origin.matches_message(msg_id)))
+            .map(|(id, job, _)| {
+                trace!(job = ?id, "Cancelling job");
+                job.cancel_token.clone()
+            });
+        drop(jobs);
+
+        cancel_token.is_some_and(|cancel_token| {
+            cancel_token.cancel();
+            true
+        })
+    }
+
+    /// Cancel the job with the ID. Returns whether it was still running.
+    pub fn cancel_by_id(id: JobId) -> bool {
+        let cancel_token = JOBS
+            .lock()
+            .expect("Jobs lock poisoned")
+            .get(&id)
+            .filter(|job| !job.cancel_token.is_cancelled())
+            .map(|job| job.cancel_token.clone());
+
+        cancel_token.is_some_and(|cancel_token| {
+            trace!(job = ?id, "Cancelling job");
+            cancel_token.cancel();
+            true
+        })
+    }
+
+    /// Whether jobs are being cancelled because the bot is shutting down
+    pub fn is_shutting_down() -> bool {
+        SHUTTING_DOWN.load(Ordering::Relaxed)
+    }
+
+    /// Wait for the running jobs to finish.
+    ///
+    /// Jobs that don't finish within the grace period are cancelled (and given a bit of time to
+    /// let their users know).
+    pub async fn shutdown(grace_period: Duration) {
+        info!(
+            jobs = Self::len(),
+            ?grace_period,
+            "Waiting for jobs to finish"
+        );
+        if Self::wait_for_all(grace_period).await {
+            return;
+        }
+
+        SHUTTING_DOWN.store(true, Ordering::Relaxed);
+        let cancel_tokens = JOBS
+            .lock()
+            .expect("Jobs lock poisoned")
+            .values()
+            .map(|x| x.cancel_token.clone())
+            .collect::<Vec<_>>();
+        warn!(jobs = cancel_tokens.len(), "Cancelling unfinished jobs");
+        for cancel_token in cancel_tokens {
+            cancel_token.cancel();
+        }
+
+        if !Self::wait_for_all(SHUTDOWN_CLEANUP_TIMEOUT).await {
+            warn!(
+                jobs = Self::len(),
+                "Some jobs didn't finish after being cancelled"
+            );
+        }
+    }
+
+    /// Returns whether all jobs finished before the timeout
+    async fn wait_for_all(timeout: Duration) -> bool {
+        let wait = async {
+            loop {
+                let finished = JOB_FINISHED.notified();
+                tokio::pin!(finished);
+                finished.as_mut().enable();
+
+                if Self::len() == 0 {
+                    return;
+                }
+
+                finished.await;
+            }
+        };
+
+        tokio::time::timeout(timeout, wait).await.is_ok()
+    }
+}
+
+/// The message the job panicked with
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|x| (*x).to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panic_messages() {
+        let message = |f: fn()| {
+            let panic = std::panic::catch_unwind(f).expect_err("Panics");
+            panic_message(&*panic)
+        };
+
+        assert_eq!(message(|| panic!("static message")), "static message");
+        assert_eq!(message(|| panic!("formatted {}", 42)), "formatted 42");
+        assert_eq!(message(|| std::panic::panic_any(42)), "unknown panic");
+    }
+}
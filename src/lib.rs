@@ -0,0 +1,40 @@
+pub use karaokify_core::{downloader, events, preflight, processor};
+
+pub mod access;
+pub mod admin_report;
+pub mod api;
+pub mod archive_channel;
+pub mod bot;
+pub mod cache;
+pub mod chat_settings;
+pub mod cli;
+pub mod config;
+pub mod eta;
+pub mod health;
+pub mod helpers;
+pub mod history;
+pub mod http_server;
+pub mod i18n;
+pub mod in_flight;
+pub mod instrumental_choice;
+pub mod jobs;
+pub mod lyrics;
+pub mod metrics;
+pub mod notifier;
+pub mod options;
+pub mod original_message;
+pub mod output_choice;
+pub mod pipeline;
+pub mod queue;
+pub mod quota;
+pub mod recent_messages;
+pub mod reprocess;
+pub mod result_sink;
+pub mod search;
+pub mod settings_commands;
+pub mod song_details;
+pub mod song_job;
+pub mod song_request;
+pub mod store;
+pub mod telegram_sink;
+pub mod webhook;
@@ -0,0 +1,457 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use tracing::{debug, trace};
+
+use crate::helpers::{audio_meta::AudioMeta, html, http::CLIENT};
+
+const LRCLIB_SEARCH_URL: &str = "https://lrclib.net/api/search";
+/// Titles and artists that share less of their words than this are a different song
+const MIN_NAME_SIMILARITY: f64 = 0.5;
+/// Songs whose duration differs by more than this are a different version (eg. a live one)
+const MAX_DURATION_DIFFERENCE: Duration = Duration::from_secs(10);
+/// The best match is only used if its overall score (from 0 to 1) is at least this
+const MIN_SCORE: f64 = 0.7;
+
+/// Lyrics of a song found on LRCLIB
+#[derive(Debug, Clone)]
+pub struct Lyrics {
+    /// The artist and title of the song the lyrics are for, which can differ from the ones that
+    /// were searched for
+    pub artist: String,
+    pub title: String,
+    pub plain: Option<String>,
+    /// Lyrics with timestamps in the LRC format
+    pub synced: Option<String>,
+}
+impl Lyrics {
+    /// Find the lyrics of the song with the metadata, using `title` if the metadata doesn't
+    /// have one.
+    ///
+    /// Returns `None` if no result matches the song closely enough.
+    #[tracing::instrument]
+    pub async fn find(meta: &AudioMeta, title: &str) -> anyhow::Result<Option<Self>> {
+        let wanted = WantedSong::new(
+            meta.title.as_deref().unwrap_or(title),
+            meta.artist.as_deref(),
+            meta.duration,
+        );
+
+        let mut query = vec![("track_name", wanted.title.as_str())];
+        if let Some(artist) = &wanted.artist {
+            query.push(("artist_name", artist));
+        }
+
+        let results = CLIENT
+            .get(LRCLIB_SEARCH_URL)
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<LrclibResult>>()
+            .await?;
+        trace!(?results, "Got lyrics search response");
+
+        let res = best_match(&wanted, results);
+        debug!(
+            found = ?res.as_ref().map(|x| (&x.artist, &x.title)),
+            "Searched for lyrics"
+        );
+
+        Ok(res)
+    }
+
+    /// The plain lyrics, or the synced ones without the timestamps if there aren't any
+    pub fn plain_text(&self) -> Option<String> {
+        if let Some(plain) = self.plain.as_deref().filter(|x| !x.trim().is_empty()) {
+            return Some(plain.trim().to_string());
+        }
+
+        let text = self
+            .synced
+            .as_deref()?
+            .lines()
+            .map(strip_lrc_tags)
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        Some(text.trim().to_string()).filter(|x| !x.is_empty())
+    }
+
+    /// Messages with the plain lyrics, each of them fitting into a Telegram message. The first
+    /// one says which song the lyrics are for, since they're often for a different one.
+    pub fn messages(&self) -> Vec<String> {
+        let Some(text) = self.plain_text() else {
+            return vec![];
+        };
+
+        let header = format!(
+            "Lyrics for: {} — {}, report if wrong\n\n",
+            html::escape_value(&self.artist),
+            html::escape_value(&self.title)
+        );
+
+        let mut res = vec![header];
+        for line in text
+            .lines()
+            .flat_map(|x| split_long_line(x, html::MAX_MESSAGE_LENGTH))
+        {
+            let current = res.last_mut().expect("There's always a message");
+            if current.chars().count() + line.chars().count() + 1 > html::MAX_MESSAGE_LENGTH {
+                res.push(String::new());
+            }
+
+            let current = res.last_mut().expect("There's always a message");
+            current.push_str(&line);
+            current.push('\n');
+        }
+
+        res
+    }
+}
+
+/// The song the lyrics are searched for, with the names normalized for matching
+#[derive(Debug)]
+struct WantedSong {
+    title: String,
+    artist: Option<String>,
+    duration: Option<Duration>,
+}
+impl WantedSong {
+    fn new(title: &str, artist: Option<&str>, duration: Option<Duration>) -> Self {
+        let title = strip_brackets(title);
+
+        // Titles from video sites are often `Artist - Title`
+        let (title, artist) = match (artist, title.split_once(" - ")) {
+            (Some(artist), _) => (title.clone(), Some(artist.to_string())),
+            (None, Some((artist, title))) => (title.to_string(), Some(artist.to_string())),
+            (None, None) => (title.clone(), None),
+        };
+
+        Self {
+            title: title.trim().to_string(),
+            artist: artist.map(|x| x.trim().to_string()),
+            duration,
+        }
+    }
+
+    /// How well the result matches the song, from 0 to 1. `None` if it's a different song.
+    fn score(&self, result: &LrclibResult) -> Option<f64> {
+        let mut scores = vec![];
+
+        let title = similarity(&self.title, &result.track_name);
+        if title < MIN_NAME_SIMILARITY {
+            return None;
+        }
+        scores.push(title);
+
+        if let (Some(artist), Some(result_artist)) = (&self.artist, &result.artist_name) {
+            let artist = similarity(artist, result_artist);
+            if artist < MIN_NAME_SIMILARITY {
+                return None;
+            }
+            scores.push(artist);
+        }
+
+        let result_duration = result
+            .duration
+            .and_then(|x| Duration::try_from_secs_f64(x).ok());
+        if let (Some(duration), Some(result_duration)) = (self.duration, result_duration) {
+            let difference = duration
+                .checked_sub(result_duration)
+                .or_else(|| result_duration.checked_sub(duration))
+                .unwrap_or_default();
+            if difference > MAX_DURATION_DIFFERENCE {
+                return None;
+            }
+            scores.push(1.0 - difference.as_secs_f64() / MAX_DURATION_DIFFERENCE.as_secs_f64());
+        }
+
+        #[allow(clippy::cast_precision_loss)]
+        Some(scores.iter().sum::<f64>() / scores.len() as f64)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LrclibResult {
+    track_name: String,
+    artist_name: Option<String>,
+    /// In seconds
+    duration: Option<f64>,
+    #[serde(default)]
+    instrumental: bool,
+    plain_lyrics: Option<String>,
+    synced_lyrics: Option<String>,
+}
+
+/// The result that matches the song best, if any match it closely enough
+fn best_match(wanted: &WantedSong, results: Vec<LrclibResult>) -> Option<Lyrics> {
+    let (score, result) = results
+        .into_iter()
+        .filter(|x| !x.instrumental && (x.plain_lyrics.is_some() || x.synced_lyrics.is_some()))
+        .filter_map(|x| Some((wanted.score(&x)?, x)))
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))?;
+    trace!(score, ?result, "Best lyrics match");
+
+    if score < MIN_SCORE {
+        return None;
+    }
+
+    Some(Lyrics {
+        artist: result.artist_name.unwrap_or_default(),
+        title: result.track_name,
+        plain: result.plain_lyrics,
+        synced: result.synced_lyrics,
+    })
+}
+
+/// How many of the words the names share, from 0 (none) to 1 (all of them).
+///
+/// Case, punctuation and the parts in brackets (eg. `(Remastered)`) are ignored.
+fn similarity(a: &str, b: &str) -> f64 {
+    let a = words(a);
+    let b = words(b);
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+
+    let common = a.iter().filter(|x| b.contains(x)).count();
+
+    #[allow(clippy::cast_precision_loss)]
+    let res = 2.0 * common as f64 / (a.len() + b.len()) as f64;
+
+    res.min(1.0)
+}
+
+fn words(text: &str) -> Vec<String> {
+    strip_brackets(text)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Remove the parts in brackets, eg. `(Official Video)` or `[Remastered 2011]`
+fn strip_brackets(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut depth = 0_usize;
+
+    for c in text.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth = depth.saturating_sub(1),
+            c if depth == 0 => res.push(c),
+            _ => {}
+        }
+    }
+
+    res.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The text of a line of LRC lyrics without its timestamps, eg. `[00:12.34]Hello` is `Hello`
+fn strip_lrc_tags(line: &str) -> &str {
+    let mut line = line.trim();
+
+    while let Some(rest) = line.strip_prefix('[') {
+        match rest.split_once(']') {
+            Some((_, rest)) => line = rest.trim_start(),
+            None => break,
+        }
+    }
+
+    line
+}
+
+/// Escape the line for a message, splitting it into parts that fit into a message if it's too
+/// long
+fn split_long_line(line: &str, max_chars: usize) -> Vec<String> {
+    // Escaping makes a character at most 5 characters long (`&amp;`)
+    let chars = line.chars().collect::<Vec<_>>();
+    if chars.len() * 5 < max_chars {
+        return vec![html::escape(line)];
+    }
+
+    chars
+        .chunks(max_chars / 5)
+        .map(|x| html::escape(&x.iter().collect::<String>()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn search_results() -> Vec<LrclibResult> {
+        let json = std::fs::read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/lrclib/search.json"),
+        )
+        .expect("Fixture exists");
+
+        serde_json::from_str(&json).expect("Valid search response")
+    }
+
+    fn lyrics(plain: Option<&str>, synced: Option<&str>) -> Lyrics {
+        Lyrics {
+            artist: "Queen".to_string(),
+            title: "Bohemian Rhapsody".to_string(),
+            plain: plain.map(Into::into),
+            synced: synced.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn wanted_songs() {
+        let wanted = WantedSong::new("Queen - Bohemian Rhapsody (Official Video)", None, None);
+        assert_eq!(wanted.title, "Bohemian Rhapsody");
+        assert_eq!(wanted.artist.as_deref(), Some("Queen"));
+
+        // The artist from the tags is trusted more
+        let wanted = WantedSong::new("AC - DC [Live]", Some("Band "), None);
+        assert_eq!(wanted.title, "AC - DC");
+        assert_eq!(wanted.artist.as_deref(), Some("Band"));
+
+        let wanted = WantedSong::new("  Yesterday  ", None, None);
+        assert_eq!(wanted.title, "Yesterday");
+        assert_eq!(wanted.artist, None);
+    }
+
+    #[test]
+    fn the_matching_version_is_found() {
+        let wanted = WantedSong::new(
+            "Bohemian Rhapsody (Remastered 2011)",
+            Some("Queen"),
+            Some(Duration::from_secs(356)),
+        );
+
+        let found = best_match(&wanted, search_results()).expect("Match");
+
+        assert_eq!(found.title, "Bohemian Rhapsody");
+        assert_eq!(found.artist, "Queen");
+        assert_eq!(
+            found.plain.as_deref(),
+            Some("Is this the real life?\nIs this just fantasy?")
+        );
+    }
+
+    #[test]
+    fn different_songs_dont_match() {
+        for wanted in [
+            WantedSong::new("Bohemian Rhapsody", Some("Panic! at the Disco"), None),
+            WantedSong::new("Another One Bites the Dust", Some("Queen"), None),
+            // Only the live version (and the instrumental) are this short
+            WantedSong::new(
+                "Bohemian Rhapsody",
+                Some("Queen"),
+                Some(Duration::from_secs(200)),
+            ),
+        ] {
+            assert!(
+                best_match(&wanted, search_results()).is_none(),
+                "{wanted:?}"
+            );
+        }
+
+        assert!(best_match(&WantedSong::new("Song", None, None), vec![]).is_none());
+    }
+
+    #[test]
+    fn scores() {
+        let results = search_results();
+        let wanted = WantedSong::new(
+            "Bohemian Rhapsody",
+            Some("Queen"),
+            Some(Duration::from_secs(354)),
+        );
+
+        assert_eq!(wanted.score(&results[1]), Some(1.0));
+        // 5 seconds off is half of the allowed difference
+        let wanted_later = WantedSong::new(
+            "Bohemian Rhapsody",
+            Some("Queen"),
+            Some(Duration::from_secs(359)),
+        );
+        let score = wanted_later.score(&results[1]).expect("Score");
+        assert!((score - (1.0 + 1.0 + 0.5) / 3.0).abs() < 1e-9, "{score}");
+
+        assert_eq!(wanted.score(&results[2]), None);
+        assert_eq!(wanted.score(&results[0]), None);
+    }
+
+    #[test]
+    fn name_similarity() {
+        assert!((similarity("Bohemian Rhapsody", "bohemian rhapsody!") - 1.0).abs() < 1e-9);
+        assert!(
+            (similarity("Bohemian Rhapsody (Live Aid)", "Bohemian Rhapsody") - 1.0).abs() < 1e-9
+        );
+        assert!((similarity("Don't Stop Me Now", "Don't Stop") - 0.75).abs() < 1e-9);
+        assert!(similarity("Yesterday", "Tomorrow").abs() < 1e-9);
+        assert!(similarity("", "Song").abs() < 1e-9);
+        assert!(similarity("(Intro)", "(Intro)").abs() < 1e-9);
+    }
+
+    #[test]
+    fn brackets() {
+        assert_eq!(strip_brackets("Song (Official Video) [HD]"), "Song");
+        assert_eq!(
+            strip_brackets("Song (feat. X (Remix))  Pt. 2"),
+            "Song Pt. 2"
+        );
+        assert_eq!(strip_brackets("Song) (unbalanced"), "Song");
+    }
+
+    #[test]
+    fn lrc_tags() {
+        assert_eq!(strip_lrc_tags("[00:12.34]Hello"), "Hello");
+        assert_eq!(strip_lrc_tags("[00:12.34][01:02.03] Chorus"), "Chorus");
+        assert_eq!(strip_lrc_tags("[ar:Queen]"), "");
+        assert_eq!(strip_lrc_tags("No tags"), "No tags");
+        assert_eq!(strip_lrc_tags("[unclosed"), "[unclosed");
+    }
+
+    #[test]
+    fn plain_text() {
+        assert_eq!(
+            lyrics(Some(" Plain \n"), Some("[00:01.00]Synced")).plain_text(),
+            Some("Plain".to_string())
+        );
+        assert_eq!(
+            lyrics(
+                Some("  "),
+                Some("[ti:Song]\n[00:01.00]Line 1\n[00:02.00] Line 2\n")
+            )
+            .plain_text(),
+            Some("Line 1\nLine 2".to_string())
+        );
+        assert_eq!(lyrics(None, Some("[00:01.00]")).plain_text(), None);
+        assert_eq!(lyrics(None, None).plain_text(), None);
+        assert!(lyrics(None, None).messages().is_empty());
+    }
+
+    #[test]
+    fn messages_fit_into_telegram_messages() {
+        let line = "Is this the real life? <Is this just fantasy?> & caught in a landslide";
+        let long_line = "x".repeat(html::MAX_MESSAGE_LENGTH);
+        let text = format!("{}\n{long_line}", [line; 200].join("\n"));
+
+        let messages = lyrics(Some(&text), None).messages();
+
+        assert!(messages.len() > 2);
+        assert_eq!(
+            messages[0].lines().next(),
+            Some("Lyrics for: Queen — Bohemian Rhapsody, report if wrong")
+        );
+        assert!(messages
+            .iter()
+            .all(|x| x.chars().count() <= html::MAX_MESSAGE_LENGTH));
+        assert!(messages[0].contains("&lt;Is this just fantasy?&gt; &amp; caught"));
+        // Lines that are too long on their own are split too
+        let x_count = messages
+            .iter()
+            .map(|x| x.matches('x').count())
+            .sum::<usize>();
+        assert_eq!(x_count, html::MAX_MESSAGE_LENGTH);
+    }
+}
@@ -1,37 +1,83 @@
-mod bot;
-mod downloader;
-mod helpers;
-mod processor;
-
-use std::{
-    path::PathBuf,
-    sync::{Arc, Mutex},
-};
+use std::{panic::AssertUnwindSafe, sync::Arc, time::Instant};
 
+use access::{Access, AccessRule};
+use api::ApiServer;
 use bot::{TelegramBot, TeloxideBot};
+use cache::ResultCache;
+use chat_settings::ChatSettings;
+use cli::{CliCommand, ProcessArgs};
+use config::{check_config_file, Config, PipelineConfig};
 use downloader::Downloader;
-use helpers::{status_message::StatusMessage, temp_dir::TempDir};
+use futures::FutureExt;
+use health::Health;
+use helpers::{
+    duration::format_duration,
+    html,
+    log_format::init_log,
+    status_message::StatusMessage,
+    telegram_file::TelegramFile,
+    temp_cleanup::TempCleanup,
+    topic::{topic_id, InTopic},
+    url_normalize::normalize_url,
+};
+use history::{History, JobStatus};
+use http_server::HttpServer;
+use i18n::Lang;
+use instrumental_choice::InstrumentalChoice;
+use jobs::{panic_message, JobOrigin, Jobs};
+use karaokify::{
+    access, api, bot, cache, chat_settings, cli, config, downloader, events, health, helpers,
+    history, http_server, i18n, instrumental_choice, jobs, metrics, options, original_message,
+    output_choice, pipeline, preflight, processor, quota, recent_messages, reprocess, search,
+    settings_commands, song_job, song_request, telegram_sink, webhook,
+};
+use metrics::Metrics;
 use once_cell::sync::Lazy;
+use options::SongOptions;
+use original_message::OriginalMessage;
+use output_choice::OutputChoice;
+use pipeline::DemucsPipeline;
 use processor::demucs::{DemucsModel, DemucsProcessor};
+use quota::Quota;
+use recent_messages::{MessageState, RecentMessages};
+use reprocess::Reprocess;
+use search::{PendingSearch, Search, SearchChoice};
+use settings_commands::{change_chat_settings, set_chat_language, settings_reply};
+use song_job::{
+    cancelled_text, process_request, report_panic, reprocess_song, run_api_job, song_span,
+    with_job_id, SongRequester, DOWNLOAD_QUEUE, PROCESSING_QUEUE,
+};
+use song_request::{
+    deep_link_payload, deep_link_url, RequestedSong, SkippedUrl, SongRequest, SongRequestError,
+    SongSource,
+};
+use telegram_sink::send_file_ids;
 use teloxide::{
-    payloads::SendMessageSetters,
+    payloads::{AnswerCallbackQuerySetters, AnswerInlineQuerySetters, SendMessageSetters},
     prelude::*,
-    types::{InputFile, InputMedia, InputMediaAudio},
+    types::{
+        BotCommand, InlineKeyboardButton, InlineKeyboardMarkup, InlineQueryResult,
+        InlineQueryResultArticle, InlineQueryResultCachedAudio, InputMessageContent,
+        InputMessageContentText, MessageEntityKind, ParseMode,
+    },
     utils::command::BotCommands,
 };
-use tokio::sync::Semaphore;
-use tracing::{debug, field, info, info_span, level_filters::LevelFilter, trace, warn, Instrument};
-use tracing_subscriber::{filter::Builder as TracingFilterBuilder, util::SubscriberInitExt};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 use url::Url;
+use webhook::Webhook;
 
-static SONG_SEMAPHORE: Lazy<Arc<Semaphore>> = Lazy::new(|| Arc::new(Semaphore::new(1)));
+static STARTED_AT: Lazy<Instant> = Lazy::new(Instant::now);
 
-const MAX_PAYLOAD_SIZE: u64 = {
-    let kb = 1000;
-    let mb = kb * 1000;
-
-    50 * mb
-};
+/// How many of the latest jobs of the user `/history` shows
+const HISTORY_LENGTH: usize = 10;
+/// How many results Telegram accepts in a single answer to an inline query
+const MAX_INLINE_RESULTS: usize = 50;
+/// How long (in seconds) Telegram keeps the answer to an inline query for a song that isn't
+/// processed yet, so the files are offered soon after it is
+const INLINE_MISS_CACHE_TIME: u32 = 10;
+/// Payload of the button shown for inline queries without a link, which just starts the bot
+const INLINE_HINT_PAYLOAD: &str = "inline";
 
 #[tokio::main]
 async fn main() {
@@ -43,21 +89,165 @@ async fn main() {
         }
     }
 
+    Lazy::force(&STARTED_AT);
     init_log();
+    events::set_listener(Metrics::pipeline_event);
+
+    // Loaded up front so mistakes in the config file show up right away
+    Config::global();
+    PipelineConfig::global();
+    check_config_file();
+
+    let command = match CliCommand::parse(std::env::args().skip(1)) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{e}\n\n{}", cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    match command {
+        CliCommand::Bot => run_bot().await,
+        CliCommand::Process(args) => std::process::exit(run_cli(args).await),
+        CliCommand::Help => println!("{}", cli::USAGE),
+    }
+}
+
+/// Run the Telegram bot until it gets a shutdown signal
+async fn run_bot() {
+    let config = Config::global();
+    info!(
+        downloads = config.max_concurrent_downloads,
+        processing = config.max_concurrent_processing,
+        "Concurrency limits set"
+    );
+
+    // Started before the checks so `/readyz` can tell that the bot is still starting up
+    HttpServer::spawn(|| {
+        vec![
+            ("download", DOWNLOAD_QUEUE.len()),
+            ("processing", PROCESSING_QUEUE.len()),
+        ]
+    });
+
+    if let Err(e) = preflight::check().await {
+        error!("Preflight check failed: {e}");
+        std::process::exit(1);
+    }
+    ApiServer::spawn(run_api_job);
+
+    info!(
+        handlers = Downloader::handler_names().join(" -> "),
+        "Download handlers set"
+    );
+
+    TempCleanup::remove_stale().await;
+    if let Some(interval) = config.temp_cleanup_interval {
+        tokio::spawn(TempCleanup::run_periodically(interval));
+    }
+
+    tokio::spawn(DemucsProcessor::warm_up(&config.prewarm_models));
 
     info!("Starting command bot...");
 
     let bot = TelegramBot::instance();
+    info!(
+        api_url = %TelegramBot::raw().api_url(),
+        max_payload_mb = config.max_payload_size / 1000 / 1000,
+        "Using Telegram Bot API"
+    );
 
     bot.set_my_commands(Command::bot_commands())
         .send()
         .await
         .expect("Failed to set commands");
+    for lang in Lang::ALL.into_iter().filter(|x| *x != Lang::En) {
+        bot.set_my_commands(translated_commands(lang))
+            .language_code(lang.code())
+            .send()
+            .await
+            .expect("Failed to set translated commands");
+    }
+    bot.get_me().await.expect("Failed to get the bot's info");
+    Health::set_ready();
+    if config.health_addr.is_some() {
+        tokio::spawn(Health::self_check());
+    }
+
+    let handler = dptree::entry()
+        .branch(Update::filter_message().endpoint(answer))
+        .branch(Update::filter_edited_message().endpoint(answer_edited))
+        .branch(Update::filter_callback_query().endpoint(answer_callback))
+        .branch(Update::filter_inline_query().endpoint(answer_inline_query));
+    let mut dispatcher = Dispatcher::builder(bot, handler)
+        .error_handler(std::sync::Arc::new(
+            |e: teloxide::RequestError| async move {
+                Metrics::telegram_error(&e);
+                error!(?e, "Failed to handle update");
+            },
+        ))
+        .build();
+
+    let shutdown_token = dispatcher.shutdown_token();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Got shutdown signal, no longer accepting new songs");
+
+        match shutdown_token.shutdown() {
+            Ok(f) => f.await,
+            Err(e) => warn!(?e, "Failed to stop dispatcher"),
+        }
+    });
+
+    match &config.webhook {
+        Some(webhook) => {
+            let listener = Webhook::listen(bot, webhook)
+                .await
+                .expect("Failed to set up the webhook");
+            dispatcher
+                .dispatch_with_listener(
+                    listener,
+                    LoggingErrorHandler::with_custom_text("Failed to receive updates"),
+                )
+                .await;
+            Webhook::delete(bot).await;
+        }
+        None => {
+            Webhook::delete_stale(bot).await;
+            dispatcher.dispatch().await;
+        }
+    }
+
+    Jobs::shutdown(config.shutdown_grace_period).await;
+    info!("Shut down");
+}
+
+/// Process the song given on the command line, printing the progress to the terminal. Returns
+/// the exit code.
+async fn run_cli(args: ProcessArgs) -> i32 {
+    if let Err(e) = preflight::check().await {
+        error!("Preflight check failed: {e}");
+        return 1;
+    }
+
+    cli::process(args, &DemucsPipeline, |status| eprintln!("{status}")).await
+}
 
-    Dispatcher::builder(bot, Update::filter_message().endpoint(answer))
-        .build()
-        .dispatch()
-        .await;
+/// Resolves when the bot should shut down (on SIGTERM or SIGINT)
+async fn shutdown_signal() {
+    let mut terminate =
+        signal(SignalKind::terminate()).expect("Failed to listen for the terminate signal");
+
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => {
+            if let Err(e) = res {
+                warn!(?e, "Failed to listen for the interrupt signal");
+                std::future::pending::<()>().await;
+            }
+        }
+
+        _ = terminate.recv() => {}
+    }
 }
 
 #[derive(BotCommands, Debug, Clone)]
@@ -69,299 +259,1139 @@ enum Command {
     #[command(description = "display this text.")]
     Help,
     #[command(description = "start using the bot.")]
-    Start,
+    Start(String),
+    #[command(
+        description = "cancel your latest song (or the one you're replying to) that's waiting \
+                       or being processed."
+    )]
+    Cancel,
+    #[command(
+        description = "karaokify the song in the message you're replying to (options can be \
+                       added after the command)."
+    )]
+    Karaokify,
+    #[command(
+        description = "forget the cached result of a link so it gets processed again \
+                             (admins only)."
+    )]
+    Forget(String),
+    #[command(description = "show how busy the bot is.")]
+    Status,
+    #[command(description = "show your recent songs and get their files again.")]
+    History,
+    #[command(
+        description = "show and change the default options of songs sent in this chat \
+                       (`/settings reset` goes back to the defaults)."
+    )]
+    Settings(String),
+    #[command(
+        description = "show or change the language of the bot in this chat (eg. `/language hr`, \
+                       `/language auto` uses the language of your app)."
+    )]
+    Language(String),
+    #[command(description = "let a user (or a group, by its ID) use the bot (admins only).")]
+    Allow(String),
+    #[command(
+        description = "stop a user (or a group, by its ID) from using the bot (admins only)."
+    )]
+    Block(String),
 }
 
 #[tracing::instrument(skip(bot, msg), fields(chat = %msg.chat.id, msg = %msg.id))]
 async fn answer(bot: &TeloxideBot, msg: Message) -> ResponseResult<()> {
     trace!(?msg, "Got message");
+    Health::record_contact();
+
+    if !Access::is_allowed(msg.chat.id, msg.from().map(|x| x.id)) {
+        return deny_access(bot, &msg).await;
+    }
+    let bot_me = bot.get_me().await?;
+
+    if let Some(cmd) = msg
+        .text()
+        .and_then(|x| Command::parse(x, bot_me.username()).ok())
+    {
+        return handle_command(bot, msg, cmd).await;
+    }
+
+    if !is_for_bot(&msg, bot_me.username()) {
+        trace!("Message in group doesn't mention the bot");
+        return Ok(());
+    }
+
+    handle_message(bot, msg).await
+}
+
+/// Handle the edited message like a new one if no job was started for it (eg. the link had a
+/// typo), replacing the previous reply. Jobs of the message that are still waiting in the queue
+/// are replaced, the ones that already started aren't processed again.
+#[tracing::instrument(skip(bot, msg), fields(chat = %msg.chat.id, msg = %msg.id))]
+async fn answer_edited(bot: &TeloxideBot, msg: Message) -> ResponseResult<()> {
+    trace!(?msg, "Got edited message");
+    Health::record_contact();
+
+    let bot_me = bot.get_me().await?;
+    if msg
+        .text()
+        .is_some_and(|x| Command::parse(x, bot_me.username()).is_ok())
+    {
+        trace!("Edited commands aren't run again");
+        return Ok(());
+    }
+
+    match RecentMessages::get(msg.chat.id, msg.id) {
+        Some(MessageState::Started) => {
+            debug!("Song of the edited message is already being processed");
+            return Ok(());
+        }
+        Some(MessageState::Queued) => {
+            info!("Replacing the queued jobs of the edited message");
+            let mut cancelled = 0;
+            while Jobs::cancel(msg.chat.id, None, Some(msg.id)) {
+                cancelled += 1;
+            }
+            // The new jobs are submitted instead
+            if let Some(user) = msg.from() {
+                Quota::refund(user.id, cancelled);
+            }
+        }
+        Some(MessageState::Replied(reply_id)) => {
+            debug!("Replacing the reply to the edited message");
+            if let Err(e) = bot.delete_message(msg.chat.id, reply_id).await {
+                debug!(?e, "Failed to delete the reply to the edited message");
+            }
+        }
+        None if !RecentMessages::is_recent(&msg) => {
+            trace!("Edited message is too old to be handled again");
+            return Ok(());
+        }
+        None => {}
+    }
+
+    answer(bot, msg).await
+}
+
+/// Offer the cached files of the song linked in the inline query, so they can be sent into any
+/// chat. Songs that weren't processed yet can be sent to the bot in a private chat first.
+#[tracing::instrument(skip(bot, query), fields(user = %query.from.id))]
+async fn answer_inline_query(bot: &TeloxideBot, query: InlineQuery) -> ResponseResult<()> {
+    trace!(?query, "Got inline query");
+    Health::record_contact();
+
+    // The files are requested by the user, as if in a private chat with the bot
+    let user_chat_id = ChatId::from(query.from.id);
+    if !Access::is_allowed(user_chat_id, Some(query.from.id)) {
+        debug!("User isn't allowed to use the bot");
+        bot.answer_inline_query(&query.id, [])
+            .is_personal(true)
+            .await?;
+        return Ok(());
+    }
+    let lang = Lang::for_chat(user_chat_id, Some(&query.from));
+
+    let Some(url) = SongRequest::inline_query_url(&query.query).await else {
+        trace!("Inline query doesn't contain a supported link");
+        bot.answer_inline_query(&query.id, [])
+            .switch_pm_text(lang.text("inline-hint"))
+            .switch_pm_parameter(INLINE_HINT_PAYLOAD)
+            .await?;
+        return Ok(());
+    };
+
+    let options = ChatSettings::get(user_chat_id).song_options();
+    let Some(cached) = ResultCache::get(&url, options) else {
+        debug!(url = ?url.as_str(), "Song of the inline query wasn't processed yet");
+        return answer_inline_miss(bot, &query, &url, lang).await;
+    };
+    info!(url = ?url.as_str(), "Offering cached song inline");
+
+    // Telegram asks for the rest with the offset of the next page
+    let offset = query.offset.parse::<usize>().unwrap_or(0);
+    let results = cached
+        .file_ids
+        .iter()
+        .enumerate()
+        .skip(offset)
+        .take(MAX_INLINE_RESULTS)
+        .map(|(i, file_id)| {
+            InlineQueryResult::CachedAudio(InlineQueryResultCachedAudio::new(
+                i.to_string(),
+                file_id,
+            ))
+        })
+        .collect::<Vec<_>>();
+    let next_offset = Some(offset + MAX_INLINE_RESULTS)
+        .filter(|x| *x < cached.file_ids.len())
+        .map(|x| x.to_string())
+        .unwrap_or_default();
+
+    bot.answer_inline_query(&query.id, results)
+        .is_personal(true)
+        .next_offset(next_offset)
+        .await?;
+
+    Ok(())
+}
+
+/// Answer the inline query for a song that wasn't processed yet with a message linking to the
+/// bot, which processes the song once it's started with the link
+async fn answer_inline_miss(
+    bot: &TeloxideBot,
+    query: &InlineQuery,
+    url: &Url,
+    lang: Lang,
+) -> ResponseResult<()> {
     let bot_me = bot.get_me().await?;
+    let payload = deep_link_payload(url);
 
-    let Some(msg_text) = msg.text() else {
+    let text = lang.format(
+        "inline-not-processed-message",
+        &[
+            ("song", &html::escape_value(url)),
+            ("bot", &bot_me.username()),
+        ],
+    );
+    let mut article = InlineQueryResultArticle::new(
+        "not-processed",
+        lang.text("inline-not-processed"),
+        InputMessageContent::Text(InputMessageContentText::new(text).parse_mode(ParseMode::Html)),
+    )
+    .description(lang.text("inline-not-processed-description"));
+
+    // Links that are too long for a deep link have to be sent to the bot by hand
+    if let Some(payload) = &payload {
+        let mut link = bot_me.tme_url();
+        link.set_query(Some(&format!("start={payload}")));
+        article = article.reply_markup(InlineKeyboardMarkup::new([[InlineKeyboardButton::url(
+            lang.text("inline-open-bot"),
+            link,
+        )]]));
+    }
+
+    let mut answer = bot
+        .answer_inline_query(&query.id, [InlineQueryResult::Article(article)])
+        .is_personal(true)
+        .cache_time(INLINE_MISS_CACHE_TIME);
+    if let Some(payload) = payload {
+        answer = answer
+            .switch_pm_text(lang.text("inline-process"))
+            .switch_pm_parameter(payload);
+    }
+    answer.await?;
+
+    Ok(())
+}
+
+/// Tell users in private chats that the bot is private (not every time though), messages in
+/// groups are ignored
+async fn deny_access(bot: &TeloxideBot, msg: &Message) -> ResponseResult<()> {
+    let Some(user) = msg.from() else {
         return Ok(());
     };
+    debug!(user = %user.id, "User isn't allowed to use the bot");
+
+    if msg.chat.is_private() && Access::should_reply_denied(user.id) {
+        bot.send_message(msg.chat.id, Lang::of_message(msg).text("access-denied"))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Whether the bot should handle the (non-command) message.
+///
+/// In groups only messages mentioning the bot are handled so it doesn't react to every link,
+/// unless the group is configured to have every message handled.
+fn is_for_bot(msg: &Message, bot_username: &str) -> bool {
+    if msg.chat.is_private() || Config::global().group_chat_ids.contains(&msg.chat.id) {
+        return true;
+    }
+
+    let mention = format!("@{bot_username}");
+    msg.parse_entities()
+        .or_else(|| msg.parse_caption_entities())
+        .unwrap_or_default()
+        .iter()
+        .any(|x| {
+            matches!(x.kind(), MessageEntityKind::Mention)
+                && x.text().eq_ignore_ascii_case(&mention)
+        })
+}
+
+#[tracing::instrument(skip(bot, query), fields(user = %query.from.id))]
+async fn answer_callback(bot: &TeloxideBot, query: CallbackQuery) -> ResponseResult<()> {
+    trace!(?query, "Got callback query");
+    Health::record_contact();
+
+    let data = query.data.as_deref().unwrap_or_default();
+    // Without the message (eg. if it is too old) the user is checked on their own
+    let chat_id = query
+        .message
+        .as_ref()
+        .map_or_else(|| ChatId::from(query.from.id), |x| x.chat.id);
+    let lang = Lang::for_chat(chat_id, Some(&query.from));
+    let res = if !Access::is_allowed(chat_id, Some(query.from.id)) {
+        Err(anyhow::anyhow!(lang.text("access-denied-short")))
+    } else if let Some((source_id, model)) = Reprocess::parse_callback(data) {
+        queue_reprocess(&query, source_id, model, lang)
+    } else if let Some((search_id, choice)) = Search::parse_callback(data) {
+        queue_search_choice(bot, &query, search_id, choice, lang).await
+    } else if let Some(entry_id) = History::parse_callback(data) {
+        resend_history_entry(&query, entry_id, lang).await
+    } else if let Some(action) = ChatSettings::parse_callback(data) {
+        change_chat_settings(bot, &query, action, lang).await
+    } else if let Some((id, choice)) = InstrumentalChoice::parse_callback(data) {
+        InstrumentalChoice::choose(id, choice, query.from.id, lang)
+    } else {
+        OutputChoice::choose(data, query.from.id, lang).map(|_| ())
+    };
 
-    match Command::parse(msg_text, bot_me.username()) {
-        Ok(c) => handle_command(bot, msg, c).await,
-        Err(_) => handle_message(bot, msg).await,
+    let mut answer = bot.answer_callback_query(query.id);
+    if let Err(e) = res {
+        trace!(?e, "Could not handle callback query");
+        answer = answer.text(e.to_string());
     }
+    answer.await?;
+
+    Ok(())
 }
 
 async fn handle_command(bot: &TeloxideBot, msg: Message, cmd: Command) -> ResponseResult<()> {
     trace!("Handling command");
+    let lang = Lang::of_message(&msg);
 
     match cmd {
         Command::Help => {
-            bot.send_message(msg.chat.id, Command::descriptions().to_string())
+            bot.send_message(msg.chat.id, help_text(lang))
+                .in_topic(topic_id(&msg))
                 .await?;
         }
 
-        Command::Start => {
-            bot.send_message(
+        Command::Start(payload) => {
+            // Deep links (`t.me/<bot>?start=<payload>`) can contain the link to a song
+            if let Some(url) = deep_link_url(&payload) {
+                if queue_deep_link(bot, &msg, &url).await? {
+                    return Ok(());
+                }
+            }
+
+            bot.send_message(msg.chat.id, start_text(lang))
+                .in_topic(topic_id(&msg))
+                .await?;
+        }
+
+        Command::Cancel => {
+            let cancelled = Jobs::cancel(
                 msg.chat.id,
-                "Just send a link to a song (YouTube, Spotify, Deezer, Tidal...) and the bot will \
-                 try and remove the vocals from it!",
-            )
-            .await?;
+                msg.from().map(|x| x.id),
+                msg.reply_to_message().map(|x| x.id),
+            );
+
+            if !cancelled {
+                bot.send_message(msg.chat.id, lang.text("cancel-nothing"))
+                    .reply_to_message_id(msg.id)
+                    .in_topic(topic_id(&msg))
+                    .allow_sending_without_reply(true)
+                    .await?;
+            }
+        }
+
+        Command::Karaokify => {
+            // Without a reply the song might be in the command message itself
+            let song_msg = msg.reply_to_message().unwrap_or(&msg);
+
+            queue_song(bot, &msg, song_msg).await?;
+        }
+
+        Command::Status => {
+            bot.send_message(msg.chat.id, status_text(lang))
+                .reply_to_message_id(msg.id)
+                .in_topic(topic_id(&msg))
+                .allow_sending_without_reply(true)
+                .await?;
+        }
+
+        Command::History => {
+            let (text, keyboard) = history_reply(&msg, lang);
+
+            let mut request = bot
+                .send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .in_topic(topic_id(&msg))
+                .allow_sending_without_reply(true);
+            if let Some(keyboard) = keyboard {
+                request = request.reply_markup(keyboard);
+            }
+            request.await?;
+        }
+
+        Command::Settings(args) => {
+            let reply = settings_reply(bot, &msg, &args, lang).await?;
+
+            bot.send_message(msg.chat.id, reply.text(lang))
+                .reply_to_message_id(msg.id)
+                .in_topic(topic_id(&msg))
+                .allow_sending_without_reply(true)
+                .reply_markup(reply.keyboard(lang))
+                .await?;
+        }
+
+        Command::Language(code) => {
+            let reply = set_chat_language(bot, &msg, &code).await?;
+
+            bot.send_message(msg.chat.id, reply)
+                .reply_to_message_id(msg.id)
+                .in_topic(topic_id(&msg))
+                .allow_sending_without_reply(true)
+                .await?;
+        }
+
+        Command::Allow(ref id) | Command::Block(ref id) => {
+            let rule = if matches!(cmd, Command::Allow(_)) {
+                AccessRule::Allowed
+            } else {
+                AccessRule::Blocked
+            };
+            let reply = set_access_rule(&msg, id, rule, lang);
+
+            bot.send_message(msg.chat.id, reply)
+                .reply_to_message_id(msg.id)
+                .in_topic(topic_id(&msg))
+                .allow_sending_without_reply(true)
+                .await?;
+        }
+
+        Command::Forget(url) => {
+            let reply = forget_cached_url(&msg, &url, lang);
+
+            bot.send_message(msg.chat.id, reply)
+                .reply_to_message_id(msg.id)
+                .in_topic(topic_id(&msg))
+                .allow_sending_without_reply(true)
+                .await?;
         }
     }
     Ok(())
 }
 
-async fn handle_message(bot: &TeloxideBot, msg: Message) -> ResponseResult<()> {
-    trace!(?msg, "Handling message");
-    let Some(msg_text) = msg.text() else {
-        trace!("Message does not contain text");
-        return Ok(());
+/// Welcome text listing the services the enabled handlers can download from
+fn start_text(lang: Lang) -> String {
+    lang.format(
+        "start",
+        &[("services", &Downloader::supported_services().join(", "))],
+    )
+}
+
+/// The commands, the supported services and the current limits
+fn help_text(lang: Lang) -> String {
+    let config = Config::global();
+
+    let mut limits = vec![
+        lang.format(
+            "help-limit-size",
+            &[(
+                "size",
+                &(PipelineConfig::global().max_download_size / 1000 / 1000),
+            )],
+        ),
+        lang.format(
+            "help-limit-links",
+            &[
+                ("links", &config.max_message_links),
+                ("tracks", &config.max_playlist_tracks),
+            ],
+        ),
+    ];
+    if let Some(quota) = config.daily_quota {
+        limits.push(lang.format("help-limit-quota", &[("quota", &quota)]));
+    }
+    if let Some(interval) = config.min_submission_interval {
+        limits.push(lang.format(
+            "help-limit-interval",
+            &[("interval", &format_duration(interval))],
+        ));
+    }
+    limits.push(lang.format(
+        "help-queues",
+        &[
+            ("downloads", &DOWNLOAD_QUEUE.len()),
+            ("processing", &PROCESSING_QUEUE.len()),
+        ],
+    ));
+
+    lang.format(
+        "help",
+        &[
+            ("commands", &command_descriptions(lang)),
+            ("services", &Downloader::supported_services().join(", ")),
+            ("limits", &limits.join("\n")),
+        ],
+    )
+}
+
+/// The list of commands, with the translated descriptions if there are any
+fn command_descriptions(lang: Lang) -> String {
+    if lang == Lang::En {
+        return Command::descriptions().to_string();
+    }
+
+    let commands = Command::bot_commands()
+        .into_iter()
+        .map(|x| {
+            let description = command_description(&x, lang).unwrap_or(&x.description);
+
+            format!("{} — {description}", x.command)
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!("{}\n\n{commands}", lang.text("commands-header"))
+}
+
+/// The commands with the descriptions translated into the language, to be shown to the users
+/// whose app is in it
+fn translated_commands(lang: Lang) -> Vec<BotCommand> {
+    Command::bot_commands()
+        .into_iter()
+        .map(|mut x| {
+            if let Some(description) = command_description(&x, lang) {
+                x.description = description.to_string();
+            }
+
+            x
+        })
+        .collect()
+}
+
+/// The translated description of the command, eg. the `command-help` message for `/help`
+fn command_description(command: &BotCommand, lang: Lang) -> Option<&'static str> {
+    let name = command.command.trim_start_matches('/');
+
+    lang.get(&format!("command-{name}"))
+}
+
+/// Queue sizes, uptime and how the download handlers are doing
+fn status_text(lang: Lang) -> String {
+    let ago = |x: Option<Instant>| {
+        x.map_or_else(
+            || lang.text("status-never"),
+            |x| lang.format("status-ago", &[("duration", &format_duration(x.elapsed()))]),
+        )
     };
 
-    let parsed_url = match url::Url::parse(msg_text) {
-        Ok(u) => u,
-        Err(e) => {
-            bot.send_message(
-                msg.chat.id,
-                "Could not parse message URL!\nPlease send a link to the song you want to \
-                 karaokify.",
+    let handlers = Downloader::handler_health()
+        .into_iter()
+        .map(|(name, health)| {
+            let ok = match (health.last_success, health.last_failure) {
+                (_, None) => true,
+                (None, Some(_)) => false,
+                (Some(success), Some(failure)) => success > failure,
+            };
+
+            let icon = if health.is_skipped() {
+                "⏸️"
+            } else if ok {
+                "✅"
+            } else {
+                "⚠️"
+            };
+
+            lang.format(
+                "status-handler",
+                &[
+                    ("icon", &icon),
+                    ("name", &name),
+                    ("success", &ago(health.last_success)),
+                    ("failure", &ago(health.last_failure)),
+                ],
             )
-            .reply_to_message_id(msg.id)
-            .await?;
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
 
-            trace!(?e, "Could not parse URL");
+    lang.format(
+        "status",
+        &[
+            ("jobs", &Jobs::len()),
+            ("downloading", &DOWNLOAD_QUEUE.active()),
+            ("downloads_waiting", &DOWNLOAD_QUEUE.len()),
+            ("processing", &PROCESSING_QUEUE.active()),
+            ("processing_waiting", &PROCESSING_QUEUE.len()),
+            ("device", &DemucsProcessor::device_description()),
+            ("uptime", &format_duration(STARTED_AT.elapsed())),
+            ("handlers", &handlers),
+        ],
+    )
+}
 
-            return Ok(());
+/// The recent songs of the user who sent the message, with a keyboard to get their files again
+fn history_reply(msg: &Message, lang: Lang) -> (String, Option<InlineKeyboardMarkup>) {
+    let Some(user) = msg.from() else {
+        return (lang.text("history-no-user"), None);
+    };
+
+    let entries = match History::list(user.id, HISTORY_LENGTH) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!(?e, "Failed to get history");
+            return (lang.text("history-failed"), None);
         }
     };
+    let entries = entries
+        .into_iter()
+        .filter(|x| x.is_visible_in(msg.chat.id))
+        .collect::<Vec<_>>();
 
-    let task_span = {
-        let span = info_span!(
-        "process_song",
-        url = ?parsed_url.as_str(),
-        uid = field::Empty,
-        user = field::Empty,
-        name = field::Empty,
-        );
+    if entries.is_empty() {
+        return (lang.text("history-empty"), None);
+    }
 
-        if let Some(from) = msg.from() {
-            if let Some(u) = &from.username {
-                span.record("user", field::display(u));
-            }
-            span.record("uid", field::display(from.id));
-            span.record("name", field::display(from.full_name()));
-        }
+    let lines = entries
+        .iter()
+        .enumerate()
+        .map(|(i, x)| {
+            let status = match (x.status, &x.file_ids) {
+                (JobStatus::Done, Some(_)) => "✅",
+                (JobStatus::Done, None) => "☑️",
+                (JobStatus::Failed, _) => "❌",
+            };
 
-        span
-    };
+            format!(
+                "{}. {status} {} ({})",
+                i + 1,
+                html::escape_value(&x.title),
+                x.date()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
 
-    tokio::task::spawn(
-        async {
-            info!("New song queued");
+    (
+        lang.format("history", &[("songs", &lines)]),
+        Some(History::keyboard(&entries)),
+    )
+}
+
+/// Send the files of the history entry again, replying to the message with the history.
+///
+/// Returns the reason shown to the user if the files can't be sent.
+async fn resend_history_entry(
+    query: &CallbackQuery,
+    entry_id: u64,
+    lang: Lang,
+) -> anyhow::Result<()> {
+    let Some(history_msg) = &query.message else {
+        anyhow::bail!(lang.text("history-expired"));
+    };
 
-            let res = process_song(msg.into(), parsed_url).await;
+    // Only the user's own entries can be found
+    let Some(entry) = History::get(query.from.id, entry_id)? else {
+        anyhow::bail!(lang.text("history-not-yours"));
+    };
+    if !entry.is_visible_in(history_msg.chat.id) {
+        anyhow::bail!(lang.text("history-other-chat"));
+    }
+    let Some(file_ids) = entry.file_ids else {
+        anyhow::bail!(lang.text("history-no-files"));
+    };
+    info!(?entry_id, "Sending song from history");
 
-            if let Err(e) = res {
-                warn!(?e, "Failed to process song");
-            } else {
-                info!("Song processed");
-            }
-        }
-        .instrument(task_span),
-    );
+    let mut msg = StatusMessage::from_message(history_msg);
+    msg.set_lang(lang);
+    send_file_ids(&msg, &file_ids, Some(&entry.title)).await?;
 
     Ok(())
 }
 
-async fn process_song(mut msg: StatusMessage, url: Url) -> ResponseResult<()> {
-    msg.update_message("Waiting in queue...").await?;
+/// Allow or block the user or group if the message was sent by an admin.
+///
+/// Returns the reply for the user.
+fn set_access_rule(msg: &Message, id: &str, rule: AccessRule, lang: Lang) -> String {
+    let config = Config::global();
+    let is_admin = msg.from().is_some_and(|x| config.admin_ids.contains(&x.id));
+    if !is_admin {
+        return lang.text("admins-only");
+    }
 
-    let permit = SONG_SEMAPHORE
-        .acquire()
-        .await
-        .expect("Semaphore should not be closed");
+    let Ok(id) = id.trim().parse::<i64>() else {
+        return lang.text("access-invalid-id");
+    };
 
-    msg.update_message("Downloading song...").await?;
+    if let Err(e) = Access::set_rule(id, rule) {
+        warn!(?e, ?id, ?rule, "Failed to set access rule");
+        return lang.text("save-failed");
+    }
+    info!(?id, ?rule, "Access rule set");
 
-    let temp_dir = TempDir::with_prefix("karaokify-").await?;
+    let blocked_in_config =
+        u64::try_from(id).is_ok_and(|x| config.access.blocked_users.contains(&UserId(x)));
+    let key = match rule {
+        AccessRule::Allowed if blocked_in_config => "access-allowed-still-blocked",
+        AccessRule::Allowed => "access-allowed",
+        AccessRule::Blocked => "access-blocked",
+    };
 
-    let song_file_path = match Downloader::download_song(temp_dir.path(), &url).await {
-        Err(e) => {
-            msg.update_message(&format!("Download failed.\n\nReason: {e}"))
-                .await?;
-            return Ok::<(), teloxide::RequestError>(());
-        }
+    lang.format(key, &[("id", &id)])
+}
+
+/// Remove the cached results of the URL if the message was sent by an admin.
+///
+/// Returns the reply for the user.
+fn forget_cached_url(msg: &Message, url: &str, lang: Lang) -> String {
+    let is_admin = msg
+        .from()
+        .is_some_and(|x| Config::global().admin_ids.contains(&x.id));
+    if !is_admin {
+        return lang.text("admins-only");
+    }
 
-        Ok(p) => p,
+    let url = match Url::parse(url.trim()) {
+        Ok(x) => x,
+        Err(e) => return lang.format("forget-invalid-url", &[("reason", &html::escape_value(e))]),
     };
 
-    trace!(?song_file_path, "Song downloaded");
+    // Cached under the normalized URL
+    let url = normalize_url(&url).url;
 
-    msg.update_message(
-        "Download finished. Processing song...\n\nThis will take approximately 2x the song \
-         duration.",
-    )
-    .await?;
+    match ResultCache::forget(&url) {
+        Ok(n) => lang.format("forget-done", &[("count", &n)]),
+        Err(e) => {
+            warn!(?e, "Failed to forget cached result");
+            lang.text("forget-failed")
+        }
+    }
+}
 
-    info!("Processing downloaded song...");
-    let stem_paths = match DemucsProcessor::split_into_stems(
-        temp_dir.path(),
-        &song_file_path,
-        DemucsModel::HTDemucs,
-    )
-    .await
+async fn handle_message(bot: &TeloxideBot, msg: Message) -> ResponseResult<()> {
+    trace!(?msg, "Handling message");
+
+    if msg.text().or_else(|| msg.caption()).is_none() && TelegramFile::from_message(&msg).is_none()
     {
-        Ok(s) => s,
-        Err(e) => {
-            msg.update_message(&format!("Failed to process song.\n\nReason:{e}"))
-                .await?;
-            return Ok(());
+        trace!("Message does not contain text or audio");
+        return Ok(());
+    }
+
+    queue_song(bot, &msg, &msg).await
+}
+
+/// Queue the songs from `song_msg` as requested by `msg`, each in its own job
+async fn queue_song(bot: &TeloxideBot, msg: &Message, song_msg: &Message) -> ResponseResult<()> {
+    let lang = Lang::of_message(msg);
+    let request = match SongRequest::from_message(song_msg, msg).await {
+        Ok(x) => x,
+        Err(SongRequestError::NoSong) if Config::global().search => {
+            if let Some(query) = SongRequest::search_query(song_msg) {
+                return offer_search(bot, msg, &query).await;
+            }
+
+            return reply_rejected(bot, msg, lang.text("no-song-search")).await;
+        }
+        Err(SongRequestError::NoSong) => {
+            trace!("Could not find a song in the message");
+
+            return reply_rejected(bot, msg, lang.text("no-song")).await;
+        }
+        Err(SongRequestError::NoSupportedUrl(skipped)) => {
+            trace!(?skipped, "None of the links in the message are supported");
+
+            let links = skipped_urls_text(&skipped, lang);
+            let text = lang.format("no-supported-links", &[("links", &links)]);
+            return reply_rejected(bot, msg, text).await;
+        }
+        Err(SongRequestError::InvalidOptions(e)) => {
+            trace!(?e, "Could not parse options");
+
+            let text = lang.format("invalid-options", &[("reason", &html::escape_value(&e))]);
+            return reply_rejected(bot, msg, text).await;
         }
     };
+    trace!(?request, "Parsed song request");
+
+    let SongRequest {
+        mut songs,
+        options,
+        mut skipped,
+    } = request;
+    let allowed = try_submit(bot, msg, songs.len()).await?;
+    if allowed == 0 {
+        return Ok(());
+    }
+    // Each song is its own job, so each one counts towards the quota
+    skipped.extend(
+        songs
+            .split_off(allowed)
+            .into_iter()
+            .filter_map(|x| match x.source {
+                SongSource::Url(url) => Some(SkippedUrl {
+                    url,
+                    reason: "skipped-quota",
+                }),
+                SongSource::File(_) | SongSource::Path(_) => None,
+            }),
+    );
+
+    let total = songs.len();
+    let original = OriginalMessage::new(msg, total);
+    for (i, song) in songs.into_iter().enumerate() {
+        let mut header = vec![];
+        if let (SongSource::Url(url), 2..) = (&song.source, total) {
+            header.push(lang.format(
+                "song-of-message",
+                &[
+                    ("number", &(i + 1)),
+                    ("total", &total),
+                    ("url", &html::escape_value(url)),
+                ],
+            ));
+        }
+        // Only mentioned once, on the first song
+        if i == 0 && !skipped.is_empty() {
+            header.push(lang.format(
+                "skipped-links",
+                &[("links", &skipped_urls_text(&skipped, lang))],
+            ));
+        }
+
+        let mut status_msg = StatusMessage::from(msg);
+        status_msg.set_header((!header.is_empty()).then(|| header.join("\n")));
 
-    drop(permit);
+        spawn_song_job(msg, song, options, status_msg, original.clone()).await?;
+    }
 
-    info!("Processed downloaded song, uploading files...");
-    trace!(?stem_paths, "Stems created");
+    Ok(())
+}
 
-    msg.update_message("Finished processing song. Uploading files...")
+/// Reply to the message that no job could be started for it. The reply is replaced if the
+/// message is edited.
+async fn reply_rejected(bot: &TeloxideBot, msg: &Message, text: String) -> ResponseResult<()> {
+    let reply = bot
+        .send_message(msg.chat.id, text)
+        .reply_to_message_id(msg.id)
+        .in_topic(topic_id(msg))
         .await?;
+    RecentMessages::set(msg.chat.id, msg.id, MessageState::Replied(reply.id));
 
-    let (stem_path_chunks, failed_files) =
-        chunk_files_by_size(stem_paths, MAX_PAYLOAD_SIZE / 10 * 8).await;
+    Ok(())
+}
 
-    trace!("Uploading files");
-    for stem_paths in stem_path_chunks {
-        trace!(?stem_paths, "Uploading files chunk");
-        let media_group = stem_paths
-            .into_iter()
-            .map(|stem| InputMedia::Audio(InputMediaAudio::new(InputFile::file(stem))))
-            .collect::<Vec<_>>();
+/// Count the `count` songs of the request towards the quota of the user who sent `msg`.
+///
+/// Returns how many of the songs can be processed, `0` if they're sending songs too often (and
+/// then tells them how long to wait).
+async fn try_submit(bot: &TeloxideBot, msg: &Message, count: usize) -> ResponseResult<usize> {
+    let Some(user) = msg.from() else {
+        return Ok(count);
+    };
+    let wait = match Quota::try_submit(user.id, count) {
+        Ok(allowed) => return Ok(allowed),
+        Err(wait) => wait,
+    };
 
-        TelegramBot::instance()
-            .send_media_group(msg.chat_id(), media_group)
-            .reply_to_message_id(msg.msg_replying_to_id())
-            .allow_sending_without_reply(true)
-            .send()
-            .await?;
-        trace!("Files chunk uploaded");
-    }
-    trace!("Files uploaded");
-
-    if !failed_files.is_empty() {
-        debug!(?failed_files, "Failed to chunk some files to size");
-        trace!("Generating failed files message");
-        let failed_files_msg = {
-            let mut msg = "Failed to upload some files:\n\n".to_string();
-
-            msg += failed_files
-                .into_iter()
-                .map(|(file, reason)| {
-                    format!(
-                        " - File: {}\n   Reason: {}\n",
-                        file.file_name().unwrap_or_default().to_string_lossy(),
-                        reason
-                    )
-                })
-                .reduce(|a, b| a + "\n" + &b)
-                .unwrap_or_default()
-                .as_str();
-
-            msg
+    bot.send_message(
+        msg.chat.id,
+        Lang::of_message(msg).format("too-often", &[("wait", &format_duration(wait))]),
+    )
+    .reply_to_message_id(msg.id)
+    .in_topic(topic_id(msg))
+    .await?;
+
+    Ok(0)
+}
+
+/// Queue the song from the link of a `/start` deep link (eg. a "karaokify this" button on a
+/// website).
+///
+/// Returns `false` if the link can't be downloaded.
+async fn queue_deep_link(bot: &TeloxideBot, msg: &Message, url: &Url) -> ResponseResult<bool> {
+    let Some(supported_url) = Downloader::supported_url(&normalize_url(url).url).await else {
+        debug!(url = ?url.as_str(), "Deep link URL isn't supported");
+        return Ok(false);
+    };
+    info!(url = ?supported_url.as_str(), "Got song from deep link");
+
+    if try_submit(bot, msg, 1).await? > 0 {
+        let normalized = normalize_url(&supported_url);
+        let song = RequestedSong {
+            source: SongSource::Url(normalized.url),
+            start: normalized.start,
         };
-        trace!(msg = ?failed_files_msg, "Failed files message generated");
 
-        trace!("Sending failed files message");
-        TelegramBot::instance()
-            .send_message(msg.chat_id(), failed_files_msg.trim())
-            .reply_to_message_id(msg.msg_replying_to_id())
-            .allow_sending_without_reply(true)
-            .send()
-            .await?;
-        trace!("Failed files message sent");
+        let options = ChatSettings::get(msg.chat.id).song_options();
+        let original = OriginalMessage::new(msg, 1);
+        spawn_song_job(msg, song, options, StatusMessage::from(msg), original).await?;
     }
 
-    trace!("Deleting status message");
-    msg.delete_message().await?;
-    trace!("Status message deleted");
+    Ok(true)
+}
 
-    Ok(())
+/// The skipped links with the reasons, one per line
+fn skipped_urls_text(skipped: &[SkippedUrl], lang: Lang) -> String {
+    skipped
+        .iter()
+        .map(|x| {
+            format!(
+                "- {}: {}",
+                html::escape_value(&x.url),
+                html::escape(&lang.text(x.reason))
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
-#[tracing::instrument(skip_all)]
-async fn chunk_files_by_size(
-    files: Vec<PathBuf>,
-    max_size: u64,
-) -> (Vec<Vec<PathBuf>>, Vec<(PathBuf, String)>) {
-    trace!("Calculating file groupings");
-    let failed = Arc::new(Mutex::new(Vec::new()));
-    let metadatas = {
-        let m = files.into_iter().map(|x| {
-            let failed = failed.clone();
-
-            async move {
-                let meta = match tokio::fs::metadata(&x).await {
-                    Ok(meta) => meta,
-                    Err(e) => {
-                        trace!(?e, "Failed to get metadata for file");
-                        if let Ok(mut failed) = failed.lock() {
-                            failed.push((x, "failed to get metadata for file".to_string()));
-                        }
-                        return None;
-                    }
-                };
-
-                Some((x, meta.len()))
-            }
-        });
+/// Queue a job processing the song requested by `msg`, with its own status message.
+/// `original` is told if the song was delivered, so `msg` can be deleted.
+async fn spawn_song_job(
+    msg: &Message,
+    song: RequestedSong,
+    options: SongOptions,
+    mut status_msg: StatusMessage,
+    original: Option<Arc<OriginalMessage>>,
+) -> ResponseResult<()> {
+    let RequestedSong { source, start } = song;
 
-        futures::future::join_all(m)
-            .await
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>()
+    let requester = SongRequester::from_message(msg);
+    let task_span = song_span(msg, &source, start, &requester.job_id);
+    let song = source.to_string();
+
+    let lang = status_msg.lang();
+    status_msg
+        .update_message(&lang.text("queue-waiting"))
+        .await?;
+
+    let origin = JobOrigin {
+        chat_id: msg.chat.id,
+        user_id: msg.from().map(|x| x.id),
+        msg_id: msg.id,
+        status_msg_id: status_msg.status_msg_id(),
     };
+    RecentMessages::set(msg.chat.id, msg.id, MessageState::Queued);
+
+    Jobs::spawn(origin, |cancel_token| {
+        async move {
+            info!("New song queued");
+
+            let res = tokio::select! {
+                res = AssertUnwindSafe(
+                    process_request(&mut status_msg, source, options, &requester)
+                ).catch_unwind() => res,
 
-    let mut res = vec![];
-    let mut res_size = 0_u64;
-    let mut res_item = vec![];
-    for (path, size) in metadatas {
-        if size > max_size {
-            trace!(?path, ?size, ?max_size, "File is too large");
-            if let Ok(mut failed) = failed.lock() {
-                failed.push((path, format!("file is too large: {} > {}", size, max_size)));
+                () = cancel_token.cancelled() => {
+                    Ok(status_msg.update_message(&cancelled_text(lang)).await.map(|()| false))
+                }
+            };
+            let res = match res {
+                Ok(res) => res,
+                Err(panic) => {
+                    let reason = panic_message(&*panic);
+                    report_panic(&mut status_msg, &song, &requester, &reason)
+                        .await
+                        .map(|()| false)
+                }
+            };
+            if let (Ok(true), Some(original)) = (&res, &original) {
+                original.delivered();
+            }
+            // Songs served from the cache never start downloading. The state of replaced jobs
+            // belongs to the new ones.
+            if !cancel_token.is_cancelled() {
+                RecentMessages::set(origin.chat_id, origin.msg_id, MessageState::Started);
+            }
+            if res.is_err() {
+                let text = with_job_id(&lang.text("job-failed"), &requester.job_id, lang);
+                // Usually Telegram failed, so this might not get through either
+                let _ = status_msg.update_message(&text).await;
+            }
+            status_msg.flush().await;
+
+            if let Err(e) = res {
+                warn!(?e, "Failed to process song");
+            } else {
+                info!("Song processed");
             }
-            continue;
         }
+        .instrument(task_span)
+    });
+
+    Ok(())
+}
 
-        if size + res_size > max_size {
-            res.push(res_item.clone());
-            res_size = 0;
-            res_item = vec![];
+/// Search for the song by name and ask the user which of the found songs to karaokify
+async fn offer_search(bot: &TeloxideBot, msg: &Message, query: &str) -> ResponseResult<()> {
+    let lang = Lang::of_message(msg);
+    let results = match Search::find(query).await {
+        Ok(x) => x,
+        Err(e) => {
+            warn!(?e, "Failed to search for song");
+            return reply_rejected(bot, msg, lang.text("search-failed")).await;
         }
+    };
 
-        res_item.push(path);
-        res_size += size;
-    }
-    if !res_item.is_empty() {
-        res.push(res_item);
+    if results.is_empty() {
+        let text = lang.format(
+            "search-no-results",
+            &[("query", &html::escape_value(query))],
+        );
+        return reply_rejected(bot, msg, text).await;
     }
-    trace!(?res, "Got file groupings");
 
-    let failed = failed
-        .lock()
-        .map_or_else(|_| vec![], |failed| failed.iter().cloned().collect());
+    let confident = Search::is_confident(query, &results);
+    let text = if confident {
+        lang.format(
+            "search-confident",
+            &[("song", &html::escape_value(&results[0]))],
+        )
+    } else {
+        lang.text("search-choose")
+    };
+    let keyboard_results = results.clone();
 
-    trace!(?failed, "Got final failed paths");
+    let search_id = Search::keep(PendingSearch {
+        user_id: msg.from().map(|x| x.id),
+        // Invalid options were already reported
+        options: SongRequest::options(msg).unwrap_or_default(),
+        results,
+    });
 
-    (res, failed)
+    let offer = bot
+        .send_message(msg.chat.id, text)
+        .reply_to_message_id(msg.id)
+        .in_topic(topic_id(msg))
+        .reply_markup(Search::keyboard(
+            search_id,
+            &keyboard_results,
+            confident,
+            lang,
+        ))
+        .await?;
+    // A new search is offered instead if the message is edited before a song is chosen
+    RecentMessages::set(msg.chat.id, msg.id, MessageState::Replied(offer.id));
+
+    Ok(())
 }
 
-fn init_log() {
-    tracing_subscriber::fmt()
-        .with_ansi(true)
-        .with_env_filter(
-            TracingFilterBuilder::default()
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env_lossy(),
+/// Process the song the user chose from the search results. The message with the results
+/// becomes the status message of the new job.
+///
+/// Returns the reason shown to the user if the song can't be processed.
+async fn queue_search_choice(
+    bot: &TeloxideBot,
+    query: &CallbackQuery,
+    search_id: u64,
+    choice: SearchChoice,
+    lang: Lang,
+) -> anyhow::Result<()> {
+    let Some(offer_msg) = &query.message else {
+        anyhow::bail!(lang.text("search-expired"));
+    };
+    let Some(msg) = offer_msg.reply_to_message() else {
+        anyhow::bail!(lang.text("search-expired"));
+    };
+    let pending = Search::take(search_id, query.from.id, lang)?;
+
+    let result = match choice {
+        SearchChoice::Result(i) => pending.results.into_iter().nth(i),
+        SearchChoice::None => None,
+    };
+    let Some(result) = result else {
+        bot.edit_message_text(offer_msg.chat.id, offer_msg.id, lang.text("search-none"))
+            .await?;
+
+        return Ok(());
+    };
+
+    if let Err(wait) = Quota::try_submit(query.from.id, 1) {
+        bot.edit_message_text(
+            offer_msg.chat.id,
+            offer_msg.id,
+            lang.format("too-often", &[("wait", &format_duration(wait))]),
         )
-        .finish()
-        .init();
+        .await?;
+
+        return Ok(());
+    }
+
+    let mut status_msg = StatusMessage::from_existing(offer_msg, msg.id);
+    status_msg.set_lang(lang);
+    status_msg.set_header(Some(
+        lang.format("song-header", &[("song", &html::escape_value(&result))]),
+    ));
+
+    let song = RequestedSong {
+        source: SongSource::Url(result.url),
+        start: None,
+    };
+    let original = OriginalMessage::new(msg, 1);
+    spawn_song_job(msg, song, pending.options, status_msg, original).await?;
+
+    Ok(())
+}
+
+/// Process the song kept by the job that sent the offer message again using `model`. The offer
+/// message becomes the status message of the new job.
+///
+/// Returns the reason shown to the user if the song can't be reprocessed.
+fn queue_reprocess(
+    query: &CallbackQuery,
+    source_id: u64,
+    model: DemucsModel,
+    lang: Lang,
+) -> anyhow::Result<()> {
+    let Some(offer_msg) = &query.message else {
+        anyhow::bail!(lang.text("reprocess-expired"));
+    };
+    let claimed = Reprocess::claim(source_id, query.from.id, lang)?;
+
+    let reply_to_id = offer_msg.reply_to_message().map_or(offer_msg.id, |x| x.id);
+    let mut status_msg = StatusMessage::from_existing(offer_msg, reply_to_id);
+    status_msg.set_lang(lang);
+
+    let origin = JobOrigin {
+        chat_id: offer_msg.chat.id,
+        user_id: Some(query.from.id),
+        msg_id: reply_to_id,
+        status_msg_id: Some(offer_msg.id),
+    };
+    let requester = SongRequester::from_user(&query.from);
+    let task_span = info_span!(
+        "reprocess_song",
+        job = requester.job_id,
+        source = source_id,
+        %model,
+        uid = %query.from.id
+    );
+    let song = claimed.source().song.title.clone();
+
+    Jobs::spawn(origin, |cancel_token| {
+        async move {
+            info!("Song queued for reprocessing");
+
+            let res = tokio::select! {
+                res = AssertUnwindSafe(
+                    reprocess_song(&mut status_msg, &claimed, model, &requester)
+                ).catch_unwind() => res,
+
+                () = cancel_token.cancelled() => {
+                    Ok(status_msg.update_message(&cancelled_text(lang)).await)
+                }
+            };
+            let res = match res {
+                Ok(res) => res,
+                Err(panic) => {
+                    let reason = panic_message(&*panic);
+                    report_panic(&mut status_msg, &song, &requester, &reason).await
+                }
+            };
+            status_msg.flush().await;
+
+            if let Err(e) = res {
+                warn!(?e, "Failed to reprocess song");
+            } else {
+                info!("Song reprocessed");
+            }
+        }
+        .instrument(task_span)
+    });
+
+    Ok(())
 }
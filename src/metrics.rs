@@ -0,0 +1,262 @@
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+use once_cell::sync::Lazy;
+use teloxide::RequestError;
+
+use crate::{
+    admin_report::FailedStage, config::Config, events::Event, processor::demucs::DemucsModel,
+};
+
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(|| Mutex::new(Registry::default()));
+
+/// Upper bounds (in seconds) of the buckets of the demucs duration histogram
+const DEMUCS_DURATION_BUCKETS: &[f64] = &[10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0];
+
+/// The exported metrics, in the order they're rendered
+const METRICS: &[MetricInfo] = &[
+    MetricInfo {
+        name: "karaokify_jobs_started_total",
+        kind: "counter",
+        help: "Songs that started processing",
+    },
+    MetricInfo {
+        name: "karaokify_jobs_succeeded_total",
+        kind: "counter",
+        help: "Songs that were processed and uploaded",
+    },
+    MetricInfo {
+        name: "karaokify_jobs_failed_total",
+        kind: "counter",
+        help: "Songs that failed, by the stage they failed in",
+    },
+    MetricInfo {
+        name: "karaokify_handler_downloads_total",
+        kind: "counter",
+        help: "Downloads tried with each handler, by outcome",
+    },
+    MetricInfo {
+        name: "karaokify_demucs_duration_seconds",
+        kind: "histogram",
+        help: "Wall-clock time it took demucs to split a song into stems, by model",
+    },
+    MetricInfo {
+        name: "karaokify_queue_depth",
+        kind: "gauge",
+        help: "Songs waiting in each queue",
+    },
+    MetricInfo {
+        name: "karaokify_telegram_errors_total",
+        kind: "counter",
+        help: "Errors returned by Telegram API requests, by kind",
+    },
+];
+
+struct MetricInfo {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+}
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Debug, Default)]
+struct Registry {
+    counters: BTreeMap<(&'static str, Labels), u64>,
+    histograms: BTreeMap<(&'static str, Labels), Histogram>,
+}
+
+#[derive(Debug)]
+struct Histogram {
+    /// Observations in each of [`DEMUCS_DURATION_BUCKETS`] (not cumulative)
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: vec![0; DEMUCS_DURATION_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = DEMUCS_DURATION_BUCKETS.iter().position(|x| value <= *x) {
+            self.buckets[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Prometheus metrics of the bot, served on `/metrics` if `KARAOKIFY_METRICS_ADDR` is set.
+///
+/// Nothing is recorded otherwise.
+pub struct Metrics;
+impl Metrics {
+    pub fn job_started() {
+        Self::increment("karaokify_jobs_started_total", vec![]);
+    }
+
+    pub fn job_succeeded() {
+        Self::increment("karaokify_jobs_succeeded_total", vec![]);
+    }
+
+    pub fn job_failed(stage: FailedStage) {
+        Self::increment(
+            "karaokify_jobs_failed_total",
+            vec![("stage", stage.to_string())],
+        );
+    }
+
+    /// Record what happened in the pipeline, set as the listener of its events
+    pub fn pipeline_event(event: &Event) {
+        match *event {
+            Event::HandlerDownload { handler, success } => Self::handler_download(handler, success),
+            Event::DemucsFinished { model, duration } => Self::demucs_duration(model, duration),
+            _ => {}
+        }
+    }
+
+    pub fn handler_download(handler: &'static str, success: bool) {
+        let outcome = if success { "success" } else { "failure" };
+
+        Self::increment(
+            "karaokify_handler_downloads_total",
+            vec![("handler", handler.into()), ("outcome", outcome.into())],
+        );
+    }
+
+    pub fn demucs_duration(model: DemucsModel, duration: Duration) {
+        if !Self::is_enabled() {
+            return;
+        }
+
+        REGISTRY
+            .lock()
+            .expect("Metrics lock poisoned")
+            .histograms
+            .entry((
+                "karaokify_demucs_duration_seconds",
+                vec![("model", model.to_string())],
+            ))
+            .or_insert_with(Histogram::new)
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn telegram_error(e: &RequestError) {
+        let kind = match e {
+            RequestError::Api(_) => "api",
+            RequestError::MigrateToChatId(_) => "migrate_to_chat_id",
+            RequestError::RetryAfter(_) => "retry_after",
+            RequestError::Network(_) => "network",
+            RequestError::InvalidJson { .. } => "invalid_json",
+            RequestError::Io(_) => "io",
+        };
+
+        Self::increment(
+            "karaokify_telegram_errors_total",
+            vec![("kind", kind.into())],
+        );
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn render(queue_depths: &[(&'static str, usize)]) -> String {
+        let registry = REGISTRY.lock().expect("Metrics lock poisoned");
+        let mut res = String::new();
+
+        for metric in METRICS {
+            let _ = writeln!(res, "# HELP {} {}", metric.name, metric.help);
+            let _ = writeln!(res, "# TYPE {} {}", metric.name, metric.kind);
+
+            for ((_, labels), value) in registry
+                .counters
+                .iter()
+                .filter(|((name, _), _)| *name == metric.name)
+            {
+                let _ = writeln!(res, "{}{} {}", metric.name, format_labels(labels), value);
+            }
+
+            for ((_, labels), histogram) in registry
+                .histograms
+                .iter()
+                .filter(|((name, _), _)| *name == metric.name)
+            {
+                let mut cumulative = 0;
+                for (le, count) in DEMUCS_DURATION_BUCKETS.iter().zip(&histogram.buckets) {
+                    cumulative += count;
+                    let mut labels = labels.clone();
+                    labels.push(("le", le.to_string()));
+                    let _ = writeln!(
+                        res,
+                        "{}_bucket{} {}",
+                        metric.name,
+                        format_labels(&labels),
+                        cumulative
+                    );
+                }
+                let mut inf_labels = labels.clone();
+                inf_labels.push(("le", "+Inf".into()));
+                let _ = writeln!(
+                    res,
+                    "{}_bucket{} {}",
+                    metric.name,
+                    format_labels(&inf_labels),
+                    histogram.count
+                );
+                let labels = format_labels(labels);
+                let _ = writeln!(res, "{}_sum{} {}", metric.name, labels, histogram.sum);
+                let _ = writeln!(res, "{}_count{} {}", metric.name, labels, histogram.count);
+            }
+
+            if metric.name == "karaokify_queue_depth" {
+                for (queue, depth) in queue_depths {
+                    let labels = format_labels(&vec![("queue", (*queue).to_string())]);
+                    let _ = writeln!(res, "{}{} {}", metric.name, labels, depth);
+                }
+            }
+        }
+        drop(registry);
+
+        res
+    }
+
+    fn increment(name: &'static str, labels: Labels) {
+        if !Self::is_enabled() {
+            return;
+        }
+
+        *REGISTRY
+            .lock()
+            .expect("Metrics lock poisoned")
+            .counters
+            .entry((name, labels))
+            .or_default() += 1;
+    }
+
+    fn is_enabled() -> bool {
+        Config::global().metrics_addr.is_some()
+    }
+}
+
+/// The labels in the `{name="value",...}` form, or nothing if there are none
+fn format_labels(labels: &Labels) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let labels = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!("{{{labels}}}")
+}
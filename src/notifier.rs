@@ -0,0 +1,245 @@
+use teloxide::{
+    payloads::{
+        SendAudioSetters, SendDocumentSetters, SendMediaGroupSetters, SendMessageSetters,
+        SendVideoSetters,
+    },
+    requests::{Request, Requester},
+    types::{ChatId, InlineKeyboardMarkup, InputFile, InputMedia, Message, MessageId},
+    RequestError,
+};
+
+use crate::{
+    bot::TelegramBot,
+    helpers::{
+        retry::{retry_after, retry_request},
+        status_message::StatusMessage,
+        topic::InTopic,
+    },
+    i18n::Lang,
+    in_flight::InFlightStatus,
+    processor::lrc::{VIDEO_HEIGHT, VIDEO_WIDTH},
+};
+
+/// What the user who requested a song is told about the job, and how the files are sent to
+/// them
+#[async_trait::async_trait]
+pub trait Notifier: Send + Sync {
+    /// Language of the messages about the job
+    fn lang(&self) -> Lang;
+
+    /// The chat the job was requested in
+    fn chat_id(&self) -> ChatId;
+
+    /// The message that requested the job
+    fn msg_replying_to_id(&self) -> MessageId;
+
+    /// Set text that is shown above every status update (eg. "Track 3/12")
+    fn set_header(&mut self, header: Option<String>);
+
+    /// Also publish every status update to the requests waiting for the same song
+    fn set_mirror(&mut self, mirror: Option<InFlightStatus>);
+
+    /// Show the text as the status of the job
+    async fn update_status(&mut self, text: &str) -> Result<(), RequestError>;
+
+    /// Show the text as the status of the job with the keyboard below it, eg. to ask the user
+    /// something. The keyboard is removed by the next update.
+    async fn update_status_with_keyboard(
+        &mut self,
+        text: &str,
+        keyboard: InlineKeyboardMarkup,
+    ) -> Result<(), RequestError>;
+
+    /// Send a separate message about the job that stays after it's done, eg. about a track of
+    /// a playlist that failed
+    async fn send_text(&self, text: &str) -> Result<(), RequestError>;
+
+    /// Send a separate message with a keyboard below it, eg. offering to process the song again
+    async fn send_text_with_keyboard(
+        &self,
+        text: &str,
+        keyboard: InlineKeyboardMarkup,
+    ) -> Result<(), RequestError>;
+
+    /// Send the audio files in a media group. Returns the sent messages.
+    async fn send_audio_group(
+        &self,
+        media_group: Vec<InputMedia>,
+    ) -> Result<Vec<Message>, RequestError>;
+
+    /// Send a file that isn't audio, eg. the zip of the files or the synced lyrics
+    async fn send_document(
+        &self,
+        document: InputFile,
+        caption: Option<&str>,
+    ) -> Result<(), RequestError>;
+
+    /// Send the karaoke video
+    async fn send_video(&self, video: InputFile, caption: &str) -> Result<(), RequestError>;
+
+    /// The job is done. The status is replaced by the summary, or removed without one.
+    async fn finish(&mut self, summary: Option<&str>) -> Result<(), RequestError>;
+}
+
+#[async_trait::async_trait]
+impl Notifier for StatusMessage {
+    fn lang(&self) -> Lang {
+        Self::lang(self)
+    }
+
+    fn chat_id(&self) -> ChatId {
+        Self::chat_id(self)
+    }
+
+    fn msg_replying_to_id(&self) -> MessageId {
+        Self::msg_replying_to_id(self)
+    }
+
+    fn set_header(&mut self, header: Option<String>) {
+        Self::set_header(self, header);
+    }
+
+    fn set_mirror(&mut self, mirror: Option<InFlightStatus>) {
+        Self::set_mirror(self, mirror);
+    }
+
+    async fn update_status(&mut self, text: &str) -> Result<(), RequestError> {
+        self.update_message(text).await
+    }
+
+    async fn update_status_with_keyboard(
+        &mut self,
+        text: &str,
+        keyboard: InlineKeyboardMarkup,
+    ) -> Result<(), RequestError> {
+        self.update_message_with_keyboard(text, keyboard).await
+    }
+
+    async fn send_text(&self, text: &str) -> Result<(), RequestError> {
+        if let Some(detached) = self.detached_status() {
+            detached.send_replace(text.to_string());
+            return Ok(());
+        }
+
+        TelegramBot::instance()
+            .send_message(self.chat_id(), text)
+            .reply_to_message_id(self.msg_replying_to_id())
+            .in_topic(self.topic_id())
+            .allow_sending_without_reply(true)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn send_text_with_keyboard(
+        &self,
+        text: &str,
+        keyboard: InlineKeyboardMarkup,
+    ) -> Result<(), RequestError> {
+        TelegramBot::instance()
+            .send_message(self.chat_id(), text)
+            .reply_markup(keyboard)
+            .reply_to_message_id(self.msg_replying_to_id())
+            .in_topic(self.topic_id())
+            .allow_sending_without_reply(true)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Media groups need at least two files, so a single file is sent as a normal audio
+    /// message.
+    async fn send_audio_group(
+        &self,
+        media_group: Vec<InputMedia>,
+    ) -> Result<Vec<Message>, RequestError> {
+        let [InputMedia::Audio(audio)] = media_group.as_slice() else {
+            return retry_request(|| {
+                TelegramBot::instance()
+                    .send_media_group(self.chat_id(), media_group.clone())
+                    .reply_to_message_id(self.msg_replying_to_id())
+                    .in_topic(self.topic_id())
+                    .allow_sending_without_reply(true)
+                    .send()
+            })
+            .await;
+        };
+
+        let sent = retry_request(|| {
+            let mut request = TelegramBot::instance()
+                .send_audio(self.chat_id(), audio.media.clone())
+                .reply_to_message_id(self.msg_replying_to_id())
+                .in_topic(self.topic_id())
+                .allow_sending_without_reply(true);
+            if let Some(caption) = &audio.caption {
+                request = request.caption(caption);
+            }
+            if let Some(title) = &audio.title {
+                request = request.title(title);
+            }
+            if let Some(performer) = &audio.performer {
+                request = request.performer(performer);
+            }
+            if let Some(duration) = audio.duration {
+                request = request.duration(u32::from(duration));
+            }
+            if let Some(thumb) = &audio.thumb {
+                request = request.thumb(thumb.clone());
+            }
+
+            request.send()
+        })
+        .await?;
+
+        Ok(vec![sent])
+    }
+
+    async fn send_document(
+        &self,
+        document: InputFile,
+        caption: Option<&str>,
+    ) -> Result<(), RequestError> {
+        retry_after(|| {
+            let mut request = TelegramBot::instance()
+                .send_document(self.chat_id(), document.clone())
+                .reply_to_message_id(self.msg_replying_to_id())
+                .in_topic(self.topic_id())
+                .allow_sending_without_reply(true);
+            if let Some(caption) = caption {
+                request = request.caption(caption);
+            }
+            request.send()
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn send_video(&self, video: InputFile, caption: &str) -> Result<(), RequestError> {
+        retry_after(|| {
+            TelegramBot::instance()
+                .send_video(self.chat_id(), video.clone())
+                .caption(caption)
+                .width(VIDEO_WIDTH)
+                .height(VIDEO_HEIGHT)
+                .supports_streaming(true)
+                .reply_to_message_id(self.msg_replying_to_id())
+                .in_topic(self.topic_id())
+                .allow_sending_without_reply(true)
+                .send()
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn finish(&mut self, summary: Option<&str>) -> Result<(), RequestError> {
+        let Some(summary) = summary else {
+            return self.delete_message().await;
+        };
+
+        // The requests waiting for the same song already got their files
+        self.set_mirror(None);
+        self.update_message(summary).await
+    }
+}
@@ -0,0 +1,280 @@
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use crate::{
+    config::Config,
+    helpers::duration::{format_timestamp, parse_timestamp},
+    output_choice::OutputChoice,
+    processor::{
+        demucs::{DemucsModel, GuideVocals, StemMode},
+        encoding::{Bitrate, Encoding, OutputFormat},
+    },
+};
+
+/// How many semitones the pitch can be shifted up or down
+const MAX_PITCH_SHIFT: i8 = 6;
+/// Range of the allowed tempo changes (in percent of the original tempo)
+const TEMPO_RANGE: std::ops::RangeInclusive<u16> = 50..=150;
+
+/// Options that don't have a value
+const FLAGS: &[&str] = &["loudnorm", "zip", "video"];
+
+/// Options the user can add after the link (or in the caption of an audio file), eg.
+/// `https://... stems=4 model=htdemucs_ft pitch=-2 tempo=0.85 loudnorm zip video 0:45-2:10`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SongOptions {
+    pub model: DemucsModel,
+    pub stem_mode: StemMode,
+    /// Only process this part of the song
+    pub trim: Option<TrimRange>,
+    /// Also create an instrumental transposed by this many semitones
+    pub pitch: Option<i8>,
+    /// Also create an instrumental with the tempo changed to this percentage of the original
+    pub tempo: Option<u16>,
+    /// How loud the vocals should be in the guide mix (the configured default if not set)
+    pub guide_vocals: Option<GuideVocals>,
+    /// Normalize the loudness of the files (also done for every song if configured)
+    pub loudnorm: bool,
+    pub format: OutputFormat,
+    /// Bitrate of MP3 files (the configured default if not set)
+    pub bitrate: Option<Bitrate>,
+    /// Which files the user wants (asked for after the download if not set)
+    pub outputs: Option<OutputChoice>,
+    /// Send all files in a single zip (also done if there are too many for a media group)
+    pub zip: bool,
+    /// Also send a karaoke video of the instrumental with the lyrics, if synced lyrics are found
+    pub video: bool,
+}
+impl SongOptions {
+    /// Whether the word from the message is an option (and not just some other text)
+    pub fn is_option(word: &str) -> bool {
+        word.contains('=') || TrimRange::looks_like_range(word) || FLAGS.contains(&word)
+    }
+
+    /// Parse `key=value` options, flags (eg. `loudnorm`, same as `loudnorm=true`) and time
+    /// ranges (eg. `0:45-2:10`)
+    pub fn parse<'a, I>(options: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        Self::parse_with_defaults(Self::default(), options)
+    }
+
+    /// Parse the options like [`Self::parse`], keeping the values of `defaults` (eg. the
+    /// settings of the chat) for the ones that aren't given
+    pub fn parse_with_defaults<'a, I>(defaults: Self, options: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut res = defaults;
+
+        for option in options {
+            if TrimRange::looks_like_range(option) {
+                res.trim = Some(option.parse()?);
+                continue;
+            }
+
+            match option {
+                "loudnorm" => {
+                    res.loudnorm = true;
+                    continue;
+                }
+                "zip" => {
+                    res.zip = true;
+                    continue;
+                }
+                "video" => {
+                    res.video = true;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let Some((key, value)) = option.split_once('=') else {
+                anyhow::bail!("invalid option {option:?}, expected `name=value`");
+            };
+
+            match key.trim().to_lowercase().as_str() {
+                "model" => res.model = value.trim().parse()?,
+                "stems" => res.stem_mode = value.trim().parse()?,
+                "pitch" => res.pitch = parse_pitch(value.trim())?,
+                "tempo" => res.tempo = parse_tempo(value.trim())?,
+                "guide" => res.guide_vocals = Some(value.trim().parse()?),
+                "format" => res.format = value.trim().parse()?,
+                "bitrate" => res.bitrate = Some(value.trim().parse()?),
+                "outputs" => res.outputs = Some(value.trim().parse()?),
+                "loudnorm" => res.loudnorm = parse_flag(value.trim())?,
+                "zip" => res.zip = parse_flag(value.trim())?,
+                "video" => res.video = parse_flag(value.trim())?,
+                _ => anyhow::bail!("unknown option {key:?}"),
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// How the files should be encoded, using the configured bitrate if none was requested
+    pub fn encoding(&self) -> Encoding {
+        Encoding {
+            format: self.format,
+            bitrate: self.bitrate.unwrap_or_else(|| Config::global().mp3_bitrate),
+        }
+    }
+}
+
+/// Parse the value of a flag, eg. `true` or `no`
+fn parse_flag(value: &str) -> anyhow::Result<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => anyhow::bail!("invalid value {value:?}, expected `true` or `false`"),
+    }
+}
+
+/// Parse the number of semitones to transpose by (eg. `-2` or `+3`), where `0` means none
+fn parse_pitch(value: &str) -> anyhow::Result<Option<i8>> {
+    let semitones = value
+        .parse::<i8>()
+        .map_err(|_| anyhow::anyhow!("invalid pitch {value:?}, expected eg. `-2` or `+3`"))?;
+
+    if !(-MAX_PITCH_SHIFT..=MAX_PITCH_SHIFT).contains(&semitones) {
+        anyhow::bail!(
+            "pitch can be shifted by at most {MAX_PITCH_SHIFT} semitones, got {semitones}"
+        );
+    }
+
+    Ok(Some(semitones).filter(|x| *x != 0))
+}
+
+/// Parse the tempo as a multiple of the original (eg. `0.85`), where `1` means no change.
+///
+/// Returns the tempo in percent.
+fn parse_tempo(value: &str) -> anyhow::Result<Option<u16>> {
+    let invalid = || {
+        anyhow::anyhow!(
+            "invalid tempo {value:?}, expected a number between {} and {} (eg. `0.85`)",
+            f64::from(*TEMPO_RANGE.start()) / 100.0,
+            f64::from(*TEMPO_RANGE.end()) / 100.0,
+        )
+    };
+
+    let tempo = value
+        .parse::<f64>()
+        .ok()
+        .filter(|x| x.is_finite())
+        .ok_or_else(invalid)?;
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let percent = (tempo * 100.0).round().clamp(0.0, f64::from(u16::MAX)) as u16;
+
+    if !TEMPO_RANGE.contains(&percent) {
+        return Err(invalid());
+    }
+
+    Ok(Some(percent).filter(|x| *x != 100))
+}
+
+/// Part of the song between two timestamps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrimRange {
+    pub start: Duration,
+    pub end: Duration,
+}
+impl TrimRange {
+    /// Whether the text looks like it's meant to be a range, eg. `0:45-2:10`
+    fn looks_like_range(text: &str) -> bool {
+        let is_timestamp =
+            |x: &str| x.contains(':') && x.chars().all(|c| c.is_ascii_digit() || c == ':');
+
+        text.split_once('-')
+            .is_some_and(|(start, end)| is_timestamp(start) && is_timestamp(end))
+    }
+
+    pub const fn duration(&self) -> Duration {
+        self.end.saturating_sub(self.start)
+    }
+
+    /// Make sure the range fits into a song that's `song_duration` long
+    pub fn validate(&self, song_duration: Duration) -> anyhow::Result<()> {
+        // The song duration is rounded down when shown to the user, so allow the same for the end
+        if self.start >= song_duration || self.end.as_secs() > song_duration.as_secs() {
+            anyhow::bail!(
+                "can't use {self} since the song is only {} long",
+                format_timestamp(song_duration)
+            );
+        }
+
+        Ok(())
+    }
+}
+impl FromStr for TrimRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("invalid time range {s:?}, expected eg. `0:45-2:10`");
+
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let start = parse_timestamp(start).ok_or_else(invalid)?;
+        let end = parse_timestamp(end).ok_or_else(invalid)?;
+
+        if start >= end {
+            anyhow::bail!("the time range {s:?} has to start before it ends");
+        }
+
+        Ok(Self { start, end })
+    }
+}
+impl Display for TrimRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            format_timestamp(self.start),
+            format_timestamp(self.end)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitrate() {
+        assert_eq!(
+            SongOptions::parse(["bitrate=v0"]).expect("VBR").bitrate,
+            Some(Bitrate::Vbr(0))
+        );
+        assert_eq!(
+            SongOptions::parse(["bitrate= 192k"]).expect("CBR").bitrate,
+            Some(Bitrate::Cbr(192))
+        );
+        assert_eq!(SongOptions::parse([]).expect("Defaults").bitrate, None);
+        assert!(SongOptions::parse(["bitrate=64"]).is_err());
+        assert!(SongOptions::parse(["bitrate=v10"]).is_err());
+    }
+
+    #[test]
+    fn pitch() {
+        for (value, pitch) in [
+            ("-2", Some(-2)),
+            ("+3", Some(3)),
+            ("6", Some(6)),
+            ("-6", Some(-6)),
+            ("0", None),
+        ] {
+            assert_eq!(
+                SongOptions::parse([format!("pitch={value}").as_str()])
+                    .expect(value)
+                    .pitch,
+                pitch,
+                "{value}"
+            );
+        }
+
+        for value in ["7", "-7", "1.5", "two", "", "-128"] {
+            assert!(
+                SongOptions::parse([format!("pitch={value}").as_str()]).is_err(),
+                "{value}"
+            );
+        }
+    }
+}
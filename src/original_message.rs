@@ -0,0 +1,126 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use teloxide::{
+    payloads::SendMessageSetters,
+    requests::Requester,
+    types::{ChatId, Message, MessageId},
+    ApiError, RequestError,
+};
+use tracing::{debug, trace, warn};
+
+use crate::{
+    bot::TelegramBot,
+    chat_settings::ChatSettings,
+    helpers::topic::{topic_id, InTopic},
+    i18n::Lang,
+    store::Store,
+};
+
+/// Chats that were told the bot can't delete the messages with the links
+const WARNED_TREE_NAME: &str = "delete_original_warned";
+
+/// The message the songs were requested with, if the chat wants it deleted (`/settings`).
+///
+/// It's deleted when the last of its jobs is done, and only if all of them delivered their
+/// songs.
+#[derive(Debug)]
+pub struct OriginalMessage {
+    chat_id: ChatId,
+    msg_id: MessageId,
+    topic_id: Option<i32>,
+    /// Jobs of the message that didn't deliver their song (yet)
+    undelivered: AtomicUsize,
+}
+impl OriginalMessage {
+    /// The message with `jobs` songs, `None` if it shouldn't be deleted
+    pub fn new(msg: &Message, jobs: usize) -> Option<Arc<Self>> {
+        if !Self::deleted_in(msg.chat.id) {
+            return None;
+        }
+
+        Some(Arc::new(Self {
+            chat_id: msg.chat.id,
+            msg_id: msg.id,
+            topic_id: topic_id(msg),
+            undelivered: AtomicUsize::new(jobs),
+        }))
+    }
+
+    /// Whether the messages with the links are deleted in the chat. Users' messages can't be
+    /// deleted by the bot in private chats, so they never are there.
+    pub fn deleted_in(chat_id: ChatId) -> bool {
+        !chat_id.is_user() && ChatSettings::get(chat_id).delete_original == Some(true)
+    }
+
+    /// One of the jobs of the message delivered its song
+    pub fn delivered(&self) {
+        self.undelivered.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Warn the chat again if the messages still can't be deleted, eg. after the setting was
+    /// turned on again
+    pub fn forget_warning(chat_id: ChatId) {
+        if let Err(e) = Store::tree(WARNED_TREE_NAME).and_then(|tree| {
+            tree.remove(chat_id.to_string())?;
+            Ok(())
+        }) {
+            debug!(?e, ?chat_id, "Failed to forget deletion warning");
+        }
+    }
+
+    async fn delete(chat_id: ChatId, msg_id: MessageId, topic_id: Option<i32>) {
+        trace!(?chat_id, ?msg_id, "Deleting original message");
+
+        match TelegramBot::instance()
+            .delete_message(chat_id, msg_id)
+            .await
+        {
+            Ok(_) => debug!(?chat_id, ?msg_id, "Original message deleted"),
+            Err(RequestError::Api(ApiError::MessageCantBeDeleted)) => {
+                warn!(?chat_id, "Not allowed to delete the original message");
+                Self::warn_once(chat_id, topic_id).await;
+            }
+            Err(e) => warn!(?e, ?chat_id, "Failed to delete original message"),
+        }
+    }
+
+    /// Tell the chat that the bot needs the permission to delete messages, unless it was told
+    /// already
+    async fn warn_once(chat_id: ChatId, topic_id: Option<i32>) {
+        let warned = Store::tree(WARNED_TREE_NAME)
+            .and_then(|tree| Ok(tree.insert(chat_id.to_string(), Vec::new())?.is_some()));
+        match warned {
+            Ok(false) => {}
+            Ok(true) => return,
+            Err(e) => {
+                debug!(?e, ?chat_id, "Failed to remember deletion warning");
+                return;
+            }
+        }
+
+        let res = TelegramBot::instance()
+            .send_message(
+                chat_id,
+                Lang::for_chat(chat_id, None).text("delete-original-failed"),
+            )
+            .in_topic(topic_id)
+            .disable_notification(true)
+            .await;
+        if let Err(e) = res {
+            warn!(?e, ?chat_id, "Failed to warn chat about deleting messages");
+        }
+    }
+}
+impl Drop for OriginalMessage {
+    fn drop(&mut self) {
+        // Never deleted if one of the songs failed, so it can still be sent again
+        if *self.undelivered.get_mut() != 0 {
+            return;
+        }
+
+        tokio::spawn(Self::delete(self.chat_id, self.msg_id, self.topic_id));
+    }
+}
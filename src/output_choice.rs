@@ -0,0 +1,196 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, UserId};
+use tokio::sync::oneshot;
+use tracing::trace;
+
+use crate::{
+    i18n::Lang,
+    processor::{self, demucs::StemMode},
+};
+
+/// Prefix of the callback data of the buttons, eg. `outputs:12:vocals`
+const CALLBACK_PREFIX: &str = "outputs";
+
+/// Songs waiting for the user to choose the outputs, keyed by the ID in the callback data
+static PENDING: Lazy<Mutex<HashMap<u64, PendingEntry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_PENDING_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Which of the created files the user wants to get
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputChoice {
+    InstrumentalOnly,
+    VocalsOnly,
+    #[default]
+    Everything,
+    /// Vocals and the separate instrument stems
+    AllStems,
+}
+impl OutputChoice {
+    pub const ALL: [Self; 4] = [
+        Self::InstrumentalOnly,
+        Self::VocalsOnly,
+        Self::Everything,
+        Self::AllStems,
+    ];
+
+    const fn id(self) -> &'static str {
+        match self {
+            Self::InstrumentalOnly => "instrumental",
+            Self::VocalsOnly => "vocals",
+            Self::Everything => "everything",
+            Self::AllStems => "stems",
+        }
+    }
+
+    pub fn label(self, lang: Lang) -> String {
+        lang.text(&format!("outputs-{}", self.id()))
+    }
+
+    /// The stems the song has to be split into. The instrument stems are only separate with
+    /// more than two stems.
+    pub const fn stem_mode(self, requested: StemMode) -> StemMode {
+        match (self, requested) {
+            (Self::AllStems, StemMode::TwoStem) => StemMode::FourStem,
+            _ => requested,
+        }
+    }
+
+    /// Whether the guide mixes and the re-encoded song should be created
+    pub const fn wants_extras(self) -> bool {
+        matches!(self, Self::Everything)
+    }
+
+    /// Whether the created file should be sent to the user
+    pub fn includes(self, file_path: &Path) -> bool {
+        let stem = processor::stem_name(file_path);
+
+        match self {
+            Self::Everything => true,
+            Self::InstrumentalOnly => stem.as_deref() == Some("music"),
+            Self::VocalsOnly => stem.as_deref() == Some("vocals"),
+            Self::AllStems => stem.is_some_and(|x| x != "music" && !x.starts_with("music-")),
+        }
+    }
+
+    /// Wait for the user to choose using the keyboard
+    pub fn ask(user_id: Option<UserId>) -> PendingChoice {
+        let id = NEXT_PENDING_ID.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+
+        PENDING
+            .lock()
+            .expect("Pending choices lock poisoned")
+            .insert(id, PendingEntry { user_id, tx });
+        trace!(?id, "Waiting for output choice");
+
+        PendingChoice { id, rx }
+    }
+
+    /// Handle a press of one of the keyboard buttons by `user_id`.
+    ///
+    /// Returns the reason shown to the user if the choice couldn't be made.
+    pub fn choose(callback_data: &str, user_id: UserId, lang: Lang) -> anyhow::Result<Self> {
+        let parsed = callback_data
+            .strip_prefix(CALLBACK_PREFIX)
+            .and_then(|x| x.strip_prefix(':'))
+            .and_then(|x| x.split_once(':'))
+            .and_then(|(id, choice)| Some((id.parse::<u64>().ok()?, choice.parse::<Self>().ok()?)));
+        let Some((id, choice)) = parsed else {
+            anyhow::bail!(lang.text("choice-unknown"));
+        };
+
+        let mut pending = PENDING.lock().expect("Pending choices lock poisoned");
+        let Some(entry) = pending.get(&id) else {
+            anyhow::bail!(lang.text("choice-expired"));
+        };
+        if entry.user_id.is_some_and(|x| x != user_id) {
+            anyhow::bail!(lang.text("choice-not-yours"));
+        }
+        let entry = pending.remove(&id).expect("Pending choice should exist");
+        drop(pending);
+
+        trace!(?id, ?choice, "Output choice made");
+        let _ = entry.tx.send(choice);
+
+        Ok(choice)
+    }
+}
+impl FromStr for OutputChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|x| x.id().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "unknown outputs {s:?}, expected one of: {}",
+                    Self::ALL.map(Self::id).join(", ")
+                )
+            })
+    }
+}
+impl Display for OutputChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.id())
+    }
+}
+
+struct PendingEntry {
+    /// Only this user can choose (anybody can if the song wasn't sent by a user)
+    user_id: Option<UserId>,
+    tx: oneshot::Sender<OutputChoice>,
+}
+
+/// A choice the user can make using the keyboard. It expires when this is dropped.
+#[derive(Debug)]
+pub struct PendingChoice {
+    id: u64,
+    rx: oneshot::Receiver<OutputChoice>,
+}
+impl PendingChoice {
+    /// Keyboard with a button for each of the choices, two per row
+    pub fn keyboard(&self, lang: Lang) -> InlineKeyboardMarkup {
+        let buttons = OutputChoice::ALL.map(|x| {
+            InlineKeyboardButton::callback(
+                x.label(lang),
+                format!("{CALLBACK_PREFIX}:{}:{x}", self.id),
+            )
+        });
+
+        InlineKeyboardMarkup::new(buttons.chunks(2).map(<[_]>::to_vec))
+    }
+
+    /// Wait for the choice, using the default one if none is made in time
+    pub async fn wait(mut self, timeout: Duration) -> OutputChoice {
+        match tokio::time::timeout(timeout, &mut self.rx).await {
+            Ok(Ok(choice)) => choice,
+            _ => {
+                trace!(id = ?self.id, "No output choice made, using the default");
+                OutputChoice::default()
+            }
+        }
+    }
+}
+impl Drop for PendingChoice {
+    fn drop(&mut self) {
+        PENDING
+            .lock()
+            .expect("Pending choices lock poisoned")
+            .remove(&self.id);
+    }
+}
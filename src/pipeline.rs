@@ -0,0 +1,96 @@
+use std::path::{Path, PathBuf};
+
+use tokio::sync::watch;
+
+use crate::{
+    downloader::DownloadedSong,
+    helpers::download::DownloadProgress,
+    processor::{
+        demucs::{DemucsModel, DemucsProcessor, DemucsProgress, GuideVocals, StemMode},
+        encoding::{Bitrate, Encoding},
+        fallback::FallbackProcessor,
+    },
+    song_request::SongSource,
+};
+
+/// What a song is split into
+#[derive(Debug, Clone, Copy)]
+pub struct SplitSettings {
+    pub model: DemucsModel,
+    pub stem_mode: StemMode,
+    /// How loud the vocals are in the extra instrumental with guide vocals, if it's created
+    pub guide_vocals: Option<GuideVocals>,
+    pub encoding: Encoding,
+}
+
+/// Downloads the songs of the jobs and splits them into stems
+#[async_trait::async_trait]
+pub trait Pipeline: Send + Sync {
+    /// Download the song into `download_dir`. Downloads of albums can contain several songs.
+    async fn download(
+        &self,
+        source: &SongSource,
+        download_dir: &Path,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<Vec<DownloadedSong>>;
+
+    /// Split the song into stems in `output_dir`. Returns the created files.
+    async fn split_into_stems(
+        &self,
+        output_dir: &Path,
+        song_file_path: &Path,
+        settings: SplitSettings,
+        progress: Option<&watch::Sender<DemucsProgress>>,
+    ) -> anyhow::Result<Vec<PathBuf>>;
+
+    /// Create an instrumental by removing the center channel, for when splitting fails
+    async fn remove_center_channel(
+        &self,
+        output_dir: &Path,
+        song_file_path: &Path,
+        bitrate: Bitrate,
+    ) -> anyhow::Result<PathBuf>;
+}
+
+/// Downloads with the handlers and splits the songs with demucs
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DemucsPipeline;
+#[async_trait::async_trait]
+impl Pipeline for DemucsPipeline {
+    async fn download(
+        &self,
+        source: &SongSource,
+        download_dir: &Path,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<Vec<DownloadedSong>> {
+        source.download(download_dir, progress).await
+    }
+
+    async fn split_into_stems(
+        &self,
+        output_dir: &Path,
+        song_file_path: &Path,
+        settings: SplitSettings,
+        progress: Option<&watch::Sender<DemucsProgress>>,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        DemucsProcessor::split_into_stems(
+            output_dir,
+            song_file_path,
+            settings.model,
+            settings.stem_mode,
+            settings.guide_vocals,
+            settings.encoding,
+            progress,
+        )
+        .await
+    }
+
+    async fn remove_center_channel(
+        &self,
+        output_dir: &Path,
+        song_file_path: &Path,
+        bitrate: Bitrate,
+    ) -> anyhow::Result<PathBuf> {
+        FallbackProcessor::remove_center_channel(output_dir, song_file_path, bitrate).await
+    }
+}
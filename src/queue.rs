@@ -0,0 +1,112 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use tokio::sync::{futures::Notified, AcquireError, Notify, Semaphore, SemaphorePermit};
+use tracing::trace;
+
+type TicketId = u64;
+
+/// A semaphore that keeps track of who is waiting for it
+#[derive(Debug)]
+pub struct SongQueue {
+    permits: usize,
+    semaphore: Semaphore,
+    waiting: Mutex<Vec<TicketId>>,
+    changed: Notify,
+    next_ticket: AtomicU64,
+}
+impl SongQueue {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            permits,
+            semaphore: Semaphore::new(permits),
+            waiting: Mutex::new(Vec::new()),
+            changed: Notify::new(),
+            next_ticket: AtomicU64::new(0),
+        }
+    }
+
+    /// Get in line for a permit.
+    ///
+    /// The ticket holds the place in the queue until it's dropped.
+    pub fn join(&self) -> QueueTicket<'_> {
+        let id = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+
+        self.waiting.lock().expect("Queue lock poisoned").push(id);
+        self.changed.notify_waiters();
+
+        trace!(ticket = ?id, "Joined queue");
+
+        QueueTicket { queue: self, id }
+    }
+
+    /// Number of tickets waiting for a permit
+    pub fn len(&self) -> usize {
+        self.waiting.lock().expect("Queue lock poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of permits that are currently held
+    pub fn active(&self) -> usize {
+        self.permits - self.semaphore.available_permits()
+    }
+
+    fn leave(&self, id: TicketId) {
+        self.waiting
+            .lock()
+            .expect("Queue lock poisoned")
+            .retain(|x| *x != id);
+        self.changed.notify_waiters();
+
+        trace!(ticket = ?id, "Left queue");
+    }
+}
+
+#[derive(Debug)]
+pub struct QueueTicket<'a> {
+    queue: &'a SongQueue,
+    id: TicketId,
+}
+impl<'a> QueueTicket<'a> {
+    /// The (1-based) position of the ticket in the queue
+    pub fn position(&self) -> usize {
+        self.queue
+            .waiting
+            .lock()
+            .expect("Queue lock poisoned")
+            .iter()
+            .position(|x| *x == self.id)
+            .map_or(0, |x| x + 1)
+    }
+
+    /// Resolves when the queue changes (somebody joins or leaves it).
+    ///
+    /// The returned future is already registered, so no changes are missed between creating it
+    /// and checking the [`position`](Self::position).
+    pub fn changed(&self) -> Pin<Box<Notified<'a>>> {
+        let mut notified = Box::pin(self.queue.changed.notified());
+        notified.as_mut().enable();
+        notified
+    }
+
+    /// Wait for a permit. The semaphore is fair so permits are handed out in queue order.
+    ///
+    /// The ticket should be dropped once the permit is acquired.
+    pub async fn acquire(&self) -> Result<SemaphorePermit<'a>, AcquireError> {
+        self.queue.semaphore.acquire().await
+    }
+}
+
+impl Drop for QueueTicket<'_> {
+    fn drop(&mut self) {
+        self.queue.leave(self.id);
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use teloxide::types::UserId;
+use tracing::trace;
+
+use crate::config::Config;
+
+/// The window in which at most `daily_quota` songs can be submitted
+const QUOTA_WINDOW: Duration = Duration::from_secs(86400);
+
+/// When each user submitted their songs (within the quota window), oldest first
+static SUBMISSIONS: Lazy<Mutex<HashMap<UserId, VecDeque<Instant>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub struct Quota;
+impl Quota {
+    /// Record a new submission of `count` songs by the user, as many of them as their quota
+    /// allows.
+    ///
+    /// Returns how many songs were recorded, or how long the user has to wait until they can
+    /// submit again if none were.
+    pub fn try_submit(user_id: UserId, count: usize) -> Result<usize, Duration> {
+        Self::record(user_id, count, true)
+    }
+
+    /// Record up to `count` more songs of a submission that was already accepted, eg. the other
+    /// tracks of a playlist. The interval between submissions doesn't apply to them.
+    ///
+    /// Returns how many songs were recorded.
+    pub fn try_add(user_id: UserId, count: usize) -> usize {
+        Self::record(user_id, count, false).unwrap_or(0)
+    }
+
+    fn record(user_id: UserId, count: usize, new_submission: bool) -> Result<usize, Duration> {
+        let config = Config::global();
+
+        if config.admin_ids.contains(&user_id) {
+            return Ok(count);
+        }
+
+        let now = Instant::now();
+        let mut submissions = SUBMISSIONS.lock().expect("Submissions lock poisoned");
+        // Forget about users who didn't submit anything for a while
+        submissions.retain(|_, times| {
+            times
+                .back()
+                .is_some_and(|x| now.duration_since(*x) < QUOTA_WINDOW)
+        });
+
+        let times = submissions.entry(user_id).or_default();
+        let interval = config.min_submission_interval.filter(|_| new_submission);
+        let res = record_songs(times, now, count, interval, config.daily_quota);
+        drop(submissions);
+
+        if let Err(wait) = res {
+            trace!(?user_id, ?wait, "User is over their quota");
+        }
+        res
+    }
+
+    /// Forget the latest `count` songs of the user, eg. when they're replaced by an edited
+    /// message
+    pub fn refund(user_id: UserId, count: usize) {
+        let mut submissions = SUBMISSIONS.lock().expect("Submissions lock poisoned");
+        if let Some(times) = submissions.get_mut(&user_id) {
+            trace!(?user_id, count, "Refunding songs");
+            times.truncate(times.len().saturating_sub(count));
+        }
+    }
+}
+
+/// Record up to `count` songs submitted `now` in the times of the user's earlier songs, as many
+/// as `daily_quota` allows. `interval` is the minimum time since their last song.
+///
+/// Returns how many songs were recorded, or how long to wait if none could be.
+fn record_songs(
+    times: &mut VecDeque<Instant>,
+    now: Instant,
+    count: usize,
+    interval: Option<Duration>,
+    daily_quota: Option<usize>,
+) -> Result<usize, Duration> {
+    while times
+        .front()
+        .is_some_and(|x| now.duration_since(*x) >= QUOTA_WINDOW)
+    {
+        times.pop_front();
+    }
+
+    let interval_wait = interval.and_then(|interval| {
+        let last = times.back()?;
+        interval.checked_sub(now.duration_since(*last))
+    });
+    let quota_wait = daily_quota.and_then(|quota| {
+        if times.len() < quota {
+            return None;
+        }
+        let oldest = times.get(times.len() - quota)?;
+        QUOTA_WINDOW.checked_sub(now.duration_since(*oldest))
+    });
+
+    if let Some(wait) = interval_wait.max(quota_wait) {
+        return Err(wait);
+    }
+
+    let allowed = daily_quota.map_or(count, |quota| count.min(quota.saturating_sub(times.len())));
+    times.extend(std::iter::repeat(now).take(allowed));
+
+    Ok(allowed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_song_counts_towards_the_quota() {
+        let now = Instant::now();
+        let mut times = VecDeque::new();
+
+        assert_eq!(record_songs(&mut times, now, 3, None, Some(5)), Ok(3));
+        // Only 2 of the next 3 songs fit
+        assert_eq!(record_songs(&mut times, now, 3, None, Some(5)), Ok(2));
+        assert_eq!(times.len(), 5);
+        assert_eq!(
+            record_songs(&mut times, now, 1, None, Some(5)),
+            Err(QUOTA_WINDOW)
+        );
+    }
+
+    #[test]
+    fn songs_are_counted_again_after_the_window() {
+        let start = Instant::now();
+        let mut times = VecDeque::new();
+        assert_eq!(record_songs(&mut times, start, 2, None, Some(2)), Ok(2));
+
+        let later = start + QUOTA_WINDOW;
+        assert_eq!(record_songs(&mut times, later, 4, None, Some(2)), Ok(2));
+    }
+
+    #[test]
+    fn the_interval_applies_to_new_submissions() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(60);
+        let mut times = VecDeque::new();
+
+        // Many songs can be submitted at once
+        assert_eq!(
+            record_songs(&mut times, start, 3, Some(interval), None),
+            Ok(3)
+        );
+
+        let soon = start + Duration::from_secs(20);
+        assert_eq!(
+            record_songs(&mut times, soon, 1, Some(interval), None),
+            Err(Duration::from_secs(40))
+        );
+        // The other tracks of an accepted submission don't wait for the interval
+        assert_eq!(record_songs(&mut times, soon, 2, None, None), Ok(2));
+    }
+
+    #[test]
+    fn without_a_quota_every_song_is_recorded() {
+        let mut times = VecDeque::new();
+
+        assert_eq!(
+            record_songs(&mut times, Instant::now(), 7, None, None),
+            Ok(7)
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use teloxide::types::{ChatId, Message, MessageId};
+use tracing::trace;
+
+/// How long after being sent messages are handled again when they're edited
+const MAX_AGE: Duration = Duration::from_secs(600);
+
+/// What happened to the recently handled messages
+static MESSAGES: Lazy<Mutex<HashMap<(ChatId, MessageId), RecentMessage>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug)]
+struct RecentMessage {
+    /// When the state last changed
+    updated_at: Instant,
+    state: MessageState,
+}
+
+/// What the bot did with a message, which decides what happens when it's edited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageState {
+    /// The bot replied without starting a job (eg. the link isn't supported or the options are
+    /// invalid). The reply is replaced if the message is edited.
+    Replied(MessageId),
+    /// The jobs of the message are waiting in the queue, they're replaced if it's edited
+    Queued,
+    /// One of the jobs of the message started downloading (or is done), so edits are ignored
+    Started,
+}
+
+/// Messages handled recently, so edits of them can be handled without processing the same song
+/// twice
+pub struct RecentMessages;
+impl RecentMessages {
+    pub fn get(chat_id: ChatId, msg_id: MessageId) -> Option<MessageState> {
+        MESSAGES
+            .lock()
+            .expect("Recent messages lock poisoned")
+            .get(&(chat_id, msg_id))
+            .map(|x| x.state)
+    }
+
+    pub fn set(chat_id: ChatId, msg_id: MessageId, state: MessageState) {
+        trace!(?chat_id, ?msg_id, ?state, "Recording message state");
+        let now = Instant::now();
+
+        let mut messages = MESSAGES.lock().expect("Recent messages lock poisoned");
+        messages.retain(|_, x| now.duration_since(x.updated_at) < MAX_AGE);
+        messages.insert(
+            (chat_id, msg_id),
+            RecentMessage {
+                updated_at: now,
+                state,
+            },
+        );
+    }
+
+    /// Whether the message was sent recently enough to be handled again if it's edited. The
+    /// states of older messages are forgotten, so they would be processed again.
+    pub fn is_recent(msg: &Message) -> bool {
+        (Utc::now() - msg.date).to_std().is_ok_and(|x| x < MAX_AGE)
+    }
+}
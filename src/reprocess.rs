@@ -0,0 +1,157 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, UserId};
+use tracing::trace;
+
+use crate::{
+    helpers::temp_dir::TempDir, i18n::Lang, options::SongOptions, processor::demucs::DemucsModel,
+    song_details::SongDetails,
+};
+
+/// How long the downloaded song is kept around so it can be processed again
+const SOURCE_TTL: Duration = Duration::from_secs(1800);
+/// Prefix of the callback data of the buttons, eg. `reprocess:12:mdx_extra`
+const CALLBACK_PREFIX: &str = "reprocess";
+/// Models the user can reprocess the song with. They're slower, but usually sound better.
+const MODELS: [DemucsModel; 2] = [DemucsModel::HTDemucsFt, DemucsModel::MDXExtra];
+
+/// Downloaded songs that can be processed again, keyed by the ID in the callback data
+static SOURCES: Lazy<Mutex<HashMap<u64, Arc<KeptSource>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+/// Sources that are currently being reprocessed
+static CLAIMED: Lazy<Mutex<HashSet<u64>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+static NEXT_SOURCE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A downloaded (and trimmed) song that was already processed once
+#[derive(Debug)]
+pub struct KeptSource {
+    /// The song is deleted along with this directory once the source expires
+    #[allow(dead_code)]
+    pub dir: TempDir,
+    pub song_file_path: PathBuf,
+    pub song_duration: Option<Duration>,
+    pub song: SongDetails,
+    /// The options the song was processed with, including the chosen outputs
+    pub options: SongOptions,
+    /// Only this user can reprocess the song (anybody can if it wasn't sent by a user)
+    pub user_id: Option<UserId>,
+}
+
+/// Lets the user process an already downloaded song again with a different model
+pub struct Reprocess;
+impl Reprocess {
+    /// Keep the source until it expires.
+    ///
+    /// Returns the ID of the source.
+    pub fn keep(source: KeptSource) -> u64 {
+        let id = NEXT_SOURCE_ID.fetch_add(1, Ordering::Relaxed);
+
+        SOURCES
+            .lock()
+            .expect("Reprocess sources lock poisoned")
+            .insert(id, Arc::new(source));
+        trace!(?id, "Keeping source for reprocessing");
+
+        tokio::spawn(async move {
+            tokio::time::sleep(SOURCE_TTL).await;
+
+            trace!(?id, "Reprocessing source expired");
+            SOURCES
+                .lock()
+                .expect("Reprocess sources lock poisoned")
+                .remove(&id);
+        });
+
+        id
+    }
+
+    /// Claim the source for reprocessing by `user_id`, so it isn't processed twice at once.
+    ///
+    /// The source isn't deleted while it's claimed, even if it expires in the meantime. Returns
+    /// the reason shown to the user if it can't be claimed.
+    pub fn claim(id: u64, user_id: UserId, lang: Lang) -> anyhow::Result<ClaimedSource> {
+        let source = SOURCES
+            .lock()
+            .expect("Reprocess sources lock poisoned")
+            .get(&id)
+            .cloned();
+        let Some(source) = source else {
+            anyhow::bail!(lang.text("reprocess-source-expired"));
+        };
+
+        if source.user_id.is_some_and(|x| x != user_id) {
+            anyhow::bail!(lang.text("reprocess-not-yours"));
+        }
+
+        if !CLAIMED
+            .lock()
+            .expect("Reprocess claims lock poisoned")
+            .insert(id)
+        {
+            anyhow::bail!(lang.text("reprocess-in-progress"));
+        }
+        trace!(?id, "Claimed source for reprocessing");
+
+        Ok(ClaimedSource { id, source })
+    }
+
+    /// Keyboard with a button for each of the models the song wasn't processed with yet
+    pub fn keyboard(id: u64, used_model: DemucsModel, lang: Lang) -> InlineKeyboardMarkup {
+        let buttons = MODELS
+            .iter()
+            .filter(|x| **x != used_model)
+            .map(|x| {
+                InlineKeyboardButton::callback(
+                    lang.format("reprocess-with", &[("model", x)]),
+                    format!("{CALLBACK_PREFIX}:{id}:{x}"),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        InlineKeyboardMarkup::new([buttons])
+    }
+
+    /// The source ID and the model from the callback data of a keyboard button, if it's one
+    /// of ours
+    pub fn parse_callback(callback_data: &str) -> Option<(u64, DemucsModel)> {
+        let (id, model) = callback_data
+            .strip_prefix(CALLBACK_PREFIX)?
+            .strip_prefix(':')?
+            .split_once(':')?;
+
+        Some((id.parse().ok()?, model.parse().ok()?))
+    }
+}
+
+/// A source that is being reprocessed. The claim is released when this is dropped.
+#[derive(Debug)]
+pub struct ClaimedSource {
+    id: u64,
+    source: Arc<KeptSource>,
+}
+impl ClaimedSource {
+    pub const fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn source(&self) -> &KeptSource {
+        &self.source
+    }
+}
+impl Drop for ClaimedSource {
+    fn drop(&mut self) {
+        CLAIMED
+            .lock()
+            .expect("Reprocess claims lock poisoned")
+            .remove(&self.id);
+    }
+}
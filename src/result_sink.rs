@@ -0,0 +1,42 @@
+use std::{path::PathBuf, time::Duration};
+
+use teloxide::RequestError;
+
+use crate::{
+    notifier::Notifier, options::SongOptions, processor::analysis::SongAnalysis,
+    song_details::SongDetails, song_request::SongSource,
+};
+
+/// The processed files of a song and what's known about them
+#[derive(Debug)]
+pub struct Delivery<'a> {
+    pub files: Vec<PathBuf>,
+    pub song: &'a SongDetails,
+    pub source: &'a SongSource,
+    pub options: SongOptions,
+    /// Duration of the (trimmed) song
+    pub song_duration: Option<Duration>,
+    /// Whether the files were created with the fallback instead of demucs
+    pub used_fallback: bool,
+    /// The detected key and tempo, if the analysis is enabled and succeeded
+    pub analysis: Option<SongAnalysis>,
+}
+
+/// Where the files of processed songs end up, eg. uploaded to the chat the song was sent in or
+/// kept for downloading through the API
+#[async_trait::async_trait]
+pub trait ResultSink: Send + Sync {
+    /// Whether the requester can be asked things in the chat (eg. which files they want) and be
+    /// sent extra messages (eg. a preview). Otherwise the defaults are used.
+    fn is_interactive(&self) -> bool;
+
+    /// Deliver the files of the processed song.
+    ///
+    /// Returns the Telegram file IDs of the files if all of them were uploaded, so the result
+    /// can be cached.
+    async fn deliver(
+        &self,
+        msg: &mut dyn Notifier,
+        delivery: Delivery<'_>,
+    ) -> Result<Option<Vec<String>>, RequestError>;
+}
@@ -0,0 +1,236 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, UserId};
+use tracing::{debug, trace};
+use url::Url;
+
+use crate::{helpers::http::CLIENT, i18n::Lang, options::SongOptions};
+
+const SEARCH_API_URL: &str = "https://itunes.apple.com/search";
+/// How many songs are offered if the top one might not be the right one
+const MAX_RESULTS: usize = 3;
+/// How long the user has to choose a song
+const PENDING_TTL: Duration = Duration::from_secs(600);
+/// Prefix of the callback data of the buttons, eg. `search:12:0` or `search:12:no`
+const CALLBACK_PREFIX: &str = "search";
+
+/// Searches waiting for the user to choose a song, keyed by the ID in the callback data
+static PENDING: Lazy<Mutex<HashMap<u64, PendingSearch>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_PENDING_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A song found by searching for the text the user sent
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    pub artist: String,
+    pub title: String,
+    /// Link to the song (on Apple Music) that the handlers can download
+    pub url: Url,
+}
+impl Display for SearchResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} - {}", self.artist, self.title)
+    }
+}
+
+/// The found songs waiting for the user to choose one
+#[derive(Debug)]
+pub struct PendingSearch {
+    /// Only this user can choose (anybody can if the text wasn't sent by a user)
+    pub user_id: Option<UserId>,
+    /// The options the user sent along with the text
+    pub options: SongOptions,
+    pub results: Vec<SearchResult>,
+}
+
+/// What the user chose using the keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchChoice {
+    /// Index of the chosen result
+    Result(usize),
+    /// None of the results are the right song
+    None,
+}
+
+/// Lets users send the name of a song instead of a link
+pub struct Search;
+impl Search {
+    /// Find songs matching the text, best match first
+    #[tracing::instrument]
+    pub async fn find(query: &str) -> anyhow::Result<Vec<SearchResult>> {
+        let res = CLIENT
+            .get(SEARCH_API_URL)
+            .query(&[
+                ("term", query),
+                ("media", "music"),
+                ("entity", "song"),
+                ("limit", &MAX_RESULTS.to_string()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<SearchResponse>()
+            .await?;
+        trace!(?res, "Got search response");
+
+        let results = res
+            .results
+            .into_iter()
+            .filter_map(|x| {
+                let mut url = Url::parse(&x.track_view_url?).ok()?;
+                // Only the `i` (track ID) parameter is needed
+                let track_id = url
+                    .query_pairs()
+                    .find(|(k, _)| k == "i")
+                    .map(|(_, v)| v.into_owned());
+                url.set_query(None);
+                if let Some(track_id) = track_id {
+                    url.query_pairs_mut().append_pair("i", &track_id);
+                }
+
+                Some(SearchResult {
+                    artist: x.artist_name?,
+                    title: x.track_name?,
+                    url,
+                })
+            })
+            .collect::<Vec<_>>();
+        debug!(?results, "Found songs");
+
+        Ok(results)
+    }
+
+    /// Whether the result is most likely the song the user is looking for, ie. it contains all
+    /// the words of the query
+    pub fn is_confident(query: &str, results: &[SearchResult]) -> bool {
+        let Some(top) = results.first() else {
+            return false;
+        };
+        if results.len() == 1 {
+            return true;
+        }
+
+        let haystack = format!("{} {}", top.artist, top.title).to_lowercase();
+
+        query
+            .split_whitespace()
+            .all(|x| haystack.contains(&x.to_lowercase()))
+    }
+
+    /// Keep the results until the user chooses one or they expire.
+    ///
+    /// Returns the ID of the pending search.
+    pub fn keep(pending: PendingSearch) -> u64 {
+        let id = NEXT_PENDING_ID.fetch_add(1, Ordering::Relaxed);
+
+        PENDING
+            .lock()
+            .expect("Pending searches lock poisoned")
+            .insert(id, pending);
+        trace!(?id, "Waiting for search choice");
+
+        tokio::spawn(async move {
+            tokio::time::sleep(PENDING_TTL).await;
+
+            if PENDING
+                .lock()
+                .expect("Pending searches lock poisoned")
+                .remove(&id)
+                .is_some()
+            {
+                trace!(?id, "Pending search expired");
+            }
+        });
+
+        id
+    }
+
+    /// Take the pending search for the choice of `user_id`.
+    ///
+    /// Returns the reason shown to the user if the choice can't be made.
+    pub fn take(id: u64, user_id: UserId, lang: Lang) -> anyhow::Result<PendingSearch> {
+        let mut pending = PENDING.lock().expect("Pending searches lock poisoned");
+        let Some(search) = pending.get(&id) else {
+            anyhow::bail!(lang.text("search-choice-expired"));
+        };
+        if search.user_id.is_some_and(|x| x != user_id) {
+            anyhow::bail!(lang.text("search-not-yours"));
+        }
+
+        Ok(pending.remove(&id).expect("Pending search should exist"))
+    }
+
+    /// Keyboard to confirm the top result, or to choose one of the results if it's not certain
+    /// which one the user wants
+    pub fn keyboard(
+        id: u64,
+        results: &[SearchResult],
+        confident: bool,
+        lang: Lang,
+    ) -> InlineKeyboardMarkup {
+        let callback = |choice: &str| format!("{CALLBACK_PREFIX}:{id}:{choice}");
+
+        if confident {
+            return InlineKeyboardMarkup::new([[
+                InlineKeyboardButton::callback(lang.text("search-yes"), callback("0")),
+                InlineKeyboardButton::callback(lang.text("search-no"), callback("no")),
+            ]]);
+        }
+
+        let mut rows = results
+            .iter()
+            .enumerate()
+            .map(|(i, x)| {
+                vec![InlineKeyboardButton::callback(
+                    x.to_string(),
+                    callback(&i.to_string()),
+                )]
+            })
+            .collect::<Vec<_>>();
+        rows.push(vec![InlineKeyboardButton::callback(
+            lang.text("search-none-of-these"),
+            callback("no"),
+        )]);
+
+        InlineKeyboardMarkup::new(rows)
+    }
+
+    /// The pending search ID and the choice from the callback data of a keyboard button, if
+    /// it's one of ours
+    pub fn parse_callback(callback_data: &str) -> Option<(u64, SearchChoice)> {
+        let (id, choice) = callback_data
+            .strip_prefix(CALLBACK_PREFIX)?
+            .strip_prefix(':')?
+            .split_once(':')?;
+
+        let choice = match choice {
+            "no" => SearchChoice::None,
+            x => SearchChoice::Result(x.parse().ok()?),
+        };
+
+        Some((id.parse().ok()?, choice))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchResponse {
+    #[serde(default)]
+    results: Vec<SearchResponseItem>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SearchResponseItem {
+    artist_name: Option<String>,
+    track_name: Option<String>,
+    track_view_url: Option<String>,
+}
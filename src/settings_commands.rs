@@ -0,0 +1,173 @@
+//! The `/settings` and `/language` commands, which change the defaults of the songs sent in a
+//! chat and the language the bot replies in
+
+use teloxide::{
+    payloads::{EditMessageTextSetters, SendMessageSetters},
+    prelude::*,
+};
+use tracing::{info, warn};
+
+use crate::{
+    bot::TeloxideBot,
+    chat_settings::{ChatSettings, SettingsAction},
+    helpers::{
+        html,
+        topic::{topic_id, InTopic},
+    },
+    i18n::Lang,
+    original_message::OriginalMessage,
+};
+
+/// The settings of the chat, going back to the defaults first for `/settings reset`
+pub async fn settings_reply(
+    bot: &TeloxideBot,
+    msg: &Message,
+    args: &str,
+    lang: Lang,
+) -> ResponseResult<ChatSettings> {
+    if args.trim() != "reset" {
+        return Ok(ChatSettings::get(msg.chat.id));
+    }
+
+    let can_change = match msg.from() {
+        Some(user) => can_change_settings(bot, &msg.chat, user.id).await?,
+        None => false,
+    };
+    if !can_change {
+        bot.send_message(msg.chat.id, lang.text("settings-admins-only"))
+            .reply_to_message_id(msg.id)
+            .in_topic(topic_id(msg))
+            .allow_sending_without_reply(true)
+            .await?;
+        return Ok(ChatSettings::get(msg.chat.id));
+    }
+
+    info!("Resetting chat settings");
+    // The language is changed with `/language`
+    let settings = ChatSettings {
+        language: ChatSettings::get(msg.chat.id).language,
+        ..ChatSettings::default()
+    };
+    if let Err(e) = settings.save(msg.chat.id) {
+        warn!(?e, "Failed to reset chat settings");
+    }
+
+    Ok(ChatSettings::get(msg.chat.id))
+}
+
+/// Change the settings of the chat the keyboard was sent in and show the new ones.
+///
+/// Returns the reason shown to the user if the settings can't be changed.
+pub async fn change_chat_settings(
+    bot: &TeloxideBot,
+    query: &CallbackQuery,
+    action: SettingsAction,
+    lang: Lang,
+) -> anyhow::Result<()> {
+    let Some(settings_msg) = &query.message else {
+        anyhow::bail!(lang.text("settings-expired"));
+    };
+    let chat_id = settings_msg.chat.id;
+    if !can_change_settings(bot, &settings_msg.chat, query.from.id).await? {
+        anyhow::bail!(lang.text("settings-admins-only"));
+    }
+
+    let current = ChatSettings::get(chat_id);
+    let settings = match action {
+        SettingsAction::Change(setting) => current.next(setting),
+        SettingsAction::Reset => ChatSettings {
+            language: current.language,
+            ..ChatSettings::default()
+        },
+    };
+    if settings == current {
+        return Ok(());
+    }
+    info!(?chat_id, ?settings, "Changing chat settings");
+    settings.save(chat_id)?;
+    if settings.delete_original == Some(true) {
+        // The bot might have the permission to delete messages now
+        OriginalMessage::forget_warning(chat_id);
+    }
+
+    bot.edit_message_text(chat_id, settings_msg.id, settings.text(lang))
+        .reply_markup(settings.keyboard(lang))
+        .await?;
+
+    Ok(())
+}
+
+/// Set the language of the chat for `/language <code>`, or show the current one without a
+/// code.
+///
+/// Returns the reply for the user.
+pub async fn set_chat_language(
+    bot: &TeloxideBot,
+    msg: &Message,
+    code: &str,
+) -> ResponseResult<String> {
+    let lang = Lang::of_message(msg);
+    let code = code.trim();
+
+    if code.is_empty() {
+        let languages = Lang::ALL
+            .map(|x| format!("<code>{}</code> ({})", x.code(), x.name()))
+            .join(", ");
+
+        return Ok(lang.format(
+            "language-current",
+            &[("language", &lang.name()), ("languages", &languages)],
+        ));
+    }
+
+    let language = if code == "auto" {
+        None
+    } else {
+        match code.parse::<Lang>() {
+            Ok(x) => Some(x),
+            Err(e) => {
+                return Ok(lang.format("language-invalid", &[("reason", &html::escape_value(e))]))
+            }
+        }
+    };
+
+    let can_change = match msg.from() {
+        Some(user) => can_change_settings(bot, &msg.chat, user.id).await?,
+        None => false,
+    };
+    if !can_change {
+        return Ok(lang.text("settings-admins-only"));
+    }
+
+    let settings = ChatSettings {
+        language,
+        ..ChatSettings::get(msg.chat.id)
+    };
+    if let Err(e) = settings.save(msg.chat.id) {
+        warn!(?e, "Failed to save chat language");
+        return Ok(lang.text("save-failed"));
+    }
+    info!(?language, "Chat language set");
+
+    // The reply is already in the new language
+    let lang = Lang::of_message(msg);
+    Ok(match language {
+        Some(_) => lang.format("language-set", &[("language", &lang.name())]),
+        None => lang.text("language-auto"),
+    })
+}
+
+/// Anybody can change the settings of their private chat, but only admins the ones of a group
+async fn can_change_settings(
+    bot: &TeloxideBot,
+    chat: &teloxide::types::Chat,
+    user_id: UserId,
+) -> ResponseResult<bool> {
+    if chat.is_private() {
+        return Ok(true);
+    }
+
+    let admins = bot.get_chat_administrators(chat.id).await?;
+
+    Ok(admins.iter().any(|x| x.user.id == user_id))
+}
@@ -0,0 +1,32 @@
+use tracing::debug;
+
+use crate::{
+    downloader::DownloadedSong,
+    helpers::{audio_meta::AudioMeta, cover_art::CoverArt},
+};
+
+/// Details of the original song that are attached to the uploaded files
+#[derive(Debug)]
+pub struct SongDetails {
+    pub title: String,
+    pub meta: AudioMeta,
+    pub cover: Option<CoverArt>,
+}
+impl SongDetails {
+    /// The details from the tags of the song file, falling back to the ones from the provider
+    pub async fn from_downloaded(song: &DownloadedSong) -> Self {
+        let song_file_path = song.path.as_path();
+        let mut meta = AudioMeta::probe(song_file_path).await.unwrap_or_else(|e| {
+            debug!(?e, "Failed to get song metadata");
+            AudioMeta::default()
+        });
+        meta.title = meta.title.or_else(|| song.title.clone());
+        meta.artist = meta.artist.or_else(|| song.artist.clone());
+
+        Self {
+            title: meta.title_or_file_stem(song_file_path),
+            meta,
+            cover: CoverArt::find(song_file_path).await,
+        }
+    }
+}
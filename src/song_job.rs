@@ -0,0 +1,1924 @@
+//! Runs the jobs of the songs: downloads them, asks the user what's needed, splits them into
+//! stems and hands the files to a [`ResultSink`]
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use once_cell::sync::Lazy;
+use teloxide::{
+    prelude::*,
+    types::{InputFile, InputMedia, InputMediaAudio, User},
+};
+use tokio::{
+    sync::{watch, SemaphorePermit},
+    time::MissedTickBehavior,
+};
+use tracing::{debug, error, field, info, info_span, trace, warn, Instrument};
+use url::Url;
+
+use crate::{
+    admin_report::{AdminReport, FailedStage},
+    api::ApiSubmission,
+    cache::{CachedResult, ResultCache},
+    chat_settings::ChatSettings,
+    config::Config,
+    downloader::{DownloadError, DownloadedSong, Downloader},
+    eta::ProcessingEta,
+    helpers::{
+        download::DownloadProgress,
+        duration::format_duration,
+        html,
+        id::short_id,
+        loudnorm::Loudnorm,
+        temp_dir::TempDir,
+        work_dir::{Reservation, WorkDir},
+    },
+    history::{History, JobStatus},
+    i18n::Lang,
+    in_flight::{InFlight, InFlightGuard, InFlightJoin, InFlightState},
+    instrumental_choice::InstrumentalChoice,
+    jobs::Jobs,
+    metrics::Metrics,
+    notifier::Notifier,
+    options::{SongOptions, TrimRange},
+    original_message::OriginalMessage,
+    output_choice::OutputChoice,
+    pipeline::{DemucsPipeline, Pipeline, SplitSettings},
+    processor::{
+        analysis::SongAnalysis,
+        demucs::{DemucsError, DemucsModel, DemucsProgress, StemMode},
+        ffmpeg::FfmpegProcessor,
+        postfx::PostFx,
+        vocal_detect::VocalDetector,
+    },
+    queue::SongQueue,
+    quota::Quota,
+    recent_messages::{MessageState, RecentMessages},
+    reprocess::{ClaimedSource, KeptSource, Reprocess},
+    result_sink::{Delivery, ResultSink},
+    song_details::SongDetails,
+    song_request::SongSource,
+    telegram_sink::{send_file_ids, upload_files, TelegramSink},
+};
+
+/// Songs wait here for their turn to be downloaded
+pub static DOWNLOAD_QUEUE: Lazy<SongQueue> =
+    Lazy::new(|| SongQueue::new(Config::global().max_concurrent_downloads));
+/// Songs wait here for their turn to be split into stems
+pub static PROCESSING_QUEUE: Lazy<SongQueue> =
+    Lazy::new(|| SongQueue::new(Config::global().max_concurrent_processing));
+
+/// How often the status message is updated with the processing progress
+const PROGRESS_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+/// Length of the preview that's sent before the whole song is processed
+const PREVIEW_LENGTH: Duration = Duration::from_secs(30);
+/// Songs shorter than this don't get a preview since processing them is quick anyway
+const MIN_PREVIEW_SONG_DURATION: Duration = Duration::from_secs(60);
+/// How long the user has to choose which files they want before they get everything
+const OUTPUT_CHOICE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long the user has to decide to process a song that looks instrumental before it's
+/// skipped
+const INSTRUMENTAL_CHOICE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Song titles and file names are shortened to this many characters in the summary of a job
+const MAX_SUMMARY_NAME_LENGTH: usize = 100;
+
+/// Let the user and the admins know that the job panicked.
+///
+/// Whatever the job held (eg. its temp dirs) was already cleaned up while unwinding.
+pub async fn report_panic(
+    msg: &mut dyn Notifier,
+    song: &str,
+    requester: &SongRequester,
+    reason: &str,
+) -> ResponseResult<()> {
+    error!(reason, "Job panicked");
+
+    Metrics::job_failed(FailedStage::Internal);
+    AdminReport::job_failed(FailedStage::Internal, song, &requester.description, reason);
+
+    let lang = msg.lang();
+    msg.update_status(&with_job_id(
+        &lang.text("internal-error"),
+        &requester.job_id,
+        lang,
+    ))
+    .await
+}
+
+/// Status text of a job that got cancelled
+pub fn cancelled_text(lang: Lang) -> String {
+    if Jobs::is_shutting_down() {
+        info!("Song cancelled because the bot is shutting down");
+        lang.text("cancelled-restart")
+    } else {
+        info!("Song cancelled");
+        lang.text("cancelled")
+    }
+}
+
+/// Who requested the song
+#[derive(Debug, Clone)]
+pub struct SongRequester {
+    pub user_id: Option<UserId>,
+    /// Who sent the message (for admin reports), eg. "John Doe (@johndoe, 1234)"
+    pub description: String,
+    /// Short ID of the job, shown to the user when something fails so the logs of the job can
+    /// be found
+    pub job_id: String,
+}
+impl SongRequester {
+    pub fn from_message(msg: &Message) -> Self {
+        msg.from().map_or_else(
+            || Self {
+                user_id: None,
+                description: "unknown".to_string(),
+                job_id: short_id(),
+            },
+            Self::from_user,
+        )
+    }
+
+    pub fn from_user(user: &User) -> Self {
+        let username = user
+            .username
+            .as_ref()
+            .map(|u| format!("@{u}, "))
+            .unwrap_or_default();
+
+        Self {
+            user_id: Some(user.id),
+            description: format!("{} ({username}{})", user.full_name(), user.id),
+            job_id: short_id(),
+        }
+    }
+}
+
+/// Who a job is for and how its songs are handled
+#[derive(Clone, Copy)]
+pub struct JobContext<'a> {
+    pub requester: &'a SongRequester,
+    /// Where the files are delivered
+    pub sink: &'a dyn ResultSink,
+    /// Downloads the songs and splits them into stems
+    pub pipeline: &'a dyn Pipeline,
+}
+
+/// The tracing span of the song job, identifying the song and the user who requested it
+pub fn song_span(
+    msg: &Message,
+    source: &SongSource,
+    start: Option<Duration>,
+    job_id: &str,
+) -> tracing::Span {
+    let span = info_span!(
+        "process_song",
+        job = job_id,
+        url = field::Empty,
+        start = field::Empty,
+        file = field::Empty,
+        uid = field::Empty,
+        user = field::Empty,
+        name = field::Empty,
+    );
+
+    match source {
+        SongSource::Url(url) => {
+            span.record("url", field::debug(url.as_str()));
+            if let Some(start) = start {
+                span.record("start", field::debug(start));
+            }
+        }
+        SongSource::File(file) => {
+            span.record("file", field::debug(file.name().unwrap_or_default()));
+        }
+        SongSource::Path(path) => {
+            span.record("file", field::debug(path));
+        }
+    }
+
+    if let Some(from) = msg.from() {
+        if let Some(u) = &from.username {
+            span.record("user", field::display(u));
+        }
+        span.record("uid", field::display(from.id));
+        span.record("name", field::display(from.full_name()));
+    }
+
+    span
+}
+
+/// How processing of a single song ended
+#[derive(Debug)]
+pub enum SongOutcome {
+    Processed {
+        /// The downloaded song if it can be processed again with a different model
+        kept: Option<Box<KeptSource>>,
+        /// What was delivered for each of the songs of the download
+        summaries: Vec<SongSummary>,
+    },
+    /// The song couldn't be processed. Contains the reason shown to the user.
+    Failed(String),
+}
+impl SongOutcome {
+    /// The files of the song were sent from the cache or by another request for the same song
+    fn reused(url: &Url, options: SongOptions) -> Self {
+        let summary = SongSummary {
+            title: url.to_string(),
+            model: Some(options.model),
+            files: None,
+        };
+
+        Self::Processed {
+            kept: None,
+            summaries: vec![summary],
+        }
+    }
+}
+
+/// What was delivered for one of the songs of a job, for the summary the status message is
+/// edited into when the job is done
+#[derive(Debug)]
+pub struct SongSummary {
+    title: String,
+    /// `None` if the lower quality fallback was used instead of demucs
+    model: Option<DemucsModel>,
+    /// Names of the uploaded files, `None` if files uploaded before were sent again
+    files: Option<Vec<String>>,
+}
+impl SongSummary {
+    fn processed(processed: &ProcessedSong, options: SongOptions) -> Self {
+        Self {
+            title: processed.song.title.clone(),
+            model: (!processed.used_fallback).then_some(options.model),
+            files: Some(processed.file_names.clone()),
+        }
+    }
+
+    fn text(&self, lang: Lang) -> String {
+        let name = |x: &str| html::escape(&html::truncate(x, MAX_SUMMARY_NAME_LENGTH));
+
+        let title = name(&self.title);
+        let model = self
+            .model
+            .map_or_else(|| lang.text("summary-fallback"), |x| x.to_string());
+
+        self.files.as_ref().map_or_else(
+            || {
+                lang.format(
+                    "summary-song-cached",
+                    &[("title", &title), ("model", &model)],
+                )
+            },
+            |files| {
+                let files = files.iter().map(|x| name(x)).collect::<Vec<_>>().join(", ");
+                lang.format(
+                    "summary-song",
+                    &[("title", &title), ("model", &model), ("files", &files)],
+                )
+            },
+        )
+    }
+}
+
+/// Process the requested song, or every song in it if it's an album or playlist.
+///
+/// Returns whether all the songs were delivered.
+pub async fn process_request(
+    msg: &mut dyn Notifier,
+    source: SongSource,
+    mut options: SongOptions,
+    requester: &SongRequester,
+) -> ResponseResult<bool> {
+    let started = Instant::now();
+    let link = match &source {
+        SongSource::Url(url) => Some(url.clone()),
+        SongSource::File(_) | SongSource::Path(_) => None,
+    };
+    let max_tracks = Config::global().max_playlist_tracks;
+    let sink = TelegramSink { requester };
+    let job = JobContext {
+        requester,
+        sink: &sink,
+        pipeline: &DemucsPipeline,
+    };
+    let lang = msg.lang();
+
+    let expanded = match &source {
+        SongSource::Url(url) => Downloader::expand_collection(url, max_tracks).await,
+        SongSource::File(_) | SongSource::Path(_) => None,
+    };
+
+    let mut track_urls = match expanded {
+        None => {
+            let title = source.to_string();
+
+            match process_song(msg, source, &mut options, &job).await? {
+                SongOutcome::Processed { kept, summaries } => {
+                    let job_id = &requester.job_id;
+                    finish_status_message(msg, &summaries, started, job_id, link.as_ref()).await?;
+
+                    if let Some(kept) = kept {
+                        offer_reprocess(msg, kept).await?;
+                    }
+                    return Ok(true);
+                }
+                SongOutcome::Failed(reason) => {
+                    record_failure(msg, requester, title);
+                    msg.update_status(&with_job_id(&reason, &requester.job_id, lang))
+                        .await?;
+                    return Ok(false);
+                }
+            }
+        }
+
+        Some(Err(e)) => {
+            msg.update_status(
+                &lang.format("collection-failed", &[("reason", &html::escape_value(&e))]),
+            )
+            .await?;
+            return Ok(false);
+        }
+
+        Some(Ok(urls)) => urls,
+    };
+
+    if track_urls.is_empty() {
+        msg.update_status(&lang.text("collection-empty")).await?;
+        return Ok(false);
+    }
+
+    if track_urls.len() > max_tracks {
+        track_urls.truncate(max_tracks);
+        msg.send_text(&lang.format("collection-truncated", &[("max", &max_tracks)]))
+            .await?;
+    }
+
+    // The link was counted as a single song when it was submitted, each other track counts too
+    if let Some(user_id) = requester.user_id {
+        let allowed = 1 + Quota::try_add(user_id, track_urls.len() - 1);
+        if allowed < track_urls.len() {
+            track_urls.truncate(allowed);
+            msg.send_text(&lang.format("collection-quota", &[("max", &allowed)]))
+                .await?;
+        }
+    }
+
+    let total = track_urls.len();
+    info!(?total, "Processing album/playlist");
+    let mut summaries = vec![];
+    let mut all_delivered = true;
+    for (i, track_url) in track_urls.into_iter().enumerate() {
+        let track = [
+            ("number", &(i + 1) as &(dyn Display + Sync)),
+            ("total", &total),
+        ];
+        msg.set_header(Some(lang.format("track-processing", &track)));
+
+        let title = track_url.to_string();
+        let outcome = process_song(msg, SongSource::Url(track_url), &mut options, &job).await?;
+        let reason = match outcome {
+            SongOutcome::Processed {
+                summaries: processed,
+                ..
+            } => {
+                summaries.extend(processed);
+                continue;
+            }
+            SongOutcome::Failed(reason) => reason,
+        };
+
+        all_delivered = false;
+        record_failure(msg, requester, title);
+        let text = format!("{}\n\n{reason}", lang.format("track-failed", &track));
+        msg.send_text(&with_job_id(&text, &requester.job_id, lang))
+            .await?;
+    }
+    msg.set_header(None);
+
+    finish_status_message(msg, &summaries, started, &requester.job_id, link.as_ref()).await?;
+
+    Ok(all_delivered)
+}
+
+/// Delete the status message when the job is done, or edit it into a summary of the job if the
+/// chat wants to keep it. It's always kept if the messages with the links are deleted, so the
+/// files can still be told apart.
+async fn finish_status_message(
+    msg: &mut dyn Notifier,
+    summaries: &[SongSummary],
+    started: Instant,
+    job_id: &str,
+    link: Option<&Url>,
+) -> ResponseResult<()> {
+    let chat_id = msg.chat_id();
+    let keep = ChatSettings::get(chat_id).status_summary() || OriginalMessage::deleted_in(chat_id);
+
+    // Nothing was delivered, so there's nothing to summarize either
+    let summary = (keep && !summaries.is_empty())
+        .then(|| job_summary(summaries, started.elapsed(), job_id, link, msg.lang()));
+
+    trace!(summary = summary.is_some(), "Finishing status message");
+    msg.finish(summary.as_deref()).await
+}
+
+/// The summary of the finished job, listing the delivered songs until the message is full.
+/// `link` is the link the songs were requested with.
+fn job_summary(
+    summaries: &[SongSummary],
+    took: Duration,
+    job_id: &str,
+    link: Option<&Url>,
+    lang: Lang,
+) -> String {
+    let link = link
+        .map(|x| lang.format("summary-link", &[("url", &html::escape_value(x))]))
+        .unwrap_or_default();
+
+    let mut songs = String::new();
+    for (i, summary) in summaries.iter().enumerate() {
+        let entry = summary.text(lang) + "\n\n";
+
+        // Leave some room for the rest of the summary
+        let max_length = html::MAX_MESSAGE_LENGTH - 200 - link.chars().count();
+        if songs.chars().count() + entry.chars().count() > max_length {
+            songs += &lang.format("summary-more", &[("count", &(summaries.len() - i))]);
+            break;
+        }
+
+        songs += &entry;
+    }
+
+    let mut summary = lang.format(
+        "summary",
+        &[
+            ("duration", &format_duration(took)),
+            ("songs", &songs.trim_end()),
+        ],
+    );
+    if !link.is_empty() {
+        summary = format!("{summary}\n\n{link}");
+    }
+
+    with_job_id(&summary, job_id, lang)
+}
+
+/// Add the job ID to the failure message so the user can report it
+pub fn with_job_id(text: &str, job_id: &str, lang: Lang) -> String {
+    format!("{text}\n\n{}", lang.format("job-id", &[("id", &job_id)]))
+}
+
+/// Remember the failed song in the history of the user who requested it
+fn record_failure(msg: &dyn Notifier, requester: &SongRequester, title: String) {
+    if let Some(user_id) = requester.user_id {
+        History::record(user_id, msg.chat_id(), title, JobStatus::Failed, None);
+    }
+}
+
+/// Process a single song of the job. If the download contains several songs (eg. an album
+/// downloaded as a single zip), each of them is processed.
+///
+/// The user's choice of files is stored in `options` so the following songs of a playlist use
+/// it as well.
+pub async fn process_song(
+    msg: &mut dyn Notifier,
+    source: SongSource,
+    options: &mut SongOptions,
+    job: &JobContext<'_>,
+) -> ResponseResult<SongOutcome> {
+    let (requester, sink) = (job.requester, job.sink);
+    let requested_options = *options;
+    let cache_url = match &source {
+        // Only the audio files are cached, so requests for a video are always processed. The
+        // cached file IDs are only usable in Telegram.
+        SongSource::Url(url) if !options.video && sink.is_interactive() => Some(url.clone()),
+        SongSource::Url(_) | SongSource::File(_) | SongSource::Path(_) => None,
+    };
+    let in_flight = match &cache_url {
+        Some(url) => match reuse_existing_result(msg, url, requested_options).await? {
+            Some(guard) => Some(guard),
+            None => return Ok(SongOutcome::reused(url, requested_options)),
+        },
+        None => None,
+    };
+    msg.set_mirror(in_flight.as_ref().map(InFlightGuard::status));
+    Metrics::job_started();
+
+    let temp_dir = TempDir::with_prefix(format!("karaokify-{}-", requester.job_id)).await?;
+
+    // Held until the job is done, so the space of its intermediate files stays reserved
+    let (songs, reservation) = match download_song(msg, &source, temp_dir.path(), job).await? {
+        Ok(p) => p,
+        Err(reason) => return Ok(SongOutcome::Failed(reason)),
+    };
+
+    if options.outputs.is_none() {
+        options.outputs = Some(choose_outputs(msg, job).await?);
+    }
+    let options = *options;
+
+    let total = songs.len();
+    let mut file_ids = Some(vec![]);
+    let mut last_processed = None;
+    let mut summaries = vec![];
+    for (i, downloaded) in songs.into_iter().enumerate() {
+        if total > 1 {
+            info!(file = i + 1, ?total, "Processing song from the download");
+        }
+
+        let processed = match process_downloaded_song(
+            msg,
+            temp_dir.path(),
+            downloaded,
+            options,
+            &source,
+            &reservation,
+            job,
+        )
+        .await?
+        {
+            Ok(x) => x,
+            Err(reason) if total == 1 => return Ok(SongOutcome::Failed(reason)),
+            Err(reason) => {
+                if sink.is_interactive() {
+                    send_song_failed(msg, i + 1, total, &reason).await?;
+                }
+                file_ids = None;
+                continue;
+            }
+        };
+
+        summaries.push(SongSummary::processed(&processed, options));
+        file_ids = file_ids
+            .zip(processed.file_ids.clone())
+            .map(|(mut ids, new_ids)| {
+                ids.extend(new_ids);
+                ids
+            });
+        last_processed = Some(processed);
+    }
+    // Only the fallback doesn't use a model
+    let used_fallback = summaries.iter().any(|x| x.model.is_none());
+
+    if let Some(in_flight) = in_flight {
+        // Others waiting for the same song didn't ask for the files the user chose
+        let same_files =
+            options.outputs.unwrap_or_default() == requested_options.outputs.unwrap_or_default();
+        in_flight.finish(file_ids.clone().filter(|_| same_files));
+    }
+
+    record_processed(
+        msg,
+        requester,
+        total,
+        last_processed.as_ref(),
+        file_ids.clone(),
+    );
+
+    // Fallback results aren't cached so the song can be processed properly later
+    if let (Some(url), Some(file_ids), false) = (cache_url, file_ids, used_fallback) {
+        let analysis = last_processed
+            .as_ref()
+            .filter(|_| total == 1)
+            .and_then(|x| x.analysis);
+        ResultCache::insert(&url, options, file_ids, analysis);
+    }
+
+    // The fallback doesn't use a model, so there's nothing to try instead
+    let reprocessable = total == 1 && !used_fallback && sink.is_interactive();
+    let kept = match last_processed {
+        Some(processed) if reprocessable => Some(KeptSource {
+            dir: temp_dir,
+            song_file_path: processed.song_file_path,
+            song_duration: processed.song_duration,
+            song: processed.song,
+            options,
+            user_id: requester.user_id,
+        }),
+        _ => None,
+    };
+
+    Ok(SongOutcome::Processed {
+        kept: kept.map(Box::new),
+        summaries,
+    })
+}
+
+/// Tell the user that one of the songs of the download failed, the others are still processed
+async fn send_song_failed(
+    msg: &dyn Notifier,
+    number: usize,
+    total: usize,
+    reason: &str,
+) -> ResponseResult<()> {
+    let text = msg.lang().format(
+        "download-song-failed",
+        &[("number", &number), ("total", &total), ("reason", &reason)],
+    );
+
+    msg.send_text(&text).await
+}
+
+/// Record the successful job in the metrics and the history of the user.
+///
+/// `processed` is the last song of the download that was processed, if any were. Failures are
+/// recorded where they happen.
+fn record_processed(
+    msg: &dyn Notifier,
+    requester: &SongRequester,
+    total: usize,
+    processed: Option<&ProcessedSong>,
+    file_ids: Option<Vec<String>>,
+) {
+    let Some(processed) = processed else {
+        return;
+    };
+    Metrics::job_succeeded();
+
+    if let Some(user_id) = requester.user_id {
+        let title = match total {
+            1 => processed.song.title.clone(),
+            _ => msg.lang().format(
+                "history-title-more",
+                &[("title", &processed.song.title), ("more", &(total - 1))],
+            ),
+        };
+        History::record(user_id, msg.chat_id(), title, JobStatus::Done, file_ids);
+    }
+}
+
+/// A downloaded song that was processed and delivered
+#[derive(Debug)]
+pub struct ProcessedSong {
+    /// Telegram file IDs of the uploaded files if all of them were uploaded
+    file_ids: Option<Vec<String>>,
+    /// Names of the delivered files
+    file_names: Vec<String>,
+    used_fallback: bool,
+    /// The (trimmed) song that was split into stems
+    song_file_path: PathBuf,
+    song_duration: Option<Duration>,
+    song: SongDetails,
+    /// The detected key and tempo, if the analysis is enabled and succeeded
+    analysis: Option<SongAnalysis>,
+}
+
+/// Trim the downloaded song, split it into stems and deliver them with the sink of the job.
+///
+/// Returns the reason shown to the user if the song couldn't be processed.
+async fn process_downloaded_song(
+    msg: &mut dyn Notifier,
+    output_dir: &Path,
+    downloaded: DownloadedSong,
+    options: SongOptions,
+    source: &SongSource,
+    reservation: &Reservation,
+    job: &JobContext<'_>,
+) -> ResponseResult<Result<ProcessedSong, String>> {
+    let JobContext {
+        requester,
+        sink,
+        pipeline,
+    } = *job;
+    let song = SongDetails::from_downloaded(&downloaded).await;
+    let song_file_path = downloaded.path;
+
+    let (song_file_path, song_duration) = match options.trim {
+        None => (song_file_path, song.meta.duration),
+        Some(trim) => match trim_song(msg, &song_file_path, trim, song.meta.duration).await? {
+            Ok(path) => (path, Some(trim.duration())),
+            Err(reason) => return Ok(Err(reason)),
+        },
+    };
+
+    // Nobody can be asked whether to process the song anyway, so it always is
+    if sink.is_interactive()
+        && !check_vocals(msg, &song_file_path, song_duration, requester).await?
+    {
+        return Ok(Err(msg.lang().text("instrumental-skipped")));
+    }
+
+    // Runs alongside the processing, the files are uploaded without it if it fails
+    let analysis = Config::global()
+        .analysis
+        .then(|| tokio::spawn(analyze_song(song_file_path.clone())));
+
+    let waiting_text = msg.lang().text("processing-waiting-after-download");
+    let processing_permit = wait_in_queue(msg, &PROCESSING_QUEUE, &waiting_text).await?;
+
+    if sink.is_interactive() {
+        send_preview(
+            msg,
+            output_dir,
+            &song_file_path,
+            &song,
+            song_duration,
+            options,
+            pipeline,
+        )
+        .await?;
+    }
+
+    info!("Processing downloaded song...");
+    let (mut stem_paths, used_fallback) = match split_song(
+        msg,
+        output_dir,
+        &song_file_path,
+        options,
+        song_duration,
+        source,
+        job,
+    )
+    .await?
+    {
+        Ok(x) => x,
+        Err(reason) => return Ok(Err(reason)),
+    };
+
+    if !used_fallback && (options.pitch.is_some() || options.tempo.is_some()) {
+        stem_paths.extend(transform_music(msg, &stem_paths, options).await?);
+    }
+
+    drop(processing_permit);
+    reservation.written(output_dir).await;
+
+    info!(
+        ?used_fallback,
+        "Processed downloaded song, uploading files..."
+    );
+    trace!(?stem_paths, "Stems created");
+
+    prepare_upload(msg, &stem_paths, &song, options, used_fallback).await?;
+
+    let analysis = match analysis {
+        Some(x) => x.await.ok().flatten(),
+        None => None,
+    };
+
+    let file_names = file_names(&stem_paths);
+    let delivery = Delivery {
+        files: stem_paths,
+        song: &song,
+        source,
+        options,
+        song_duration,
+        used_fallback,
+        analysis,
+    };
+    let file_ids = sink.deliver(msg, delivery).await.inspect_err(|e| {
+        Metrics::job_failed(FailedStage::Upload);
+        AdminReport::job_failed(
+            FailedStage::Upload,
+            source,
+            &requester.description,
+            &e.to_string(),
+        );
+    })?;
+
+    Ok(Ok(ProcessedSong {
+        file_ids,
+        file_names,
+        used_fallback,
+        song_file_path,
+        song_duration,
+        song,
+        analysis,
+    }))
+}
+
+/// Names of the files, eg. for listing them in the summary of the job
+pub fn file_names(file_paths: &[PathBuf]) -> Vec<String> {
+    file_paths
+        .iter()
+        .filter_map(|x| x.file_name())
+        .map(|x| x.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Process a song submitted through the API. The files are kept for the client to download
+/// instead of being uploaded.
+pub fn run_api_job(submission: ApiSubmission) -> BoxFuture<'static, Result<(), String>> {
+    let ApiSubmission {
+        id,
+        url,
+        mut options,
+        status: mut msg,
+        sink,
+    } = submission;
+    let requester = SongRequester {
+        user_id: None,
+        description: "API".to_string(),
+        job_id: id,
+    };
+    let task_span = info_span!(
+        "process_song",
+        job = requester.job_id,
+        url = url.as_str(),
+        origin = "api"
+    );
+
+    async move {
+        info!("New song submitted through the API");
+
+        let source = SongSource::Url(url);
+        let job = JobContext {
+            requester: &requester,
+            sink: &sink,
+            pipeline: &DemucsPipeline,
+        };
+        match process_song(&mut msg, source, &mut options, &job).await {
+            Ok(SongOutcome::Processed { .. }) => Ok(()),
+            Ok(SongOutcome::Failed(reason)) => Err(reason),
+            Err(e) => {
+                warn!(?e, "Failed to process song");
+                Err(msg.lang().text("job-failed"))
+            }
+        }
+    }
+    .instrument(task_span)
+    .boxed()
+}
+
+/// Detect the key and the tempo of the song, ignoring failures
+async fn analyze_song(song_file_path: PathBuf) -> Option<SongAnalysis> {
+    match SongAnalysis::analyze(&song_file_path).await {
+        Ok(x) => Some(x),
+        Err(e) => {
+            debug!(?e, "Failed to analyze song");
+            None
+        }
+    }
+}
+
+/// Caption of the first file, eg. `Detected: A minor, 128 BPM`
+pub fn analysis_caption(analysis: SongAnalysis, lang: Lang) -> String {
+    lang.format("analysis", &[("analysis", &analysis)])
+}
+
+/// Keep the song and send a message below the files that lets the user process it again with a
+/// different model
+async fn offer_reprocess(msg: &dyn Notifier, kept: Box<KeptSource>) -> ResponseResult<()> {
+    // Splitting into six stems is only supported by a single model
+    if kept.options.stem_mode == StemMode::SixStem {
+        return Ok(());
+    }
+
+    let model = kept.options.model;
+    let source_id = Reprocess::keep(*kept);
+
+    send_reprocess_offer(msg, source_id, model).await
+}
+
+/// Send the message with a button for each of the models the song can be reprocessed with
+async fn send_reprocess_offer(
+    msg: &dyn Notifier,
+    source_id: u64,
+    used_model: DemucsModel,
+) -> ResponseResult<()> {
+    let lang = msg.lang();
+
+    msg.send_text_with_keyboard(
+        &lang.text("reprocess-offer"),
+        Reprocess::keyboard(source_id, used_model, lang),
+    )
+    .await
+}
+
+/// Split the kept song into stems again using `model` and upload the ones the user chose.
+///
+/// The other models are offered again once it's done.
+pub async fn reprocess_song(
+    msg: &mut dyn Notifier,
+    claimed: &ClaimedSource,
+    model: DemucsModel,
+    requester: &SongRequester,
+) -> ResponseResult<()> {
+    let started = Instant::now();
+    let source = claimed.source();
+    let options = SongOptions {
+        model,
+        ..source.options
+    };
+    let output_dir = TempDir::with_prefix("karaokify-reprocess-").await?;
+
+    let lang = msg.lang();
+    let processing_permit =
+        wait_in_queue(msg, &PROCESSING_QUEUE, &lang.text("processing-waiting")).await?;
+
+    info!("Reprocessing song...");
+    let stems = split_into_stems_with_progress(
+        msg,
+        output_dir.path(),
+        &source.song_file_path,
+        options,
+        source.song_duration,
+        &DemucsPipeline,
+    )
+    .await;
+    drop(processing_permit);
+
+    let mut stem_paths = match stems {
+        Ok(x) => x,
+        Err(e) => {
+            warn!(?e, "Failed to reprocess song");
+            return msg
+                .update_status(&lang.format(
+                    "reprocess-failed",
+                    &[("model", &model), ("reason", &html::escape_value(&e))],
+                ))
+                .await;
+        }
+    };
+    let outputs = options.outputs.unwrap_or_default();
+    stem_paths.retain(|x| outputs.includes(x));
+
+    msg.update_status(&lang.text("uploading")).await?;
+    if let Some(cover) = &source.song.cover {
+        cover.embed_into(&stem_paths).await;
+    }
+    let summary = SongSummary {
+        title: source.song.title.clone(),
+        model: Some(model),
+        files: Some(file_names(&stem_paths)),
+    };
+    upload_files(msg, stem_paths, &source.song, options.zip, None, requester).await?;
+
+    // The offer is sent again so it's below the new files
+    finish_status_message(msg, &[summary], started, &requester.job_id, None).await?;
+    send_reprocess_offer(msg, claimed.id(), model).await
+}
+
+/// Normalize the loudness of the processed files (if requested) and embed the cover art into
+/// them before they're uploaded
+async fn prepare_upload(
+    msg: &mut dyn Notifier,
+    file_paths: &[PathBuf],
+    song: &SongDetails,
+    options: SongOptions,
+    used_fallback: bool,
+) -> ResponseResult<()> {
+    let lang = msg.lang();
+    if used_fallback {
+        msg.update_status(&lang.text("uploading-fallback")).await?;
+    } else {
+        msg.update_status(&lang.text("uploading")).await?;
+    }
+
+    if options.loudnorm || Config::global().loudnorm {
+        msg.update_status(&lang.text("normalizing")).await?;
+        Loudnorm::normalize_all(file_paths, options.encoding().bitrate).await;
+    }
+
+    if let Some(cover) = &song.cover {
+        cover.embed_into(file_paths).await;
+    }
+
+    Ok(())
+}
+
+/// Send the files of an identical request instead of processing the song again, either from
+/// the cache or by waiting for the request that is currently processing the same song.
+///
+/// Returns `None` if the files were sent, otherwise the caller should process the song.
+async fn reuse_existing_result(
+    msg: &mut dyn Notifier,
+    url: &Url,
+    options: SongOptions,
+) -> ResponseResult<Option<InFlightGuard>> {
+    loop {
+        if let Some(CachedResult {
+            file_ids, analysis, ..
+        }) = ResultCache::get(url, options)
+        {
+            info!("Sending song from cache");
+            let lang = msg.lang();
+            msg.update_status(&lang.text("cached")).await?;
+
+            let mut caption = lang.text("cached-caption");
+            if let Some(analysis) = analysis {
+                caption = format!("{caption}\n{}", analysis_caption(analysis, lang));
+            }
+
+            // The files might not be available anymore, process the song again in that case
+            match send_file_ids(msg, &file_ids, Some(&caption)).await {
+                Ok(()) => return Ok(None),
+                Err(e) => {
+                    warn!(?e, "Failed to send cached files");
+                    let _ = ResultCache::forget(url);
+                }
+            }
+        }
+
+        let in_flight = match InFlight::join(ResultCache::key(url, options)) {
+            InFlightJoin::Leader(guard) => return Ok(Some(guard)),
+            InFlightJoin::Follower(x) => x,
+        };
+
+        info!("Song is already being processed, waiting for it to finish");
+        // If the other request fails, the song is processed again (or waited for) from scratch
+        if let Some(file_ids) = wait_for_in_flight(msg, in_flight).await? {
+            match send_file_ids(msg, &file_ids, None).await {
+                Ok(()) => return Ok(None),
+                Err(e) => {
+                    warn!(?e, "Failed to send files of the other request");
+                }
+            }
+        }
+    }
+}
+
+/// Mirror the status of the request that is processing the same song until it finishes.
+///
+/// Returns the uploaded file IDs if the other request succeeded.
+async fn wait_for_in_flight(
+    msg: &mut dyn Notifier,
+    mut in_flight: watch::Receiver<InFlightState>,
+) -> ResponseResult<Option<Vec<String>>> {
+    loop {
+        let state = in_flight.borrow_and_update().clone();
+
+        match state {
+            InFlightState::Finished(file_ids) => return Ok(file_ids),
+            InFlightState::Running(status) => {
+                let text = msg
+                    .lang()
+                    .format("waiting-in-flight", &[("status", &status)]);
+                msg.update_status(text.trim_end()).await?;
+            }
+        }
+
+        if in_flight.changed().await.is_err() {
+            return Ok(None);
+        }
+    }
+}
+
+/// Wait for a queue permit while keeping the status message updated with the queue position
+async fn wait_in_queue<'a>(
+    msg: &mut dyn Notifier,
+    queue: &'a SongQueue,
+    waiting_text: &str,
+) -> ResponseResult<SemaphorePermit<'a>> {
+    let ticket = queue.join();
+
+    let acquire = ticket.acquire();
+    tokio::pin!(acquire);
+
+    let mut last_position = None;
+    loop {
+        let changed = ticket.changed();
+
+        if let Some(permit) = acquire.as_mut().now_or_never() {
+            return Ok(permit.expect("Semaphore should not be closed"));
+        }
+
+        let position = ticket.position();
+        if last_position != Some(position) {
+            trace!(?position, "Queue position changed");
+            let text = msg.lang().format(
+                "queue-position",
+                &[("waiting", &waiting_text), ("position", &position)],
+            );
+            msg.update_status(&text).await?;
+            last_position = Some(position);
+        }
+
+        tokio::select! {
+            permit = &mut acquire => {
+                return Ok(permit.expect("Semaphore should not be closed"));
+            }
+
+            () = changed => {}
+        }
+    }
+}
+
+/// Download the song once there's a free download slot and enough space in the work dir.
+///
+/// Returns the downloaded songs (usually just one) along with the space reserved for processing
+/// them, or the reason shown to the user if the download failed.
+async fn download_song(
+    msg: &mut dyn Notifier,
+    source: &SongSource,
+    download_dir: &Path,
+    job: &JobContext<'_>,
+) -> ResponseResult<Result<(Vec<DownloadedSong>, Reservation), String>> {
+    let requester = job.requester;
+    let lang = msg.lang();
+    let download_permit = wait_in_queue(msg, &DOWNLOAD_QUEUE, &lang.text("queue-waiting")).await?;
+    // Too late to replace the job if the message is edited now
+    RecentMessages::set(
+        msg.chat_id(),
+        msg.msg_replying_to_id(),
+        MessageState::Started,
+    );
+
+    match WorkDir::available_space() {
+        Ok(x) if x < Config::global().min_free_disk_space => {
+            warn!(
+                available_mb = x / 1000 / 1000,
+                "Low on disk space, rejecting song"
+            );
+            return Ok(Err(lang.text("low-disk-space")));
+        }
+        Ok(_) => {}
+        Err(e) => debug!(?e, "Could not check the available disk space"),
+    }
+    let Some(reservation) = WorkDir::reserve().await else {
+        return Ok(Err(lang.text("too-busy")));
+    };
+
+    msg.update_status(&lang.text("downloading")).await?;
+
+    let songs = match download_with_progress(msg, source, download_dir, job.pipeline).await {
+        Err(e) => {
+            Metrics::job_failed(FailedStage::Download);
+            AdminReport::job_failed(
+                FailedStage::Download,
+                source,
+                &requester.description,
+                &format!("{e:#}"),
+            );
+            return Ok(Err(download_failed_text(&e, lang)));
+        }
+
+        Ok(p) => p,
+    };
+
+    drop(download_permit);
+    reservation.downloaded(download_dir).await;
+
+    trace!(?songs, "Song downloaded");
+
+    Ok(Ok((songs, reservation)))
+}
+
+/// The failure message shown to the user, listing the reasons of all the handlers that were
+/// tried
+fn download_failed_text(e: &anyhow::Error, lang: Lang) -> String {
+    let Some(e) = e.downcast_ref::<DownloadError>() else {
+        return lang.format(
+            "download-failed",
+            &[("reason", &html::escape_value(format!("{e:#}")))],
+        );
+    };
+
+    if e.failures.is_empty() {
+        return lang.format("download-failed", &[("reason", &html::escape_value(e))]);
+    }
+
+    let reasons = e
+        .reasons()
+        .map(|x| format!("- {}", html::escape_value(x)))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    lang.format("download-failed-reasons", &[("reasons", &reasons)])
+}
+
+/// Download the song, showing how much of it is downloaded in the status message
+async fn download_with_progress(
+    msg: &mut dyn Notifier,
+    source: &SongSource,
+    download_dir: &Path,
+    pipeline: &dyn Pipeline,
+) -> anyhow::Result<Vec<DownloadedSong>> {
+    let (progress_tx, mut progress_rx) = watch::channel(DownloadProgress::starting());
+
+    let download = pipeline.download(source, download_dir, Some(&progress_tx));
+    tokio::pin!(download);
+
+    loop {
+        tokio::select! {
+            res = &mut download => return res,
+
+            Ok(()) = progress_rx.changed() => {
+                let progress = progress_rx.borrow_and_update().to_string();
+
+                let res = msg.update_status(&progress).await;
+
+                if let Err(e) = res {
+                    debug!(?e, "Failed to update download progress message");
+                }
+            }
+        }
+    }
+}
+
+/// Ask the user which files they want using a keyboard attached to the status message.
+///
+/// Everything is created if they don't choose in time (or can't be asked).
+async fn choose_outputs(
+    msg: &mut dyn Notifier,
+    job: &JobContext<'_>,
+) -> ResponseResult<OutputChoice> {
+    if !job.sink.is_interactive() {
+        return Ok(OutputChoice::default());
+    }
+
+    let lang = msg.lang();
+    let pending = OutputChoice::ask(job.requester.user_id);
+    msg.update_status_with_keyboard(
+        &lang.format(
+            "choose-outputs",
+            &[("timeout", &format_duration(OUTPUT_CHOICE_TIMEOUT))],
+        ),
+        pending.keyboard(lang),
+    )
+    .await?;
+
+    let choice = pending.wait(OUTPUT_CHOICE_TIMEOUT).await;
+    info!(?choice, "Outputs chosen");
+
+    Ok(choice)
+}
+
+/// Check whether the song has vocals and ask the user whether to process it if it doesn't seem
+/// to.
+///
+/// Returns whether the song should be processed. It's skipped if the user doesn't answer in
+/// time.
+async fn check_vocals(
+    msg: &mut dyn Notifier,
+    song_file_path: &Path,
+    song_duration: Option<Duration>,
+    requester: &SongRequester,
+) -> ResponseResult<bool> {
+    if !Config::global().instrumental_check {
+        return Ok(true);
+    }
+
+    match VocalDetector::looks_instrumental(song_file_path, song_duration).await {
+        Ok(false) => return Ok(true),
+        Ok(true) => {}
+        Err(e) => {
+            debug!(
+                ?e,
+                "Failed to check the song for vocals, processing it anyway"
+            );
+            return Ok(true);
+        }
+    }
+
+    let lang = msg.lang();
+    let pending = InstrumentalChoice::ask(requester.user_id);
+    msg.update_status_with_keyboard(
+        &lang.format(
+            "looks-instrumental",
+            &[("timeout", &format_duration(INSTRUMENTAL_CHOICE_TIMEOUT))],
+        ),
+        pending.keyboard(lang),
+    )
+    .await?;
+
+    let choice = pending.wait(INSTRUMENTAL_CHOICE_TIMEOUT).await;
+    info!(?choice, "Song looks instrumental");
+
+    Ok(choice == InstrumentalChoice::Process)
+}
+
+/// Cut the song down to the requested range.
+///
+/// Returns the reason shown to the user if the song couldn't be trimmed.
+async fn trim_song(
+    msg: &mut dyn Notifier,
+    song_file_path: &Path,
+    trim: TrimRange,
+    song_duration: Option<Duration>,
+) -> ResponseResult<Result<PathBuf, String>> {
+    let lang = msg.lang();
+    if let Some(Err(e)) = song_duration.map(|x| trim.validate(x)) {
+        return Ok(Err(
+            lang.format("trim-failed", &[("reason", &html::escape_value(&e))])
+        ));
+    }
+
+    msg.update_status(&lang.format("trimming", &[("trim", &trim)]))
+        .await?;
+
+    let output_dir = song_file_path.parent().unwrap_or(song_file_path);
+    Ok(
+        FfmpegProcessor::trim(song_file_path, output_dir, trim.start, trim.end)
+            .await
+            .map_err(|e| {
+                warn!(?e, "Failed to trim song");
+                lang.format("trim-failed", &[("reason", &html::escape_value(&e))])
+            }),
+    )
+}
+
+/// Process a short part from the middle of the song and send its instrumental so the user can
+/// check that it's the right song while the whole one is processed.
+///
+/// Failing to do so isn't fatal since the whole song is processed anyway.
+async fn send_preview(
+    msg: &mut dyn Notifier,
+    output_dir: &Path,
+    song_file_path: &Path,
+    song: &SongDetails,
+    song_duration: Option<Duration>,
+    options: SongOptions,
+    pipeline: &dyn Pipeline,
+) -> ResponseResult<()> {
+    let Some(song_duration) = song_duration else {
+        return Ok(());
+    };
+    if !Config::global().preview || song_duration < MIN_PREVIEW_SONG_DURATION {
+        return Ok(());
+    }
+
+    let lang = msg.lang();
+    msg.update_status(&lang.text("creating-preview")).await?;
+
+    let start = song_duration.saturating_sub(PREVIEW_LENGTH) / 2;
+    let preview = create_preview(
+        &output_dir.join("preview"),
+        song_file_path,
+        start,
+        start + PREVIEW_LENGTH,
+        options,
+        pipeline,
+    )
+    .await;
+    let preview_path = match preview {
+        Ok(x) => x,
+        Err(e) => {
+            warn!(?e, "Failed to create preview");
+            return Ok(());
+        }
+    };
+
+    let mut preview = InputMediaAudio::new(InputFile::file(preview_path))
+        .caption(lang.text("preview-caption"))
+        .title(lang.format("preview-title", &[("title", &song.title)]));
+    if let Some(performer) = &song.meta.artist {
+        preview = preview.performer(performer);
+    }
+
+    if let Err(e) = msg.send_audio_group(vec![InputMedia::Audio(preview)]).await {
+        warn!(?e, "Failed to send preview");
+    }
+
+    Ok(())
+}
+
+/// Split the part of the song between `start` and `end` into stems.
+///
+/// Returns the path of the instrumental.
+async fn create_preview(
+    output_dir: &Path,
+    song_file_path: &Path,
+    start: Duration,
+    end: Duration,
+    options: SongOptions,
+    pipeline: &dyn Pipeline,
+) -> anyhow::Result<PathBuf> {
+    tokio::fs::create_dir_all(output_dir).await?;
+
+    let snippet_path = FfmpegProcessor::trim(song_file_path, output_dir, start, end).await?;
+    let settings = SplitSettings {
+        model: options.model,
+        stem_mode: StemMode::TwoStem,
+        guide_vocals: None,
+        encoding: options.encoding(),
+    };
+    let stem_paths = pipeline
+        .split_into_stems(output_dir, &snippet_path, settings, None)
+        .await?;
+
+    stem_paths
+        .into_iter()
+        .find(|x| {
+            x.file_stem()
+                .is_some_and(|x| x.to_string_lossy().ends_with(".music"))
+        })
+        .ok_or_else(|| anyhow::anyhow!("instrumental wasn't created"))
+}
+
+/// Split the song into stems, falling back to just removing the center channel if that fails.
+///
+/// Returns the created files the user chose and whether the fallback was used, or the reason
+/// shown to the user if even the fallback failed.
+async fn split_song(
+    msg: &mut dyn Notifier,
+    output_dir: &Path,
+    song_file_path: &Path,
+    options: SongOptions,
+    song_duration: Option<Duration>,
+    source: &SongSource,
+    job: &JobContext<'_>,
+) -> ResponseResult<Result<(Vec<PathBuf>, bool), String>> {
+    let stems_result = split_into_stems_with_progress(
+        msg,
+        output_dir,
+        song_file_path,
+        options,
+        song_duration,
+        job.pipeline,
+    )
+    .await;
+    let e = match stems_result {
+        Ok(mut s) => {
+            let outputs = options.outputs.unwrap_or_default();
+            s.retain(|x| outputs.includes(x));
+
+            return Ok(Ok((s, false)));
+        }
+        Err(e) => e,
+    };
+
+    warn!(?e, "Failed to split song into stems, trying fallback");
+    let lang = msg.lang();
+    msg.update_status(&lang.text("trying-fallback")).await?;
+
+    let fallback =
+        job.pipeline
+            .remove_center_channel(output_dir, song_file_path, options.encoding().bitrate);
+    match fallback.await {
+        Ok(path) => Ok(Ok((vec![path], true))),
+        Err(fallback_e) => {
+            debug!(?fallback_e, "Fallback failed");
+            let mut error = format!("{e:#}\n\nFallback: {fallback_e:#}");
+            if let Some(e) = e
+                .downcast_ref::<DemucsError>()
+                .filter(|x| !x.is_classified())
+            {
+                error.push_str("\n\nDemucs output:\n");
+                error.push_str(&e.stderr_excerpt());
+            }
+            Metrics::job_failed(FailedStage::Processing);
+            AdminReport::job_failed(
+                FailedStage::Processing,
+                source,
+                &job.requester.description,
+                &error,
+            );
+            Ok(Err(lang.format(
+                "processing-failed",
+                &[("reason", &html::escape_value(&e))],
+            )))
+        }
+    }
+}
+
+/// Create a transposed and/or sped up (or slowed down) version of the instrumental.
+///
+/// Failing to do so isn't fatal since the rest of the stems are still useful.
+async fn transform_music(
+    msg: &mut dyn Notifier,
+    stem_paths: &[PathBuf],
+    options: SongOptions,
+) -> ResponseResult<Option<PathBuf>> {
+    let Some(music_path) = stem_paths.iter().find(|x| {
+        x.file_stem()
+            .is_some_and(|x| x.to_string_lossy().ends_with(".music"))
+    }) else {
+        return Ok(None);
+    };
+
+    msg.update_status(&msg.lang().text("applying-effects"))
+        .await?;
+
+    match PostFx::transform(music_path, options.pitch, options.tempo, options.encoding()).await {
+        Ok(path) => Ok(Some(path)),
+        Err(e) => {
+            warn!(?e, "Failed to apply effects to the instrumental");
+            Ok(None)
+        }
+    }
+}
+
+/// Split the song into stems while periodically updating the status message with the progress
+/// and the estimated time until it's done
+async fn split_into_stems_with_progress(
+    msg: &mut dyn Notifier,
+    output_dir: &Path,
+    song_file_path: &Path,
+    options: SongOptions,
+    song_duration: Option<Duration>,
+    pipeline: &dyn Pipeline,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let outputs = options.outputs.unwrap_or_default();
+    let stem_mode = outputs.stem_mode(options.stem_mode);
+    let eta = ProcessingEta::start(stem_mode.model(options.model), song_duration);
+    let lang = msg.lang();
+    msg.update_status(&eta.status_text(None, lang)).await?;
+
+    let (progress_tx, mut progress_rx) = watch::channel(DemucsProgress::default());
+
+    let settings = SplitSettings {
+        model: options.model,
+        stem_mode,
+        guide_vocals: outputs.wants_extras().then(|| {
+            options
+                .guide_vocals
+                .unwrap_or_else(|| Config::global().guide_vocals)
+        }),
+        encoding: options.encoding(),
+    };
+    let split = pipeline.split_into_stems(output_dir, song_file_path, settings, Some(&progress_tx));
+    tokio::pin!(split);
+
+    let mut update_interval = tokio::time::interval(PROGRESS_UPDATE_INTERVAL);
+    update_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            res = &mut split => {
+                if res.is_ok() {
+                    eta.finish();
+                }
+
+                return res;
+            }
+
+            _ = update_interval.tick() => {
+                if !progress_rx.has_changed().unwrap_or_default() {
+                    continue;
+                }
+
+                let progress = *progress_rx.borrow_and_update();
+                trace!(?progress, "Demucs progress updated");
+
+                let mut text = eta.status_text(Some(progress.percent), lang);
+                if progress.reduced_memory {
+                    text.push_str("\n\n");
+                    text.push_str(&lang.text("reduced-memory"));
+                }
+                let res = msg.update_status(&text).await;
+
+                if let Err(e) = res {
+                    debug!(?e, "Failed to update progress message");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{panic::AssertUnwindSafe, sync::Mutex};
+
+    use teloxide::types::{ChatId, InlineKeyboardMarkup, MessageId};
+
+    use super::*;
+    use crate::{in_flight::InFlightStatus, jobs::panic_message, processor::encoding::Bitrate};
+
+    /// Records what the user would be told instead of sending it to Telegram
+    #[derive(Default)]
+    struct RecordingNotifier {
+        statuses: Vec<String>,
+        /// The separate messages, eg. `text: ...` or `audio group: 2 files`
+        sent: Mutex<Vec<String>>,
+        /// Whether the job is done, its summary is the last status
+        finished: bool,
+    }
+    impl RecordingNotifier {
+        fn record(&self, message: String) {
+            self.sent.lock().expect("Sent lock poisoned").push(message);
+        }
+
+        fn sent(&self) -> Vec<String> {
+            self.sent.lock().expect("Sent lock poisoned").clone()
+        }
+    }
+    #[async_trait::async_trait]
+    impl Notifier for RecordingNotifier {
+        fn lang(&self) -> Lang {
+            Lang::En
+        }
+
+        fn chat_id(&self) -> ChatId {
+            ChatId(1)
+        }
+
+        fn msg_replying_to_id(&self) -> MessageId {
+            MessageId(1)
+        }
+
+        fn set_header(&mut self, _header: Option<String>) {}
+
+        fn set_mirror(&mut self, _mirror: Option<InFlightStatus>) {}
+
+        async fn update_status(&mut self, text: &str) -> ResponseResult<()> {
+            self.statuses.push(text.to_string());
+            Ok(())
+        }
+
+        async fn update_status_with_keyboard(
+            &mut self,
+            text: &str,
+            _keyboard: InlineKeyboardMarkup,
+        ) -> ResponseResult<()> {
+            self.statuses.push(text.to_string());
+            Ok(())
+        }
+
+        async fn send_text(&self, text: &str) -> ResponseResult<()> {
+            self.record(format!("text: {text}"));
+            Ok(())
+        }
+
+        async fn send_text_with_keyboard(
+            &self,
+            text: &str,
+            _keyboard: InlineKeyboardMarkup,
+        ) -> ResponseResult<()> {
+            self.record(format!("text with keyboard: {text}"));
+            Ok(())
+        }
+
+        async fn send_audio_group(
+            &self,
+            media_group: Vec<InputMedia>,
+        ) -> ResponseResult<Vec<Message>> {
+            self.record(format!("audio group: {} files", media_group.len()));
+            Ok(vec![])
+        }
+
+        async fn send_document(
+            &self,
+            _document: InputFile,
+            _caption: Option<&str>,
+        ) -> ResponseResult<()> {
+            self.record("document".to_string());
+            Ok(())
+        }
+
+        async fn send_video(&self, _video: InputFile, _caption: &str) -> ResponseResult<()> {
+            self.record("video".to_string());
+            Ok(())
+        }
+
+        async fn finish(&mut self, summary: Option<&str>) -> ResponseResult<()> {
+            self.statuses.extend(summary.map(ToString::to_string));
+            self.finished = true;
+            Ok(())
+        }
+    }
+
+    /// How splitting a song with the [`FakePipeline`] ends
+    #[derive(Default)]
+    enum FakeSplit {
+        #[default]
+        Stems,
+        Fails,
+        Panics,
+    }
+
+    /// Creates empty files instead of downloading and processing the songs, or fails on purpose
+    #[derive(Default)]
+    struct FakePipeline {
+        download_fails: bool,
+        split: FakeSplit,
+        fallback_fails: bool,
+        /// Where the songs were downloaded to
+        download_dirs: Mutex<Vec<PathBuf>>,
+    }
+    #[async_trait::async_trait]
+    impl Pipeline for FakePipeline {
+        async fn download(
+            &self,
+            _source: &SongSource,
+            download_dir: &Path,
+            _progress: Option<&watch::Sender<DownloadProgress>>,
+        ) -> anyhow::Result<Vec<DownloadedSong>> {
+            if self.download_fails {
+                anyhow::bail!("the provider is down");
+            }
+
+            self.download_dirs
+                .lock()
+                .expect("Lock")
+                .push(download_dir.to_path_buf());
+            let path = download_dir.join("song.mp3");
+            tokio::fs::write(&path, b"").await?;
+            Ok(vec![DownloadedSong::from_path(path)])
+        }
+
+        async fn split_into_stems(
+            &self,
+            output_dir: &Path,
+            _song_file_path: &Path,
+            _settings: SplitSettings,
+            _progress: Option<&watch::Sender<DemucsProgress>>,
+        ) -> anyhow::Result<Vec<PathBuf>> {
+            match self.split {
+                FakeSplit::Stems => {}
+                FakeSplit::Fails => anyhow::bail!("demucs crashed"),
+                FakeSplit::Panics => panic!("demucs exploded"),
+            }
+
+            let mut stems = vec![];
+            for name in ["song.music.mp3", "song.vocals.mp3"] {
+                let path = output_dir.join(name);
+                tokio::fs::write(&path, b"").await?;
+                stems.push(path);
+            }
+            Ok(stems)
+        }
+
+        async fn remove_center_channel(
+            &self,
+            output_dir: &Path,
+            _song_file_path: &Path,
+            _bitrate: Bitrate,
+        ) -> anyhow::Result<PathBuf> {
+            if self.fallback_fails {
+                anyhow::bail!("ffmpeg crashed");
+            }
+
+            let path = output_dir.join("song.music-fallback.mp3");
+            tokio::fs::write(&path, b"").await?;
+            Ok(path)
+        }
+    }
+
+    /// Keeps the names of the delivered files and whether the fallback was used
+    #[derive(Default)]
+    struct RecordingSink {
+        deliveries: Mutex<Vec<(Vec<String>, bool)>>,
+    }
+    #[async_trait::async_trait]
+    impl ResultSink for RecordingSink {
+        fn is_interactive(&self) -> bool {
+            false
+        }
+
+        async fn deliver(
+            &self,
+            _msg: &mut dyn Notifier,
+            delivery: Delivery<'_>,
+        ) -> ResponseResult<Option<Vec<String>>> {
+            let mut names = file_names(&delivery.files);
+            names.sort();
+            self.deliveries
+                .lock()
+                .expect("Deliveries lock poisoned")
+                .push((names, delivery.used_fallback));
+            Ok(None)
+        }
+    }
+
+    /// Process a song with the fake pipeline, returning the outcome and what the user was told
+    async fn run(pipeline: FakePipeline) -> (SongOutcome, RecordingNotifier, RecordingSink) {
+        let requester = SongRequester {
+            user_id: None,
+            description: "test".to_string(),
+            job_id: short_id(),
+        };
+        let sink = RecordingSink::default();
+        let mut notifier = RecordingNotifier::default();
+        let job = JobContext {
+            requester: &requester,
+            sink: &sink,
+            pipeline: &pipeline,
+        };
+        let source = SongSource::Path(PathBuf::from("song.mp3"));
+
+        let outcome = process_song(&mut notifier, source, &mut SongOptions::default(), &job)
+            .await
+            .expect("Nothing is sent to Telegram");
+
+        (outcome, notifier, sink)
+    }
+
+    #[tokio::test]
+    async fn failed_downloads_are_reported() {
+        let pipeline = FakePipeline {
+            download_fails: true,
+            ..FakePipeline::default()
+        };
+
+        let (outcome, notifier, sink) = run(pipeline).await;
+
+        let SongOutcome::Failed(reason) = outcome else {
+            panic!("Download should fail, got {outcome:?}");
+        };
+        assert!(reason.contains("the provider is down"), "{reason}");
+        assert!(notifier.statuses.contains(&Lang::En.text("downloading")));
+        assert!(!notifier.finished);
+        assert!(sink.deliveries.lock().expect("Lock").is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_fallback_is_used_when_splitting_fails() {
+        let pipeline = FakePipeline {
+            split: FakeSplit::Fails,
+            ..FakePipeline::default()
+        };
+
+        let (outcome, notifier, sink) = run(pipeline).await;
+
+        let SongOutcome::Processed { summaries, kept } = outcome else {
+            panic!("Fallback should succeed, got {outcome:?}");
+        };
+        assert!(kept.is_none());
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].model, None);
+        assert!(notifier
+            .statuses
+            .contains(&Lang::En.text("trying-fallback")));
+        assert!(notifier
+            .statuses
+            .contains(&Lang::En.text("uploading-fallback")));
+        assert_eq!(
+            *sink.deliveries.lock().expect("Lock"),
+            vec![(vec!["song.music-fallback.mp3".to_string()], true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_processing_is_reported() {
+        let pipeline = FakePipeline {
+            split: FakeSplit::Fails,
+            fallback_fails: true,
+            ..FakePipeline::default()
+        };
+
+        let (outcome, notifier, sink) = run(pipeline).await;
+
+        let SongOutcome::Failed(reason) = outcome else {
+            panic!("Processing should fail, got {outcome:?}");
+        };
+        assert!(reason.contains("demucs crashed"), "{reason}");
+        assert!(notifier
+            .statuses
+            .contains(&Lang::En.text("trying-fallback")));
+        assert!(notifier.sent().is_empty());
+        assert!(sink.deliveries.lock().expect("Lock").is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_stems_are_delivered() {
+        let (outcome, notifier, sink) = run(FakePipeline::default()).await;
+
+        let SongOutcome::Processed { summaries, .. } = outcome else {
+            panic!("Song should be processed, got {outcome:?}");
+        };
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].model, Some(DemucsModel::default()));
+        assert!(notifier.statuses.contains(&Lang::En.text("uploading")));
+        assert_eq!(
+            *sink.deliveries.lock().expect("Lock"),
+            vec![(
+                vec!["song.music.mp3".to_string(), "song.vocals.mp3".to_string()],
+                false
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn panics_are_reported() {
+        let pipeline = FakePipeline {
+            split: FakeSplit::Panics,
+            ..FakePipeline::default()
+        };
+        let requester = SongRequester {
+            user_id: None,
+            description: "test".to_string(),
+            job_id: short_id(),
+        };
+        let sink = RecordingSink::default();
+        let mut notifier = RecordingNotifier::default();
+        let job = JobContext {
+            requester: &requester,
+            sink: &sink,
+            pipeline: &pipeline,
+        };
+        let source = SongSource::Path(PathBuf::from("song.mp3"));
+
+        let panic = AssertUnwindSafe(process_song(
+            &mut notifier,
+            source,
+            &mut SongOptions::default(),
+            &job,
+        ))
+        .catch_unwind()
+        .await
+        .expect_err("Processing panics");
+        let reason = panic_message(&*panic);
+        assert_eq!(reason, "demucs exploded");
+
+        report_panic(&mut notifier, "song.mp3", &requester, &reason)
+            .await
+            .expect("Nothing is sent to Telegram");
+
+        assert_eq!(
+            notifier.statuses.last(),
+            Some(&with_job_id(
+                &Lang::En.text("internal-error"),
+                &requester.job_id,
+                Lang::En
+            ))
+        );
+        assert!(sink.deliveries.lock().expect("Lock").is_empty());
+        // The temp dirs were removed while unwinding
+        let download_dirs = pipeline.download_dirs.lock().expect("Lock").clone();
+        assert_eq!(download_dirs.len(), 1);
+        assert!(!download_dirs[0].exists());
+    }
+}
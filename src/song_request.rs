@@ -0,0 +1,428 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use base64::{
+    alphabet,
+    engine::{general_purpose::GeneralPurposeConfig, DecodePaddingMode, GeneralPurpose},
+    Engine,
+};
+use teloxide::types::{Message, MessageEntityKind};
+use tokio::sync::watch;
+use tracing::trace;
+use url::Url;
+
+use crate::{
+    chat_settings::ChatSettings,
+    config::Config,
+    downloader::{DownloadedSong, Downloader},
+    helpers::{
+        download::DownloadProgress,
+        telegram_file::TelegramFile,
+        url_normalize::{normalize_url, NormalizedUrl},
+    },
+    options::SongOptions,
+};
+
+/// Base64url with or without padding, encoded without it (deep link payloads can't contain `=`)
+const BASE64_URL_SAFE: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+/// Telegram allows at most this many characters in the payload of a deep link
+const MAX_DEEP_LINK_PAYLOAD_LENGTH: usize = 64;
+
+#[derive(Debug)]
+pub enum SongSource {
+    Url(Url),
+    File(TelegramFile),
+    /// A file on this machine, eg. given on the command line
+    Path(PathBuf),
+}
+impl Display for SongSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Url(url) => write!(f, "{url}"),
+            Self::File(file) => write!(f, "file {}", file.name().unwrap_or("(unnamed)")),
+            Self::Path(path) => write!(f, "file {}", path.display()),
+        }
+    }
+}
+impl SongSource {
+    /// Download the song. Some downloads contain several songs (eg. a whole album).
+    ///
+    /// Progress of downloads from URLs is reported through `progress`.
+    pub async fn download(
+        &self,
+        download_dir: &Path,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<Vec<DownloadedSong>> {
+        match self {
+            Self::Url(url) => Ok(Downloader::download_song(download_dir, url, progress).await?),
+            Self::File(file) => Ok(vec![DownloadedSong::from_path(
+                file.download(download_dir).await?,
+            )]),
+            Self::Path(path) => {
+                let Some(file_name) = path.file_name() else {
+                    anyhow::bail!("{path:?} is not a file");
+                };
+
+                // Copied so the job can't change or delete the original
+                let file_path = download_dir.join(file_name);
+                tokio::fs::copy(path, &file_path).await?;
+
+                Ok(vec![DownloadedSong::from_path(file_path)])
+            }
+        }
+    }
+}
+
+/// A song the user wants karaokified
+#[derive(Debug)]
+pub struct RequestedSong {
+    pub source: SongSource,
+    /// Where the link wants playback to start, eg. from `t=90` on `YouTube`
+    pub start: Option<Duration>,
+}
+
+/// The songs the user wants karaokified, all with the same options
+#[derive(Debug)]
+pub struct SongRequest {
+    /// Each song is processed by its own job
+    pub songs: Vec<RequestedSong>,
+    pub options: SongOptions,
+    /// Links in the message that won't be processed, with the reasons
+    pub skipped: Vec<SkippedUrl>,
+}
+
+/// A link from the message that won't be processed
+#[derive(Debug)]
+pub struct SkippedUrl {
+    pub url: Url,
+    /// Key of the translated reason (see [`crate::i18n`])
+    pub reason: &'static str,
+}
+
+#[derive(Debug)]
+pub enum SongRequestError {
+    /// The message doesn't contain a link or an audio file
+    NoSong,
+    /// None of the links in the message can be downloaded
+    NoSupportedUrl(Vec<SkippedUrl>),
+    InvalidOptions(anyhow::Error),
+}
+impl Display for SongRequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoSong => f.write_str("message doesn't contain a link or an audio file"),
+            Self::NoSupportedUrl(skipped) => {
+                write!(f, "none of the {} link(s) are supported", skipped.len())
+            }
+            Self::InvalidOptions(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl SongRequest {
+    /// Find the song in `msg`.
+    ///
+    /// Options are read from the text of `options_msg` (usually the same message), unless it
+    /// was forwarded since the text then wasn't written by the user.
+    pub async fn from_message(
+        msg: &Message,
+        options_msg: &Message,
+    ) -> Result<Self, SongRequestError> {
+        let options = Self::options(options_msg)?;
+
+        if let Some(file) = TelegramFile::from_message(msg) {
+            trace!(?file, "Message contains an audio file");
+            return Ok(Self {
+                songs: vec![RequestedSong {
+                    source: SongSource::File(file),
+                    start: None,
+                }],
+                options,
+                skipped: vec![],
+            });
+        }
+
+        // Normalized before choosing the handler, so handlers and the cache see a single form of
+        // the link
+        let urls = Self::message_urls(msg)
+            .iter()
+            .map(normalize_url)
+            .collect::<Vec<_>>();
+        trace!(?urls, "Found URLs in message");
+
+        if urls.is_empty() {
+            return Err(SongRequestError::NoSong);
+        }
+
+        // Every link might be resolved over the network to find its handler, so only the first
+        // few are looked at, whether they're supported or not
+        let mut urls = urls;
+        let max_links = Config::global().max_message_links;
+        let extra_urls = urls.split_off(max_links.min(urls.len()));
+        let supported_urls =
+            futures::future::join_all(urls.iter().map(|x| Downloader::supported_url(&x.url))).await;
+
+        let mut songs = vec![];
+        let mut skipped = vec![];
+        for (NormalizedUrl { url, start }, supported_url) in urls.into_iter().zip(supported_urls) {
+            let Some(supported_url) = supported_url else {
+                skipped.push(SkippedUrl {
+                    url,
+                    reason: "skipped-unsupported",
+                });
+                continue;
+            };
+
+            // Redirects might lead to a link that needs normalizing as well
+            let supported = normalize_url(&supported_url);
+            let is_duplicate = songs.iter().any(
+                |x: &RequestedSong| matches!(&x.source, SongSource::Url(u) if *u == supported.url),
+            );
+            if is_duplicate {
+                skipped.push(SkippedUrl {
+                    url,
+                    reason: "skipped-duplicate",
+                });
+                continue;
+            }
+
+            songs.push(RequestedSong {
+                source: SongSource::Url(supported.url),
+                start: start.or(supported.start),
+            });
+        }
+        skipped.extend(extra_urls.into_iter().map(|x| SkippedUrl {
+            url: x.url,
+            reason: "skipped-too-many-links",
+        }));
+        trace!(?songs, ?skipped, "Chose songs from message");
+
+        if songs.is_empty() {
+            return Err(SongRequestError::NoSupportedUrl(skipped));
+        }
+
+        Ok(Self {
+            songs,
+            options,
+            skipped,
+        })
+    }
+
+    /// The options from the text of the message, unless it was forwarded since the text then
+    /// wasn't written by the user. The settings of the chat are used for the other options.
+    pub fn options(msg: &Message) -> Result<SongOptions, SongRequestError> {
+        let options_text = if msg.forward().is_some() {
+            String::new()
+        } else {
+            Self::text_without_urls(msg)
+        };
+
+        SongOptions::parse_with_defaults(
+            ChatSettings::get(msg.chat.id).song_options(),
+            options_text
+                .split_whitespace()
+                .filter(|x| SongOptions::is_option(x)),
+        )
+        .map_err(SongRequestError::InvalidOptions)
+    }
+
+    /// The text of the message without the links, commands and options, to search for a song
+    /// by its name. `None` if there's nothing left.
+    pub fn search_query(msg: &Message) -> Option<String> {
+        let query = Self::text_without_urls(msg)
+            .split_whitespace()
+            .filter(|x| !SongOptions::is_option(x))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        (!query.is_empty()).then_some(query)
+    }
+
+    /// The link to a song that can be downloaded from the text of an inline query (which has to
+    /// be just the link)
+    pub async fn inline_query_url(text: &str) -> Option<Url> {
+        let text = text.trim();
+        let url = Url::parse(text)
+            .ok()
+            .filter(is_web_url)
+            .or_else(|| spotify_uri_to_url(text))?;
+
+        let supported_url = Downloader::supported_url(&normalize_url(&url).url).await?;
+        // Redirects might lead to a link that needs normalizing as well
+        Some(normalize_url(&supported_url).url)
+    }
+
+    /// All links in the message (or its caption), in order of appearance
+    fn message_urls(msg: &Message) -> Vec<Url> {
+        let entities = msg
+            .parse_entities()
+            .or_else(|| msg.parse_caption_entities())
+            .unwrap_or_default();
+
+        let urls = entities
+            .iter()
+            .filter_map(|entity| match entity.kind() {
+                MessageEntityKind::Url => parse_url(entity.text()),
+                MessageEntityKind::TextLink { url } => Some(url.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if !urls.is_empty() {
+            return urls;
+        }
+
+        // Entities might be missing (eg. for messages that weren't parsed by Telegram)
+        msg.text()
+            .or_else(|| msg.caption())
+            .unwrap_or_default()
+            .split_whitespace()
+            .filter_map(|x| {
+                Url::parse(x)
+                    .ok()
+                    .filter(is_web_url)
+                    .or_else(|| spotify_uri_to_url(x))
+            })
+            .collect()
+    }
+
+    /// The message text (or caption) with all the links, commands and mentions removed
+    fn text_without_urls(msg: &Message) -> String {
+        let Some(text) = msg.text().or_else(|| msg.caption()) else {
+            return String::new();
+        };
+
+        let entities = msg
+            .parse_entities()
+            .or_else(|| msg.parse_caption_entities())
+            .unwrap_or_default();
+
+        let mut res = text.to_string();
+        let mut ranges = entities
+            .iter()
+            .filter(|x| {
+                matches!(
+                    x.kind(),
+                    MessageEntityKind::Url
+                        | MessageEntityKind::TextLink { .. }
+                        | MessageEntityKind::BotCommand
+                        | MessageEntityKind::Mention
+                )
+            })
+            .map(teloxide::types::MessageEntityRef::range)
+            .collect::<Vec<_>>();
+        // Remove from the back so the ranges stay valid
+        ranges.sort_by_key(|x| std::cmp::Reverse(x.start));
+        for range in ranges {
+            res.replace_range(range, " ");
+        }
+
+        res.split_whitespace()
+            .filter(|x| !Url::parse(x).is_ok_and(|x| is_web_url(&x)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Parse the URL, assuming HTTPS if the scheme is missing (eg. `youtu.be/...`)
+fn parse_url(text: &str) -> Option<Url> {
+    Url::parse(text)
+        .ok()
+        .filter(is_web_url)
+        .or_else(|| Url::parse(&format!("https://{text}")).ok())
+}
+
+/// The link from the payload of a `/start` deep link, which is the base64url encoded link (the
+/// payload can't contain `:` or `/`, HTTPS is assumed without the scheme), or the plain link
+pub fn deep_link_url(payload: &str) -> Option<Url> {
+    let payload = payload.trim();
+    if payload.is_empty() {
+        return None;
+    }
+
+    let decoded = BASE64_URL_SAFE
+        .decode(payload)
+        .ok()
+        .and_then(|x| String::from_utf8(x).ok());
+
+    decoded
+        .and_then(|x| parse_url(x.trim()))
+        .or_else(|| Url::parse(payload).ok())
+        .filter(is_web_url)
+}
+
+/// The payload of a `/start` deep link to the song (see [`deep_link_url`]), `None` if the link
+/// is too long for one
+pub fn deep_link_payload(url: &Url) -> Option<String> {
+    // Leaves more room for the rest of the link
+    let link = url
+        .as_str()
+        .strip_prefix("https://")
+        .unwrap_or(url.as_str());
+    let payload = BASE64_URL_SAFE.encode(link);
+
+    (payload.len() <= MAX_DEEP_LINK_PAYLOAD_LENGTH).then_some(payload)
+}
+
+/// Convert a Spotify URI (eg. `spotify:track:4cOdK2wGLETKBW3PvgPWqT`, as copied from the desktop
+/// app) into a link to the same thing
+fn spotify_uri_to_url(text: &str) -> Option<Url> {
+    let mut parts = text.strip_prefix("spotify:")?.split(':');
+    let (kind, id) = (parts.next()?, parts.next()?);
+    if parts.next().is_some() || id.is_empty() || !id.chars().all(|x| x.is_ascii_alphanumeric()) {
+        return None;
+    }
+
+    Url::parse(&format!("https://open.spotify.com/{kind}/{id}")).ok()
+}
+
+fn is_web_url(url: &Url) -> bool {
+    matches!(url.scheme(), "http" | "https")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spotify_uris() {
+        for (uri, url) in [
+            (
+                "spotify:track:4cOdK2wGLETKBW3PvgPWqT",
+                Some("https://open.spotify.com/track/4cOdK2wGLETKBW3PvgPWqT"),
+            ),
+            (
+                "spotify:album:1DFixLWuPkv3KT3TnV35m3",
+                Some("https://open.spotify.com/album/1DFixLWuPkv3KT3TnV35m3"),
+            ),
+            (
+                "spotify:episode:512ojhOuo1ktJprKbVcKyQ",
+                Some("https://open.spotify.com/episode/512ojhOuo1ktJprKbVcKyQ"),
+            ),
+            ("spotify:track:", None),
+            ("spotify:track", None),
+            ("spotify:track:4cOdK2wGLETKBW3PvgPWqT:extra", None),
+            ("spotify:track:../../evil", None),
+            ("spotify:user:x:playlist:37i9dQZF1DXcBWIGoYBM5M", None),
+            ("spotify", None),
+            (
+                "https://open.spotify.com/track/4cOdK2wGLETKBW3PvgPWqT",
+                None,
+            ),
+        ] {
+            assert_eq!(
+                spotify_uri_to_url(uri).as_ref().map(Url::as_str),
+                url,
+                "{uri}"
+            );
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use once_cell::sync::Lazy;
+use tracing::info;
+
+use crate::config::Config;
+
+static DB: Lazy<sled::Db> = Lazy::new(|| {
+    let path = &Config::global().data_dir;
+    info!(?path, "Opening database");
+
+    sled::open(path).expect("Failed to open database")
+});
+
+/// Persistent key-value storage of the bot
+pub struct Store;
+impl Store {
+    /// Open the named tree (a separate keyspace) of the database
+    pub fn tree(name: &str) -> anyhow::Result<sled::Tree> {
+        Ok(DB.open_tree(name)?)
+    }
+
+    /// A new unique ID, greater than all the previous ones (even across restarts)
+    pub fn generate_id() -> anyhow::Result<u64> {
+        Ok(DB.generate_id()?)
+    }
+}
@@ -0,0 +1,768 @@
+//! Uploads the files of the processed songs to the chat they were requested in
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use teloxide::{
+    prelude::*,
+    types::{InputFile, InputMedia, InputMediaAudio},
+};
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    admin_report::{AdminReport, FailedStage},
+    archive_channel::{ArchiveChannel, ArchivedSong},
+    config::Config,
+    helpers::{archive::Archive, audio_meta::AudioMeta, html, output_dir::OutputDir},
+    lyrics::Lyrics,
+    notifier::Notifier,
+    options::SongOptions,
+    processor::{self, encoding::OutputFormat, ffmpeg::FfmpegProcessor, video::KaraokeVideo},
+    result_sink::{Delivery, ResultSink},
+    song_details::SongDetails,
+    song_job::{analysis_caption, SongRequester},
+};
+
+/// Maximum number of files Telegram allows in a single media group
+const MAX_MEDIA_GROUP_SIZE: usize = 10;
+
+/// Uploads the files to the chat the song was requested in
+pub struct TelegramSink<'a> {
+    pub requester: &'a SongRequester,
+}
+#[async_trait::async_trait]
+impl ResultSink for TelegramSink<'_> {
+    fn is_interactive(&self) -> bool {
+        true
+    }
+
+    async fn deliver(
+        &self,
+        msg: &mut dyn Notifier,
+        delivery: Delivery<'_>,
+    ) -> ResponseResult<Option<Vec<String>>> {
+        let Delivery {
+            files,
+            song,
+            options,
+            song_duration,
+            used_fallback,
+            analysis,
+            ..
+        } = delivery;
+
+        // The instrumental comes before the transposed (or sped up) ones
+        let instrumental = files
+            .iter()
+            .find(|x| {
+                processor::stem_name(x).is_some_and(|x| x == "music" || x == "music-fallback")
+            })
+            .cloned();
+        let caption = analysis.map(|x| analysis_caption(x, msg.lang()));
+
+        let output_paths = files.clone();
+        let file_ids = upload_files(
+            msg,
+            files,
+            song,
+            options.zip,
+            caption.as_deref(),
+            self.requester,
+        )
+        .await?;
+
+        post_to_archive(
+            song,
+            self.requester,
+            options,
+            used_fallback,
+            file_ids.as_deref(),
+        );
+        send_extras(msg, song, instrumental.as_deref(), options, song_duration).await?;
+
+        // Moved only now since the extras (eg. the karaoke video) use the files
+        OutputDir::persist(&output_paths, &song.title, &self.requester.job_id).await;
+
+        Ok(file_ids)
+    }
+}
+
+/// Post the uploaded files of the song to the archive chat (if configured).
+///
+/// Songs uploaded as a zip can't be posted since there are no audio files to reuse.
+fn post_to_archive(
+    song: &SongDetails,
+    requester: &SongRequester,
+    options: SongOptions,
+    used_fallback: bool,
+    file_ids: Option<&[String]>,
+) {
+    let Some(file_ids) = file_ids else {
+        return;
+    };
+
+    ArchiveChannel::post(ArchivedSong {
+        title: song.title.clone(),
+        requester: requester.description.clone(),
+        model: if used_fallback {
+            "fallback".to_string()
+        } else {
+            options.model.to_string()
+        },
+        file_ids: file_ids.to_vec(),
+    });
+}
+
+/// Send already uploaded files in media groups
+pub async fn send_file_ids(
+    msg: &dyn Notifier,
+    file_ids: &[String],
+    caption: Option<&str>,
+) -> ResponseResult<()> {
+    for (i, chunk) in file_ids.chunks(MAX_MEDIA_GROUP_SIZE).enumerate() {
+        let media_group = chunk
+            .iter()
+            .enumerate()
+            .map(|(j, file_id)| {
+                let media = InputMediaAudio::new(InputFile::file_id(file_id));
+                let media = match caption {
+                    Some(caption) if i == 0 && j == 0 => media.caption(caption),
+                    _ => media,
+                };
+
+                InputMedia::Audio(media)
+            })
+            .collect::<Vec<_>>();
+
+        msg.send_audio_group(media_group).await?;
+    }
+
+    Ok(())
+}
+
+/// Upload the files in as few media groups as possible and report the ones that couldn't be
+/// uploaded. A media group that fails to upload doesn't stop the rest from being uploaded.
+///
+/// The files are sent in a single zip instead if `zip` is set or there are too many of them
+/// for a media group, unless the zip is too large.
+///
+/// The `caption` is shown below the first file (or the zip).
+///
+/// Returns the Telegram file IDs of the uploaded files if all of them were uploaded as audio.
+pub async fn upload_files(
+    msg: &mut dyn Notifier,
+    file_paths: Vec<PathBuf>,
+    song: &SongDetails,
+    zip: bool,
+    caption: Option<&str>,
+    requester: &SongRequester,
+) -> ResponseResult<Option<Vec<String>>> {
+    let max_file_size = Config::global().max_payload_size / 10 * 8;
+
+    // Zips aren't cached since the cached files are sent as audio
+    if (zip || file_paths.len() > MAX_MEDIA_GROUP_SIZE)
+        && upload_zip(msg, &file_paths, song, max_file_size, caption).await?
+    {
+        return Ok(None);
+    }
+
+    let file_paths = fit_files_to_size(msg, file_paths, max_file_size).await?;
+    let FileChunks {
+        chunks: file_path_chunks,
+        failed: mut failed_files,
+    } = chunk_files_by_size(file_paths, max_file_size).await;
+
+    trace!("Uploading files");
+    let mut file_ids = vec![];
+    for (i, file_paths) in file_path_chunks.into_iter().enumerate() {
+        trace!(?file_paths, "Uploading files chunk");
+        let mut media_group = vec![];
+        for (j, file_path) in file_paths.iter().enumerate() {
+            let caption = caption.filter(|_| i == 0 && j == 0);
+            media_group.push(audio_media(file_path.clone(), song, caption).await);
+        }
+
+        match msg.send_audio_group(media_group).await {
+            Ok(sent) => {
+                file_ids.extend(
+                    sent.iter()
+                        .filter_map(|x| x.audio())
+                        .map(|x| x.file.id.clone()),
+                );
+                trace!("Files chunk uploaded");
+            }
+            Err(e) => {
+                warn!(?e, "Failed to upload files chunk");
+                AdminReport::job_failed(
+                    FailedStage::Upload,
+                    &song.title,
+                    &requester.description,
+                    &format!("{e:#}"),
+                );
+
+                let reason = e.to_string();
+                failed_files.extend(file_paths.into_iter().map(|x| (x, reason.clone())));
+            }
+        }
+    }
+    trace!("Files uploaded");
+
+    if failed_files.is_empty() {
+        return Ok(Some(file_ids));
+    }
+
+    debug!(?failed_files, "Failed to upload some files");
+    trace!("Generating failed files message");
+    let lang = msg.lang();
+    let failed_files_msg = {
+        let mut msg = lang.text("upload-failed") + "\n\n";
+
+        let total = failed_files.len();
+        for (i, (file, reason)) in failed_files.into_iter().enumerate() {
+            let entry = lang.format(
+                "upload-failed-file",
+                &[
+                    (
+                        "file",
+                        &html::escape_value(file.file_name().unwrap_or_default().to_string_lossy()),
+                    ),
+                    ("reason", &html::escape_value(reason)),
+                ],
+            ) + "\n\n";
+
+            // Leave some room for the note about the files that didn't fit
+            if msg.chars().count() + entry.chars().count() > html::MAX_MESSAGE_LENGTH - 100 {
+                msg += &lang.format("upload-failed-more", &[("count", &(total - i))]);
+                break;
+            }
+
+            msg += &entry;
+        }
+
+        msg
+    };
+    trace!(msg = ?failed_files_msg, "Failed files message generated");
+
+    trace!("Sending failed files message");
+    msg.send_text(failed_files_msg.trim()).await?;
+    trace!("Failed files message sent");
+
+    Ok(None)
+}
+
+/// Send the lyrics (if enabled) and the karaoke video (if requested) below the files
+async fn send_extras(
+    msg: &mut dyn Notifier,
+    song: &SongDetails,
+    instrumental: Option<&Path>,
+    options: SongOptions,
+    song_duration: Option<Duration>,
+) -> ResponseResult<()> {
+    let send_lyrics_text = Config::global().lyrics;
+    if !send_lyrics_text && !options.video {
+        return Ok(());
+    }
+
+    let lyrics = match Lyrics::find(&song.meta, &song.title).await {
+        Ok(x) => x,
+        Err(e) => {
+            debug!(?e, "Failed to look up lyrics");
+            None
+        }
+    };
+    if let Some(lyrics) = &lyrics {
+        info!(artist = lyrics.artist, title = lyrics.title, "Found lyrics");
+    }
+
+    if let (true, Some(lyrics)) = (send_lyrics_text, &lyrics) {
+        send_lyrics(msg, song, lyrics).await?;
+    }
+
+    if options.video {
+        let trim_start = options.trim.map_or(Duration::ZERO, |x| x.start);
+        let synced = lyrics.as_ref().and_then(|x| x.synced.as_deref());
+        send_karaoke_video(msg, song, instrumental, synced, trim_start, song_duration).await?;
+    }
+
+    Ok(())
+}
+
+/// Send the lyrics as an `.lrc` file if they're synced and as text
+async fn send_lyrics(
+    msg: &dyn Notifier,
+    song: &SongDetails,
+    lyrics: &Lyrics,
+) -> ResponseResult<()> {
+    if let Some(synced) = &lyrics.synced {
+        let file_name = format!("{}.lrc", song.title.replace(['/', '\\'], "_"));
+        msg.send_document(InputFile::memory(synced.clone()).file_name(file_name), None)
+            .await?;
+    }
+
+    for text in lyrics.messages() {
+        msg.send_text(text.trim()).await?;
+    }
+
+    Ok(())
+}
+
+/// Render a video of the instrumental with the synced lyrics and send it below the files.
+///
+/// The user is told why if it can't be created, eg. because no synced lyrics were found.
+async fn send_karaoke_video(
+    msg: &mut dyn Notifier,
+    song: &SongDetails,
+    instrumental: Option<&Path>,
+    synced_lyrics: Option<&str>,
+    lyrics_offset: Duration,
+    song_duration: Option<Duration>,
+) -> ResponseResult<()> {
+    let lang = msg.lang();
+    let video = match (instrumental, synced_lyrics) {
+        (None, _) => Err(lang.text("video-no-instrumental")),
+        (_, None) => Err(lang.text("video-no-lyrics")),
+        (Some(instrumental), Some(lrc)) => {
+            msg.update_status(&lang.text("video-creating")).await?;
+
+            let duration = match song_duration {
+                Some(x) => Some(x),
+                None => FfmpegProcessor::duration(instrumental).await.ok(),
+            };
+            let max_size = Config::global().max_payload_size / 10 * 8;
+
+            match duration {
+                Some(duration) => KaraokeVideo::render(
+                    instrumental,
+                    lrc,
+                    &song.title,
+                    lyrics_offset,
+                    duration,
+                    max_size,
+                )
+                .await
+                .map_err(|e| {
+                    warn!(?e, "Failed to render karaoke video");
+                    e.to_string()
+                }),
+                None => Err(lang.text("video-no-duration")),
+            }
+        }
+    };
+
+    let video_path = match video {
+        Ok(x) => x,
+        Err(reason) => {
+            return msg
+                .send_text(&lang.format("video-failed", &[("reason", &html::escape_value(reason))]))
+                .await;
+        }
+    };
+
+    msg.update_status(&lang.text("video-uploading")).await?;
+    let caption = lang.format(
+        "video-caption",
+        &[("title", &html::escape_value(&song.title))],
+    );
+    msg.send_video(InputFile::file(video_path), &caption).await
+}
+
+/// Package the files into a single zip and upload it as a document.
+///
+/// Returns whether the zip was uploaded. It isn't if it's larger than `max_size`.
+async fn upload_zip(
+    msg: &mut dyn Notifier,
+    file_paths: &[PathBuf],
+    song: &SongDetails,
+    max_size: u64,
+    caption: Option<&str>,
+) -> ResponseResult<bool> {
+    let Some(dir) = file_paths.first().and_then(|x| x.parent()) else {
+        return Ok(false);
+    };
+
+    let lang = msg.lang();
+    msg.update_status(&lang.text("zip-packaging")).await?;
+
+    let file_name = song.title.replace(['/', '\\'], "_");
+    let zip_path = dir.join(format!("{file_name}.zip"));
+    if let Err(e) = Archive::create_zip(&zip_path, file_paths).await {
+        warn!(?e, "Failed to create zip");
+        return Ok(false);
+    }
+
+    let size = tokio::fs::metadata(&zip_path)
+        .await
+        .map_or(u64::MAX, |x| x.len());
+    if size > max_size {
+        debug!(
+            ?size,
+            ?max_size,
+            "Zip is too large, uploading the files separately"
+        );
+        return Ok(false);
+    }
+
+    msg.update_status(&lang.text("zip-uploading")).await?;
+    trace!(?zip_path, "Uploading zip");
+    msg.send_document(InputFile::file(zip_path), caption)
+        .await?;
+    trace!("Zip uploaded");
+
+    Ok(true)
+}
+
+/// Build the uploaded audio with the song metadata, eg. titled "Song Name (instrumental)"
+async fn audio_media(file_path: PathBuf, song: &SongDetails, caption: Option<&str>) -> InputMedia {
+    let title = processor::stem_label(&file_path).map_or_else(
+        || song.title.clone(),
+        |label| format!("{} ({label})", song.title),
+    );
+    let title = match file_part_number(&file_path) {
+        Some(part) => format!("{title} [part {part}]"),
+        None => title,
+    };
+
+    let duration = AudioMeta::probe(&file_path)
+        .await
+        .ok()
+        .and_then(|x| x.duration)
+        .and_then(|x| u16::try_from(x.as_secs()).ok());
+
+    let mut media = InputMediaAudio::new(InputFile::file(file_path)).title(title);
+    if let Some(performer) = &song.meta.artist {
+        media = media.performer(performer);
+    }
+    if let Some(duration) = duration {
+        media = media.duration(duration);
+    }
+    if let Some(thumbnail) = song.cover.as_ref().and_then(|x| x.thumbnail.as_ref()) {
+        media = media.thumb(InputFile::file(thumbnail));
+    }
+    if let Some(caption) = caption {
+        media = media.caption(caption);
+    }
+
+    InputMedia::Audio(media)
+}
+
+/// Part number of a file that was split into parts, eg. `2` for `song.music.part2.mp3`
+fn file_part_number(file_path: &Path) -> Option<u32> {
+    file_path
+        .file_stem()?
+        .to_string_lossy()
+        .rsplit_once(".part")
+        .and_then(|(_, x)| x.parse().ok())
+}
+
+/// Re-encode (or split) files that are larger than `max_size` so they can be uploaded.
+///
+/// Files that can't be made to fit are returned as-is.
+#[tracing::instrument(skip(msg))]
+async fn fit_files_to_size(
+    msg: &mut dyn Notifier,
+    files: Vec<PathBuf>,
+    max_size: u64,
+) -> ResponseResult<Vec<PathBuf>> {
+    let mut res = vec![];
+    let mut notified = false;
+
+    for file in files {
+        let size = tokio::fs::metadata(&file)
+            .await
+            .map(|x| x.len())
+            .unwrap_or_default();
+
+        // Lossless files would have to be split into a lot of parts, so they're reported as too
+        // large instead
+        if size <= max_size || OutputFormat::is_lossless_file(&file) {
+            res.push(file);
+            continue;
+        }
+
+        if !notified {
+            msg.update_status(&msg.lang().text("fitting-files")).await?;
+            notified = true;
+        }
+
+        match FfmpegProcessor::fit_to_size(&file, max_size).await {
+            Ok(files) => {
+                trace!(?file, ?files, "Fitted file to size");
+                res.extend(files);
+            }
+            Err(e) => {
+                debug!(?e, ?file, "Failed to fit file to size");
+                res.push(file);
+            }
+        }
+    }
+
+    if notified {
+        msg.update_status(&msg.lang().text("uploading")).await?;
+    }
+
+    Ok(res)
+}
+
+/// Files grouped into media groups, in the order they should be uploaded
+#[derive(Debug, Default)]
+struct FileChunks {
+    chunks: Vec<Vec<PathBuf>>,
+    /// Files that can't be uploaded along with the reason
+    failed: Vec<(PathBuf, String)>,
+}
+
+/// Group the files into chunks which are at most `max_size` bytes large and fit into a media
+/// group.
+///
+/// The order of the files is preserved, a file only starts a new chunk if it doesn't fit into
+/// the current one.
+#[tracing::instrument(skip_all)]
+async fn chunk_files_by_size(files: Vec<PathBuf>, max_size: u64) -> FileChunks {
+    trace!("Calculating file groupings");
+    let sizes = futures::future::join_all(files.iter().map(tokio::fs::metadata)).await;
+
+    let mut res = FileChunks::default();
+    let mut chunks: Vec<Vec<(PathBuf, u64)>> = vec![];
+    let mut chunk_size = 0_u64;
+    for (path, meta) in files.into_iter().zip(sizes) {
+        let size = match meta {
+            Ok(meta) => meta.len(),
+            Err(e) => {
+                trace!(?e, ?path, "Failed to get metadata for file");
+                res.failed
+                    .push((path, "failed to get metadata for file".to_string()));
+                continue;
+            }
+        };
+
+        if size > max_size {
+            trace!(?path, ?size, ?max_size, "File is too large");
+            let reason = if OutputFormat::is_lossless_file(&path) {
+                format!(
+                    "file is too large: {} > {}, try again with format=mp3",
+                    size, max_size
+                )
+            } else {
+                format!("file is too large: {} > {}", size, max_size)
+            };
+            res.failed.push((path, reason));
+            continue;
+        }
+
+        match chunks.last_mut() {
+            Some(chunk) if chunk_size + size <= max_size && chunk.len() < MAX_MEDIA_GROUP_SIZE => {
+                chunk.push((path, size));
+                chunk_size += size;
+            }
+            _ => {
+                chunks.push(vec![(path, size)]);
+                chunk_size = size;
+            }
+        }
+    }
+    avoid_single_file_chunks(&mut chunks, max_size);
+
+    res.chunks = chunks
+        .into_iter()
+        .map(|x| x.into_iter().map(|(path, _)| path).collect())
+        .collect();
+    trace!(chunks = ?res.chunks, failed = ?res.failed, "Got file groupings");
+
+    res
+}
+
+/// Move the last file of the previous chunk into chunks that only contain a single file, if
+/// both chunks still fit into `max_size` and keep at least two files.
+///
+/// Media groups need at least two files, so single files have to be sent separately.
+fn avoid_single_file_chunks(chunks: &mut [Vec<(PathBuf, u64)>], max_size: u64) {
+    for i in 1..chunks.len() {
+        let (before, after) = chunks.split_at_mut(i);
+        let (previous, current) = (&mut before[i - 1], &mut after[0]);
+
+        let Some((_, last_size)) = previous.last() else {
+            continue;
+        };
+        let current_size = current.iter().map(|(_, size)| size).sum::<u64>();
+        if current.len() != 1 || previous.len() <= 2 || current_size + last_size > max_size {
+            continue;
+        }
+
+        if let Some(moved) = previous.pop() {
+            trace!(file = ?moved.0, "Moving file to avoid a chunk with a single file");
+            current.insert(0, moved);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::temp_dir::TempDir;
+
+    /// Chunks of files with the given sizes, as the indices of the files
+    struct Chunked {
+        chunks: Vec<Vec<usize>>,
+        failed: Vec<(usize, String)>,
+    }
+
+    /// Chunk files with the given sizes. Files without a size don't exist.
+    async fn chunk(sizes: &[Option<u64>], extension: &str, max_size: u64) -> Chunked {
+        let dir = TempDir::with_prefix("karaokify-test-chunks-")
+            .await
+            .expect("Temp dir");
+        let files = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, size)| {
+                let path = dir.path().join(format!("{i}.{extension}"));
+                if let Some(size) = size {
+                    std::fs::File::create(&path)
+                        .and_then(|f| f.set_len(*size))
+                        .expect("Test file");
+                }
+                path
+            })
+            .collect::<Vec<_>>();
+
+        let index = |path: &Path| {
+            path.file_stem()
+                .and_then(|x| x.to_str())
+                .and_then(|x| x.parse::<usize>().ok())
+                .expect("Index")
+        };
+        let res = chunk_files_by_size(files, max_size).await;
+
+        Chunked {
+            chunks: res
+                .chunks
+                .iter()
+                .map(|x| x.iter().map(|x| index(x)).collect())
+                .collect(),
+            failed: res
+                .failed
+                .into_iter()
+                .map(|(path, reason)| (index(&path), reason))
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn no_files_no_chunks() {
+        let res = chunk(&[], "mp3", 100).await;
+
+        assert!(res.chunks.is_empty());
+        assert!(res.failed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn files_fit_exactly() {
+        let res = chunk(&[Some(40), Some(60), Some(100)], "mp3", 100).await;
+
+        assert_eq!(res.chunks, [vec![0, 1], vec![2]]);
+        assert!(res.failed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn single_file_chunks_are_avoided() {
+        // The third file would be alone, so the second one joins it
+        let res = chunk(&[Some(30), Some(30), Some(30), Some(50)], "mp3", 100).await;
+        assert_eq!(res.chunks, [vec![0, 1], vec![2, 3]]);
+
+        // Unless that would leave the previous chunk with a single file
+        let res = chunk(&[Some(30), Some(30), Some(50)], "mp3", 100).await;
+        assert_eq!(res.chunks, [vec![0, 1], vec![2]]);
+
+        // Or the files wouldn't fit together
+        let res = chunk(&[Some(20), Some(20), Some(20), Some(90)], "mp3", 100).await;
+        assert_eq!(res.chunks, [vec![0, 1, 2], vec![3]]);
+
+        let res = chunk(&[Some(100)], "mp3", 100).await;
+        assert_eq!(res.chunks, [vec![0]]);
+    }
+
+    #[tokio::test]
+    async fn media_groups_are_limited() {
+        let res = chunk(&[Some(1); 12], "mp3", 100).await;
+        assert_eq!(
+            res.chunks,
+            [(0..10).collect::<Vec<_>>(), (10..12).collect::<Vec<_>>()]
+        );
+
+        let res = chunk(&[Some(1); 11], "mp3", 100).await;
+        assert_eq!(
+            res.chunks,
+            [(0..9).collect::<Vec<_>>(), (9..11).collect::<Vec<_>>()]
+        );
+    }
+
+    #[tokio::test]
+    async fn random_chunks_keep_the_files_in_order() {
+        // xorshift, so the failures are reproducible
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut random = move |max: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % max
+        };
+
+        for _ in 0..200 {
+            let max_size = 50 + random(100);
+            let sizes = (0..random(25))
+                .map(|_| (random(10) != 0).then(|| random(max_size + 20)))
+                .collect::<Vec<_>>();
+            let res = chunk(&sizes, "mp3", max_size).await;
+
+            for chunk in &res.chunks {
+                let size = chunk.iter().filter_map(|&i| sizes[i]).sum::<u64>();
+                assert!(size <= max_size, "{sizes:?} ({max_size}): {chunk:?}");
+                assert!(
+                    (1..=MAX_MEDIA_GROUP_SIZE).contains(&chunk.len()),
+                    "{sizes:?}: {chunk:?}"
+                );
+            }
+
+            let uploaded = res.chunks.concat();
+            let expected = (0..sizes.len())
+                .filter(|&i| sizes[i].is_some_and(|x| x <= max_size))
+                .collect::<Vec<_>>();
+            assert_eq!(uploaded, expected, "{sizes:?} ({max_size})");
+
+            let mut failed = res.failed.iter().map(|(i, _)| *i).collect::<Vec<_>>();
+            failed.sort_unstable();
+            assert_eq!(
+                failed,
+                (0..sizes.len())
+                    .filter(|i| !expected.contains(i))
+                    .collect::<Vec<_>>(),
+                "{sizes:?} ({max_size})"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn files_that_cant_be_uploaded() {
+        let res = chunk(&[Some(10), Some(101), None, Some(10)], "mp3", 100).await;
+        assert_eq!(res.chunks, [vec![0, 3]]);
+        assert_eq!(
+            res.failed,
+            [
+                (1, "file is too large: 101 > 100".to_string()),
+                (2, "failed to get metadata for file".to_string()),
+            ]
+        );
+
+        let res = chunk(&[Some(101)], "flac", 100).await;
+        assert!(res.chunks.is_empty());
+        assert_eq!(
+            res.failed,
+            [(
+                0,
+                "file is too large: 101 > 100, try again with format=mp3".to_string()
+            )]
+        );
+    }
+}
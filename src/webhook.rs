@@ -0,0 +1,196 @@
+//! Receives the updates from Telegram with a webhook instead of polling for them, for hosts
+//! that only allow inbound connections.
+//!
+//! Telegram sends every update to `KARAOKIFY_WEBHOOK_URL`, which has to be forwarded (eg. by a
+//! reverse proxy terminating HTTPS) to `KARAOKIFY_WEBHOOK_ADDR`. Requests without the secret
+//! token (`KARAOKIFY_WEBHOOK_SECRET`) are rejected.
+
+use std::convert::Infallible;
+
+use futures::channel::mpsc;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use teloxide::{
+    prelude::*,
+    stop::{mk_stop_token, StopFlag, StopToken},
+    types::Update,
+    update_listeners::{StatefulListener, UpdateListener},
+};
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+
+use crate::{bot::TeloxideBot, config::WebhookConfig};
+
+/// Header Telegram sends the secret token in
+const SECRET_TOKEN_HEADER: &str = "x-telegram-bot-api-secret-token";
+/// Updates are small, anything much larger isn't one
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+type UpdateSender = mpsc::UnboundedSender<Result<Update, Infallible>>;
+type UpdateReceiver = mpsc::UnboundedReceiver<Result<Update, Infallible>>;
+
+pub struct Webhook;
+impl Webhook {
+    /// Set the webhook and start receiving the updates.
+    ///
+    /// The server stops once the dispatcher stops the listener, the webhook has to be removed
+    /// with [`Self::delete`] after that.
+    pub async fn listen(
+        bot: &TeloxideBot,
+        config: &'static WebhookConfig,
+    ) -> anyhow::Result<impl UpdateListener<Err = Infallible>> {
+        let listener = TcpListener::bind(config.listen_addr).await?;
+
+        bot.set_webhook(config.url.clone())
+            .secret_token(config.secret.clone())
+            .await?;
+        info!(url = %config.url, addr = %config.listen_addr, "Webhook set");
+
+        let (tx, rx) = mpsc::unbounded();
+        let (stop_token, stop_flag) = mk_stop_token();
+        tokio::spawn(Self::serve(listener, config, tx, stop_flag));
+
+        Ok(StatefulListener::new(
+            (rx, stop_token),
+            updates,
+            |state: &mut (UpdateReceiver, StopToken)| state.1.clone(),
+        ))
+    }
+
+    /// Remove the webhook set by [`Self::listen`] so the updates can be polled for again
+    pub async fn delete(bot: &TeloxideBot) {
+        match bot.delete_webhook().await {
+            Ok(_) => info!("Webhook removed"),
+            Err(e) => warn!(?e, "Failed to remove the webhook"),
+        }
+    }
+
+    /// Remove a webhook left over from running in the webhook mode, since Telegram doesn't
+    /// allow polling for the updates while one is set
+    pub async fn delete_stale(bot: &TeloxideBot) {
+        let info = match bot.get_webhook_info().await {
+            Ok(x) => x,
+            Err(e) => {
+                debug!(?e, "Failed to get the webhook info");
+                return;
+            }
+        };
+        let Some(url) = info.url else {
+            return;
+        };
+
+        info!(%url, "Removing a stale webhook to poll for updates");
+        Self::delete(bot).await;
+    }
+
+    async fn serve(
+        listener: TcpListener,
+        config: &'static WebhookConfig,
+        tx: UpdateSender,
+        stop_flag: StopFlag,
+    ) {
+        let mut stopped = stop_flag.clone();
+
+        loop {
+            let stream = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        debug!(?e, "Failed to accept webhook connection");
+                        continue;
+                    }
+                },
+
+                () = &mut stopped => break,
+            };
+
+            let tx = tx.clone();
+            let stop_flag = stop_flag.clone();
+            tokio::spawn(async move {
+                let service = service_fn(|req| {
+                    let tx = tx.clone();
+                    let stop_flag = stop_flag.clone();
+
+                    async move { Ok::<_, Infallible>(Self::respond(req, config, &tx, &stop_flag).await) }
+                });
+
+                let res = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+
+                if let Err(e) = res {
+                    debug!(?e, "Failed to serve webhook connection");
+                }
+            });
+        }
+
+        // Ends the stream of updates, even if Telegram keeps a connection open
+        tx.close_channel();
+        info!("Stopped receiving updates with the webhook");
+    }
+
+    async fn respond(
+        req: Request<Incoming>,
+        config: &WebhookConfig,
+        tx: &UpdateSender,
+        stop_flag: &StopFlag,
+    ) -> Response<Full<Bytes>> {
+        if req.method() != Method::POST || req.uri().path() != config.url.path() {
+            return status_response(StatusCode::NOT_FOUND);
+        }
+
+        let authorized = req
+            .headers()
+            .get(SECRET_TOKEN_HEADER)
+            .is_some_and(|x| x.as_bytes() == config.secret.as_bytes());
+        if !authorized {
+            debug!("Webhook request without the secret token");
+            return status_response(StatusCode::UNAUTHORIZED);
+        }
+
+        // Telegram sends the update again later if it isn't accepted
+        if stop_flag.is_stopped() {
+            return status_response(StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        let body = match Limited::new(req.into_body(), MAX_BODY_SIZE).collect().await {
+            Ok(x) => x.to_bytes(),
+            Err(e) => {
+                debug!(?e, "Failed to read webhook request");
+                return status_response(StatusCode::BAD_REQUEST);
+            }
+        };
+
+        // Updates that can't be parsed are acknowledged anyway, Telegram would only keep
+        // resending them
+        match serde_json::from_slice::<Update>(&body) {
+            Ok(update) => {
+                if tx.unbounded_send(Ok(update)).is_err() {
+                    return status_response(StatusCode::SERVICE_UNAVAILABLE);
+                }
+            }
+            Err(e) => warn!(?e, "Failed to parse update"),
+        }
+
+        status_response(StatusCode::OK)
+    }
+}
+
+/// The stream of the listener's state, a function since closures returning references to
+/// their arguments can't be generic over the lifetime
+const fn updates(state: &mut (UpdateReceiver, StopToken)) -> &mut UpdateReceiver {
+    &mut state.0
+}
+
+fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut res = Response::new(Full::new(Bytes::new()));
+    *res.status_mut() = status;
+
+    res
+}
@@ -0,0 +1,144 @@
+//! Runs `karaokify process` on a song from the fixtures, with a pipeline that doesn't need a
+//! network connection or demucs
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use karaokify::{
+    cli::{self, CliCommand},
+    downloader::DownloadedSong,
+    helpers::{download::DownloadProgress, temp_dir::TempDir},
+    pipeline::{Pipeline, SplitSettings},
+    processor::{demucs::DemucsProgress, encoding::Bitrate},
+    song_request::SongSource,
+};
+use tokio::sync::watch;
+
+/// Copies the song instead of downloading it, and copies it again as each of the stems
+struct FakePipeline;
+#[async_trait::async_trait]
+impl Pipeline for FakePipeline {
+    async fn download(
+        &self,
+        source: &SongSource,
+        download_dir: &Path,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> anyhow::Result<Vec<DownloadedSong>> {
+        let SongSource::Path(song_path) = source else {
+            anyhow::bail!("only files are processed in the tests, got {source:?}");
+        };
+
+        let path = download_dir.join(song_path.file_name().expect("Song has a name"));
+        let size = tokio::fs::copy(song_path, &path).await?;
+        if let Some(progress) = progress {
+            let _ = progress.send(DownloadProgress::File {
+                downloaded: size,
+                total: Some(size),
+            });
+            // Gives the status a chance to be printed before it's replaced
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        Ok(vec![DownloadedSong::from_path(path)])
+    }
+
+    async fn split_into_stems(
+        &self,
+        output_dir: &Path,
+        song_file_path: &Path,
+        _settings: SplitSettings,
+        _progress: Option<&watch::Sender<DemucsProgress>>,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let name = song_file_path
+            .file_stem()
+            .expect("Song has a name")
+            .to_string_lossy();
+
+        // Gives the processing status a chance to be printed too
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut stems = vec![];
+        for stem in ["music", "vocals"] {
+            let path = output_dir.join(format!("{name}.{stem}.mp3"));
+            tokio::fs::copy(song_file_path, &path).await?;
+            stems.push(path);
+        }
+        Ok(stems)
+    }
+
+    async fn remove_center_channel(
+        &self,
+        _output_dir: &Path,
+        _song_file_path: &Path,
+        _bitrate: Bitrate,
+    ) -> anyhow::Result<PathBuf> {
+        anyhow::bail!("splitting never fails in the tests")
+    }
+}
+
+#[tokio::test]
+async fn the_stems_are_written_to_the_directory() {
+    let song_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/process/song.mp3");
+    let dir = TempDir::with_prefix("karaokify-test-process-")
+        .await
+        .expect("Created temp dir");
+    // Doesn't exist yet
+    let out_dir = dir.path().join("out");
+
+    let command = CliCommand::parse(
+        [
+            "process",
+            &song_path.to_string_lossy(),
+            "--out",
+            &out_dir.to_string_lossy(),
+        ]
+        .map(ToString::to_string),
+    );
+    let Ok(CliCommand::Process(args)) = command else {
+        panic!("Expected a process command, got {command:?}");
+    };
+
+    let printed = Arc::new(Mutex::new(vec![]));
+    let code = cli::process(args, &FakePipeline, {
+        let printed = printed.clone();
+        move |status| printed.lock().expect("Lock").push(status.to_string())
+    })
+    .await;
+    let printed = printed.lock().expect("Lock").clone();
+
+    assert_eq!(code, 0, "{printed:#?}");
+
+    let mut files = std::fs::read_dir(&out_dir)
+        .expect("Output dir exists")
+        .map(|x| {
+            x.expect("Dir entry")
+                .file_name()
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    assert_eq!(files, ["song.music.mp3", "song.vocals.mp3"]);
+
+    let song = std::fs::read(&song_path).expect("Read the song");
+    for file in files {
+        let stem = std::fs::read(out_dir.join(&file)).expect("Read the stem");
+        assert!(stem == song, "{file} was changed");
+    }
+
+    assert!(
+        printed.contains(&"Downloading song... 0.1/0.1 MB".to_string()),
+        "{printed:#?}"
+    );
+    assert!(
+        printed.iter().any(|x| x.starts_with("Processing song")),
+        "{printed:#?}"
+    );
+    assert_eq!(
+        printed.last().map(String::as_str),
+        Some("Finished processing song. Writing files...")
+    );
+}